@@ -12,6 +12,8 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -29,6 +31,36 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // Whether the tree is dirty, which commit it is, and when it was built,
+    // see version.rs. Re-run for any source change, so they stay current.
+    let status = Command::new("git").args(["status", "--porcelain"]).output();
+    if let Ok(status) = status {
+        if status.status.success() {
+            let dirty = !status.stdout.is_empty();
+            println!("cargo:rustc-env=AUNISOMA_DIRTY={}", dirty);
+        }
+    }
+    // Which commit, for VERSION_HASH
+    let commit = Command::new("git").args(["rev-parse", "HEAD"]).output();
+    if let Ok(commit) = commit {
+        if commit.status.success() {
+            let commit = String::from_utf8_lossy(&commit.stdout);
+            println!("cargo:rustc-env=AUNISOMA_COMMIT={}", commit.trim());
+        }
+    }
+    let built = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch,
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs())
+            .to_string(),
+    };
+    println!("cargo:rustc-env=AUNISOMA_BUILT={}", built);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
 }
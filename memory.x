@@ -1,6 +1,10 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* The last 6K is the master's macros, see macros.rs, its saved mapping,
+     see saved_mapping.rs, the color correction table, see correction.rs,
+     the radio channel, see flash::get_channel(), and the operating hours
+     log, see hours.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 58K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
 
@@ -0,0 +1,106 @@
+/// How often an active master sends a MasterBeacon
+pub const BEACON_INTERVAL_US: u64 = 500_000;
+
+/// A standby master takes over after missing this many beacons in a row
+pub const MISSED_BEACONS: u64 = 3;
+
+const TAKEOVER_US: u64 = BEACON_INTERVAL_US * MISSED_BEACONS;
+
+/// Whether a master is the one talking to the panels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Role {
+    Active,
+    /// Quiet, while a higher master is heard, or at the start, before any
+    /// has been. `primary` is the master being heard.
+    Standby {
+        primary: Option<u8>,
+    },
+}
+
+/// Decides which of the masters sharing a bus gets to talk to the panels,
+/// so one can stand by for another.
+///
+/// Active masters send a beacon with their priority every BEACON_INTERVAL_US.
+/// A master that hears a beacon from a higher one goes to standby, and sends
+/// nothing until it has missed MISSED_BEACONS of them, when it takes over.
+/// Masters start out in standby, listening for one takeover period, so one
+/// that reboots doesn't talk over the master that took over from it. Two
+/// masters that are both active, like after the bus is reconnected, sort it
+/// out at the first beacon the lower one hears. Equal priorities go to the
+/// higher ID.
+///
+pub struct Arbiter {
+    id: u8,
+    priority: u8,
+    role: Role,
+    /// When a higher master was last heard, or when standby began, in µs
+    heard_at: u64,
+    next_beacon_at: u64,
+}
+
+impl Arbiter {
+    pub fn new(id: u8, priority: u8, now_us: u64) -> Self {
+        Self {
+            id,
+            priority,
+            role: Role::Standby { primary: None },
+            heard_at: now_us,
+            next_beacon_at: now_us,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Notes a beacon from master `from`, which arrived at `now_us`. Beacons
+    /// from lower masters are ignored, they go quiet once they hear ours.
+    pub fn heard(&mut self, from: u8, priority: u8, now_us: u64) {
+        if from == self.id || (priority, from) < (self.priority, self.id) {
+            return;
+        }
+        self.role = Role::Standby {
+            primary: Some(from),
+        };
+        self.heard_at = now_us;
+    }
+
+    /// Takes over if no higher master has been heard for the takeover
+    /// period, and returns the role.
+    pub fn poll(&mut self, now_us: u64) -> Role {
+        if let Role::Standby { .. } = self.role {
+            if now_us.saturating_sub(self.heard_at) >= TAKEOVER_US {
+                self.role = Role::Active;
+                self.next_beacon_at = now_us;
+            }
+        }
+        self.role
+    }
+
+    /// Whether an active master should send a beacon now. If so, the next
+    /// one is due BEACON_INTERVAL_US later.
+    pub fn beacon_due(&mut self, now_us: u64) -> bool {
+        if self.poll(now_us) != Role::Active || now_us < self.next_beacon_at {
+            return false;
+        }
+        self.next_beacon_at = now_us + BEACON_INTERVAL_US;
+        true
+    }
+
+    /// When poll() or beacon_due() next have something to do, in µs.
+    pub fn next_event_at(&self) -> u64 {
+        match self.role {
+            Role::Active => self.next_beacon_at,
+            Role::Standby { .. } => self.heard_at + TAKEOVER_US,
+        }
+    }
+}
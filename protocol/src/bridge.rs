@@ -0,0 +1,67 @@
+use crate::Packet;
+
+/// How many forwarded packets a LoopGuard remembers
+const RECENT: usize = 8;
+
+/// A packet that comes back within this long of being forwarded went around a
+/// loop. The same packet sent again on purpose, like a Reliable message
+/// nobody acknowledged, comes at least 10 ms later.
+pub const LOOP_WINDOW_US: u64 = 5_000;
+
+/// Keeps a bridge from forwarding a packet that it forwarded itself a moment
+/// ago, which happens when a second bridge joins the same radio and bus and
+/// sends it back around.
+///
+/// There's no room in the wire format for a hop count or a bridged flag that
+/// older panels would ignore, since they check every header byte. So packets
+/// are told apart by a hash of their contents, and a copy is only a copy if it
+/// comes back within LOOP_WINDOW_US.
+///
+pub struct LoopGuard {
+    /// Hashes of the packets forwarded last, and when, in µs
+    recent: [Option<(u32, u64)>; RECENT],
+    next: usize,
+}
+
+impl LoopGuard {
+    pub fn new() -> Self {
+        Self {
+            recent: [None; RECENT],
+            next: 0,
+        }
+    }
+
+    /// Whether to forward `packet`, which arrived at `now_us`. If so, it's
+    /// remembered, so the copy that comes back isn't forwarded again.
+    pub fn check(&mut self, packet: &Packet, now_us: u64) -> bool {
+        let hash = hash(packet);
+        let looped = self
+            .recent
+            .iter()
+            .flatten()
+            .any(|&(h, at)| h == hash && now_us.saturating_sub(at) <= LOOP_WINDOW_US);
+        if looped {
+            return false;
+        }
+        self.recent[self.next] = Some((hash, now_us));
+        self.next = (self.next + 1) % RECENT;
+        true
+    }
+}
+
+impl Default for LoopGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FNV-1a over everything that's sent
+fn hash(packet: &Packet) -> u32 {
+    let header = [packet.to.value(), packet.from.value(), packet.tag.into()];
+    header
+        .iter()
+        .chain(&packet.data)
+        .fold(0x811c_9dc5, |h, &b| {
+            (h ^ b as u32).wrapping_mul(0x0100_0193)
+        })
+}
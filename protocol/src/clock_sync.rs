@@ -0,0 +1,74 @@
+/// A sample this far, in ms, from the offset so far means the master or the
+/// panel reset, so the offset starts over from it rather than creeping there
+pub const RESYNC_AFTER_MS: u32 = 1000;
+
+/// Each sample moves the offset this fraction of the way to it, so the jitter
+/// in when a TimeSync is heard is smoothed out
+const SMOOTHING: i32 = 4;
+
+/// Scheduled times further off than this are taken to be in the past, since
+/// they're more likely a clock that wrapped or a bad sync than a real wait
+pub const MAX_SCHEDULE_AHEAD_MS: u32 = 60_000;
+
+/// A panel's idea of the master's clock, from the TimeSync broadcasts, so
+/// panels can act at the same master time, see SetColorAt.
+///
+/// Both clocks are ms counters that wrap. Each TimeSync gives a sample of the
+/// offset between them, taken when it arrived. The first sets the offset, and
+/// each one after moves it a quarter of the way, so one that was held up on
+/// the bus barely counts. The delay every panel sees for the same broadcast
+/// cancels out between panels, which is what matters for them changing
+/// together.
+///
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    /// The master's clock less ours, None until the first TimeSync
+    offset: Option<u32>,
+}
+
+impl ClockSync {
+    pub const fn new() -> Self {
+        Self { offset: None }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.offset.is_some()
+    }
+
+    /// Takes a TimeSync saying the master's clock read `master_ms`, which
+    /// arrived when ours read `local_ms`.
+    pub fn update(&mut self, master_ms: u32, local_ms: u32) {
+        let sample = master_ms.wrapping_sub(local_ms);
+        let offset = match self.offset {
+            Some(offset) => {
+                let diff = sample.wrapping_sub(offset) as i32;
+                if diff.unsigned_abs() > RESYNC_AFTER_MS {
+                    sample
+                } else {
+                    // Rounded, so it settles within a ms of the samples
+                    offset.wrapping_add((diff + SMOOTHING / 2).div_euclid(SMOOTHING) as u32)
+                }
+            }
+            None => sample,
+        };
+        self.offset = Some(offset);
+    }
+
+    /// What our clock reads when the master's reads `master_ms`, or None if
+    /// there's been no TimeSync yet.
+    pub fn to_local(&self, master_ms: u32) -> Option<u32> {
+        Some(master_ms.wrapping_sub(self.offset?))
+    }
+
+    /// How many ms from `local_ms` until the master's clock reads
+    /// `master_ms`: 0 if that's passed or more than MAX_SCHEDULE_AHEAD_MS
+    /// off, and None if there's been no TimeSync yet.
+    pub fn ms_until(&self, master_ms: u32, local_ms: u32) -> Option<u32> {
+        let wait = self.to_local(master_ms)?.wrapping_sub(local_ms);
+        Some(if wait > MAX_SCHEDULE_AHEAD_MS {
+            0
+        } else {
+            wait
+        })
+    }
+}
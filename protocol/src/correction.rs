@@ -0,0 +1,13 @@
+/// A gain of 1, since gains are in 128ths
+pub const UNITY_GAIN: u8 = 0x80;
+
+/// Scales the red, green, and blue of `color` by `gains`, in 128ths, so
+/// strips from different batches can be made to match. Results round to the
+/// nearest step and stop at 255. Unity gains leave the color exactly as it
+/// was, and anything after blue, like white, is left alone.
+pub fn correct_color(color: &mut [u8], gains: [u8; 3]) {
+    for (value, gain) in color.iter_mut().zip(gains) {
+        let scaled = (*value as u16 * gain as u16 + UNITY_GAIN as u16 / 2) / UNITY_GAIN as u16;
+        *value = scaled.min(u8::MAX as u16) as u8;
+    }
+}
@@ -0,0 +1,102 @@
+/// The derating factor when there's none, full brightness
+pub const FULL_OUTPUT: u8 = 255;
+
+/// Derating never takes the output below this, about 40%
+pub const MIN_OUTPUT: u8 = 102;
+
+/// How long the channel takes to warm up or cool down most of the way, in
+/// ticks of one second
+pub const TIME_CONSTANT_SECS: u32 = 600;
+
+/// Once it's derating, the heat has to fall this many points below the
+/// threshold before the output comes back up, so it doesn't hunt
+pub const HYSTERESIS: u8 = 5;
+
+/// How much the output changes per tick, so it fades rather than steps
+const STEP: u8 = 1;
+
+/// A panel's estimate of how hot its strip's channel is getting, and how
+/// much to turn the strip down to keep it from cooking.
+///
+/// Every second, tick() is given the load, the average duty of the strip's
+/// channels from 0 to 255. The heat follows it like an RC circuit with a
+/// time constant of TIME_CONSTANT_SECS, so full white for good reads 100%.
+/// Above the threshold, the output is turned down a little each tick, to no
+/// less than MIN_OUTPUT, and it comes back up the same way once the heat has
+/// fallen HYSTERESIS below it. Off, the heat is still tracked, but the output
+/// stays full.
+///
+#[derive(Debug, Clone)]
+pub struct Derating {
+    /// Percent of the heat at full white, None for off
+    threshold: Option<u8>,
+    /// The load summed over time, with a fraction leaking away each tick
+    heat: u32,
+    output: u8,
+}
+
+impl Derating {
+    pub const fn new() -> Self {
+        Self {
+            threshold: None,
+            heat: 0,
+            output: FULL_OUTPUT,
+        }
+    }
+
+    pub fn threshold(&self) -> Option<u8> {
+        self.threshold
+    }
+
+    /// Turns derating on at `threshold` percent, or off with None. Off puts
+    /// the output straight back to full.
+    pub fn set_threshold(&mut self, threshold: Option<u8>) {
+        self.threshold = threshold;
+        if threshold.is_none() {
+            self.output = FULL_OUTPUT;
+        }
+    }
+
+    /// The factor the strip's colors are scaled by, FULL_OUTPUT for none.
+    pub fn output(&self) -> u8 {
+        self.output
+    }
+
+    /// The estimated heat, as a percent of what full white comes to.
+    pub fn heat_percent(&self) -> u8 {
+        let full = FULL_OUTPUT as u32 * TIME_CONSTANT_SECS;
+        (self.heat * 100 / full).min(100) as u8
+    }
+
+    /// Scales one channel by the output.
+    pub fn apply(&self, value: u8) -> u8 {
+        ((value as u32 * self.output as u32 + 127) / FULL_OUTPUT as u32) as u8
+    }
+
+    /// Moves the model on by a second, with the strip at `load`. Returns true
+    /// if the output changed, so the colors need setting again.
+    pub fn tick(&mut self, load: u8) -> bool {
+        self.heat = self.heat - self.heat / TIME_CONSTANT_SECS + load as u32;
+
+        let Some(threshold) = self.threshold else {
+            return false;
+        };
+        let heat = self.heat_percent();
+        let output = if heat >= threshold {
+            self.output.saturating_sub(STEP).max(MIN_OUTPUT)
+        } else if heat < threshold.saturating_sub(HYSTERESIS) {
+            self.output.saturating_add(STEP)
+        } else {
+            self.output
+        };
+        let changed = output != self.output;
+        self.output = output;
+        changed
+    }
+}
+
+impl Default for Derating {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,50 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// Why a command failed, for hosts that turned on machine replies. The reply
+/// is `-` and the code in decimal, then what's noted here, if anything, after
+/// a space. A code never changes meaning, new ones go on the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// No such command. Older firmware sends it for WrongMode too.
+    UnknownCommand = 1,
+    /// Hex arguments didn't parse. Followed by the column, counting the
+    /// command letter as 1.
+    BadHex = 2,
+    /// An argument isn't one the command takes
+    BadArgument = 3,
+    /// The command doesn't take arguments
+    UnexpectedArgument = 4,
+    /// More slots or panels than fit
+    TooMany = 5,
+    /// Needs panels mapped, or a slot that is
+    NotMapped = 6,
+    /// Saving in flash failed
+    FlashWrite = 7,
+    /// The radio didn't answer over SPI
+    RadioNotResponding = 8,
+    /// Only in the other comm mode
+    WrongCommMode = 9,
+    /// Not in this build
+    Unsupported = 10,
+    /// Panels didn't acknowledge. Followed by their IDs, like `FAILED`.
+    Failed = 11,
+    /// The command was abandoned, like `TIMEOUT`
+    Timeout = 12,
+    /// The reply didn't fit. Followed by how long it would have been, a
+    /// space, and the most that fits.
+    ReplyTooLarge = 13,
+    /// Another master has the panels, like `STANDBY`
+    Standby = 14,
+    /// A command line was too long, and was thrown away without a reply of
+    /// its own
+    LineTooLong = 15,
+    /// A command, but not in the board's current mode
+    WrongMode = 16,
+    /// Panels don't have the slots they were mapped to. Followed by the
+    /// slots, like `MISMATCH`.
+    Mismatch = 17,
+    /// Only from the other command port, serial or USB
+    WrongPort = 18,
+}
@@ -0,0 +1,49 @@
+// Bump when the messages change in a way that older firmware can't follow.
+// Sent in PingReply, so the master can warn about mixed installations.
+// 2: SetColorZones, which older panels ignore
+// 3: Reliable and Ack, which older panels ignore
+// 4: SetTxPower, which older panels ignore
+// 5: PollPirs, which older panels ignore
+// 6: SetDerating, which older panels ignore, and the derating in StatusReply
+// 7: sensor bits 2 and 3 in SetColorReply, which older masters print wrong
+// 8: QuerySlot, which older panels ignore
+// 9: TimeSync and SetColorAt, which older panels ignore
+// 10: the USB flag in StatusReply, which older masters take for no reply
+// 11: MapPanelsPending and CommitMapping, which older panels ignore
+// 12: SetColorDelta, which older panels ignore until the next full frame
+// 13: SetColorNoReply, which older panels ignore
+pub const PROTOCOL_VERSION: u8 = 13;
+
+/// Which firmware a board is running, as sent in PingReply.
+///
+/// Wire format:
+///
+/// [protocol_version, version_hash (2 bytes, little-endian)]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareId {
+    pub protocol: u8,
+    pub hash: u16,
+}
+
+impl FirmwareId {
+    pub const WIRE_LEN: usize = 3;
+
+    /// Reads the start of `bytes`, or None if it's too short, like from
+    /// firmware too old to send one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [protocol, hash_lo, hash_hi, ..] => Some(Self {
+                protocol,
+                hash: u16::from_le_bytes([hash_lo, hash_hi]),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::WIRE_LEN] {
+        let hash = self.hash.to_le_bytes();
+        [self.protocol, hash[0], hash[1]]
+    }
+}
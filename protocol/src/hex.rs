@@ -0,0 +1,106 @@
+use core::fmt;
+
+/// Where hex arguments to a command went wrong.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct HexError {
+    /// Offset into the arguments of the bad character, or their length if
+    /// they ended too soon
+    pub offset: usize,
+    pub problem: HexProblem,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HexProblem {
+    NotHex,
+    /// Spaces and tabs are never allowed between hex digits
+    Whitespace,
+    TooShort,
+    TooLong,
+}
+
+impl fmt::Display for HexProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HexProblem::NotHex => "expected hex digit",
+            HexProblem::Whitespace => "unexpected whitespace",
+            HexProblem::TooShort => "expected more hex digits",
+            HexProblem::TooLong => "too many hex digits",
+        })
+    }
+}
+
+/// Parse two hex digits into a byte. Returns None if the input is not a valid
+/// hex byte. Upper and lower case digits both work.
+pub fn parse_hex_byte(input: &[u8]) -> Option<u8> {
+    match input {
+        &[hi, lo] => Some(hex_digit(hi)? << 4 | hex_digit(lo)?),
+        _ => None,
+    }
+}
+
+/// Parses exactly N bytes, two hex digits each.
+pub fn hex_fields<const N: usize>(args: &[u8]) -> Result<[u8; N], HexError> {
+    let mut bytes = [0; N];
+    let len = parse_into(args, &mut bytes)?;
+    if len < N {
+        return Err(HexError {
+            offset: args.len(),
+            problem: HexProblem::TooShort,
+        });
+    }
+    Ok(bytes)
+}
+
+/// Parses groups of `group_len` bytes, two hex digits each, like the colors
+/// in `L` or the IDs in `M`, into `out`. Returns how many bytes there were,
+/// which may be none. More than fit in `out` is TooLong.
+pub fn hex_groups(args: &[u8], group_len: usize, out: &mut [u8]) -> Result<usize, HexError> {
+    let len = parse_into(args, out)?;
+    if len % group_len != 0 {
+        return Err(HexError {
+            offset: args.len(),
+            problem: HexProblem::TooShort,
+        });
+    }
+    Ok(len)
+}
+
+/// Parses as many bytes as there are in `args`, and returns how many. The
+/// first bad character is reported, from left to right.
+fn parse_into(args: &[u8], out: &mut [u8]) -> Result<usize, HexError> {
+    let mut len = 0;
+    for (i, pair) in args.chunks(2).enumerate() {
+        let offset = i * 2;
+        let Some(slot) = out.get_mut(i) else {
+            return Err(HexError {
+                offset,
+                problem: HexProblem::TooLong,
+            });
+        };
+        let mut byte = 0;
+        for (j, &c) in pair.iter().enumerate() {
+            let digit = hex_digit(c).ok_or(HexError {
+                offset: offset + j,
+                problem: if c.is_ascii_whitespace() {
+                    HexProblem::Whitespace
+                } else {
+                    HexProblem::NotHex
+                },
+            })?;
+            byte = byte << 4 | digit;
+        }
+        if pair.len() < 2 {
+            return Err(HexError {
+                offset: args.len(),
+                problem: HexProblem::TooShort,
+            });
+        }
+        *slot = byte;
+        len += 1;
+    }
+    Ok(len)
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
@@ -0,0 +1,74 @@
+use crate::{Message, Packet};
+
+/// Bytes for each slot in a SetColorDelta, the slot then its RGB
+pub const DELTA_ENTRY_LEN: usize = 4;
+
+/// Finds a slot's colors in a SetColor, SetColorRgbw, SetColorZones, or
+/// SetColorDelta message, or None if the message is too short, or is a
+/// delta that leaves the slot out.
+///
+/// SetColor has 3 bytes per slot, and SetColorRgbw 4. SetColorZones starts
+/// with a u32 (little-endian) with a bit set for each slot whose panel has two
+/// zones. Those slots have 6 bytes, the first zone's RGB then the second's,
+/// and the rest have 3. SetColorDelta only has the slots that changed, in
+/// any order, each as its slot number and RGB.
+///
+pub fn slot_colors(packet: &Packet, slot: usize) -> Option<&[u8]> {
+    let (colors, start, len) = match packet.tag {
+        Message::SetColor => (&packet.data[..], slot * 3, 3),
+        Message::SetColorRgbw => (&packet.data[..], slot * 4, 4),
+        Message::SetColorZones => {
+            let (mask, colors) = packet.data.split_first_chunk::<4>()?;
+            let two_zone_slots = u32::from_le_bytes(*mask);
+            let before = (0..slot)
+                .filter(|&s| has_two_zones(two_zone_slots, s))
+                .count();
+            let len = if has_two_zones(two_zone_slots, slot) {
+                6
+            } else {
+                3
+            };
+            (colors, (slot + before) * 3, len)
+        }
+        Message::SetColorDelta => {
+            let entry = packet
+                .data
+                .chunks_exact(DELTA_ENTRY_LEN)
+                .find(|entry| entry[0] as usize == slot)?;
+            return Some(&entry[1..]);
+        }
+        _ => return None,
+    };
+    colors.get(start..start + len)
+}
+
+/// How many slots a SetColor, SetColorRgbw, or SetColorZones message has
+/// colors for, or None if it doesn't come out even. None for SetColorDelta,
+/// which doesn't say.
+pub fn slot_count(packet: &Packet) -> Option<usize> {
+    match packet.tag {
+        Message::SetColor => (packet.data.len() % 3 == 0).then_some(packet.data.len() / 3),
+        Message::SetColorRgbw => (packet.data.len() % 4 == 0).then_some(packet.data.len() / 4),
+        Message::SetColorZones => {
+            let (mask, colors) = packet.data.split_first_chunk::<4>()?;
+            let two_zone_slots = u32::from_le_bytes(*mask);
+            let mut slots = 0;
+            let mut len = 0;
+            while len < colors.len() {
+                len += if has_two_zones(two_zone_slots, slots) {
+                    6
+                } else {
+                    3
+                };
+                slots += 1;
+            }
+            (len == colors.len()).then_some(slots)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `slot` is marked as having two zones in a SetColorZones mask.
+pub fn has_two_zones(two_zone_slots: u32, slot: usize) -> bool {
+    slot < 32 && two_zone_slots & (1 << slot) != 0
+}
@@ -0,0 +1,49 @@
+//! The parts of the panel protocol that don't need the hardware: packets,
+//! their wire formats, and picking them out of the bus, the messages, the
+//! protocol version and which firmware a board is running, how
+//! colors are laid out in a Set Color message and correcting its colors,
+//! keeping a bridge out of loops, keeping panels' clocks in step with the
+//! master's, weighing RSSI readings, choosing between masters on one bus,
+//! splitting command input into lines and parsing their hex arguments, the
+//! error codes of machine replies, and where a board last panicked.
+//!
+//! The firmware uses this crate for the board, but it also builds for the
+//! machine doing the build, so its tests run there with `cargo test` from
+//! this directory. Turn on the `defmt` feature to log packets with defmt.
+//!
+#![no_std]
+
+mod arbiter;
+mod bridge;
+mod clock_sync;
+mod correction;
+mod derating;
+mod error_code;
+mod firmware_id;
+mod hex;
+mod layout;
+mod line_breaker;
+mod message;
+mod packet;
+mod panic_location;
+mod rssi;
+mod serial_parser;
+
+pub use arbiter::{Arbiter, BEACON_INTERVAL_US, MISSED_BEACONS, Role};
+pub use bridge::{LOOP_WINDOW_US, LoopGuard};
+pub use clock_sync::{ClockSync, MAX_SCHEDULE_AHEAD_MS, RESYNC_AFTER_MS};
+pub use correction::{UNITY_GAIN, correct_color};
+pub use derating::{Derating, FULL_OUTPUT, HYSTERESIS, MIN_OUTPUT, TIME_CONSTANT_SECS};
+pub use error_code::ErrorCode;
+pub use firmware_id::{FirmwareId, PROTOCOL_VERSION};
+pub use hex::{HexError, HexProblem, hex_fields, hex_groups, parse_hex_byte};
+pub use layout::{DELTA_ENTRY_LEN, has_two_zones, slot_colors, slot_count};
+pub use line_breaker::{LineBreaker, LineTooLong};
+pub use message::Message;
+pub use packet::{
+    Address, BROADCAST_ADDRESS, MAX_PAYLOAD_SIZE, MAX_RADIO_FRAME_LEN, MAX_SERIAL_FRAME_LEN,
+    Packet, WireError,
+};
+pub use panic_location::PanicLocation;
+pub use rssi::stronger_rssi;
+pub use serial_parser::SerialParser;
@@ -0,0 +1,275 @@
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Most echo output we'll hold for one call to process(). Echo is for humans
+/// typing, so this only needs to keep up with a keystroke or a short paste.
+const MAX_ECHO_LEN: usize = 64;
+
+/// A line was thrown away for being too long, see LineBreaker::take_too_long().
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LineTooLong;
+
+pub struct LineBreaker<const N: usize> {
+    /// The line being assembled, followed by any input that arrived after the
+    /// end of the previous line and hasn't been looked at yet.
+    buffer: heapless::Vec<u8, N>,
+    /// Longer lines are thrown away. Less than N leaves room for the lines
+    /// behind a long one, when they arrive all at once.
+    max_line_len: usize,
+    line_len: usize,
+    used_prefix: usize,
+    /// A finished line is waiting to be taken
+    ready: bool,
+    discard: bool,
+    /// The line being discarded was garbled, not too long, see discard_line()
+    garbled: bool,
+    /// An over-long line was thrown away
+    too_long: bool,
+    after_cr: bool,
+    echo: bool,
+    echo_buf: heapless::Vec<u8, MAX_ECHO_LEN>,
+}
+
+impl<const N: usize> LineBreaker<N> {
+    pub fn new() -> Self {
+        Self::with_max_line_len(N)
+    }
+
+    /// Holds N bytes of input, but only lines of up to `max_line_len`, for a
+    /// port whose host sends several lines at a time.
+    pub fn with_max_line_len(max_line_len: usize) -> Self {
+        Self {
+            buffer: heapless::Vec::new(),
+            max_line_len: max_line_len.min(N),
+            line_len: 0,
+            used_prefix: 0,
+            ready: false,
+            discard: false,
+            garbled: false,
+            too_long: false,
+            after_cr: false,
+            echo: false,
+            echo_buf: heapless::Vec::new(),
+        }
+    }
+
+    /// Keep calling process() with chunks of input. It returns false if it
+    /// needs more, or true if it found a line, which take_line() returns. A
+    /// line ends with `\r`, `\n`, or `\r\n`, and the line ending is not
+    /// included in the line. Backspace and DEL remove the previous character
+    /// of the line.
+    ///
+    /// Input after the end of the line is kept for the next call, so call
+    /// process() with an empty buf to check for another line before waiting
+    /// for more input.
+    ///
+    /// Works best if buf is at least 2*MAX_PACKET_SIZE. Otherwise it may drop
+    /// the line after an over-long line.
+    ///
+    pub fn process(&mut self, buf: &[u8]) -> bool {
+        // Whatever line we had is gone now
+        self.ready = false;
+        if self.used_prefix > 0 {
+            let len = self.buffer.len();
+            self.buffer.copy_within(self.used_prefix..len, 0);
+            self.buffer.truncate(len - self.used_prefix);
+            self.used_prefix = 0;
+            self.line_len = 0;
+        }
+
+        self.echo_buf.clear();
+
+        // First look at what's left over from last time. The line is edited
+        // in place, and never grows faster than we read, so it can't catch up
+        // with the unexamined input behind it.
+        let mut i = self.line_len;
+        while i < self.buffer.len() {
+            let b = self.buffer[i];
+            i += 1;
+            if self.accept(b) {
+                let len = self.buffer.len();
+                self.buffer.copy_within(i..len, self.line_len);
+                self.buffer.truncate(self.line_len + len - i);
+                return self.finish_line(buf);
+            }
+        }
+
+        // Anything past the end of the line is stale now
+        self.buffer.truncate(self.line_len);
+
+        for (i, &b) in buf.iter().enumerate() {
+            if self.accept(b) {
+                self.buffer.truncate(self.line_len);
+                return self.finish_line(&buf[i + 1..]);
+            }
+        }
+
+        self.buffer.truncate(self.line_len);
+        false
+    }
+
+    /// Returns the line process() found, once. The line stays here until
+    /// then, so it isn't lost if the caller is interrupted before taking it.
+    pub fn take_line(&mut self) -> Option<&[u8]> {
+        if !core::mem::replace(&mut self.ready, false) {
+            return None;
+        }
+        Some(&self.buffer[..self.used_prefix])
+    }
+
+    /// Returns true, once, if process() threw away a line that was too long,
+    /// so the caller can tell whoever sent it.
+    pub fn take_too_long(&mut self) -> bool {
+        core::mem::replace(&mut self.too_long, false)
+    }
+
+    /// Throws away the line being typed, and what follows up to its end,
+    /// for input that was lost or garbled on the way, like a UART read
+    /// error. Even if none of it has arrived yet, the end of the line makes
+    /// no line, so it can't look like an empty one. A line already found is
+    /// kept.
+    pub fn discard_line(&mut self) {
+        // Past a line that's waiting to be taken, it's all the garbled line
+        self.buffer.truncate(self.used_prefix);
+        self.line_len = 0;
+        self.discard = true;
+        self.garbled = true;
+    }
+
+    /// Throws away the lines that have already arrived, and returns how many
+    /// there were. `read` fills `buf` with input that's already waiting, and
+    /// returns how much, or 0 when there's no more. The start of a line
+    /// still being typed is kept. `echo` gets the echo output for what's
+    /// thrown away, and lines too long are dropped like the rest.
+    pub fn drop_waiting(
+        &mut self,
+        buf: &mut [u8],
+        mut read: impl FnMut(&mut [u8]) -> usize,
+        mut echo: impl FnMut(&[u8]),
+    ) -> usize {
+        let mut dropped = 0;
+        let mut n = 0;
+        loop {
+            if self.take_line().is_some() {
+                dropped += 1;
+            }
+
+            let found = self.process(&buf[..n]);
+            if !self.echo_buf.is_empty() {
+                echo(&self.echo_buf);
+            }
+            self.take_too_long();
+            if found {
+                n = 0;
+                continue;
+            }
+
+            n = read(buf);
+            if n == 0 {
+                return dropped;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.line_len = 0;
+        self.used_prefix = 0;
+        self.ready = false;
+        self.discard = false;
+        self.garbled = false;
+        self.too_long = false;
+        self.after_cr = false;
+        self.echo_buf.clear();
+    }
+
+    /// When echo is on, process() collects what a terminal should display
+    /// for the input it was given, available from echo_output().
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+        self.echo_buf.clear();
+    }
+
+    /// Echo output generated by the last call to process().
+    pub fn echo_output(&self) -> &[u8] {
+        &self.echo_buf
+    }
+
+    /// Marks the line complete, saving the rest of the input (the start of the
+    /// next line) behind it.
+    fn finish_line(&mut self, rest: &[u8]) -> bool {
+        if self.buffer.extend_from_slice(rest).is_err() {
+            // We didn't have room for the beginning of the next line, so
+            // discard the rest of it.
+            self.buffer.truncate(self.line_len);
+            self.discard = true;
+        }
+        self.used_prefix = self.line_len;
+        self.ready = true;
+        true
+    }
+
+    /// Handles one byte of input. Returns true if it completed a line.
+    fn accept(&mut self, b: u8) -> bool {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        match b {
+            // Second half of a CRLF
+            b'\n' if after_cr => false,
+            b'\r' | b'\n' => {
+                self.after_cr = b == b'\r';
+                self.echo_bytes(b"\r\n");
+                if self.discard {
+                    // End of an over-long or garbled line, start over with
+                    // the next one
+                    self.discard = false;
+                    self.too_long |= !core::mem::take(&mut self.garbled);
+                    self.line_len = 0;
+                    return false;
+                }
+                true
+            }
+            BACKSPACE | DELETE => {
+                if !self.discard && self.line_len > 0 {
+                    self.line_len -= 1;
+                    self.echo_bytes(b"\x08 \x08");
+                }
+                false
+            }
+            _ => {
+                if self.discard {
+                    return false;
+                }
+                if self.line_len == self.max_line_len {
+                    // Line too long, discard it. Input after it may still be
+                    // in the buffer, so leave that be.
+                    self.line_len = 0;
+                    self.discard = true;
+                    return false;
+                } else if self.line_len < self.buffer.len() {
+                    self.buffer[self.line_len] = b;
+                } else if self.buffer.push(b).is_err() {
+                    // Line too long, discard it
+                    self.buffer.clear();
+                    self.line_len = 0;
+                    self.discard = true;
+                    return false;
+                }
+                self.line_len += 1;
+                self.echo_bytes(&[b]);
+                false
+            }
+        }
+    }
+
+    fn echo_bytes(&mut self, bytes: &[u8]) {
+        if self.echo {
+            let _ = self.echo_buf.extend_from_slice(bytes);
+        }
+    }
+}
+
+impl<const N: usize> Default for LineBreaker<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
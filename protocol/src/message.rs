@@ -0,0 +1,98 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// What a packet is for, the tag byte on the wire. See reply_tag() for what
+/// panels send back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Message {
+    Ping = b'P',
+    SetColor = b'C',
+    SetColorRgbw = b'W',
+    SetColorZones = b'Z',
+    MapPanels = b'M',
+    Reset = b'R',
+    SetStatus = b'S',
+    PirConfig = b'F',
+    SetPirProfile = b'Y',
+    StatusRequest = b'Q',
+    SetBaud = b'B',
+    Announce = b'A',
+    SetColorOrder = b'O',
+    FlashId = b'N',
+    Reliable = b'K',
+    SetTxPower = b'T',
+    SetChannel = b'H',
+    /// Sent by an active master, for the others on the bus, see Arbiter
+    MasterBeacon = b'G',
+    PollPirs = b'U',
+    SetDerating = b'D',
+    /// Asks panels for their slot, without changing anything, see SlotReply
+    QuerySlot = b'V',
+    /// The master's clock, in ms, for panels to keep theirs in step, see
+    /// ClockSync
+    TimeSync = b'J',
+    /// A Set Color message to apply at a time on the master's clock, see
+    /// Packet::to_scheduled()
+    SetColorAt = b'X',
+    /// A mapping for panels to hold until CommitMapping, see MapPanels
+    MapPanelsPending = b'E',
+    /// Switches panels to the mapping they're holding, all at once
+    CommitMapping = b'L',
+    /// Colors for just the slots that changed, see slot_colors(). Lowercase,
+    /// since the capitals are all taken.
+    SetColorDelta = b'd',
+    /// A Set Color message that panels show without answering, for shows
+    /// that don't need the sensors, see Packet::to_no_reply(). Lowercase,
+    /// like SetColorDelta.
+    SetColorNoReply = b's',
+    Test = b'_',
+    PingReply = b'I',
+    SetColorReply = b'c',
+    MapPanelsReply = b'm',
+    StatusReply = b'q',
+    SetBaudReply = b'b',
+    SetChannelReply = b'h',
+    NotMapped = b'n',
+    Ack = b'k',
+    /// The panel's slot, or 0xff if it has none
+    SlotReply = b'v',
+}
+
+impl Message {
+    /// The reply panels send to this message, if it has one.
+    pub fn reply_tag(self) -> Option<Message> {
+        match self {
+            Message::Ping => Some(Message::PingReply),
+            Message::SetColor
+            | Message::SetColorRgbw
+            | Message::SetColorZones
+            | Message::SetColorDelta
+            | Message::SetColorAt
+            | Message::PollPirs => Some(Message::SetColorReply),
+            Message::MapPanels | Message::MapPanelsPending => Some(Message::MapPanelsReply),
+            Message::StatusRequest => Some(Message::StatusReply),
+            Message::SetBaud => Some(Message::SetBaudReply),
+            Message::SetChannel => Some(Message::SetChannelReply),
+            Message::Reliable => Some(Message::Ack),
+            Message::QuerySlot => Some(Message::SlotReply),
+            Message::Test => Some(Message::Test),
+            _ => None,
+        }
+    }
+
+    /// Only ever sent by panels, in reply to the master.
+    pub fn is_reply(self) -> bool {
+        matches!(
+            self,
+            Message::PingReply
+                | Message::SetColorReply
+                | Message::MapPanelsReply
+                | Message::StatusReply
+                | Message::SetBaudReply
+                | Message::SetChannelReply
+                | Message::NotMapped
+                | Message::Ack
+                | Message::SlotReply
+        )
+    }
+}
@@ -0,0 +1,239 @@
+use crate::Message;
+
+pub const MAX_PAYLOAD_SIZE: usize = 61;
+
+/// Longest packet on the panel bus: the header, from, tag, data, and CRC
+pub const MAX_SERIAL_FRAME_LEN: usize = MAX_PAYLOAD_SIZE + 7;
+
+/// Longest packet on the radio: the length, to, from, tag, and data
+pub const MAX_RADIO_FRAME_LEN: usize = MAX_PAYLOAD_SIZE + 4;
+
+/// Stands in for a CRC at the end of a packet on the panel bus, until there's
+/// a real one.
+const SERIAL_CRC: u8 = b'C';
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Address(pub u8);
+
+impl Address {
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+}
+
+pub const BROADCAST_ADDRESS: Address = Address(0xFF);
+
+type PacketData = heapless::Vec<u8, { MAX_PAYLOAD_SIZE }>;
+
+/// Why bytes received aren't a packet.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WireError {
+    /// Doesn't start with 0x55, 0xaa
+    BadHeader,
+    /// The length byte doesn't match the data
+    BadLength,
+    BadTag(u8),
+    BadCrc(u8),
+}
+
+/// Internal representation of a packet
+///
+/// The wire format of a packet is a little goofy because it's
+/// backwards-compatible with the C++ version:
+///
+/// [0x55, 0xaa, to, data_len+2, from, tag, data*, crc]
+///
+/// On the panel bus, 0xaa is XORed with the group, see PanelSerial.
+///
+/// For this struct, only to, from, tag, and data are stored, the rest are calculated
+/// when the packet is serialized. So self.data is:
+///
+/// [to, data_len+2, from, tag, data*]
+///
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Packet {
+    pub from: Address,
+    pub to: Address,
+    pub tag: Message,
+    pub data: PacketData,
+}
+
+impl Packet {
+    pub fn new(from: Address, to: Address, tag: Message) -> Self {
+        Self {
+            from,
+            to,
+            tag,
+            data: PacketData::new(),
+        }
+    }
+
+    pub fn push_data(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data).unwrap();
+    }
+
+    /// Write the packet to a buffer in wire format.
+    ///
+    /// The buffer must be at least MAX_SERIAL_FRAME_LEN bytes long.
+    ///
+    pub fn serial_wire_format<'a>(&self, buf: &'a mut [u8]) -> &'a [u8] {
+        buf[0..6].copy_from_slice(&[
+            0x55,
+            0xaa,
+            self.to.value(),
+            self.data.len() as u8 + 2,
+            self.from.value(),
+            self.tag.into(),
+        ]);
+        buf[6..6 + self.data.len()].copy_from_slice(&self.data);
+        // TODO: calculate crc
+        buf[6 + self.data.len()] = SERIAL_CRC;
+        &buf[..6 + self.data.len() + 1]
+    }
+
+    /// How long a packet on the panel bus is, from its length byte, the
+    /// fourth. None if it's too short or too long to be a packet.
+    pub fn serial_frame_len(len: u8) -> Option<usize> {
+        // len counts from, tag, and data
+        let len = len as usize;
+        (2..=MAX_PAYLOAD_SIZE + 2).contains(&len).then_some(len + 5)
+    }
+
+    /// Reads a whole packet in the format from serial_wire_format(). The
+    /// group has to be taken off the header first.
+    pub fn from_serial_wire_format(buf: &[u8]) -> Result<Packet, WireError> {
+        if !buf.starts_with(&[0x55, 0xaa]) {
+            return Err(WireError::BadHeader);
+        }
+        let [_, _, to, len, from, tag, ref rest @ ..] = buf[..] else {
+            return Err(WireError::BadLength);
+        };
+        if Self::serial_frame_len(len) != Some(buf.len()) {
+            return Err(WireError::BadLength);
+        }
+        let tag = Message::try_from(tag).map_err(|_| WireError::BadTag(tag))?;
+        let (&crc, data) = rest.split_last().ok_or(WireError::BadLength)?;
+        // TODO: real crc check
+        if crc != SERIAL_CRC {
+            return Err(WireError::BadCrc(crc));
+        }
+        let mut packet = Packet::new(Address(from), Address(to), tag);
+        packet.push_data(data);
+        Ok(packet)
+    }
+
+    pub fn radio_wire_format<'a>(&self, buf: &'a mut [u8]) -> &'a [u8] {
+        buf[0..4].copy_from_slice(&[
+            self.data.len() as u8 + 3,
+            self.to.value(),
+            self.from.value(),
+            self.tag.into(),
+        ]);
+        buf[4..4 + self.data.len()].copy_from_slice(&self.data);
+        &buf[..4 + self.data.len()]
+    }
+
+    /// How long a packet from the radio is, from its first byte. None if it's
+    /// too short or too long to be a packet.
+    pub fn radio_frame_len(len: u8) -> Option<usize> {
+        // len counts to, from, tag, and data
+        let len = len as usize;
+        (3..=MAX_PAYLOAD_SIZE + 3).contains(&len).then_some(len + 1)
+    }
+
+    /// Reads a whole packet in the format from radio_wire_format(). The radio
+    /// checks the CRC itself.
+    pub fn from_radio_wire_format(buf: &[u8]) -> Result<Packet, WireError> {
+        let [len, to, from, tag, ref data @ ..] = buf[..] else {
+            return Err(WireError::BadLength);
+        };
+        if Self::radio_frame_len(len) != Some(buf.len()) {
+            return Err(WireError::BadLength);
+        }
+        let tag = Message::try_from(tag).map_err(|_| WireError::BadTag(tag))?;
+        let mut packet = Packet::new(Address(from), Address(to), tag);
+        packet.push_data(data);
+        Ok(packet)
+    }
+
+    /// Wraps the packet in a Reliable message with sequence number `seq`, or
+    /// None if there isn't room for the two extra bytes.
+    pub fn to_reliable(&self, seq: u8) -> Option<Packet> {
+        let mut packet = Packet::new(self.from, self.to, Message::Reliable);
+        packet
+            .data
+            .extend_from_slice(&[seq, self.tag.into()])
+            .ok()?;
+        packet.data.extend_from_slice(&self.data).ok()?;
+        Some(packet)
+    }
+
+    /// Unwraps a Reliable message into its sequence number and the message
+    /// inside, or None if it doesn't hold a message.
+    pub fn from_reliable(&self) -> Option<(u8, Packet)> {
+        let [seq, tag, ref data @ ..] = self.data[..] else {
+            return None;
+        };
+        let mut packet = Packet::new(self.from, self.to, Message::try_from(tag).ok()?);
+        packet.push_data(data);
+        Some((seq, packet))
+    }
+
+    /// Wraps the packet in a SetColorNoReply message, for panels to act on
+    /// without answering, or None if there isn't room for the extra byte.
+    pub fn to_no_reply(&self) -> Option<Packet> {
+        let mut packet = Packet::new(self.from, self.to, Message::SetColorNoReply);
+        packet.data.push(self.tag.into()).ok()?;
+        packet.data.extend_from_slice(&self.data).ok()?;
+        Some(packet)
+    }
+
+    /// Unwraps a SetColorNoReply message into the message inside, or None if
+    /// it doesn't hold a message.
+    pub fn from_no_reply(&self) -> Option<Packet> {
+        let [tag, ref data @ ..] = self.data[..] else {
+            return None;
+        };
+        let mut packet = Packet::new(self.from, self.to, Message::try_from(tag).ok()?);
+        packet.push_data(data);
+        Some(packet)
+    }
+
+    /// Wraps the packet in a SetColorAt message, to be applied when the
+    /// master's clock reads `at` ms, or None if there isn't room for the five
+    /// extra bytes.
+    pub fn to_scheduled(&self, at: u32) -> Option<Packet> {
+        let mut packet = Packet::new(self.from, self.to, Message::SetColorAt);
+        packet.data.extend_from_slice(&at.to_le_bytes()).ok()?;
+        packet.data.push(self.tag.into()).ok()?;
+        packet.data.extend_from_slice(&self.data).ok()?;
+        Some(packet)
+    }
+
+    /// Unwraps a SetColorAt message into the master time it's for and the
+    /// message inside, or None if it doesn't hold a message.
+    pub fn from_scheduled(&self) -> Option<(u32, Packet)> {
+        let [a, b, c, d, tag, ref data @ ..] = self.data[..] else {
+            return None;
+        };
+        let mut packet = Packet::new(self.from, self.to, Message::try_from(tag).ok()?);
+        packet.push_data(data);
+        Some((u32::from_le_bytes([a, b, c, d]), packet))
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Packet {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        let data = self.data.as_slice();
+        defmt::write!(
+            fmt,
+            "({:x} -> {:x}) {:a} {:02x}",
+            self.from.value(),
+            self.to.value(),
+            self.tag as u8 as char,
+            data
+        );
+    }
+}
@@ -0,0 +1,30 @@
+/// Where a board last panicked, kept across the reset that follows, see the
+/// firmware's last_panic module.
+///
+/// The firmware is built with panic_immediate_abort, which leaves the panic
+/// strings and locations out of flash, so a panic is a HardFault at the
+/// aborting instruction, and `pc` is its address. A host finds the source
+/// line with `addr2line -e` on the ELF of the same build, see the `fw` hash.
+/// Other HardFaults, like a bad pointer, are reported the same way.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PanicLocation {
+    pub pc: u32,
+}
+
+impl PanicLocation {
+    /// The address, little-endian, in StatusReply and PingReply
+    pub const WIRE_LEN: usize = 4;
+
+    /// All zeros for no panic, which no real one looks like, since the
+    /// vector table is at address 0.
+    pub fn to_bytes(location: Option<Self>) -> [u8; Self::WIRE_LEN] {
+        location.map_or(0, |l| l.pc).to_le_bytes()
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::WIRE_LEN]) -> Option<Self> {
+        let pc = u32::from_le_bytes(bytes);
+        (pc != 0).then_some(Self { pc })
+    }
+}
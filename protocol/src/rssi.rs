@@ -0,0 +1,9 @@
+/// The stronger of two RSSI readings in dBm, for when a panel replies more
+/// than once to the same message. 0 is no reading, like on the panel bus or
+/// from firmware too old to say, so any real reading beats it.
+pub fn stronger_rssi(a: i8, b: i8) -> i8 {
+    match (a, b) {
+        (0, rssi) | (rssi, 0) => rssi,
+        _ => a.max(b),
+    }
+}
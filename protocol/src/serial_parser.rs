@@ -0,0 +1,158 @@
+use crate::Message;
+use crate::packet::{MAX_SERIAL_FRAME_LEN, Packet, WireError};
+
+/// Bytes up to and including the tag, which every frame has and which can be
+/// checked before waiting for the rest
+const HEADER_LEN: usize = 6;
+
+/// What the bytes held so far look like.
+enum Check {
+    /// The start of a frame, or nothing
+    Partial,
+    /// A frame of this many bytes, with the group still on
+    Whole(usize),
+    /// Not a frame, for this reason
+    Bad(WireError),
+}
+
+/// Picks packets out of the bytes arriving on the panel bus.
+///
+/// Bytes go in with feed(), and packets, or the errors worth logging, come
+/// out. Noise, a bad tag or length, or a frame that fails its CRC only costs
+/// the bytes up to the next 0x55 inside it, which are looked at again as the
+/// start of a frame, so a packet right behind garbage is still found. Nothing
+/// more than one frame is ever held, however long the garbage.
+///
+/// The second header byte is expected XORed with the group, see PanelSerial.
+///
+pub struct SerialParser {
+    group: u8,
+    /// The start of a frame, then, after a packet or an error, bytes that
+    /// haven't been looked at yet
+    buffer: heapless::Vec<u8, MAX_SERIAL_FRAME_LEN>,
+    /// Everything in the buffer has been looked at, and is a partial frame
+    checked: bool,
+}
+
+impl SerialParser {
+    pub const fn new(group: u8) -> Self {
+        Self {
+            group,
+            buffer: heapless::Vec::new(),
+            checked: true,
+        }
+    }
+
+    /// How many bytes are held.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Most bytes to read before the next feed(), so they all fit: enough to
+    /// finish the frame or header so far. 0 when there may be another packet
+    /// or error in what's held, and feed(&[]) should be called first.
+    pub fn wanted(&self) -> usize {
+        let len = self.buffer.len();
+        match self.frame_len() {
+            _ if !self.checked => 0,
+            Some(frame_len) if len >= HEADER_LEN => frame_len - len,
+            _ => HEADER_LEN - len,
+        }
+    }
+
+    /// Takes bytes off the bus, and returns the next packet, or bad tag or
+    /// CRC, found. Bytes past what fits are dropped, so feed no more than
+    /// wanted(). After a packet or error, call again, with no bytes if need
+    /// be, until it returns None.
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<Result<Packet, WireError>> {
+        let room = self.buffer.capacity() - self.buffer.len();
+        let _ = self
+            .buffer
+            .extend_from_slice(&bytes[..bytes.len().min(room)]);
+
+        loop {
+            match self.check() {
+                Check::Partial => {
+                    self.checked = true;
+                    return None;
+                }
+                Check::Whole(len) => {
+                    let mut frame = [0; MAX_SERIAL_FRAME_LEN];
+                    frame[..len].copy_from_slice(&self.buffer[..len]);
+                    frame[1] ^= self.group;
+                    let result = Packet::from_serial_wire_format(&frame[..len]);
+                    match result {
+                        Ok(_) => self.discard(len),
+                        Err(_) => self.resync(),
+                    }
+                    self.checked = false;
+                    return Some(result);
+                }
+                Check::Bad(WireError::BadTag(tag)) => {
+                    self.resync();
+                    self.checked = false;
+                    return Some(Err(WireError::BadTag(tag)));
+                }
+                Check::Bad(_) => self.resync(),
+            }
+        }
+    }
+
+    /// Gives up on the frame being received, for when the rest of it isn't
+    /// coming. What arrived after its start is looked at again by the next
+    /// feed(), in case that's where a packet starts.
+    pub fn cut_short(&mut self) {
+        if !self.buffer.is_empty() {
+            self.resync();
+            self.checked = false;
+        }
+    }
+
+    /// The length of the frame being received, once the header says.
+    fn frame_len(&self) -> Option<usize> {
+        Packet::serial_frame_len(*self.buffer.get(3)?)
+    }
+
+    fn check(&self) -> Check {
+        let buffer = &self.buffer[..];
+        if buffer.first().is_some_and(|&b| b != 0x55)
+            || buffer.get(1).is_some_and(|&b| b != 0xaa ^ self.group)
+        {
+            return Check::Bad(WireError::BadHeader);
+        }
+        if buffer.len() < 4 {
+            return Check::Partial;
+        }
+        let Some(len) = self.frame_len() else {
+            return Check::Bad(WireError::BadLength);
+        };
+        // Checked before the rest arrives, so a bad tag doesn't swallow the
+        // next packet while waiting for the rest of this one
+        if let Some(&tag) = buffer.get(5) {
+            if Message::try_from(tag).is_err() {
+                return Check::Bad(WireError::BadTag(tag));
+            }
+        }
+        if buffer.len() < len {
+            Check::Partial
+        } else {
+            Check::Whole(len)
+        }
+    }
+
+    /// Gives up on the frame at the start, keeping what's after it from the
+    /// next 0x55 on.
+    fn resync(&mut self) {
+        let next = self.buffer[1..]
+            .iter()
+            .position(|&b| b == 0x55)
+            .map_or(self.buffer.len(), |i| i + 1);
+        self.discard(next);
+    }
+
+    fn discard(&mut self, count: usize) {
+        let len = self.buffer.len();
+        self.buffer.copy_within(count.., 0);
+        self.buffer.truncate(len - count);
+    }
+}
@@ -0,0 +1,65 @@
+use aunisoma_protocol::{Arbiter, BEACON_INTERVAL_US, MISSED_BEACONS, Role};
+
+const TAKEOVER: u64 = BEACON_INTERVAL_US * MISSED_BEACONS;
+
+#[test]
+fn listens_before_taking_over() {
+    let mut arbiter = Arbiter::new(1, 10, 0);
+    assert_eq!(arbiter.poll(0), Role::Standby { primary: None });
+    assert!(!arbiter.beacon_due(TAKEOVER - 1));
+    assert!(arbiter.beacon_due(TAKEOVER));
+    assert_eq!(arbiter.role(), Role::Active);
+}
+
+#[test]
+fn beacons_at_the_interval() {
+    let mut arbiter = Arbiter::new(1, 10, 0);
+    assert!(arbiter.beacon_due(TAKEOVER));
+    assert!(!arbiter.beacon_due(TAKEOVER + 1));
+    assert_eq!(arbiter.next_event_at(), TAKEOVER + BEACON_INTERVAL_US);
+    assert!(arbiter.beacon_due(TAKEOVER + BEACON_INTERVAL_US));
+}
+
+#[test]
+fn stands_by_for_a_higher_master() {
+    let mut arbiter = Arbiter::new(1, 10, 0);
+    arbiter.poll(TAKEOVER);
+    arbiter.heard(2, 20, TAKEOVER + 5);
+    assert_eq!(arbiter.role(), Role::Standby { primary: Some(2) });
+    assert!(!arbiter.beacon_due(TAKEOVER + 10));
+
+    // Hearing it keeps it in standby
+    let mut now = TAKEOVER + 5;
+    for _ in 0..10 {
+        now += BEACON_INTERVAL_US;
+        arbiter.heard(2, 20, now);
+        assert!(!arbiter.beacon_due(now + 1));
+    }
+
+    // Until it goes quiet
+    assert_eq!(arbiter.next_event_at(), now + TAKEOVER);
+    assert!(!arbiter.beacon_due(now + TAKEOVER - 1));
+    assert!(arbiter.beacon_due(now + TAKEOVER));
+}
+
+#[test]
+fn ignores_lower_masters_and_itself() {
+    let mut arbiter = Arbiter::new(5, 10, 0);
+    arbiter.poll(TAKEOVER);
+    arbiter.heard(2, 9, TAKEOVER);
+    arbiter.heard(5, 10, TAKEOVER);
+    assert_eq!(arbiter.role(), Role::Active);
+}
+
+#[test]
+fn higher_id_breaks_ties() {
+    let mut low = Arbiter::new(3, 10, 0);
+    let mut high = Arbiter::new(4, 10, 0);
+    low.poll(TAKEOVER);
+    high.poll(TAKEOVER);
+    // Both active, like after a partition heals
+    low.heard(4, 10, TAKEOVER + 1);
+    high.heard(3, 10, TAKEOVER + 1);
+    assert_eq!(low.role(), Role::Standby { primary: Some(4) });
+    assert_eq!(high.role(), Role::Active);
+}
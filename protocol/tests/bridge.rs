@@ -0,0 +1,43 @@
+use aunisoma_protocol::{Address, BROADCAST_ADDRESS, LOOP_WINDOW_US, LoopGuard, Message, Packet};
+
+fn ping(from: u8) -> Packet {
+    Packet::new(Address(from), BROADCAST_ADDRESS, Message::Ping)
+}
+
+#[test]
+fn copy_within_window_is_dropped() {
+    let mut guard = LoopGuard::new();
+    assert!(guard.check(&ping(1), 1_000));
+    assert!(!guard.check(&ping(1), 1_000 + LOOP_WINDOW_US));
+}
+
+#[test]
+fn same_packet_later_is_forwarded() {
+    let mut guard = LoopGuard::new();
+    assert!(guard.check(&ping(1), 1_000));
+    assert!(guard.check(&ping(1), 1_001 + LOOP_WINDOW_US));
+}
+
+#[test]
+fn different_packets_are_forwarded() {
+    let mut guard = LoopGuard::new();
+    assert!(guard.check(&ping(1), 1_000));
+    assert!(guard.check(&ping(2), 1_000));
+
+    let mut color = Packet::new(Address(1), BROADCAST_ADDRESS, Message::SetColor);
+    color.push_data(&[1, 2, 3]);
+    assert!(guard.check(&color, 1_000));
+    color.data[2] = 4;
+    assert!(guard.check(&color, 1_000));
+}
+
+#[test]
+fn oldest_is_forgotten() {
+    let mut guard = LoopGuard::new();
+    for from in 0..9 {
+        assert!(guard.check(&ping(from), 1_000));
+    }
+    // Pushed out by the ninth
+    assert!(guard.check(&ping(0), 1_000));
+    assert!(!guard.check(&ping(8), 1_000));
+}
@@ -0,0 +1,71 @@
+use aunisoma_protocol::{ClockSync, MAX_SCHEDULE_AHEAD_MS, RESYNC_AFTER_MS};
+
+#[test]
+fn not_synced_until_the_first_sample() {
+    let mut clock = ClockSync::new();
+    assert!(!clock.is_synced());
+    assert_eq!(clock.to_local(1000), None);
+    assert_eq!(clock.ms_until(1000, 0), None);
+
+    clock.update(5000, 200);
+    assert!(clock.is_synced());
+    assert_eq!(clock.to_local(5000), Some(200));
+    assert_eq!(clock.ms_until(5100, 200), Some(100));
+}
+
+#[test]
+fn jitter_is_smoothed_out() {
+    let mut clock = ClockSync::new();
+    clock.update(10_000, 0);
+    // One held up by 8 ms only moves it a quarter of the way
+    clock.update(11_000, 1008);
+    assert_eq!(clock.to_local(12_000), Some(2002));
+    // And it settles back once they're on time again
+    for second in 2..10 {
+        clock.update(10_000 + second * 1000, second * 1000);
+    }
+    let local = clock.to_local(20_000).unwrap();
+    assert!(local.abs_diff(10_000) <= 1, "{local}");
+}
+
+#[test]
+fn settles_within_a_ms_of_the_samples() {
+    let mut clock = ClockSync::new();
+    clock.update(0, 0);
+    for second in 1..20 {
+        clock.update(second * 1000 + 3, second * 1000);
+    }
+    let local = clock.to_local(30_003).unwrap();
+    assert!(local.abs_diff(30_000) <= 1, "{local}");
+}
+
+#[test]
+fn a_big_jump_resyncs() {
+    let mut clock = ClockSync::new();
+    clock.update(50_000, 1000);
+    // The master reset
+    clock.update(100 + RESYNC_AFTER_MS, 2000 + RESYNC_AFTER_MS);
+    assert_eq!(clock.to_local(100), Some(2000));
+}
+
+#[test]
+fn wraps_around() {
+    let mut clock = ClockSync::new();
+    clock.update(u32::MAX - 10, 100);
+    assert_eq!(clock.to_local(20), Some(131));
+    clock.update(30, 142);
+    assert_eq!(clock.to_local(30), Some(141));
+    assert_eq!(clock.ms_until(5, u32::MAX - 10), Some(127));
+}
+
+#[test]
+fn past_and_far_off_times_are_now() {
+    let mut clock = ClockSync::new();
+    clock.update(1000, 1000);
+    assert_eq!(clock.ms_until(900, 1000), Some(0));
+    assert_eq!(
+        clock.ms_until(1000 + MAX_SCHEDULE_AHEAD_MS, 1000),
+        Some(MAX_SCHEDULE_AHEAD_MS)
+    );
+    assert_eq!(clock.ms_until(1001 + MAX_SCHEDULE_AHEAD_MS, 1000), Some(0));
+}
@@ -0,0 +1,32 @@
+use aunisoma_protocol::{UNITY_GAIN, correct_color};
+
+#[test]
+fn unity_is_exact() {
+    for value in 0..=u8::MAX {
+        let mut color = [value, value, value];
+        correct_color(&mut color, [UNITY_GAIN; 3]);
+        assert_eq!(color, [value; 3]);
+    }
+}
+
+#[test]
+fn saturates() {
+    let mut color = [0xff, 0xc0, 0x10];
+    correct_color(&mut color, [0xff; 3]);
+    assert_eq!(color, [0xff, 0xff, 0x20]);
+}
+
+#[test]
+fn scales_and_rounds() {
+    // Half, three quarters, and off
+    let mut color = [0xff, 0x81, 0x42];
+    correct_color(&mut color, [0x40, 0x60, 0x00]);
+    assert_eq!(color, [0x80, 0x61, 0x00]);
+}
+
+#[test]
+fn white_is_left_alone() {
+    let mut color = [0x10, 0x20, 0x30, 0x40];
+    correct_color(&mut color, [0x00; 3]);
+    assert_eq!(color, [0x00, 0x00, 0x00, 0x40]);
+}
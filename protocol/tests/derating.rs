@@ -0,0 +1,83 @@
+use aunisoma_protocol::{Derating, FULL_OUTPUT, MIN_OUTPUT, TIME_CONSTANT_SECS};
+
+/// Ticks `secs` times at `load`.
+fn run(derating: &mut Derating, load: u8, secs: u32) {
+    for _ in 0..secs {
+        derating.tick(load);
+    }
+}
+
+#[test]
+fn heat_settles_at_the_load() {
+    let mut derating = Derating::new();
+    run(&mut derating, 255, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.heat_percent(), 100);
+    run(&mut derating, 128, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.heat_percent(), 50);
+}
+
+#[test]
+fn off_never_derates() {
+    let mut derating = Derating::new();
+    run(&mut derating, 255, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.output(), FULL_OUTPUT);
+    assert_eq!(derating.apply(200), 200);
+}
+
+#[test]
+fn derates_above_the_threshold_but_not_too_far() {
+    let mut derating = Derating::new();
+    derating.set_threshold(Some(50));
+    // Under the threshold for as long as you like
+    run(&mut derating, 100, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.output(), FULL_OUTPUT);
+
+    run(&mut derating, 255, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.output(), MIN_OUTPUT);
+}
+
+#[test]
+fn follows_its_own_output() {
+    let mut derating = Derating::new();
+    derating.set_threshold(Some(60));
+    // Full white, as turned down by the derating, like a panel does it
+    for _ in 0..TIME_CONSTANT_SECS * 20 {
+        let load = derating.apply(255);
+        derating.tick(load);
+    }
+    // Settles where the heat hovers around the threshold
+    let output = derating.output();
+    assert!(output > MIN_OUTPUT && output < FULL_OUTPUT, "{}", output);
+    let heat = derating.heat_percent();
+    assert!((55..=60).contains(&heat), "{}", heat);
+}
+
+#[test]
+fn recovers_once_cool() {
+    let mut derating = Derating::new();
+    derating.set_threshold(Some(50));
+    run(&mut derating, 255, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.output(), MIN_OUTPUT);
+    run(&mut derating, 0, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.output(), FULL_OUTPUT);
+}
+
+#[test]
+fn turning_off_restores_full_output() {
+    let mut derating = Derating::new();
+    derating.set_threshold(Some(30));
+    run(&mut derating, 255, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.output(), MIN_OUTPUT);
+    derating.set_threshold(None);
+    assert_eq!(derating.output(), FULL_OUTPUT);
+}
+
+#[test]
+fn apply_scales_and_rounds() {
+    let mut derating = Derating::new();
+    derating.set_threshold(Some(1));
+    run(&mut derating, 255, TIME_CONSTANT_SECS * 10);
+    assert_eq!(derating.apply(255), MIN_OUTPUT);
+    assert_eq!(derating.apply(0), 0);
+    assert_eq!(derating.apply(100), 40);
+}
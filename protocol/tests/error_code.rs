@@ -0,0 +1,20 @@
+use aunisoma_protocol::ErrorCode;
+
+// Host bindings hard-code these, so they can't move
+#[test]
+fn codes_are_stable() {
+    assert_eq!(u8::from(ErrorCode::UnknownCommand), 1);
+    assert_eq!(u8::from(ErrorCode::BadHex), 2);
+    assert_eq!(u8::from(ErrorCode::Failed), 11);
+    assert_eq!(u8::from(ErrorCode::ReplyTooLarge), 13);
+}
+
+#[test]
+fn codes_round_trip() {
+    for code in 1..=18 {
+        let error = ErrorCode::try_from(code).unwrap();
+        assert_eq!(u8::from(error), code);
+    }
+    assert!(ErrorCode::try_from(0).is_err());
+    assert!(ErrorCode::try_from(19).is_err());
+}
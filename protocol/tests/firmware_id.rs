@@ -0,0 +1,31 @@
+use aunisoma_protocol::{FirmwareId, PROTOCOL_VERSION};
+
+#[test]
+fn round_trip() {
+    let id = FirmwareId {
+        protocol: PROTOCOL_VERSION,
+        hash: 0x3fa2,
+    };
+    let bytes = id.to_bytes();
+    assert_eq!(bytes, [PROTOCOL_VERSION, 0xa2, 0x3f]);
+    assert_eq!(FirmwareId::from_bytes(&bytes), Some(id));
+}
+
+#[test]
+fn reads_the_start_of_a_ping_reply() {
+    // Zones and faults come after it
+    let id = FirmwareId::from_bytes(&[2, 0xa2, 0x3f, 1, 0]);
+    assert_eq!(
+        id,
+        Some(FirmwareId {
+            protocol: 2,
+            hash: 0x3fa2
+        })
+    );
+}
+
+#[test]
+fn too_short_is_none() {
+    assert_eq!(FirmwareId::from_bytes(&[]), None);
+    assert_eq!(FirmwareId::from_bytes(&[PROTOCOL_VERSION, 0xa2]), None);
+}
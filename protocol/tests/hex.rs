@@ -0,0 +1,102 @@
+use aunisoma_protocol::{HexError, HexProblem, hex_fields, hex_groups, parse_hex_byte};
+
+fn error(offset: usize, problem: HexProblem) -> HexError {
+    HexError { offset, problem }
+}
+
+#[test]
+fn either_case() {
+    assert_eq!(parse_hex_byte(b"ab"), Some(0xab));
+    assert_eq!(parse_hex_byte(b"AB"), Some(0xab));
+    assert_eq!(parse_hex_byte(b"aB"), Some(0xab));
+    assert_eq!(hex_fields(b"0aF1"), Ok([0x0a, 0xf1]));
+}
+
+#[test]
+fn parse_hex_byte_needs_two_digits() {
+    assert_eq!(parse_hex_byte(b""), None);
+    assert_eq!(parse_hex_byte(b"a"), None);
+    assert_eq!(parse_hex_byte(b"abc"), None);
+    // from_str_radix would take a sign
+    assert_eq!(parse_hex_byte(b"+f"), None);
+    assert_eq!(parse_hex_byte(b"-1"), None);
+    assert_eq!(parse_hex_byte(b" f"), None);
+}
+
+#[test]
+fn fields() {
+    assert_eq!(hex_fields::<0>(b""), Ok([]));
+    assert_eq!(hex_fields(b"0c818283"), Ok([0x0c, 0x81, 0x82, 0x83]));
+}
+
+#[test]
+fn fields_report_where() {
+    assert_eq!(hex_fields::<2>(b"0c8"), Err(error(3, HexProblem::TooShort)));
+    assert_eq!(hex_fields::<2>(b"0c"), Err(error(2, HexProblem::TooShort)));
+    assert_eq!(
+        hex_fields::<2>(b"0c8182"),
+        Err(error(4, HexProblem::TooLong))
+    );
+    assert_eq!(hex_fields::<2>(b"0cg1"), Err(error(2, HexProblem::NotHex)));
+    assert_eq!(hex_fields::<2>(b"0c1x"), Err(error(3, HexProblem::NotHex)));
+}
+
+#[test]
+fn whitespace_is_rejected() {
+    assert_eq!(
+        hex_fields::<2>(b"0c 81"),
+        Err(error(2, HexProblem::Whitespace))
+    );
+    assert_eq!(
+        hex_fields::<2>(b"0c\t8"),
+        Err(error(2, HexProblem::Whitespace))
+    );
+    assert_eq!(
+        hex_groups(b"01 ", 1, &mut [0; 4]),
+        Err(error(2, HexProblem::Whitespace))
+    );
+}
+
+#[test]
+fn first_problem_wins() {
+    // The bad digit comes before the end
+    assert_eq!(hex_fields::<4>(b"0x1"), Err(error(1, HexProblem::NotHex)));
+    // The bad digit is past what fits
+    assert_eq!(hex_fields::<1>(b"01zz"), Err(error(2, HexProblem::TooLong)));
+}
+
+#[test]
+fn groups() {
+    let mut out = [0; 6];
+    assert_eq!(hex_groups(b"", 3, &mut out), Ok(0));
+    assert_eq!(hex_groups(b"818283717273", 3, &mut out), Ok(6));
+    assert_eq!(out, [0x81, 0x82, 0x83, 0x71, 0x72, 0x73]);
+}
+
+#[test]
+fn partial_group() {
+    let mut out = [0; 6];
+    assert_eq!(
+        hex_groups(b"81828371", 3, &mut out),
+        Err(error(8, HexProblem::TooShort))
+    );
+    assert_eq!(
+        hex_groups(b"8182837", 3, &mut out),
+        Err(error(7, HexProblem::TooShort))
+    );
+}
+
+#[test]
+fn too_many_groups() {
+    let mut out = [0; 3];
+    assert_eq!(
+        hex_groups(b"818283717273", 3, &mut out),
+        Err(error(6, HexProblem::TooLong))
+    );
+}
+
+#[test]
+fn problems_read_well() {
+    let message = format!("ERROR at char {}: {}", 13, HexProblem::NotHex);
+    assert_eq!(message, "ERROR at char 13: expected hex digit");
+}
@@ -0,0 +1,97 @@
+use aunisoma_protocol::{
+    Address, BROADCAST_ADDRESS, DELTA_ENTRY_LEN, Message, Packet, has_two_zones, slot_colors,
+    slot_count,
+};
+
+fn packet(tag: Message, data: &[u8]) -> Packet {
+    let mut packet = Packet::new(Address(0), BROADCAST_ADDRESS, tag);
+    packet.push_data(data);
+    packet
+}
+
+#[test]
+fn set_color_slots() {
+    let packet = packet(Message::SetColor, &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(slot_colors(&packet, 0), Some(&[1, 2, 3][..]));
+    assert_eq!(slot_colors(&packet, 1), Some(&[4, 5, 6][..]));
+    assert_eq!(slot_colors(&packet, 2), None);
+}
+
+#[test]
+fn set_color_rgbw_slots() {
+    let packet = packet(Message::SetColorRgbw, &[1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(slot_colors(&packet, 0), Some(&[1, 2, 3, 4][..]));
+    // Cut short
+    assert_eq!(slot_colors(&packet, 1), None);
+}
+
+#[test]
+fn set_color_zones_slots() {
+    // Slot 1 has two zones
+    let mut data = vec![0b10, 0, 0, 0];
+    data.extend_from_slice(&[1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4]);
+    let packet = packet(Message::SetColorZones, &data);
+    assert_eq!(slot_colors(&packet, 0), Some(&[1, 1, 1][..]));
+    assert_eq!(slot_colors(&packet, 1), Some(&[2, 2, 2, 3, 3, 3][..]));
+    assert_eq!(slot_colors(&packet, 2), Some(&[4, 4, 4][..]));
+    assert_eq!(slot_colors(&packet, 3), None);
+}
+
+#[test]
+fn set_color_zones_needs_the_mask() {
+    let packet = packet(Message::SetColorZones, &[0, 0, 0]);
+    assert_eq!(slot_colors(&packet, 0), None);
+}
+
+#[test]
+fn set_color_delta_slots() {
+    let packet = packet(Message::SetColorDelta, &[5, 1, 2, 3, 0, 4, 5, 6]);
+    assert_eq!(slot_colors(&packet, 0), Some(&[4, 5, 6][..]));
+    assert_eq!(slot_colors(&packet, 5), Some(&[1, 2, 3][..]));
+    // Left out, so it keeps its color
+    assert_eq!(slot_colors(&packet, 1), None);
+    assert_eq!(slot_count(&packet), None);
+}
+
+#[test]
+fn set_color_delta_ignores_a_partial_entry() {
+    let packet = packet(Message::SetColorDelta, &[0, 1, 2, 3, 1, 4, 5]);
+    assert_eq!(packet.data.len() % DELTA_ENTRY_LEN, 3);
+    assert_eq!(slot_colors(&packet, 0), Some(&[1, 2, 3][..]));
+    assert_eq!(slot_colors(&packet, 1), None);
+}
+
+#[test]
+fn other_messages_have_no_colors() {
+    let packet = packet(Message::Ping, &[1, 2, 3]);
+    assert_eq!(slot_colors(&packet, 0), None);
+}
+
+#[test]
+fn two_zone_mask() {
+    assert!(has_two_zones(0b101, 0));
+    assert!(!has_two_zones(0b101, 1));
+    assert!(has_two_zones(0b101, 2));
+    // Only 32 slots fit in the mask
+    assert!(has_two_zones(u32::MAX, 31));
+    assert!(!has_two_zones(u32::MAX, 32));
+}
+
+#[test]
+fn slot_counts() {
+    assert_eq!(slot_count(&packet(Message::SetColor, &[0; 9])), Some(3));
+    assert_eq!(slot_count(&packet(Message::SetColor, &[0; 8])), None);
+    assert_eq!(slot_count(&packet(Message::SetColorRgbw, &[0; 8])), Some(2));
+    assert_eq!(slot_count(&packet(Message::Ping, &[])), None);
+
+    // Slot 1 has two zones
+    let mut data = vec![0b10, 0, 0, 0];
+    data.extend_from_slice(&[0; 12]);
+    assert_eq!(slot_count(&packet(Message::SetColorZones, &data)), Some(3));
+    // Cut off partway through slot 1
+    assert_eq!(
+        slot_count(&packet(Message::SetColorZones, &data[..10])),
+        None
+    );
+    assert_eq!(slot_count(&packet(Message::SetColorZones, &[0, 0])), None);
+}
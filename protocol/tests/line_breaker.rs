@@ -0,0 +1,189 @@
+use aunisoma_protocol::LineBreaker;
+
+/// Feeds `input` in chunks of `chunk` bytes, and collects the lines.
+fn lines<const N: usize>(breaker: &mut LineBreaker<N>, input: &[u8], chunk: usize) -> Vec<Vec<u8>> {
+    let mut lines = Vec::new();
+    for piece in input.chunks(chunk) {
+        let mut found = breaker.process(piece);
+        while found {
+            lines.push(breaker.take_line().unwrap().to_vec());
+            found = breaker.process(&[]);
+        }
+    }
+    lines
+}
+
+#[test]
+fn line_endings() {
+    for input in [&b"P\nJ\n"[..], b"P\rJ\r", b"P\r\nJ\r\n"] {
+        let mut breaker = LineBreaker::<64>::new();
+        assert_eq!(lines(&mut breaker, input, input.len()), [b"P", b"J"]);
+    }
+}
+
+#[test]
+fn crlf_split_across_chunks() {
+    let mut breaker = LineBreaker::<64>::new();
+    assert_eq!(
+        lines(&mut breaker, b"P\r\n\r\nJ\r\n", 1),
+        [&b"P"[..], b"", b"J"]
+    );
+}
+
+#[test]
+fn every_chunk_size_finds_the_same_lines() {
+    let input = b"C0102030405\r\nL\n\nE\rV\r\n";
+    for chunk in 1..=input.len() {
+        let mut breaker = LineBreaker::<64>::new();
+        assert_eq!(
+            lines(&mut breaker, input, chunk),
+            [&b"C0102030405"[..], b"L", b"", b"E", b"V"],
+            "chunk {chunk}"
+        );
+    }
+}
+
+#[test]
+fn take_line_only_once() {
+    let mut breaker = LineBreaker::<64>::new();
+    assert!(breaker.process(b"P\n"));
+    assert_eq!(breaker.take_line(), Some(&b"P"[..]));
+    assert_eq!(breaker.take_line(), None);
+    assert!(!breaker.process(&[]));
+}
+
+#[test]
+fn backspace_and_delete() {
+    let mut breaker = LineBreaker::<64>::new();
+    assert_eq!(
+        lines(&mut breaker, b"PX\x08J\x7fK\n\x08\x08L\n", 3),
+        [&b"PK"[..], b"L"]
+    );
+}
+
+#[test]
+fn too_long_line_is_dropped() {
+    let mut breaker = LineBreaker::<8>::new();
+    assert_eq!(lines(&mut breaker, b"0123456789abcdef\nP\n", 4), [b"P"]);
+    assert!(breaker.take_too_long());
+    assert!(!breaker.take_too_long());
+}
+
+#[test]
+fn line_that_just_fits() {
+    let mut breaker = LineBreaker::<8>::new();
+    assert_eq!(lines(&mut breaker, b"01234567\n", 9), [b"01234567"]);
+    assert!(!breaker.take_too_long());
+}
+
+#[test]
+fn lines_behind_a_too_long_one_are_kept() {
+    let mut breaker = LineBreaker::<32>::with_max_line_len(8);
+    assert_eq!(
+        lines(
+            &mut breaker,
+            b"0123456789abcdef
+P
+J
+",
+            32
+        ),
+        [b"P", b"J"]
+    );
+    assert!(breaker.take_too_long());
+}
+
+#[test]
+fn burst_of_lines_longer_than_max_line_len() {
+    let mut breaker = LineBreaker::<32>::with_max_line_len(8);
+    assert_eq!(
+        lines(
+            &mut breaker,
+            b"01234567
+abcdefgh
+P
+",
+            20
+        ),
+        [&b"01234567"[..], b"abcdefgh", b"P"]
+    );
+    assert!(!breaker.take_too_long());
+}
+
+#[test]
+fn echo() {
+    let mut breaker = LineBreaker::<64>::new();
+    breaker.set_echo(true);
+    assert!(!breaker.process(b"PX\x08"));
+    assert_eq!(breaker.echo_output(), b"PX\x08 \x08");
+    assert!(breaker.process(b"\r"));
+    assert_eq!(breaker.echo_output(), b"\r\n");
+
+    breaker.set_echo(false);
+    assert!(!breaker.process(b"J"));
+    assert_eq!(breaker.echo_output(), b"");
+}
+
+#[test]
+fn reset_forgets_everything() {
+    let mut breaker = LineBreaker::<64>::new();
+    assert!(!breaker.process(b"PART"));
+    breaker.reset();
+    assert_eq!(lines(&mut breaker, b"J\n", 2), [b"J"]);
+}
+
+#[test]
+fn discarded_line_makes_no_line() {
+    let mut breaker = LineBreaker::<64>::new();
+    assert_eq!(lines(&mut breaker, b"R\ng0", 64), [b"R"]);
+    breaker.discard_line();
+    assert_eq!(lines(&mut breaker, b"3\r\nV\r\n", 1), [b"V"]);
+    assert!(!breaker.take_too_long());
+
+    // With nothing of the line in yet, the end of it isn't an empty line
+    breaker.discard_line();
+    assert_eq!(lines(&mut breaker, b"\r\n\r\n", 64), [b""]);
+}
+
+/// Drops what's waiting, with `input` read a few bytes at a time, and
+/// returns how many lines there were and the echo.
+fn drop_waiting<const N: usize>(breaker: &mut LineBreaker<N>, input: &[u8]) -> (usize, Vec<u8>) {
+    let mut rest = input;
+    let mut echoed = Vec::new();
+    let dropped = breaker.drop_waiting(
+        &mut [0; 4],
+        |buf| {
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            len
+        },
+        |echo| echoed.extend_from_slice(echo),
+    );
+    assert!(rest.is_empty());
+    (dropped, echoed)
+}
+
+#[test]
+fn drop_waiting_keeps_the_line_being_typed() {
+    let mut breaker = LineBreaker::<64>::new();
+    // One found but not taken yet, and two more behind it
+    assert!(breaker.process(
+        b"P
+L0102030405
+J
+E"
+    ));
+    assert_eq!(drop_waiting(&mut breaker, b"V"), (3, Vec::new()));
+    assert_eq!(lines(&mut breaker, b"\n", 1), [b"EV"]);
+}
+
+#[test]
+fn drop_waiting_echoes_and_drops_too_long_lines() {
+    let mut breaker = LineBreaker::<8>::new();
+    breaker.set_echo(true);
+    let (dropped, echoed) = drop_waiting(&mut breaker, b"0123456789\nJ\n");
+    assert_eq!(dropped, 1);
+    assert_eq!(echoed, b"01234567\r\nJ\r\n");
+    assert!(!breaker.take_too_long());
+}
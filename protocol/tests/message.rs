@@ -0,0 +1,51 @@
+use aunisoma_protocol::Message;
+
+#[test]
+fn replies_are_replies() {
+    for tag in 0..=u8::MAX {
+        let Ok(message) = Message::try_from(tag) else {
+            continue;
+        };
+        if let Some(reply) = message.reply_tag() {
+            // Test is echoed back as it is
+            assert!(reply.is_reply() || reply == Message::Test, "{message:?}");
+            assert!(!message.is_reply(), "{message:?}");
+        }
+    }
+}
+
+#[test]
+fn query_slot_is_answered() {
+    assert_eq!(Message::QuerySlot.reply_tag(), Some(Message::SlotReply));
+    assert!(Message::SlotReply.is_reply());
+}
+
+#[test]
+fn time_sync_is_not_answered() {
+    assert_eq!(Message::TimeSync.reply_tag(), None);
+    assert_eq!(
+        Message::SetColorAt.reply_tag(),
+        Some(Message::SetColorReply)
+    );
+}
+
+#[test]
+fn announce_is_not_a_reply() {
+    assert_eq!(Message::Announce.reply_tag(), None);
+    assert!(!Message::Announce.is_reply());
+}
+
+#[test]
+fn only_the_panels_in_a_delta_answer_it() {
+    assert_eq!(
+        Message::SetColorDelta.reply_tag(),
+        Some(Message::SetColorReply)
+    );
+    assert!(!Message::SetColorDelta.is_reply());
+}
+
+#[test]
+fn no_reply_frames_are_not_answered() {
+    assert_eq!(Message::SetColorNoReply.reply_tag(), None);
+    assert!(!Message::SetColorNoReply.is_reply());
+}
@@ -0,0 +1,239 @@
+use aunisoma_protocol::{
+    Address, BROADCAST_ADDRESS, MAX_PAYLOAD_SIZE, MAX_RADIO_FRAME_LEN, MAX_SERIAL_FRAME_LEN,
+    Message, Packet, WireError,
+};
+
+fn packet(tag: Message, data: &[u8]) -> Packet {
+    let mut packet = Packet::new(Address(0x12), Address(0x34), tag);
+    packet.push_data(data);
+    packet
+}
+
+#[test]
+fn serial_round_trip() {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    for data in [&[][..], &[1, 2, 3], &[0x55; MAX_PAYLOAD_SIZE]] {
+        let sent = packet(Message::SetColor, data);
+        let wire = sent.serial_wire_format(&mut buf);
+        assert_eq!(Packet::serial_frame_len(wire[3]), Some(wire.len()));
+        assert_eq!(Packet::from_serial_wire_format(wire), Ok(sent));
+    }
+}
+
+#[test]
+fn serial_wire_format_matches_the_c_plus_plus_version() {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    let wire = packet(Message::Ping, &[0xab]).serial_wire_format(&mut buf);
+    assert_eq!(wire, [0x55, 0xaa, 0x34, 3, 0x12, b'P', 0xab, b'C']);
+}
+
+#[test]
+fn radio_round_trip() {
+    let mut buf = [0; MAX_RADIO_FRAME_LEN];
+    for data in [&[][..], &[1, 2, 3], &[0xff; MAX_PAYLOAD_SIZE]] {
+        let sent = packet(Message::StatusReply, data);
+        let wire = sent.radio_wire_format(&mut buf);
+        assert_eq!(Packet::radio_frame_len(wire[0]), Some(wire.len()));
+        assert_eq!(Packet::from_radio_wire_format(wire), Ok(sent));
+    }
+}
+
+#[test]
+fn every_tag_round_trips() {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    for tag in 0..=u8::MAX {
+        let Ok(message) = Message::try_from(tag) else {
+            continue;
+        };
+        let sent = Packet::new(Address(1), BROADCAST_ADDRESS, message);
+        let wire = sent.serial_wire_format(&mut buf);
+        assert_eq!(wire[5], tag);
+        assert_eq!(Packet::from_serial_wire_format(wire), Ok(sent));
+    }
+}
+
+#[test]
+fn serial_rejects_bad_header() {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    let wire = packet(Message::Ping, &[]).serial_wire_format(&mut buf);
+    for i in 0..2 {
+        let mut bad = wire.to_vec();
+        bad[i] ^= 0x01;
+        assert_eq!(
+            Packet::from_serial_wire_format(&bad),
+            Err(WireError::BadHeader)
+        );
+    }
+}
+
+#[test]
+fn serial_rejects_bad_length() {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    let wire = packet(Message::SetColor, &[1, 2, 3]).serial_wire_format(&mut buf);
+
+    // Cut short, or with extra bytes
+    for len in 0..wire.len() {
+        let result = Packet::from_serial_wire_format(&wire[..len]);
+        assert!(
+            matches!(result, Err(WireError::BadLength | WireError::BadHeader)),
+            "len {len}: {result:?}"
+        );
+    }
+    let mut long = wire.to_vec();
+    long.push(0);
+    assert_eq!(
+        Packet::from_serial_wire_format(&long),
+        Err(WireError::BadLength)
+    );
+
+    // Length byte that doesn't match
+    let mut bad = wire.to_vec();
+    bad[3] += 1;
+    assert_eq!(
+        Packet::from_serial_wire_format(&bad),
+        Err(WireError::BadLength)
+    );
+}
+
+#[test]
+fn serial_frame_len_limits() {
+    assert_eq!(Packet::serial_frame_len(0), None);
+    assert_eq!(Packet::serial_frame_len(1), None);
+    assert_eq!(Packet::serial_frame_len(2), Some(7));
+    assert_eq!(
+        Packet::serial_frame_len(MAX_PAYLOAD_SIZE as u8 + 2),
+        Some(MAX_SERIAL_FRAME_LEN)
+    );
+    assert_eq!(Packet::serial_frame_len(MAX_PAYLOAD_SIZE as u8 + 3), None);
+    assert_eq!(Packet::serial_frame_len(0xff), None);
+}
+
+#[test]
+fn serial_rejects_bad_tag() {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    let wire = packet(Message::Ping, &[]).serial_wire_format(&mut buf);
+    let mut bad = wire.to_vec();
+    bad[5] = b'x';
+    assert_eq!(
+        Packet::from_serial_wire_format(&bad),
+        Err(WireError::BadTag(b'x'))
+    );
+}
+
+#[test]
+fn serial_checks_crc() {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    let wire = packet(Message::SetColor, &[1, 2, 3]).serial_wire_format(&mut buf);
+    let crc = *wire.last().unwrap();
+    for wrong in [0, crc ^ 0x01, 0xff] {
+        let mut bad = wire.to_vec();
+        *bad.last_mut().unwrap() = wrong;
+        assert_eq!(
+            Packet::from_serial_wire_format(&bad),
+            Err(WireError::BadCrc(wrong))
+        );
+    }
+}
+
+#[test]
+fn radio_rejects_malformed() {
+    let mut buf = [0; MAX_RADIO_FRAME_LEN];
+    let wire = packet(Message::Ping, &[1, 2]).radio_wire_format(&mut buf);
+
+    for len in 0..wire.len() {
+        assert_eq!(
+            Packet::from_radio_wire_format(&wire[..len]),
+            Err(WireError::BadLength)
+        );
+    }
+    let mut bad = wire.to_vec();
+    bad[3] = 0;
+    assert_eq!(
+        Packet::from_radio_wire_format(&bad),
+        Err(WireError::BadTag(0))
+    );
+
+    assert_eq!(Packet::radio_frame_len(2), None);
+    assert_eq!(Packet::radio_frame_len(3), Some(4));
+    assert_eq!(
+        Packet::radio_frame_len(MAX_PAYLOAD_SIZE as u8 + 3),
+        Some(MAX_RADIO_FRAME_LEN)
+    );
+    assert_eq!(Packet::radio_frame_len(MAX_PAYLOAD_SIZE as u8 + 4), None);
+}
+
+#[test]
+fn reliable_round_trip() {
+    let sent = packet(Message::SetStatus, &[7, 8]);
+    let reliable = sent.to_reliable(42).unwrap();
+    assert_eq!(reliable.tag, Message::Reliable);
+    assert_eq!(reliable.data[..], [42, b'S', 7, 8]);
+    assert_eq!(reliable.from_reliable(), Some((42, sent)));
+}
+
+#[test]
+fn reliable_needs_room_and_a_message() {
+    let full = packet(Message::SetColor, &[0; MAX_PAYLOAD_SIZE - 1]);
+    assert_eq!(full.to_reliable(0), None);
+    let fits = packet(Message::SetColor, &[0; MAX_PAYLOAD_SIZE - 2]);
+    assert!(fits.to_reliable(0).is_some());
+
+    assert_eq!(packet(Message::Reliable, &[1]).from_reliable(), None);
+    assert_eq!(packet(Message::Reliable, &[1, b'x']).from_reliable(), None);
+}
+
+#[test]
+fn scheduled_round_trip() {
+    let sent = packet(Message::SetColor, &[1, 2, 3]);
+    let scheduled = sent.to_scheduled(0x1234_5678).unwrap();
+    assert_eq!(scheduled.tag, Message::SetColorAt);
+    assert_eq!(scheduled.data[..], [0x78, 0x56, 0x34, 0x12, b'C', 1, 2, 3]);
+    assert_eq!(scheduled.from_scheduled(), Some((0x1234_5678, sent)));
+}
+
+#[test]
+fn scheduled_needs_room_and_a_message() {
+    let full = packet(Message::SetColor, &[0; MAX_PAYLOAD_SIZE - 4]);
+    assert_eq!(full.to_scheduled(0), None);
+    let fits = packet(Message::SetColor, &[0; MAX_PAYLOAD_SIZE - 5]);
+    assert!(fits.to_scheduled(0).is_some());
+
+    assert_eq!(
+        packet(Message::SetColorAt, &[1, 2, 3, 4]).from_scheduled(),
+        None
+    );
+    assert_eq!(
+        packet(Message::SetColorAt, &[1, 2, 3, 4, b'x']).from_scheduled(),
+        None
+    );
+}
+
+#[test]
+fn no_reply_round_trip() {
+    let sent = packet(Message::SetColorRgbw, &[1, 2, 3, 4]);
+    let quiet = sent.to_no_reply().unwrap();
+    assert_eq!(quiet.tag, Message::SetColorNoReply);
+    assert_eq!(quiet.data[..], [b'W', 1, 2, 3, 4]);
+    assert_eq!(quiet.from_no_reply(), Some(sent));
+
+    // A scheduled frame can go without replies too
+    let scheduled = packet(Message::SetColor, &[1, 2, 3])
+        .to_scheduled(7)
+        .unwrap();
+    let quiet = scheduled.to_no_reply().unwrap();
+    assert_eq!(quiet.from_no_reply(), Some(scheduled));
+}
+
+#[test]
+fn no_reply_needs_room_and_a_message() {
+    let full = packet(Message::SetColor, &[0; MAX_PAYLOAD_SIZE]);
+    assert_eq!(full.to_no_reply(), None);
+    let fits = packet(Message::SetColor, &[0; MAX_PAYLOAD_SIZE - 1]);
+    assert!(fits.to_no_reply().is_some());
+
+    assert_eq!(packet(Message::SetColorNoReply, &[]).from_no_reply(), None);
+    assert_eq!(
+        packet(Message::SetColorNoReply, &[b'x', 1, 2, 3]).from_no_reply(),
+        None
+    );
+}
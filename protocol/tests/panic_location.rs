@@ -0,0 +1,15 @@
+use aunisoma_protocol::PanicLocation;
+
+#[test]
+fn round_trip() {
+    let location = PanicLocation { pc: 0x0800_3a5c };
+    let bytes = PanicLocation::to_bytes(Some(location));
+    assert_eq!(bytes, [0x5c, 0x3a, 0x00, 0x08]);
+    assert_eq!(PanicLocation::from_bytes(bytes), Some(location));
+}
+
+#[test]
+fn zeros_are_no_panic() {
+    assert_eq!(PanicLocation::to_bytes(None), [0; 4]);
+    assert_eq!(PanicLocation::from_bytes([0; 4]), None);
+}
@@ -0,0 +1,14 @@
+use aunisoma_protocol::stronger_rssi;
+
+#[test]
+fn stronger_wins() {
+    assert_eq!(stronger_rssi(-70, -42), -42);
+    assert_eq!(stronger_rssi(-42, -70), -42);
+}
+
+#[test]
+fn no_reading_loses() {
+    assert_eq!(stronger_rssi(0, -90), -90);
+    assert_eq!(stronger_rssi(-90, 0), -90);
+    assert_eq!(stronger_rssi(0, 0), 0);
+}
@@ -0,0 +1,255 @@
+use aunisoma_protocol::{
+    Address, MAX_PAYLOAD_SIZE, MAX_SERIAL_FRAME_LEN, Message, Packet, SerialParser, WireError,
+};
+
+fn packet(tag: Message, data: &[u8]) -> Packet {
+    let mut packet = Packet::new(Address(0x12), Address(0x34), tag);
+    packet.push_data(data);
+    packet
+}
+
+/// The packet as it goes out on the bus of `group`.
+fn wire(packet: &Packet, group: u8) -> Vec<u8> {
+    let mut buf = [0; MAX_SERIAL_FRAME_LEN];
+    let mut wire = packet.serial_wire_format(&mut buf).to_vec();
+    wire[1] ^= group;
+    wire
+}
+
+/// Feeds the output of feed() back in until it has nothing more, checking
+/// that it never holds or asks for more than a frame.
+fn drain(parser: &mut SerialParser, bytes: &[u8], found: &mut Vec<Result<Packet, WireError>>) {
+    let mut next = parser.feed(bytes);
+    loop {
+        assert!(parser.buffered() <= MAX_SERIAL_FRAME_LEN);
+        assert!(parser.buffered() + parser.wanted() <= MAX_SERIAL_FRAME_LEN);
+        let Some(result) = next else {
+            break;
+        };
+        if let Ok(packet) = &result {
+            assert!(packet.data.len() <= MAX_PAYLOAD_SIZE);
+        }
+        found.push(result);
+        next = parser.feed(&[]);
+    }
+    assert!(parser.wanted() > 0);
+}
+
+/// Feeds `stream` in pieces as big as wanted() asks for, like the firmware.
+fn parse(parser: &mut SerialParser, stream: &[u8]) -> Vec<Result<Packet, WireError>> {
+    let mut found = Vec::new();
+    let mut rest = stream;
+    while !rest.is_empty() {
+        let (piece, after) = rest.split_at(parser.wanted().min(rest.len()));
+        drain(parser, piece, &mut found);
+        rest = after;
+    }
+    found
+}
+
+fn packets(found: &[Result<Packet, WireError>]) -> Vec<Packet> {
+    found.iter().filter_map(|r| r.clone().ok()).collect()
+}
+
+/// xorshift32, so the streams are the same every run
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.next() as usize % n
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.next() as u8
+    }
+}
+
+/// Garbage that looks as much like frames as possible: headers, lengths
+/// out of range or as long as they go, bad tags, bad CRCs, other groups,
+/// and frames cut short.
+fn garbage(rng: &mut Rng, group: u8) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for _ in 0..rng.below(8) {
+        match rng.below(7) {
+            0 => stream.extend((0..rng.below(80)).map(|_| rng.byte())),
+            1 => stream.extend((0..rng.below(20)).map(|_| [0x55, 0xaa ^ group][rng.below(2)])),
+            2 => {
+                let len = [
+                    0,
+                    1,
+                    MAX_PAYLOAD_SIZE as u8 + 2,
+                    MAX_PAYLOAD_SIZE as u8 + 3,
+                    0xff,
+                ];
+                let len = len[rng.below(len.len())];
+                stream.extend([0x55, 0xaa ^ group, 0x34, len, 0x12, b'P']);
+            }
+            3 => stream.extend([0x55, 0xaa ^ group, 0x34, 2, 0x12, 0x00]),
+            4 | 5 => {
+                let data: Vec<u8> = (0..rng.below(MAX_PAYLOAD_SIZE + 1))
+                    .map(|_| rng.byte())
+                    .collect();
+                let mut frame = wire(&packet(Message::SetColor, &data), group);
+                match rng.below(3) {
+                    0 => *frame.last_mut().unwrap() = b'X',
+                    1 => frame[1] ^= 1 + rng.below(255) as u8,
+                    _ => frame.truncate(rng.below(frame.len())),
+                }
+                stream.extend(frame);
+            }
+            _ => stream.extend([0x55; 3]),
+        }
+    }
+    stream
+}
+
+#[test]
+fn packets_back_to_back() {
+    let sent = [
+        packet(Message::Ping, &[]),
+        packet(Message::SetColor, &[0x55, 0xaa, 0x55]),
+        packet(Message::Test, &[0x55; MAX_PAYLOAD_SIZE]),
+    ];
+    for group in [0, 3] {
+        let stream: Vec<u8> = sent.iter().flat_map(|p| wire(p, group)).collect();
+
+        let mut parser = SerialParser::new(group);
+        assert_eq!(packets(&parse(&mut parser, &stream)), sent);
+
+        let mut parser = SerialParser::new(group);
+        let mut found = Vec::new();
+        for &byte in &stream {
+            drain(&mut parser, &[byte], &mut found);
+        }
+        assert_eq!(packets(&found), sent, "group {group}");
+    }
+}
+
+#[test]
+fn bad_tags_and_crcs_are_reported() {
+    let mut stream = vec![0x55, 0xaa, 0x34, 2, 0x12, 0x00];
+    let mut bad_crc = wire(&packet(Message::Ping, &[1]), 0);
+    *bad_crc.last_mut().unwrap() = b'X';
+    stream.extend(bad_crc);
+    stream.extend(wire(&packet(Message::Ping, &[2]), 0));
+
+    let mut parser = SerialParser::new(0);
+    assert_eq!(
+        parse(&mut parser, &stream),
+        [
+            Err(WireError::BadTag(0x00)),
+            Err(WireError::BadCrc(b'X')),
+            Ok(packet(Message::Ping, &[2]))
+        ]
+    );
+}
+
+#[test]
+fn other_groups_are_ignored() {
+    let mut stream = wire(&packet(Message::Ping, &[1]), 5);
+    stream.extend(wire(&packet(Message::Ping, &[2]), 6));
+
+    let mut parser = SerialParser::new(6);
+    assert_eq!(
+        parse(&mut parser, &stream),
+        [Ok(packet(Message::Ping, &[2]))]
+    );
+}
+
+#[test]
+fn packet_inside_a_frame_cut_short() {
+    // A header that wants the longest frame there is, and gets a packet
+    // and some quiet instead
+    let sent = packet(Message::Ping, &[7]);
+    let mut stream = vec![0x55, 0xaa, 0x34, MAX_PAYLOAD_SIZE as u8 + 2, 0x12, b'P'];
+    stream.extend(wire(&sent, 0));
+    stream.resize(MAX_SERIAL_FRAME_LEN + 8, 0);
+
+    let mut parser = SerialParser::new(0);
+    assert_eq!(
+        parse(&mut parser, &stream),
+        [Err(WireError::BadCrc(0)), Ok(sent)]
+    );
+    assert_eq!(parser.buffered(), 0);
+}
+
+#[test]
+fn frame_cut_short() {
+    // The sender went quiet partway through a long frame
+    let mut long = wire(&packet(Message::Test, &[0x55; 40]), 0);
+    long.truncate(30);
+    let ping = packet(Message::Ping, &[]);
+
+    let mut parser = SerialParser::new(0);
+    assert_eq!(parse(&mut parser, &long), []);
+    assert!(parser.buffered() > 0);
+    parser.cut_short();
+    let mut found = Vec::new();
+    drain(&mut parser, &[], &mut found);
+    assert_eq!(found, []);
+    assert_eq!(parse(&mut parser, &wire(&ping, 0)), [Ok(ping)]);
+}
+
+#[test]
+fn random_bytes() {
+    let mut rng = Rng(0x1234_5678);
+    for _ in 0..2000 {
+        let group = rng.below(4) as u8;
+        let stream: Vec<u8> = (0..rng.below(400)).map(|_| rng.byte()).collect();
+        let mut parser = SerialParser::new(group);
+        parse(&mut parser, &stream);
+
+        let mut parser = SerialParser::new(group);
+        let mut found = Vec::new();
+        for &byte in &stream {
+            drain(&mut parser, &[byte], &mut found);
+        }
+    }
+}
+
+#[test]
+fn packet_after_garbage_is_found_within_a_frame() {
+    let mut rng = Rng(0x9e37_79b9);
+    for round in 0..5000 {
+        let group = rng.below(4) as u8;
+        // No stand-in CRCs inside the packet, so a frame the garbage started
+        // can only be taken for a whole one by ending where the packet does
+        let data: Vec<u8> = (0..rng.below(MAX_PAYLOAD_SIZE + 1))
+            .map(|_| rng.byte())
+            .filter(|&b| b != b'C')
+            .collect();
+        let sent = packet(Message::SetColorZones, &data);
+        let frame = wire(&sent, group);
+
+        let mut stream = garbage(&mut rng, group);
+        let start = stream.len();
+        let end = start + frame.len();
+        stream.extend(&frame);
+        // Whatever frame the garbage started is over by here
+        stream.resize(end + MAX_SERIAL_FRAME_LEN - frame.len(), 0);
+
+        let mut parser = SerialParser::new(group);
+        let found = parse(&mut parser, &stream);
+
+        // If the garbage makes a whole frame out of the end of the packet,
+        // it's indistinguishable from one sent that way
+        let swallowed = (0..start).any(|s| {
+            let mut candidate = stream[s..end].to_vec();
+            if candidate.len() > 1 {
+                candidate[1] ^= group;
+            }
+            Packet::from_serial_wire_format(&candidate).is_ok()
+        });
+        assert!(
+            packets(&found).contains(&sent) || swallowed,
+            "round {round}: {stream:02x?}"
+        );
+    }
+}
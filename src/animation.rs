@@ -0,0 +1,110 @@
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+use num_enum::TryFromPrimitive;
+
+use crate::cmd_processor::Message;
+use crate::comm::{Address, BROADCAST_ADDRESS, Packet, PanelComm};
+use crate::pir::PIR_BITS;
+
+const FRAME_TIME: Duration = Duration::from_millis(40);
+
+/// Frames per step of the chase pattern
+const CHASE_FRAMES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Pattern {
+    Rainbow = b'1',
+    White = b'2',
+    Chase = b'3',
+    Pir = b'4',
+}
+
+/// Built-in animations the master can show without a host, to check that the
+/// panels are alive and mapped right.
+///
+pub struct Animation {
+    pattern: Pattern,
+    frame: u32,
+    /// Slots whose panels reported PIR activity on the last frame
+    pir_slots: u32,
+}
+
+impl Animation {
+    pub fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            frame: 0,
+            pir_slots: 0,
+        }
+    }
+
+    /// Sends SetColor frames to the mapped panels forever. Meant to be raced
+    /// against incoming commands.
+    ///
+    pub async fn run(&mut self, comm: &mut PanelComm, from: Address, mapping: &[u8]) -> ! {
+        loop {
+            let frame_deadline = Instant::now() + FRAME_TIME;
+
+            let mut packet = Packet::new(from, BROADCAST_ADDRESS, Message::SetColor);
+            for slot in 0..mapping.len() {
+                packet.push_data(&self.color(slot, mapping.len()));
+            }
+            comm.send_packet(&packet).await;
+
+            self.pir_slots = 0;
+            while let Either::First(reply) =
+                select(comm.recv_packet(), Timer::at(frame_deadline)).await
+            {
+                let motion = reply.data.first().is_some_and(|&pirs| pirs & PIR_BITS != 0);
+                if reply.tag != Message::SetColorReply || !motion {
+                    continue;
+                }
+                if let Some(slot) = mapping.iter().position(|&id| id == reply.from.value()) {
+                    self.pir_slots |= 1 << slot;
+                }
+            }
+
+            self.frame = self.frame.wrapping_add(1);
+        }
+    }
+
+    fn color(&self, slot: usize, num_slots: usize) -> [u8; 3] {
+        match self.pattern {
+            Pattern::Rainbow => {
+                let offset = slot * 256 / num_slots;
+                wheel((self.frame as usize * 2 + offset) as u8)
+            }
+            Pattern::White => [0xff, 0xff, 0xff],
+            Pattern::Chase => {
+                if (self.frame / CHASE_FRAMES) as usize % num_slots == slot {
+                    [0xff, 0xff, 0xff]
+                } else {
+                    [0, 0, 0]
+                }
+            }
+            Pattern::Pir => {
+                if self.pir_slots & (1 << slot) != 0 {
+                    [0xff, 0xff, 0xff]
+                } else {
+                    [0, 0, 0x20]
+                }
+            }
+        }
+    }
+}
+
+/// Maps 0-255 to a color around the color wheel, red to green to blue.
+fn wheel(pos: u8) -> [u8; 3] {
+    match pos {
+        0..=84 => [255 - pos * 3, pos * 3, 0],
+        85..=169 => {
+            let pos = pos - 85;
+            [0, 255 - pos * 3, pos * 3]
+        }
+        _ => {
+            let pos = pos - 170;
+            [pos * 3, 0, 255 - pos * 3]
+        }
+    }
+}
@@ -1,22 +1,44 @@
-use core::sync::atomic::{AtomicU32, Ordering};
+use aunisoma_protocol::Derating;
+use defmt::Format;
 use embassy_stm32::exti::ExtiInput;
 use embassy_stm32::gpio::{Input, Level, Output, OutputType, Pull, Speed};
 use embassy_stm32::peripherals::{self, IWDG};
-use embassy_stm32::peripherals::{SPI1, TIM2, USART1, USART2};
+use embassy_stm32::peripherals::{SPI1, TIM2, TIM4, USART1, USART2};
 use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::GeneralInstance4Channel;
 use embassy_stm32::timer::low_level::CountingMode;
 use embassy_stm32::timer::simple_pwm::{self, PwmPin, SimplePwm, SimplePwmChannel};
 use embassy_stm32::wdg::IndependentWatchdog;
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{Duration, Instant};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 
+use crate::boot;
 use crate::debouncer::Debouncer;
 
+#[cfg(not(any(feature = "rev-d", feature = "rev-e")))]
+compile_error!("Pick a board revision: rev-d, rev-e, or rev-f");
+
+#[cfg(all(feature = "rev-d", feature = "rev-e"))]
+compile_error!(
+    "rev-d and rev-e are different boards. rev-e is the default, so build rev-d with \
+     --no-default-features --features rev-d"
+);
+
+#[cfg(all(feature = "rev-d", feature = "panel-bus"))]
+compile_error!(
+    "rev-d drives blue from PA2, which is the panel bus's USART2 TX, so it can't have the \
+     panel-bus feature. Build it with --no-default-features --features rev-d, for radio only"
+);
+
 pub type DbgUsart = USART1;
 pub type DbgUsartRx = peripherals::PA10;
 pub type DbgUsartTx = peripherals::PA9;
+#[cfg(feature = "panel-bus")]
 pub type PanelBusUsart = USART2;
+#[cfg(feature = "panel-bus")]
 pub type PanelBusUsartTx = peripherals::PA2;
 pub type LedTimer = TIM2;
+pub type Zone2Timer = TIM4;
 pub type RadioSpi = SPI1;
 pub type RadioSck = peripherals::PA5;
 pub type RadioMiso = peripherals::PA6;
@@ -30,15 +52,236 @@ pub struct LedStrip {
     pub red_pwm: SimplePwmChannel<'static, LedTimer>,
     pub green_pwm: SimplePwmChannel<'static, LedTimer>,
     pub blue_pwm: SimplePwmChannel<'static, LedTimer>,
+    /// Only on boards with a spare timer channel, for RGBW strips
+    pub white_pwm: Option<SimplePwmChannel<'static, LedTimer>>,
+    /// Only on rev-f boards, which have a second strip
+    zone_2: Option<LedZone>,
+    color_order: ColorOrder,
+    /// What set_zone_colors() was last asked for
+    colors: ZoneColors,
+    /// Turns the strip down when it's been bright for too long, see
+    /// poll_derating()
+    derating: Derating,
+    next_derating_tick: Instant,
+}
+
+/// How often the derating model moves on, which its time constant counts in
+const DERATING_TICK: Duration = Duration::from_secs(1);
+
+/// RGBW for the first zone, and RGB for the second.
+pub type ZoneColors = ([u8; 4], [u8; 3]);
+
+/// The second strip on boards that have one. RGB only.
+pub struct LedZone {
+    pub red_pwm: SimplePwmChannel<'static, Zone2Timer>,
+    pub green_pwm: SimplePwmChannel<'static, Zone2Timer>,
+    pub blue_pwm: SimplePwmChannel<'static, Zone2Timer>,
 }
 
 impl LedStrip {
+    /// How many independently colored strips the board has, 1 or 2.
+    pub fn zones(&self) -> u8 {
+        if self.zone_2.is_some() { 2 } else { 1 }
+    }
+
+    /// Sets all the zones to the same color.
     pub fn set_colors(&mut self, red: u8, green: u8, blue: u8) {
+        self.set_colors_rgbw(red, green, blue, 0);
+    }
+
+    /// Like set_colors(), plus the white channel if there is one.
+    pub fn set_colors_rgbw(&mut self, red: u8, green: u8, blue: u8, white: u8) {
+        self.set_zone_colors([red, green, blue, white], [red, green, blue]);
+    }
+
+    /// Sets the first zone to an RGBW color, and the second zone, if there is
+    /// one, to an RGB color.
+    pub fn set_zone_colors(&mut self, zone_1: [u8; 4], zone_2: [u8; 3]) {
+        self.colors = (zone_1, zone_2);
+        // Safety: Only the LedStrip touches it, and there's only one
+        unsafe { LAST_COLORS = SavedColors::new(self.colors) };
+        self.show();
+    }
+
+    /// On a warm boot, like a watchdog reset mid-show, goes straight back to
+    /// the colors shown before the reset, so nobody sees it. They're only
+    /// trusted if they check out, since RAM holds whatever it likes after a
+    /// power dip. Returns whether they were restored.
+    pub fn restore_colors(&mut self) -> bool {
+        if !boot::is_warm_boot() {
+            return false;
+        }
+        // Safety: Only the LedStrip touches it, and there's only one. It's
+        // copied out, since a reference to a static mut isn't allowed.
+        let saved = unsafe { LAST_COLORS };
+        let Some((zone_1, zone_2)) = saved.colors() else {
+            return false;
+        };
+        self.set_zone_colors(zone_1, zone_2);
+        true
+    }
+
+    /// Writes the colors to the PWM channels, turned down by the derating.
+    fn show(&mut self) {
+        let derating = &self.derating;
+        let (zone_1, zone_2) = self.colors;
+        let [red, green, blue, white] = zone_1.map(|c| derating.apply(c));
+        if let Some(zone) = &mut self.zone_2 {
+            let [red, green, blue] = self.color_order.apply(zone_2.map(|c| derating.apply(c)));
+            zone.red_pwm.set_duty_cycle_fraction(255 - red as u16, 255);
+            zone.green_pwm
+                .set_duty_cycle_fraction(255 - green as u16, 255);
+            zone.blue_pwm
+                .set_duty_cycle_fraction(255 - blue as u16, 255);
+        }
+        let [red, green, blue] = self.color_order.apply([red, green, blue]);
         self.red_pwm.set_duty_cycle_fraction(255 - red as u16, 255);
         self.green_pwm
             .set_duty_cycle_fraction(255 - green as u16, 255);
         self.blue_pwm
             .set_duty_cycle_fraction(255 - blue as u16, 255);
+        if let Some(white_pwm) = &mut self.white_pwm {
+            white_pwm.set_duty_cycle_fraction(255 - white as u16, 255);
+        }
+    }
+
+    /// The colors last set, before the color order is applied.
+    pub fn colors(&self) -> ZoneColors {
+        self.colors
+    }
+
+    pub fn color_order(&self) -> ColorOrder {
+        self.color_order
+    }
+
+    /// Takes effect with the next color set.
+    pub fn set_color_order(&mut self, order: ColorOrder) {
+        self.color_order = order;
+    }
+
+    pub fn derating(&self) -> &Derating {
+        &self.derating
+    }
+
+    /// Turns derating on at `threshold` percent heat, or off with None, which
+    /// takes effect right away.
+    pub fn set_derating(&mut self, threshold: Option<u8>) {
+        self.derating.set_threshold(threshold);
+        self.show();
+    }
+
+    /// Moves the derating model on, once a second, and turns the strip down
+    /// or back up if it says so. Call it often.
+    pub fn poll_derating(&mut self) {
+        let now = Instant::now();
+        if now < self.next_derating_tick {
+            return;
+        }
+        self.next_derating_tick = (self.next_derating_tick + DERATING_TICK).max(now);
+
+        // The brighter zone, since they share the channel
+        let (zone_1, zone_2) = self.colors;
+        let zone_1_load = match self.white_pwm {
+            Some(_) => zone_1.iter().map(|&c| c as u32).sum::<u32>() / 4,
+            None => zone_1[..3].iter().map(|&c| c as u32).sum::<u32>() / 3,
+        };
+        let zone_2_load = match self.zone_2 {
+            Some(_) => zone_2.iter().map(|&c| c as u32).sum::<u32>() / 3,
+            None => 0,
+        };
+        let load = self.derating.apply(zone_1_load.max(zone_2_load) as u8);
+        if self.derating.tick(load) {
+            self.show();
+        }
+    }
+}
+
+/// What the LedStrip last showed, kept across a warm boot, see
+/// LedStrip::restore_colors()
+#[unsafe(link_section = ".noinit")]
+static mut LAST_COLORS: SavedColors = SavedColors {
+    magic: 0,
+    colors: ([0; 4], [0; 3]),
+    check: 0,
+};
+const SAVED_COLORS_MAGIC: u32 = 0x1ed5_c010;
+
+#[derive(Clone, Copy)]
+struct SavedColors {
+    magic: u32,
+    colors: ZoneColors,
+    check: u16,
+}
+
+impl SavedColors {
+    fn new(colors: ZoneColors) -> Self {
+        Self {
+            magic: SAVED_COLORS_MAGIC,
+            colors,
+            check: Self::checksum(colors),
+        }
+    }
+
+    /// The colors, if this is what new() made, rather than leftover RAM.
+    fn colors(&self) -> Option<ZoneColors> {
+        let valid = self.magic == SAVED_COLORS_MAGIC && self.check == Self::checksum(self.colors);
+        valid.then_some(self.colors)
+    }
+
+    /// Fletcher-16
+    fn checksum((zone_1, zone_2): ZoneColors) -> u16 {
+        let (mut low, mut high) = (0u16, 0u16);
+        for &byte in zone_1.iter().chain(&zone_2) {
+            low = (low + byte as u16) % 255;
+            high = (high + low) % 255;
+        }
+        (high << 8) | low
+    }
+}
+
+/// Starts a strip channel dark. The strip's drivers invert, lighting it
+/// while the output is low, so dark is full duty, not 0. The duty goes in
+/// before the channel is enabled, since enabling it at the timer's reset
+/// duty of 0 would flash the strip full on at power-up.
+fn start_dark<T: GeneralInstance4Channel>(channel: &mut SimplePwmChannel<'static, T>) {
+    channel.set_duty_cycle_fully_on();
+    channel.enable();
+    defmt::debug_assert_eq!(channel.current_duty_cycle(), channel.max_duty_cycle());
+}
+
+/// How the strip's channels are wired, for strips that aren't RGB. Stored in
+/// flash, where erased bits read as Rgb.
+#[derive(Debug, Format, PartialEq, Eq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ColorOrder {
+    Grb = 0,
+    Bgr = 1,
+    Brg = 2,
+    Rgb = 3,
+}
+
+impl ColorOrder {
+    /// Indexed by value. The name is the strip's channels in the order
+    /// they're wired to our outputs, and the permutation says which of red,
+    /// green, and blue goes to each output.
+    const TABLE: [(&'static str, [usize; 3]); 4] = [
+        ("GRB", [1, 0, 2]),
+        ("BGR", [2, 1, 0]),
+        ("BRG", [2, 0, 1]),
+        ("RGB", [0, 1, 2]),
+    ];
+
+    pub fn name(self) -> &'static str {
+        Self::TABLE[self as usize].0
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        let i = Self::TABLE.iter().position(|(n, _)| n.as_bytes() == name)?;
+        Self::try_from(i as u8).ok()
+    }
+
+    fn apply(self, rgb: [u8; 3]) -> [u8; 3] {
+        Self::TABLE[self as usize].1.map(|i| rgb[i])
     }
 }
 
@@ -48,6 +291,7 @@ pub struct CmdPortPeripherals {
     pub cmd_usart_tx: DbgUsartTx,
 }
 
+#[cfg(feature = "panel-bus")]
 pub struct PanelBusPeripherals {
     pub panel_bus_usart: PanelBusUsart,
     pub panel_bus_usart_tx: PanelBusUsartTx,
@@ -75,10 +319,14 @@ pub struct UsbPeripherals {
 pub struct Pirs {
     pub pir_1: Input<'static>,
     pub pir_2: Input<'static>,
+    /// The extra inputs, like a door switch and a tilt sensor, None on boards
+    /// built without them
+    pub aux: [Option<Input<'static>>; 2],
 }
 
 pub struct Board {
     pub cmd_port: CmdPortPeripherals,
+    #[cfg(feature = "panel-bus")]
     pub panel_bus: PanelBusPeripherals,
     pub radio: RadioPeripherals,
     pub usb: UsbPeripherals,
@@ -122,34 +370,83 @@ pub fn hookup() -> Board {
 
     let led_red = PwmPin::<TIM2, simple_pwm::Ch1>::new_ch1(p.PA0, OutputType::PushPull);
     let led_green = PwmPin::<TIM2, simple_pwm::Ch2>::new_ch2(p.PA1, OutputType::PushPull);
+    // Everything the revisions do differently with the first strip's timer.
+    // rev-d has blue on channel 3, which takes PA2 from the panel bus, and
+    // channel 4 to spare, for the white channel of RGBW strips. rev-e has
+    // blue on channel 4, and leaves channel 3 alone.
     #[cfg(feature = "rev-d")]
-    let led_blue = PwmPin::<TIM2, simple_pwm::Ch3>::new_ch3(p.PA2, OutputType::PushPull);
+    let (led_ch3, led_ch4, blue_and_white) = (
+        Some(PwmPin::<TIM2, simple_pwm::Ch3>::new_ch3(
+            p.PA2,
+            OutputType::PushPull,
+        )),
+        Some(PwmPin::<TIM2, simple_pwm::Ch4>::new_ch4(
+            p.PA3,
+            OutputType::PushPull,
+        )),
+        |ch3, ch4| (ch3, Some(ch4)),
+    );
     #[cfg(feature = "rev-e")]
-    let led_blue = PwmPin::<TIM2, simple_pwm::Ch4>::new_ch4(p.PA3, OutputType::PushPull);
+    let (led_ch3, led_ch4, blue_and_white) = (
+        None,
+        Some(PwmPin::<TIM2, simple_pwm::Ch4>::new_ch4(
+            p.PA3,
+            OutputType::PushPull,
+        )),
+        |_, ch4| (ch4, None),
+    );
+
+    // rev-f has a second strip. TIM3's pins are taken by the radio, so it's on
+    // TIM4.
+    #[cfg(feature = "rev-f")]
+    let zone_2 = {
+        let mut pwm = SimplePwm::new(
+            p.TIM4,
+            Some(PwmPin::<TIM4, simple_pwm::Ch1>::new_ch1(
+                p.PB6,
+                OutputType::PushPull,
+            )),
+            Some(PwmPin::<TIM4, simple_pwm::Ch2>::new_ch2(
+                p.PB7,
+                OutputType::PushPull,
+            )),
+            Some(PwmPin::<TIM4, simple_pwm::Ch3>::new_ch3(
+                p.PB8,
+                OutputType::PushPull,
+            )),
+            None,
+            Hertz(1000),
+            CountingMode::EdgeAlignedUp,
+        )
+        .split();
+        for ch in [&mut pwm.ch1, &mut pwm.ch2, &mut pwm.ch3] {
+            start_dark(ch);
+        }
+        Some(LedZone {
+            red_pwm: pwm.ch1,
+            green_pwm: pwm.ch2,
+            blue_pwm: pwm.ch3,
+        })
+    };
+    #[cfg(not(feature = "rev-f"))]
+    let zone_2 = None;
 
     let mut pwm = SimplePwm::new(
         p.TIM2,
         Some(led_red),
         Some(led_green),
-        None,
-        Some(led_blue),
+        led_ch3,
+        led_ch4,
         Hertz(1000),
         CountingMode::EdgeAlignedUp,
     )
     .split();
-    pwm.ch1.enable();
-    pwm.ch1.set_duty_cycle_fraction(255, 255);
-    pwm.ch2.enable();
-    pwm.ch2.set_duty_cycle_fraction(255, 255);
-    #[cfg(feature = "rev-d")]
-    {
-        pwm.ch3.enable();
-        pwm.ch3.set_duty_cycle_fraction(255, 255);
-    }
-    #[cfg(feature = "rev-e")]
-    {
-        pwm.ch4.enable();
-        pwm.ch4.set_duty_cycle_fraction(255, 255);
+    start_dark(&mut pwm.ch1);
+    start_dark(&mut pwm.ch2);
+    let (mut blue_pwm, mut white_pwm): (_, Option<SimplePwmChannel<'static, LedTimer>>) =
+        blue_and_white(pwm.ch3, pwm.ch4);
+    for ch in core::iter::once(&mut blue_pwm).chain(&mut white_pwm) {
+        start_dark(ch);
     }
 
     unsafe {
@@ -162,10 +459,12 @@ pub fn hookup() -> Board {
             cmd_usart_rx: p.PA10,
             cmd_usart_tx: p.PA9,
         },
+        #[cfg(feature = "panel-bus")]
         panel_bus: PanelBusPeripherals {
             panel_bus_usart: p.USART2,
             panel_bus_usart_tx: p.PA2,
-            ser_out_en: Output::new(p.PA4, Level::High, Speed::VeryHigh),
+            // The RS-485 driver stays off until there's something to send
+            ser_out_en: Output::new(p.PA4, Level::Low, Speed::VeryHigh),
         },
         radio: RadioPeripherals {
             rf_cs: Output::new(p.PB0, Level::High, Speed::VeryHigh),
@@ -186,7 +485,13 @@ pub fn hookup() -> Board {
         led_strip: LedStrip {
             red_pwm: pwm.ch1,
             green_pwm: pwm.ch2,
-            blue_pwm: pwm.ch4,
+            blue_pwm,
+            white_pwm,
+            zone_2,
+            color_order: ColorOrder::Rgb,
+            colors: ([0; 4], [0; 3]),
+            derating: Derating::new(),
+            next_derating_tick: Instant::now(),
         },
         status_leds: [
             Output::new(p.PB15, Level::High, Speed::VeryHigh),
@@ -197,6 +502,15 @@ pub fn hookup() -> Board {
         pirs: Pirs {
             pir_1: Input::new(p.PB10, Pull::None),
             pir_2: Input::new(p.PB2, Pull::None),
+            // Freed from JTAG above. Pulled down, so a switch that's missing
+            // reads 0
+            #[cfg(feature = "aux-sensors")]
+            aux: [
+                Some(Input::new(p.PB4, Pull::Down)),
+                Some(Input::new(p.PB5, Pull::Down)),
+            ],
+            #[cfg(not(feature = "aux-sensors"))]
+            aux: [None, None],
         },
     }
 }
@@ -218,29 +532,6 @@ pub fn pet_the_watchdog() {
     }
 }
 
-pub async fn watchdog_petter() {
-    const WATCHDOG_INTERVAL_MS: u64 = 500;
-
-    // Scale to make it fit in u32 but still last a long time
-    const DEADLINE_SCALE: u64 = 100;
-    static NEXT_DEADLINE: AtomicU32 = AtomicU32::new(0);
-
-    let mut deadline_in_ms: u64 = NEXT_DEADLINE.load(Ordering::Relaxed) as u64 * DEADLINE_SCALE;
-
-    if deadline_in_ms == 0 {
-        // New interval
-        deadline_in_ms = Instant::now().as_millis() + WATCHDOG_INTERVAL_MS;
-        NEXT_DEADLINE.store((deadline_in_ms / DEADLINE_SCALE) as u32, Ordering::Release);
-        // debug!("New watchdog deadline: {} ms", deadline_in_ms);
-    }
-
-    Timer::at(Instant::from_millis(deadline_in_ms)).await;
-
-    pet_the_watchdog();
-
-    NEXT_DEADLINE.store(0, Ordering::Release);
-}
-
 pub struct Controls {
     pub user_btn: Debouncer<ExtiInput<'static>>,
 }
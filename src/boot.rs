@@ -1,12 +1,14 @@
-use defmt::debug;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::{Format, debug, warn};
 use embassy_futures::select::{self};
+use embassy_stm32::pac::RCC;
 use embassy_time::{Duration, Instant, Timer};
+use num_enum::{FromPrimitive, IntoPrimitive};
 
 use crate::{
-    Mode,
-    board::{self, watchdog_petter},
+    Mode, board,
     comm::{Address, CommMode},
-    flash,
+    flash, last_panic,
     status_leds::StatusLEDs,
 };
 
@@ -19,6 +21,66 @@ const BOOT_MAGIC_VALUE: u32 = 0x31337cde;
 
 static mut IS_WARM_BOOT: bool = false;
 
+static mut RESET_CAUSE: ResetCause = ResetCause::Unknown;
+
+/// Cleared by the m command. It's in ordinary RAM, so any reset sets it again.
+static REBOOT_SEEN: AtomicBool = AtomicBool::new(true);
+
+/// Why we last reset, from the RCC reset flags.
+///
+/// The NRST pin is pulsed for every internal reset too, so Pin only means
+/// the pin if nothing else is set.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format, IntoPrimitive, FromPrimitive)]
+#[repr(u8)]
+pub enum ResetCause {
+    #[num_enum(default)]
+    Unknown = 0,
+    PowerOn = 1,
+    Pin = 2,
+    Software = 3,
+    IndependentWatchdog = 4,
+    WindowWatchdog = 5,
+    LowPower = 6,
+}
+
+impl ResetCause {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetCause::Unknown => "unknown",
+            ResetCause::PowerOn => "por",
+            ResetCause::Pin => "pin",
+            ResetCause::Software => "sw",
+            ResetCause::IndependentWatchdog => "iwdg",
+            ResetCause::WindowWatchdog => "wwdg",
+            ResetCause::LowPower => "lpwr",
+        }
+    }
+
+    /// Reads the reset flags and clears them, so next boot only sees its own.
+    fn read_and_clear() -> Self {
+        let csr = RCC.csr().read();
+        let cause = if csr.lpwrrstf() {
+            ResetCause::LowPower
+        } else if csr.wwdgrstf() {
+            ResetCause::WindowWatchdog
+        } else if csr.iwdgrstf() {
+            ResetCause::IndependentWatchdog
+        } else if csr.sftrstf() {
+            ResetCause::Software
+        } else if csr.porrstf() {
+            // Also covers brown-out
+            ResetCause::PowerOn
+        } else if csr.pinrstf() {
+            ResetCause::Pin
+        } else {
+            ResetCause::Unknown
+        };
+        RCC.csr().modify(|w| w.set_rmvf(true));
+        cause
+    }
+}
+
 pub fn check_boot_status() {
     // Safety: We just booted so there aren't any threads
     unsafe {
@@ -37,7 +99,12 @@ pub fn check_boot_status() {
         }
 
         debug!("is_warm_boot={}", IS_WARM_BOOT);
+
+        RESET_CAUSE = ResetCause::read_and_clear();
+        debug!("reset_cause={:?}", RESET_CAUSE);
     }
+
+    last_panic::check();
 }
 
 pub fn is_warm_boot() -> bool {
@@ -45,11 +112,27 @@ pub fn is_warm_boot() -> bool {
     unsafe { IS_WARM_BOOT }
 }
 
+pub fn get_reset_cause() -> ResetCause {
+    // Safety: This is only written once at boot time.
+    unsafe { RESET_CAUSE }
+}
+
 pub fn get_boot_count() -> u8 {
     // Safety: This is only written once at boot time.
     unsafe { BOOT_COUNT }
 }
 
+/// Whether the board has reset since clear_reboot_seen(), or ever, if it
+/// hasn't been called. For a technician to mark a board and see later if it
+/// rebooted, without comparing boot counts. A soft restart doesn't count.
+pub fn reboot_seen() -> bool {
+    REBOOT_SEEN.load(Ordering::Relaxed)
+}
+
+pub fn clear_reboot_seen() {
+    REBOOT_SEEN.store(false, Ordering::Relaxed);
+}
+
 /// Board 0 is always in Spy mode.
 ///
 /// Boards store their default mode in flash. Uninitialized boards default to
@@ -67,7 +150,8 @@ pub fn determine_mode(address: Address) -> Mode {
     flash::get_default_mode()
 }
 
-/// On-board so-called UX for toggling between modes
+/// On-board so-called UX for toggling between modes. Reached by holding the
+/// button at boot, or for a few seconds at runtime (see UserButton).
 ///
 pub async fn toggle_mode(mode: Mode) -> ! {
     // Status LEDS 0-3 represent the following combinations:
@@ -95,39 +179,37 @@ pub async fn toggle_mode(mode: Mode) -> ! {
     // Short press cycles through the settings, long press
     // writes the current setting to flash and reboots.
 
-    'outer: loop {
+    loop {
         debug!("Index {}", index);
         StatusLEDs::set_all(1 << index);
 
-        while match select::select(watchdog_petter(), user_btn.wait_for_high()).await {
-            select::Either::First(_) => true,
-            select::Either::Second(_) => false,
-        } {}
+        user_btn.wait_for_high().await;
 
         let long_press_deadline = Instant::now() + Duration::from_millis(1000);
-        while match select::select3(
-            watchdog_petter(),
-            user_btn.wait_for_low(),
-            Timer::at(long_press_deadline),
-        )
-        .await
+        if let select::Either::Second(_) =
+            select::select(user_btn.wait_for_low(), Timer::at(long_press_deadline)).await
         {
-            select::Either3::First(_) => true,
-            select::Either3::Second(_) => false,
-            select::Either3::Third(_) => {
-                break 'outer;
-            }
-        } {}
+            break;
+        }
 
         index = (index + 1) % SETTINGS.len();
     }
 
     debug!("Writing mode to flash: {:?}", SETTINGS[index]);
 
-    flash::set_default_mode(SETTINGS[index].0);
-    flash::set_comm_mode(SETTINGS[index].1);
-
-    blink_lights(user_btn).await;
+    let (mode, comm_mode) = SETTINGS[index];
+    let saved = flash::write_settings(|| {
+        flash::set_default_mode(mode).and_then(|()| flash::set_comm_mode(comm_mode))
+    })
+    .await;
+
+    match saved {
+        Ok(()) => blink_lights(user_btn).await,
+        Err(e) => {
+            warn!("Couldn't save settings: {:?}", e);
+            blink_error().await;
+        }
+    }
 
     cortex_m::peripheral::SCB::sys_reset();
 }
@@ -139,22 +221,22 @@ async fn blink_lights(
 ) {
     debug!("Blinking lights");
     let mut lights_on = true;
-    while match select::select3(
-        watchdog_petter(),
-        Timer::after_millis(250),
-        user_btn.wait_for_low(),
-    )
-    .await
-    {
-        select::Either3::First(_) => {
-            // Watchdog petted
-            true
-        }
-        select::Either3::Second(_) => {
+    while match select::select(Timer::after_millis(250), user_btn.wait_for_low()).await {
+        select::Either::First(_) => {
             StatusLEDs::set_all(if lights_on { 0xF } else { 0 });
             lights_on = !lights_on;
             true
         }
-        select::Either3::Third(_) => false,
+        select::Either::Second(_) => false,
     } {}
 }
+
+/// Flash alternate pairs of lights quickly, to say the settings weren't saved.
+/// The board reboots with whatever was there before.
+///
+async fn blink_error() {
+    for i in 0..20 {
+        StatusLEDs::set_all(if i % 2 == 0 { 0b0101 } else { 0b1010 });
+        Timer::after_millis(100).await;
+    }
+}
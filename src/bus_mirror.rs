@@ -0,0 +1,95 @@
+use embassy_time::Instant;
+use heapless::Vec;
+
+use crate::command_serial;
+
+/*
+    Bus mirror frames
+
+    With `w1`, the bytes on the panel bus, both ways, are copied out the
+    command serial port, for timing the bus without a logic analyzer. Each
+    frame is a header, then the bytes as they were on the wire. Multi-byte
+    fields are little-endian.
+
+    | Offset | Size | Field                                                          |
+    | ------ | ---- | -------------------------------------------------------------- |
+    | 0      | 1    | SYNC                                                           |
+    | 1      | 1    | Length of the rest of the frame, from the direction on         |
+    | 2      | 1    | Direction, SENT or RECEIVED                                    |
+    | 3      | 4    | Microseconds since boot (u32, wraps) of the first byte         |
+    | 7      | ...  | Up to MAX_FRAME_BYTES bytes                                    |
+
+    A frame ends when the direction changes, when it's full, or at the end
+    of a packet, or of junk between packets. Received bytes are stamped when
+    they were read from the UART, which is a little after they arrived.
+    Frames are written whole or dropped, and text lines still go out the
+    port between them.
+
+*/
+
+pub const SYNC: u8 = 0xa6;
+
+pub const SENT: u8 = b'>';
+pub const RECEIVED: u8 = b'<';
+
+const HEADER_LEN: usize = 7;
+
+/// Most bus bytes in one frame
+const MAX_FRAME_BYTES: usize = 64;
+
+/// Copies the panel bus out the command serial port, see PanelSerial.
+///
+/// The bus never waits for the port, which is slower than the bus at its
+/// default rate. A frame that doesn't fit in the port's queue is dropped and
+/// counted.
+///
+pub struct BusMirror {
+    frame: Vec<u8, { HEADER_LEN + MAX_FRAME_BYTES }>,
+    dropped: u32,
+}
+
+impl BusMirror {
+    pub fn new() -> Self {
+        Self {
+            frame: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Frames dropped since the mirror was turned on.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Adds bytes that went `direction` just now.
+    pub fn record(&mut self, direction: u8, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.frame.get(2).is_some_and(|&d| d != direction) {
+                self.flush();
+            }
+            if self.frame.is_empty() {
+                let us = Instant::now().as_micros() as u32;
+                let _ = self.frame.extend_from_slice(&[SYNC, 0, direction]);
+                let _ = self.frame.extend_from_slice(&us.to_le_bytes());
+            }
+            let n = bytes.len().min(self.frame.capacity() - self.frame.len());
+            let _ = self.frame.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if self.frame.is_full() {
+                self.flush();
+            }
+        }
+    }
+
+    /// Sends the frame so far, if there is one.
+    pub fn flush(&mut self) {
+        if self.frame.is_empty() {
+            return;
+        }
+        self.frame[1] = (self.frame.len() - 2) as u8;
+        if !command_serial::mirror(&self.frame) {
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        self.frame.clear();
+    }
+}
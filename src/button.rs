@@ -0,0 +1,127 @@
+use embassy_futures::select::{Either, select};
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::board;
+use crate::comm::Address;
+use crate::debouncer::Debouncer;
+use crate::status_leds::StatusLEDs;
+
+/// Holding the button this long at runtime gets to the settings, like holding
+/// it at boot does.
+const SETTINGS_HOLD_TIME: Duration = Duration::from_secs(3);
+
+const ID_NIBBLE_TIME: Duration = Duration::from_millis(1000);
+const ID_GAP_TIME: Duration = Duration::from_millis(150);
+
+/// How long the button was held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Press {
+    Short,
+    /// Held for SETTINGS_HOLD_TIME
+    Long,
+}
+
+/// Watches the user button while the board is running.
+///
+/// A short press shows the board ID on the status LEDs, high nibble first,
+/// then puts back whatever they were showing. A long press is left to the
+/// caller, which is expected to go to boot::toggle_mode(). Callers that do
+/// something of their own with short presses too use watch_presses().
+///
+/// Both are meant to be raced against packet handling, so all its progress
+/// is kept here and it's fine to drop it at any await.
+///
+pub struct UserButton {
+    address: Address,
+    pressed_at: Option<Instant>,
+    /// Next step of showing the ID, and when to take it
+    id_step: u8,
+    id_step_at: Option<Instant>,
+    saved_leds: u8,
+}
+
+impl UserButton {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            pressed_at: None,
+            id_step: 0,
+            id_step_at: None,
+            saved_leds: 0,
+        }
+    }
+
+    /// Handles short presses, and returns when the button has been held for
+    /// SETTINGS_HOLD_TIME.
+    pub async fn watch(&mut self) {
+        while self.watch_presses().await != Press::Long {}
+    }
+
+    /// Like watch(), but returns after short presses too, once it's started
+    /// showing the ID.
+    pub async fn watch_presses(&mut self) -> Press {
+        loop {
+            let id_step_at = self.id_step_at;
+            let id_step = async move {
+                match id_step_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            match select(self.wait_for_press(), id_step).await {
+                Either::First(true) => return Press::Long,
+                Either::First(false) => {
+                    if self.id_step_at.is_none() {
+                        self.saved_leds = StatusLEDs::get_all();
+                    }
+                    self.id_step = 0;
+                    self.show_id_step();
+                    return Press::Short;
+                }
+                Either::Second(()) => self.show_id_step(),
+            }
+        }
+    }
+
+    /// Waits for a press. Returns true if it was a long one. Both edges go
+    /// through the button's Debouncer, so contact bounce on the way down
+    /// isn't taken for a short press.
+    async fn wait_for_press(&mut self) -> bool {
+        let user_btn: &mut Debouncer<ExtiInput<'static>> = board::controls().user_btn();
+        let pressed_at = match self.pressed_at {
+            Some(at) => at,
+            None => {
+                user_btn.wait_for_high().await;
+                *self.pressed_at.insert(Instant::now())
+            }
+        };
+
+        let long_press_deadline = pressed_at + SETTINGS_HOLD_TIME;
+        match select(user_btn.wait_for_low(), Timer::at(long_press_deadline)).await {
+            Either::First(()) => {
+                self.pressed_at = None;
+                false
+            }
+            Either::Second(()) => true,
+        }
+    }
+
+    fn show_id_step(&mut self) {
+        let id = self.address.value();
+        let (leds, time) = match self.id_step {
+            0 | 2 => (0, ID_GAP_TIME),
+            1 => (id >> 4, ID_NIBBLE_TIME),
+            3 => (id & 0xf, ID_NIBBLE_TIME),
+            _ => {
+                StatusLEDs::set_all(self.saved_leds);
+                self.id_step_at = None;
+                return;
+            }
+        };
+        // Kept from the heartbeat until the next step
+        StatusLEDs::show_for(leds, time);
+        self.id_step += 1;
+        self.id_step_at = Some(Instant::now() + time);
+    }
+}
@@ -0,0 +1,103 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::comm::{CommMode, MAX_PAYLOAD_SIZE, Packet};
+use crate::usb_port;
+
+/*
+    Capture records
+
+    In spy mode, `S1` makes the spy write a binary record to USB for each packet
+    it sees, for analysis on the host. Multi-byte fields are little-endian.
+
+    | Offset | Size | Field                                                          |
+    | ------ | ---- | -------------------------------------------------------------- |
+    | 0      | 1    | SYNC                                                           |
+    | 1      | 1    | Length of the rest of the record, from the type on             |
+    | 2      | 1    | Type, RECORD_PACKET or RECORD_HEARTBEAT                        |
+    | 3      | 4    | Milliseconds since boot (u32)                                  |
+    | 7      | 1    | RSSI in dBm (i8), 0 on the serial bus and in heartbeats        |
+    | 8      | ...  | Packet: the packet in the bus's wire format<br>Heartbeat: records dropped so far (u32) |
+
+    Records are either written whole or dropped, so the host only has to
+    resync if it starts reading in the middle of one, or if the spy was
+    unplugged mid-record. Replies to commands sent over USB are mixed in as
+    text lines.
+
+*/
+
+pub const SYNC: u8 = 0xa5;
+
+pub const RECORD_PACKET: u8 = b'P';
+pub const RECORD_HEARTBEAT: u8 = b'H';
+
+pub const LEN_OFFSET: usize = 1;
+pub const TYPE_OFFSET: usize = 2;
+pub const TIME_OFFSET: usize = 3;
+pub const RSSI_OFFSET: usize = 7;
+pub const BODY_OFFSET: usize = 8;
+
+/// Wire formats need up to MAX_PAYLOAD_SIZE + 8 bytes, see Packet
+pub const MAX_RECORD_LEN: usize = BODY_OFFSET + MAX_PAYLOAD_SIZE + 8;
+
+/// A heartbeat goes out this often, so the host can tell a quiet bus from a
+/// spy that has gone away.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type Record = Vec<u8, MAX_RECORD_LEN>;
+
+/// Turns what the spy sees into capture records.
+pub struct Capture {
+    dropped: u32,
+    next_heartbeat: Instant,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self {
+            dropped: 0,
+            next_heartbeat: Instant::now() + HEARTBEAT_INTERVAL,
+        }
+    }
+
+    pub fn next_heartbeat(&self) -> Instant {
+        self.next_heartbeat
+    }
+
+    /// Counts a record that couldn't be queued. Ones that were queued but
+    /// couldn't be written are counted by usb_port.
+    pub fn count_drop(&mut self) {
+        self.dropped = self.dropped.wrapping_add(1);
+    }
+
+    pub fn packet_record(&self, packet: &Packet, mode: CommMode, rssi: i8) -> Record {
+        let mut buf = [0; MAX_PAYLOAD_SIZE + 8];
+        let wire = match mode {
+            CommMode::Radio => packet.radio_wire_format(&mut buf),
+            CommMode::Serial => packet.serial_wire_format(&mut buf),
+        };
+        record(RECORD_PACKET, rssi, wire)
+    }
+
+    /// Makes the next heartbeat record, and schedules the one after it.
+    pub fn heartbeat_record(&mut self) -> Record {
+        self.next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
+        let dropped = self.dropped.wrapping_add(usb_port::dropped_records());
+        record(RECORD_HEARTBEAT, 0, &dropped.to_le_bytes())
+    }
+}
+
+fn record(kind: u8, rssi: i8, body: &[u8]) -> Record {
+    let mut record = Record::new();
+    // Can't fail, the body is never bigger than a wire format packet
+    let _ = record.resize(BODY_OFFSET, 0);
+    let _ = record.extend_from_slice(body);
+
+    let time = Instant::now().as_millis() as u32;
+    record[0] = SYNC;
+    record[LEN_OFFSET] = (record.len() - TYPE_OFFSET) as u8;
+    record[TYPE_OFFSET] = kind;
+    record[TIME_OFFSET..RSSI_OFFSET].copy_from_slice(&time.to_le_bytes());
+    record[RSSI_OFFSET] = rssi as u8;
+    record
+}
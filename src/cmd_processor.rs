@@ -1,45 +1,345 @@
-use crate::board::{self, watchdog_petter, LedStrip, Pirs};
-use crate::boot::get_boot_count;
-use crate::comm::{BROADCAST_ADDRESS, Packet, PanelComm};
-use crate::status_leds::StatusLEDs;
+use crate::animation::{Animation, Pattern};
+use crate::board::{ColorOrder, LedStrip, Pirs, ZoneColors};
+use crate::boot::{self, ResetCause, get_boot_count, get_reset_cause, is_warm_boot};
+use crate::button::{Press, UserButton};
+use crate::capture::Capture;
+use crate::comm::{
+    BROADCAST_ADDRESS, BusBaud, CommMode, MAX_PAYLOAD_SIZE, NoAck, Packet, PanelComm, PanelRadio,
+    TxPower,
+};
+use crate::command_serial::CommandSerial;
+use crate::correction::ColorCorrection;
+use crate::health::HealthMonitor;
+use crate::hours::OperatingHours;
+use crate::id_flash::IdFlash;
+use crate::identify::{self, Identify};
+use crate::interactor::{Interactor, PORTS_JSON_LEN};
+use crate::last_panic;
+use crate::led_test::LedTest;
+use crate::logging::{self, packet_debug};
+use crate::macros::{
+    AUTOEXEC, LINE_SEPARATOR, MAX_MACRO_LINES, MacroError, MacroName, Macros, valid_name,
+};
+use crate::outbox::{OUTBOX_JSON_LEN, Outbox, Queued};
+use crate::pir::{
+    PIR_BITS, PIR_SAMPLE_INTERVAL, PirConfig, PirLog, PirProfile, PirSensors, SENSOR_BITS,
+};
+use crate::pir_stream::{MIN_POLL_INTERVAL, PirStream};
+use crate::reply::ReplyBuf;
+use crate::saved_mapping::SavedMapping;
+use crate::self_test;
+use crate::sim::{MAX_SIM_PANELS, SimPanels};
+use crate::stats::{CommandStats, PHASES_JSON_LEN, RTT_BUCKET_US, Rtt, RttHistogram, USB_JSON_LEN};
+use crate::status_leds::{FULL_BRIGHTNESS, LedHeartbeat, STATUS_HOLD_TIME, StatusLEDs};
+use crate::status_mirror::StatusMirror;
+use crate::usb_port::{self, UsbPort};
 use crate::version;
-use crate::{Interactor, Mode, comm::Address, flash::set_default_mode};
+use crate::watchdog::{self, Subsystem};
+use crate::{MAX_COMMAND_LEN, Mode, comm::Address, flash, flash::set_default_mode};
+use aunisoma_protocol::{
+    ClockSync, DELTA_ENTRY_LEN, ErrorCode, FULL_OUTPUT, FirmwareId, HexError, HexProblem,
+    PROTOCOL_VERSION, PanicLocation, Role, has_two_zones, hex_fields, hex_groups, stronger_rssi,
+};
+pub use aunisoma_protocol::{Message, slot_colors, slot_count};
 use core::fmt::Write;
-use defmt::{debug, info, trace};
-use embassy_futures::select::{Either, Either3, select, select3};
-use embassy_time::{Duration, Instant, Timer};
+use core::pin::pin;
+use defmt::{debug, info, trace, warn};
+use embassy_futures::select::{Either, Either3, Either4, select, select3, select4};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
 use heapless::Vec;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 // Protocol message types and constants
-const MAX_PANEL_SLOTS: usize = 32;
+pub const MAX_PANEL_SLOTS: usize = 32;
+
+// How long a panel waits after a message arrives before replying
+pub const PANEL_REPLY_DELAY: Duration = Duration::from_millis(2);
+
+// Replies a panel holds while waiting to send them, see send_replies(). A
+// Reliable's Ack and the reply to what was in it take two.
+const MAX_QUEUED_REPLIES: usize = 2;
+
+// A queued reply still unsent this long after its message arrived is dropped.
+// By then the master has stopped waiting, and it would be taken for a reply
+// to the next frame. The same as the master's Set Color reply window.
+const STALE_REPLY_AFTER: Duration = Duration::from_millis(MAX_PANEL_SLOTS as u64);
+
+// A panel only resets when a second Reset comes within this long of the first,
+// so one stray packet can't take down the installation.
+pub const RESET_WINDOW: Duration = Duration::from_millis(500);
+
+// Gap between the master's two Reset messages
+const RESET_REPEAT_GAP: Duration = Duration::from_millis(50);
+
+// How long a panel takes to boot before it starts its announce delay
+const PANEL_BOOT_TIME: Duration = Duration::from_millis(500);
+
+// How long to wait for a reply from a single panel
+const SINGLE_REPLY_TIME: Duration = Duration::from_millis(10);
+
+// A message to one panel that isn't acknowledged is sent up to this many more
+// times, see PanelComm::send_unicast_reliable()
+const ACK_RETRIES: u8 = 3;
+
+// A Reliable message arriving again with the same sequence number within this
+// long is a resend, and only acknowledged again
+pub const RESEND_WINDOW: Duration = Duration::from_millis(500);
+
+// An unmapped panel answers a Set Color with NotMapped at most this often, so
+// a whole unmapped installation doesn't fill every frame
+pub const NOT_MAPPED_INTERVAL: Duration = Duration::from_secs(3);
+
+// NotMapped replies are spread out by this much per ID, to keep clear of each
+// other within the Set Color reply window
+pub const NOT_MAPPED_STEP: Duration = Duration::from_micros(750);
+
+// With deltas, every slot's colors still go out at least this often, so a
+// panel that missed one doesn't stay wrong, see e
+const DEFAULT_FULL_FRAME_INTERVAL: Duration = Duration::from_secs(1);
+
+// Most slots one SetColorDelta can change
+const MAX_DELTA_SLOTS: usize = MAX_PAYLOAD_SIZE / DELTA_ENTRY_LEN;
+
+// A command still going after this long is abandoned, and replies TIMEOUT
+const COMMAND_BUDGET: Duration = Duration::from_secs(10);
+
+// Lines that were already waiting when a command this slow has replied are
+// thrown away, see Interactor::drop_stale()
+const STALE_AFTER: Duration = Duration::from_secs(1);
+
+// Commands going for longer than this send progress lines, see progress()
+const PROGRESS_AFTER: Duration = Duration::from_millis(500);
+const PROGRESS_LEN: usize = 64;
+
+// Most frames the ramp adds in front of one L, see r. At about 32 ms a frame,
+// that's a second.
+const MAX_RAMP_FRAMES: u32 = 30;
+
+// Longest command remembered for repeating with an empty line. Long enough for
+// anything typed by hand.
+const MAX_REPEAT_LEN: usize = 64;
+
+// Panels announce themselves after a delay of up to this long after boot
+const MAX_ANNOUNCE_DELAY_MS: u64 = 2000;
+
+// How long MV waits for SlotReplies, the same as a Set Color's, so it fits
+// between frames
+const SLOT_QUERY_WINDOW: Duration = Duration::from_millis(MAX_PANEL_SLOTS as u64);
+
+// Gap between the master's two CommitMappings, see MS
+const COMMIT_REPEAT_GAP: Duration = Duration::from_millis(5);
+
+// Most a reply before a reset waits to be written out, see reply_and_reset()
+const RESET_FLUSH_TIME: Duration = Duration::from_millis(500);
+
+// How often an idle master sends the panels its clock, see ClockSync
+const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// Most management messages sent in one gap between commands, see Outbox
+const OUTBOX_BUDGET: usize = 4;
+
+// With the host quiet, how long between sending OUTBOX_BUDGET more
+const OUTBOX_GAP: Duration = Duration::from_millis(20);
+
+// Longest entries in the Enumerate and Health replies
+const PANEL_JSON_LEN: usize = 208;
+
+// How long each round of an Enumerate waits for PingReplies. The most rounds,
+// see E, take under 400 ms.
+const ENUMERATE_WINDOW: Duration = Duration::from_millis(40);
+const MAX_ENUMERATE_ROUNDS: u8 = 9;
+// Longest an `EI` shows each panel
+const MAX_IDENTIFY_DWELL: Duration = Duration::from_secs(60);
+const MISSING_JSON_LEN: usize = 64;
+
+// After an Enumerate, the adaptive TX power wants more power when the weakest
+// link is below WEAK_LINK_DBM, and less when even it is above STRONG_LINK_DBM,
+// where receivers right next to each other start to desense. See W.
+const WEAK_LINK_DBM: i8 = -80;
+const STRONG_LINK_DBM: i8 = -40;
+
+const NOTIFICATION_LEN: usize = 32;
+// Holds one less than this
+const MAX_NOTIFICATIONS: usize = 5;
+
+pub type Notification = heapless::String<NOTIFICATION_LEN>;
+pub type Notifications = heapless::spsc::Queue<Notification, MAX_NOTIFICATIONS>;
 
 /*
     M protocol lines
 
+    Hex arguments are two digits per byte, in either case, with nothing in
+    between. A command whose hex doesn't parse replies with where, counting
+    the command letter as 1, e.g. `ERROR at char 13: expected hex digit`.
+
+    A command that isn't available in the board's mode replies with which
+    mode it needs and the board's mode, e.g. `ERROR Master-only command
+    (current mode: Panel)`, rather than `ERROR Unknown command (current mode:
+    Panel)`, which is for letters that aren't commands at all.
+
+    A command line longer than 256 characters is thrown away, and the port
+    that sent it gets `ERROR LineTooLong` in place of the reply, so the host
+    isn't left waiting. USB holds 1 KB of lines, for hosts that write several
+    commands at once.
+
+    Machine replies
+
+    With `vm`, replies that say how a command went are terse. `OK` is `+`,
+    and errors are `-` and an ErrorCode in decimal, with nothing after it
+    except for `BadHex`, which has the column after a space, e.g. `-2 13`,
+    `Failed`, which has the IDs, like `FAILED`, e.g. `-11 0a0c`,
+    `Mismatch`, which has the slots, like `MISMATCH`, e.g. `-17 0205`, and
+    `ReplyTooLarge`, which has {needed} {capacity}. `TIMEOUT` is `-12`,
+    `STANDBY` is `-14`, and `LineTooLong` is `-15`. `WrongPort`, `-18`, is
+    for a command the port it came from can't do, like `w1` from serial.
+    Replies with something to say, like JSON and PIR digits, are the same
+    either way, and so is `V`, which hosts use to sync up.
+
     Master and panel mode commands
 
     | Command                   | Response                                              | Description                                                                  |
     | ------------------------- | ----------------------------------------------------- | ---------------------------------------------------------------------------- |
-    | Default Mode<br>`D`{mode} | `OK` or an error message                              | Sets the default mode. {mode} is `M` for master, `P` for panel, `S` for spy. |
-    | Version<br>`V`            | Build version string<br>E.g., `"4fa9105"`             | Firmware version. Can be used as a safe way to synchronize the protocol.     |
+    | Default Mode<br>`D`{mode} | `OK` or an error message                              | Sets the default mode. {mode} is `M` for master, `P` for panel, `S` for spy, `B` for bridge. Replies `OK` once it's saved, and resets once that's out. |
+    | Version<br>`V`            | Build version string<br>E.g., `"4fa9105"`             | Firmware version. Can be used as a safe way to synchronize the protocol. The line also has `Channel=` and `Freq=`, the radio channel and its frequency, see h, `SelfTest=`, the power-on self-test's fault bits in hex, plus 8 while the panel bus is stuck, see self_test, `Hours=`, the board's operating hours, see OperatingHours, which only panel mode counts, `Boot=`, the boot count, which changes at every reset, `Warm=`, `true` if RAM survived the last reset, `Rebooted=`, see m, `Sensors=`, how many sensor bits the digits in `L` can have, `Features=`, the cargo features it was built with, e.g. `revE,bus`, see version::FEATURES, `Dirty=`, `true` if it was built with uncommitted changes, and `Built=`, when, in seconds since 1970. The last two are `?` if the build couldn't tell. `Panic=` is the address the board panicked at before it last reset, like `08003a5c`, see PanicLocation, or `none`. New fields only ever go on the end. |
+    | Echo<br>`X`{0\|1}         | `OK` or an error message                              | Turns echo of typed input off (`0`) or on (`1`) for the port that sent it.   |
+    | Verbosity<br>`v`{h\|m}    | `OK` or an error message                              | Switches replies between words for humans (`h`, the default) and terse replies for machines (`m`), for the port that sent it. Not saved. See Machine replies. |
+    | Packet Logs<br>`K`{0\|1}  | `OK` or an error message                              | Turns per-packet defmt debug logs off (`0`, the default) or on (`1`). Logging every packet over RTT can make panels miss replies. |
+    | Comm Stats<br>`C`         | JSON `{radioReinits, truncatedFrames, busOverruns, suppressedSends, noiseFloor}` | Counters for comm problems since boot. `truncatedFrames` is frames on the panel bus whose sender went quiet partway through, which are given up on after the time the rest would take plus 5 ms. `busOverruns` is bytes the panel bus UART lost because they weren't taken in time, which otherwise look like CRC errors or noise. `suppressedSends` is packets a spy was asked to send and didn't, since it never transmits, so it should stay 0. `noiseFloor` is the radio channel's average noise in dBm from the last `z`, or `null` before there's been one. |
+    | Radio Test<br>`z`         | JSON `{radio, modeReady, pllLock, noiseDbm, noisePeakDbm, packetSent, fifoEmpty, pass}`<br>E.g., `{"radio":true, "modeReady":true, "pllLock":true, "noiseDbm":-104, "noisePeakDbm":-97, "packetSent":true, "fifoEmpty":true, "pass":true}` | Checks the radio path as far as a board can on its own, to tell a dead radio from a bad antenna or a noisy site, see PanelRadio::self_test(). `radio` is whether the RFM69 answers over SPI, `modeReady` whether it switches modes, and `pllLock` whether its synthesizer locks on the channel. `noiseDbm` and `noisePeakDbm` are the average and loudest of 32 RSSI samples on the channel over about 64 ms, which is also what `C` shows. `packetSent` is whether a test packet addressed to this board went out, and `fifoEmpty` whether all of it did. Nothing echoes the packet, so whether it can be heard is for `E` from another board to say. Checks after one that failed are `null`, and so are `packetSent` and `fifoEmpty` on a spy, which never transmits. `pass` is whether everything that ran passed. A board on the panel bus sets its radio up first. |
+    | Help<br>`?`               | One line per available command                        | Lists the commands available in the current mode.                            |
+    | PIR Config<br>`F`{inv}{t1}{t2}\[{rf}{profile}\]\[{id}\] | `OK`, `FAILED `{id}, or an error message | Sets how PIRs are read, as hex bytes (see PirConfig). Without {rf} and {profile}, sets profile A with no refractory period. In master mode, sends it to panel {id}, or all panels if omitted. A single panel has to acknowledge it, see Reliable. |
+    | PIR Profile<br>`Y`{A\|B}  | `OK` or an error message                              | Switches PIR timings to profile `A` or `B`. In master mode, switches all panels at once, and remembers it for panels that reboot. |
+    | Derating<br>`d`\[{pct}\]  | The threshold, `off`, `OK`, or an error message       | Turns the LED strip down when it has been bright for so long the channel it's mounted in gets hot, see Derating. {pct}, in decimal, `1` to `99`, is the estimated heat to start at, as a percent of full white for good. Above it, the output fades down, to no less than 40%, and back up once the heat falls 5 below it. `d0`, the default, turns it off. Without {pct}, a panel replies with its threshold, heat, and output, e.g. `60 heat 63% output 81%`. In master mode, sets all panels at once, and remembers it for panels that reboot, and `d` alone replies with the threshold. Not saved. Not in spy mode. |
+    | Color Order<br>`O`{order}\[{id}\] | `OK`, `FAILED `{id}, or an error message  | Sets how the LED strip is wired: `RGB`, `GRB`, `BGR`, or `BRG`. Saved in flash. In master mode, sends it to panel {id} (two hex digits), or all panels if omitted. A single panel has to acknowledge it, see Reliable. |
+    | Flash ID<br>`I`\[{id}\]   | `OK`, `FAILED `{id}, or an error message              | Flashes the panel's ID in decimal on its LED strip, see IdFlash. In panel mode, flashes its own ID. In master mode, tells panel {id} (two hex digits) to, and FAILED means it didn't acknowledge, see Reliable. Any SetColor for the panel stops it. Not in spy mode. |
+    | Group<br>`g`\[{group}\]    | Group as two hex digits, or an error message          | Without {group}, replies with the installation group this board is in. With it (`00` to `1f`), saves it in flash, replies `OK`, and resets once that's out. Boards only hear others in the same group, see flash::set_group(). Set on each board before deployment, like the ID. Boards start out in group `00`. |
+    | RadioRegisters<br>`Z`r{reg}<br>`Z`w{reg}{val}<br>`Zd` | The register as two hex digits, `OK`, or the dump | Raw access to the RFM69's registers for tuning, only in builds with the `radio-debug` feature; other builds reply `ERROR Unsupported`. {reg} and {val} are two hex digits each. `r` reads `01` to `4f`. `w` only writes the modulation, frequency, power, LNA, bandwidth, and RSSI threshold registers, see PanelRadio::tunable_register(). `d` dumps `00` to `4f`, 16 to a line, with `--` for the FIFO. Changes are lost at reset. |
+    | TX Power<br>`W`\[{power}\[{id}\]\]<br>`Wa`{adapt} | `{power} {name} {dBm}dBm`, `OK`, `FAILED `{id}, or an error message | Without {power}, replies with this board's TX power, and in master mode, the adaptive setting, e.g. `0 Max 13dBm adapt off`. {power} is `0` (Max, +13 dBm), `1` (Medium, +5 dBm), `2` (Low, -2 dBm), or `3` (Min, -11 dBm), and is saved in flash. In master mode, sends it to panel {id} (two hex digits), or if omitted, to all panels and then uses it too. A single panel has to acknowledge it, see Reliable. Master only: {adapt} is `0` (off, the default), `1` to suggest a change after each Enumerate, or `2` to make it, see `!txpower`. Not saved. |
+    | Channel<br>`h`\[{channel}\]  | `{channel} {freq}MHz`, `OK`, `FAILED `{id}, or an error message | Without {channel}, replies with this board's radio channel and its frequency, e.g. `08 915.0MHz`. {channel} is `00` to `0f`, 911.0 to 918.5 MHz in 500 kHz steps, see PanelRadio::frequency(), and is saved in flash. Boards start out on `08`. In panel and spy mode, switches this board only, which is also how to bring back a panel that missed a change. In master mode, tells all panels to switch, after they acknowledge on the old channel, then switches itself, like Bus Baud. FAILED lists mapped panels that didn't acknowledge. Radio comm mode only in master mode. |
+    | Soft Restart<br>`b`       | `OK` or an error message                              | Starts over without resetting the board, for development: forgets the mapping and the panels, stops anything running, and turns the LED strip off, after replying. USB stays connected, so the host doesn't have to reopen the port. Settings that need a reset, like the mode, still reset. See CmdProcessor::restarted(). |
+    | LED Brightness<br>`i`\[{pct}\] | The brightness, `OK`, or an error message     | Dims the status LEDs, for a dark venue, without losing what they show. {pct}, in decimal, `0` to `100`, is the brightness in percent, `0` for off, and `100`, the default, for full. Without {pct}, replies with it. Not saved. See StatusLEDs. |
+    | Bus Mirror<br>`w`\[`0`\|`1`\] | `on dropped=`{n}, `off`, `OK`, or an error message | Copies every byte on the panel bus, both ways, out the command serial port as binary frames, with which way they went and when, see bus_mirror. Only from USB, since the serial port is carried away, and a command from the serial port turns it off. The bus never waits for the port: frames that don't fit are dropped, and {n} counts them. Without an argument, replies with whether it's on. Boards without a panel bus reply `ERROR Unsupported`. |
+    | Reboot Seen<br>`m`        | `OK` or an error message                              | Clears `Rebooted=` in V. Any reset sets it to `true` again, so a technician can mark a board and come back later to see if it rebooted in between, without comparing boot counts. A soft restart doesn't count. |
+    | Info<br>`J`               | JSON `{uptime, bootCount, commands, frames, frameAvgUs, frameMaxUs, flashWrites, outputDropped, outOfPhase, unknownReplies, usbConnects, usbDisconnects, usbDisconnectedSecs, outboxDepth, outboxDropped, serialIn, serialOut, serialDiscarded, usbIn, usbOut, usbDiscarded, parseAvgUs, parseMaxUs, sendAvgUs, sendMaxUs, collectAvgUs, collectMaxUs, fps, quietFrames, quietFps}` | Uptime in seconds, commands handled, and Set Color frames sent. The frame times cover the last 100 Set Color commands, from sending to collecting the replies, to 10 us. `parse` is from `L` arriving to its frame being ready to send, not counting ramp frames, `send` is writing it to the bus or radio, and `collect` is the reply window, so `parse` plus `send` is how long the host waits for the light to change. `flashWrites` counts option byte writes since power-up; settings that didn't change aren't written. `outputDropped` counts output to the command ports thrown away because a terminal couldn't keep up, like notifications and echo. Replies wait for the terminal instead. `outOfPhase` counts replies that came while the master was waiting for a different kind, like a PingReply during `L`, which usually means a reply window is too short. `unknownReplies` counts packets to the master that aren't replies at all. Neither adds the sender to the panel list. `usbConnects` and `usbDisconnects` count a host connecting to the USB port and going away, by unplugging, resetting the port, or going to sleep, and `usbDisconnectedSecs` is how long ago it last went, or `null` if it never has, for telling a flaky cable from a host that stopped listening. `outboxDepth` is how many management messages, TimeSync and the status mirror's SetStatus, are waiting for a gap between commands, and `outboxDropped` counts the ones thrown away because too many were waiting, see Outbox; either growing means the bus has no room to spare. `serialIn`, `serialOut`, `usbIn` and `usbOut` count command lines read and whole lines written on each port, and `serialDiscarded` and `usbDiscarded` count command lines thrown away unrun, for being too long or going stale. `fps` is how many `L` frames a second the master could keep up, going by the time the recent ones took, and `quietFps` is the same for frames sent with `q1`, which `quietFrames` counts, so the two show what skipping the replies gains; either is `null` until there have been some. |
+    | *(empty line)*            | Reply to the repeated command                         | Repeats the previous command, if it was short enough to remember.            |
     Master-only commands
 
     | Command                        | Response                                                                                                                                                                                                 | Description                                                                                                                                                                                                                     |
     | ------------------------------ | -------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------- | ------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------- |
-    | Enumerate<br>`E`               | JSON `[{id, bootCount, rssiM, rssiP}]`<br>E.g., `[{"id":12, "bootCount": 123, "rssiM":-35, "rssiP":-42]}, {"id":9, bootCount: 97, "rssiM":-35, "rssiP":-42}]`                                            | Enumerates the IDs and signal strength of the reachable panels. `bootCount` is an arbitrary number that changes on each reboot. `rssiM` is the RSSI on the master, `rssiP` is the RSSI on the panel.                            |
-    | Set Color<br>`L`\[{r}{g}{b}\]* | *Single* digits for PIR values from panels, in map order. PIR1 is 1, PIR2 is 2, both is 3.<br>E.g., after `M04080a` and `L<18 digits>`, if panel 8 has PIR1 and panel 10 has PIR1&2, responds `013`.<br> | Sets the panel colors. The order of the panels must have been set previously by the `M` command. Colors are RGB as two hex digits each. E.g., `L818283717273` sets the first two mapped panels to colors 0x818283 and 0x717273. |
-    | Map Panels<br>`M` \[{id}\]*    | `OK` or `FAILED 010203`                                                                                                                                                                                  | Sets the panel IDs for the Set Color command Panel IDs are two ASCII hex bytes. E.g., `M04080a` sets the panel order to 4, 8, 10.                                                                                               |
-    | Reset All<br>`R`               | `OK` or `FAILED 010203`                                                                                                                                                                                  | Restarts all controllers.                                                                                                                                                                                                       |
+    | Enumerate<br>`E`\[{rounds}\]\[`J`\|`I`\[{dwell}\]\] | JSON `[{id, bootCount, rssiM, rssiP, resetCause, fw, rttUs, zones, faults, txPower, lastPanic, seen}]`<br>E.g., `[{"id":12, "bootCount": 123, "rssiM":-35, "rssiP":-42, "resetCause":"por", "fw":"2.3fa2", "rttUs":610, "zones":1, "faults":0, "txPower":"Max", "lastPanic":null, "seen":1]}, {"id":9, bootCount: 97, "rssiM":-35, "rssiP":-42, "resetCause":"iwdg", "fw":null, "rttUs":655, "zones":1, "faults":4, "txPower":null, "lastPanic":null, "seen":1}]` | Enumerates the IDs and signal strength of the reachable panels, lowest ID first. A panel heard twice is listed once, with its stronger RSSI. `bootCount` is an arbitrary number that changes on each reboot. `rssiM` is the RSSI on the master, `rssiP` is the RSSI on the panel, or 0 for firmware too old to say. `resetCause` is why the panel last reset, see ResetCause. `fw` is the panel's protocol version and build hash, or `null` for firmware too old to say. Panels on a different protocol version than the master get a `!protocol` notification. `rttUs` is how long the reply took to arrive after the ping was sent, less the panel's fixed reply delay. `zones` is how many separately colored LED strips the panel has. `faults` is what the panel's self-test found at power-on, plus 8 while its panel bus is stuck, 0 if nothing, or `null` for firmware too old to say, see self_test. `txPower` is the panel's TX power, see W, or `null` for firmware too old to say. `lastPanic` is where the panel panicked before it last reset, like in `P`, or `null` if it didn't or its firmware is too old to say. With {rounds}, `2` to `9`, pings that many times, 40 ms apart, instead of once, for panels on marginal links that miss some, and `seen` is how many rounds the panel answered, so a flaky link shows as less than {rounds}. With adaptive TX power on, this is followed by a look at the weakest link, see `!txpower`. `EJ` replies with the same objects one per line instead of in an array, then a last line `{"count":12, "truncated":false}`. `truncated` is true if the panel list filled up, so there may have been more panels. `EI` replies like `E`, then goes through the panels in the same order, writing a line `IDENTIFY id=0c` just before blinking each one's status LEDs and lighting it up white for {dwell} ms (decimal, 1 to 60000, 2000 if left out), then putting it back, like `N`. Any command stops it at once, and a panel that's lit up is put back first. |
+    | Set Color<br>`L`\[`@`{delay}\]\[`W`\]\[{r}{g}{b}\[{w}\]\]* | *Single* hex digits for sensor values from panels, in map order. PIR1 is 1, PIR2 is 2, both is 3. Panels built with extra inputs add 4 and 8 for them, e.g. `d` for PIR1 and both inputs, and panels without read 0 for them.<br>E.g., after `M04080a` and `L<18 digits>`, if panel 8 has PIR1 and panel 10 has PIR1&2, responds `013`.<br>If panels that aren't mapped answered, see Not Mapped, the digits are followed by `!` and their IDs, e.g. `013!0b11`, so the host can map them again. | Sets the panel colors. The order of the panels must have been set previously by the `M` command. Colors are RGB as two hex digits each. E.g., `L818283717273` sets the first two mapped panels to colors 0x818283 and 0x717273. With `W`, colors are RGBW, for strips with a white channel. Panels without one ignore the white. Without `W`, a slot whose panel said it has two zones when it was mapped takes two colors, one per zone. Panels with two zones show the same color on both otherwise. With `@`{delay} in front, four hex digits of ms, e.g. `L@0064818283`, the panels all show the colors {delay} ms after the master got the command, within a couple of ms of each other, rather than as each one hears it, using the clock the master sends them while it's idle, see ClockSync. Panels that haven't had the clock yet, like right after the master boots, and older firmware, show them straight away. A scheduled frame holds 5 fewer color bytes, and isn't ramped, see r. |
+    | Set Panel Color<br>`l`{id}{r}{g}{b} | *Single* hex digit for the panel's sensor value, as in `L`, or `FAILED `{id} if it didn't answer | Sets the color of one panel, mapped or not, without touching the others. {id} and the colors are two hex digits each. E.g., `l0c818283`. |
+    | Set Slot Colors<br>`u`\[{slot}{r}{g}{b}\]* | *Single* hex digits for sensor values, as in `L`, one for each slot given, in the order given | Sets the colors of just the slots given, as a SetColorDelta, and leaves the other slots alone. Only their panels answer, so it takes less airtime than `L` for a few changes in a big installation. {slot} and the colors are two hex digits each, and the gains from `k` apply. E.g., `u03818283` sets slot 3. When it's time for every slot to go out, see `e`, the last `L` goes instead, with these changed. |
+    | Map Panels<br>`M`\[`S`\]\[{id}\]*<br>`MV` | `OK`, `FAILED 010203`, or `MISMATCH 0205`                                                                                                                                                                                  | Sets the panel IDs for the Set Color command Panel IDs are two ASCII hex bytes. E.g., `M04080a` sets the panel order to 4, 8, 10. A mapping every panel confirmed is saved in flash, if it changed, and sent again when the master boots, see `!remapped`. `Mx` clears the saved mapping, but leaves the current one. `MV` checks the mapping without changing it, on the master or the panels, so it's fine between frames: it asks every panel for its slot, and replies `OK`, or `MISMATCH ` and the slots, two hex digits each, whose panel has a different one or didn't answer. Panels with older firmware don't answer. `MS` changes the mapping mid-show without a frame of wrong colors: the panels hold the new slots, still using the old ones, until every panel has confirmed, and then the master tells them all to switch at once. A panel that misses that switches on the first frame with as many slots as the new mapping. If any panel doesn't confirm, it replies `FAILED` as usual, and the master and panels keep the old mapping. Panels with older firmware never confirm `MS`. |
+    | Reset All<br>`R`               | `OK` or `FAILED 010203`                                                                                                                                                                                  | Restarts all panels, and maps them again once they're back. FAILED lists mapped panels that didn't announce themselves afterwards. Takes a few seconds.                                                                       |
+    | Animate<br>`A`{pattern}       | `OK` or an error message                                                                                                                                                                                 | Shows a built-in animation on the mapped panels until the next command. {pattern} is `1` rainbow, `2` all white, `3` chase by slot, `4` light up on PIR.                                                                |
+    | Health<br>`H`\[{secs}\]       | JSON `[{id, misses, goneSecs}]`<br>E.g., `[{"id":12, "misses":3, "goneSecs":15}]`                                                                                                                         | Lists mapped panels that have stopped answering the pings the master sends when idle, lowest ID first. With {secs} (two hex digits), sets how long the master waits while idle before pinging instead; `00` turns health checks off.           |
+    | Bus Baud<br>`B`{baud}         | `OK` or `FAILED 010203`                                                                                                                                                                                  | Switches the panel bus to a new baud rate. {baud} is `0` for 115200, `1` for 256000, `2` for 512000, `3` for the default. Panels switch after acknowledging at the old rate, and only save the new rate once they hear from the master at it, so a panel that gets lost goes back to the old rate when it's reset or power cycled. FAILED lists mapped panels that didn't acknowledge. Serial comm mode only. |
+    | PIR Log<br>`G`\[`0`\]         | JSON lines `{t, slot, pirs}`, then `{dropped}`<br>E.g., `{"t":81234, "slot":3, "pirs":1}` ... `{"dropped":0}`                                                                                              | Lists when motion started in each slot, oldest first. `t` is ms since boot. `dropped` counts events lost because the log filled up. `G0` clears the log.                                                                  |
+    | Dry Run<br>`T`{0\|1}         | `OK` or an error message                                                                                                                                                                                 | Turns dry runs of `L` and `M` off (`0`, the default) or on (`1`). In a dry run they're parsed and checked as usual, but nothing is sent or changed. The reply is `DRY slots=`{n} or `DRY panels=`{n}, then `msg=`{tag} `bytes=`{n} `packets=`{n} `data=`{hex}. |
+    | Ramp<br>`r`\[{max}\]           | The limit, `off`, `OK`, or an error message | Without {max}, replies with the limit. With it, in decimal, limits how much `L` changes the total brightness per frame, summed over every channel of every slot. A bigger change is spread over frames in between, sent one after the other, so a supply shared by many panels ramps up instead of tripping. Up to MAX_RAMP_FRAMES are added, however small {max} is. The PIR reply is from the last frame. `r0`, the default, turns it off. |
+    | Delta Frames<br>`e`\[{ms}\]   | The interval, `off`, `OK`, or an error message | Without {ms}, replies with the interval. With it, in decimal, `L` sends a SetColorDelta with only the slots that changed since the last `L`, when that's shorter, and every slot at least once every {ms}, so a panel that missed a delta doesn't stay wrong. A new mapping or a panel rebooting sends every slot next time. Slots a delta leaves out don't answer, so their digits in the reply are the ones from the last frame they answered. `e0` turns it off, which is how it starts. `u` sends every slot at least once a second when it's off. Only plain RGB frames are sent as deltas, and not with `@`. |
+    | Quiet Frames<br>`q`\[{0\|1}\] | `on`, `off`, `OK`, or an error message | Turns frames without replies off (`0`, the default) or on (`1`), for shows that don't use the PIRs. `L` goes as a Set Color No Reply, which panels show without answering, and replies `OK` as soon as it's sent rather than waiting out the reply window, so frames can go out several times as often, see `fps` and `quietFps` in `J`. Ramp frames go the same way. `u` still gets answers. The panels keep up with their PIRs meanwhile, and after `q0`, the next `L` goes to every slot, so the PIRs are all current again. Without an argument, replies with whether it's on. |
+    | Arbitration<br>`a`\[{priority}\] | `active `{priority}, `standby `{priority} {id}, `off`, `OK`, or an error message | Shares the panel bus with other masters, for a hot standby. Without {priority}, replies with this master's role, and in standby, the ID of the master it's standing by for, `--` if it hasn't heard one yet. With {priority} (two hex digits), starts sharing: active masters send a beacon every 500 ms, a master that hears a higher one stands by, sending nothing, and it takes over after missing 3 beacons, see Arbiter. Equal priorities go to the higher ID. A master starts in standby, and is active 1.5 s later if it hasn't heard a higher one. `a00`, the default, stops sharing. Not saved, so the host sets it after each boot. In standby, `E`, `L`, `l`, `M`, `R`, and `A` reply `STANDBY`, and other commands for the panels fail, since nothing is sent, so the host should talk to the other master. Role changes are notified, see `!standby`. A master running an animation or identify doesn't hear beacons. Serial comm mode only. |
+    | PIR Stream<br>`p`\[{ms}\]     | The interval, `off`, `OK`, or an error message | Without {ms}, replies with the interval. With it, in decimal, 50 or more, the master polls the mapped panels' PIRs every {ms} ms while it's waiting for commands, with a PollPirs broadcast, which is much shorter than an `L`. After a poll whose PIRs differ from the last one, it sends `PIR `{bits} to both ports, see Notifications. Commands go ahead of polls, and a poll cuts its reply window short for one. `L` doesn't change what's compared. `p0`, the default, stops polling, and nothing more is sent. Panels with older firmware don't answer. |
+    | Color Correction<br>`k`\[{slot}{r}{g}{b}\]<br>`kw`<br>`kx` | JSON lines `{slot, gains}`, then `{saved}`, `OK`, or an error message<br>E.g., `{"slot":3, "gains":"8090a0"}` ... `{"saved":false}` | Gains the master applies to each slot's colors in `L`, so strips from different batches can be made to match. {slot} and the gains are two hex digits each, and a gain of `80` is 1, so `k03ff8080` doubles the red of slot 3, up to `ff`. White and the other commands' colors are left alone. `k` alone lists the slots that aren't all `80`, and whether that's what's saved. `kw` saves the gains in flash, see ColorCorrection, and `kx` puts every slot back to `80`, which isn't saved until `kw`. Unsaved gains are lost at reset. |
+    | Status Mirror<br>`s`{0\|1}    | `OK` or an error message | Turns showing comm health on the panels' own status LEDs off (`0`, the default) or on (`1`), for a look along the line during tear-down. After each `L`, mapped panels whose status changed are sent it, a few in each gap between commands: LED0 if the panel replied to that frame, LED1 if it has missed 3 or more frames in a row, and LED2 if it saw motion. A panel shows it for 5 seconds, then goes back to its heartbeat. Off puts every panel's status LEDs back to normal. See StatusMirror. |
+    | Latency<br>`U`{id}\[{n}\]       | JSON `{id, sent, received, minUs, avgUs, maxUs, bucketUs, hist}`<br>E.g., `{"id":12, "sent":16, "received":16, "minUs":580, "avgUs":611, "maxUs":702, "bucketUs":250, "hist":[0,0,14,2,0,0,0,0]}` | Pings panel {id} {n} times (two hex digits each, default 16 times) and reports the round-trip times like Enumerate's `rttUs`. `hist` counts them in `bucketUs` wide buckets, with the last one taking everything longer. |
+    | Simulate<br>`Q`\[\[`-`\]{id}\]* | `OK` or an error message                                                                                                                                                                                 | Swaps the radio or panel bus for up to 8 panels simulated in software, for trying out the master on the bench. {id} is two hex digits, and a panel with `-` in front never replies. The panels answer like real ones, with made-up boot counts and RSSI, and their PIRs go on and off on a schedule. `Q` alone goes back to the real panels. Version shows `Comm=Sim` meanwhile. |
+    | Identify<br>`N`\[{slot}\]      | `OK` or an error message                                                                                                                                                                                 | Blinks the status LEDs of the panel in {slot} (two hex digits) and lights it up white for a few seconds, then puts its color back. Without {slot}, goes through all the mapped slots in order. Any command stops it.         |
+    | Macro<br>`x`\[{name}\[`=`{line}\[`;`{line}\]*\]\] | Lines `{name}={line};{line}...`, then `OK`, `OK` then a line for each reply, `OK`, or an error message | Named lists of commands, kept in flash, for the setup typed in at every power-up, see Macros. Alone, lists the macros, one to a line. `x`{name} runs the macro's lines as commands, after `OK`, each reply after its line's index and a space, e.g. `0 [...]`. `x`{name}`=` with lines, separated by `;`, saves it, replacing one by that name, and with none, deletes it. {name} is 1 to 8 of `a`-`z`, `0`-`9`, and `_`. There are at most 4 macros of at most 8 lines, about 300 characters in all, and a line can't be empty or run a macro. The one named `autoexec` runs when the master boots, after the saved mapping is replayed, with its replies to the serial port. A corrupt page is treated as no macros. |
+    | Panel Status<br>`P`{id}        | JSON `{id, bootCount, rssiM, rssiP, slot, color, pirs, uptime, pirProfile, hours, output, usb, lastPanic}`<br>E.g., `{"id":12, "bootCount":123, "rssiM":-35, "rssiP":-42, "slot":3, "color":"818283", "pirs":1, "uptime":3600, "pirProfile":"A", "hours":1520, "output":100, "usb":false, "lastPanic":"08003a5c"}`, or `{"id":12, "error":"no reply"}` | Queries one panel directly. {id} is two hex digits. `slot` is `null` if the panel isn't mapped. `uptime` is in seconds. `hours` is the panel's operating hours over its life. `output` is the percent its LEDs are derated to, under 100 if it's throttling, see d. `usb` is true if something has the panel's USB port, like a technician's laptop. `lastPanic` is where the panel panicked before it last reset, like `Panic=` in `V`, or `null` if it didn't. `pirProfile`, `hours`, `output`, and `usb` are `null` for firmware too old to say, and so is `lastPanic`.                                                    |
+
+    Spy-only commands
+
+    | Command                   | Response                 | Description                                                                                         |
+    | ------------------------- | ------------------------ | --------------------------------------------------------------------------------------------------- |
+    | Capture<br>`S`{0\|1}      | `OK` or an error message | Stops (`0`) or starts (`1`) writing a binary record to USB for each packet seen, see capture.        |
+
+    Bridge mode
+
+    A bridge takes panel mode commands, and answers the master like a panel,
+    so it shows up in Enumerate. It also passes every packet between the
+    radio and the panel bus, so one master can drive panels on both. Set it
+    up with `DB`. The master is best on the radio side, see
+    PanelComm::start_bridge().
+
+    Notifications
+
+    Lines starting with `!` aren't replies. They can come between replies on
+    both ports, whenever the master has something to report.
+
+    | Notification        | Description                                                        |
+    | ------------------- | ------------------------------------------------------------------ |
+    | `!missing `{id}     | Mapped panel {id} stopped answering the master's health pings.     |
+    | `!back `{id}        | Mapped panel {id} is answering again.                              |
+    | `!rebooted `{id}    | Mapped panel {id} rebooted, so the master sent the mapping again.  |
+    | `!announce `{id}    | Panel {id} just booted. If it's mapped, the master sends it the mapping. |
+    | `!remapped `{n} {slots} | The master booted with a saved mapping of {slots} slots, see M, and sent it to the panels. {n} of them confirmed. Commands are read once it's done. |
+    | `!protocol `{id} {version} | Panel {id} speaks a different protocol version than the master, `?` if it's too old to say. Sent by Enumerate. |
+    | `PIR `{bits}        | With `p`, the slots whose panels saw motion changed. {bits} is eight hex digits, with bit 0 for slot 0, set for motion on either PIR. Panels that didn't answer count as no motion. No `!`, but not a reply either. |
+    | `!standby `{id}     | With `a`, master {id} has the panels, and this one stopped sending. |
+    | `!active`           | With `a`, this master has taken over the panels.                   |
+    | `!usb connected`<br>`!usb disconnected` | Only to the serial port. A host connected to or went away from the USB port, see Info. |
+    | `!stale `{n}        | Only to the port concerned. {n} commands that came in during a slow command were thrown away, see Progress and timeouts. |
+    | `!txpower `{action} {power} {id} {rssi} | After Enumerate, with `Wa1` or `Wa2`. The weakest link, to panel {id}, was {rssi} dBm, so {power} would be better. {action} is `suggest`, or `set` if it's already been changed everywhere. |
+
+    The IDs in a `FAILED` reply are lowest first, whatever order the panels
+    are mapped in.
+
+    Progress and timeouts
+
+    Commands that take longer than PROGRESS_AFTER, like M retrying and R
+    waiting for the panels, can send lines starting with `...` before their
+    reply, e.g. `...retry 2/4, 3 of 5 panels confirmed`. The reply is still
+    the last line. A command still going after COMMAND_BUDGET (10 s) is
+    abandoned, and its reply is `TIMEOUT`.
+
+    Commands don't overlap. One that arrives while another is going waits
+    for it, and the serial port holds up to four full lines meanwhile. Once
+    a command that took STALE_AFTER (1 s) or longer has replied, the lines
+    already waiting are stale: they're thrown away unanswered, and each port
+    that had some gets `!stale `{n}, how many. Anything typed after the reply
+    is handled as usual, so a host should wait for each reply before sending
+    the next command.
 
     P Protocol messages
 
+    Panels reply PANEL_REPLY_DELAY after the message arrives. A message that
+    arrives before the reply to the last one is out doesn't cancel it, but a
+    reply still unsent after STALE_REPLY_AFTER is dropped, see
+    send_replies().
+
     | Command                            | Reply                | Description                                                                                                           |
     | ---------------------------------- | -------------------- | --------------------------------------------------------------------------------------------------------------------- |
-    | Ping<br>`P`                        | `I`{bootCount}{rssi} | {rssi} is a signed byte of RSSI                                                                                       |
-    | Set Color<br>`C`\[{r}{g}{b}\]*     | `c`{PIR}             | {r}, {g}, {b} are RGB intensity bytes.<br>{PIR} byte: bitwise OR of 1 for PIR1, 2 for PIR2 |    | Map Panels<br>`M`[{id}]*           | `m`{slot}            | Sets the ID to slot mapping to be used when interpreting Set Color commands                                           |
-    | Reset<br>`R`                       | *none*               | Restart the controller                                                                                                |
+    | Ping<br>`P`                        | `I`{bootCount}{rssi}{resetCause}{fw}{zones}{faults}{txPower}{lastPanic} | {rssi} is a signed byte, the RSSI of the Ping on the panel. {resetCause} is a ResetCause, {fw} is a FirmwareId, {zones} is how many LED zones the panel has, {faults} is what its self-test found, or that its panel bus is stuck, see self_test, {txPower} is its TxPower, and {lastPanic} is a PanicLocation. Older firmware leaves off the ones it doesn't know |
+    | Set Color<br>`C`\[{r}{g}{b}\]*     | `c`{PIR}             | {r}, {g}, {b} are RGB intensity bytes, one set per slot. Sent to a single panel with just one set, it's that panel's color whatever its slot. With two sets, they're for its two zones.<br>{PIR} byte: bitwise OR of 1 for PIR1, 2 for PIR2 |
+    | Set Color RGBW<br>`W`\[{r}{g}{b}{w}\]* | `c`{PIR}         | Like Set Color, with a white intensity byte per slot                                                                  |
+    | Set Color Zones<br>`Z`{twoZoneSlots}\[{r}{g}{b}\]* | `c`{PIR} | Like Set Color, but slots with a bit set in {twoZoneSlots} (u32, little-endian) have a set for each zone, see slot_colors() |
+    | Set Color Delta<br>`d`\[{slot}{r}{g}{b}\]* | `c`{PIR}    | Colors for only the slots that changed, in any order. Panels whose slot isn't in it keep their colors and don't answer, and unmapped panels don't answer either. Panels with two zones show the color on both |
+    | Map Panels<br>`M`[{id}]*           | `m`{slot}{zones}     | Sets the ID to slot mapping to be used when interpreting Set Color commands                                           |
+    | Reset<br>`R`                       | *none*               | Restart the controller. Only the second of two Resets within RESET_WINDOW does, so a stray one is ignored             |
     | Set Status<br>`S`{status}          | *none*               | Sets the status lights on the controller to the low four bits of the byte {s}                                         |
+    | Set Baud<br>`B`{baud}{confirm}     | `b` if {confirm} is 0 | Panel bus baud rate, see BusBaud. With {confirm} 0, acknowledge and switch. With 1, save the rate if it's the current one |
+    | Status Request<br>`Q`              | `q`{status}          | {status} is {bootCount}{slot}{r}{g}{b}{PIR}{uptime}{pirProfile}{hours}{derating}{usb}{lastPanic}, see PanelStatus                                  |
+    | PIR Config<br>`F`{inv}{t1}{t2}\[{rf}{profile}\] | *none* | Sets PIR polarity, minimum active time, and refractory period, see PirConfig                                         |
+    | Set PIR Profile<br>`Y`{profile}    | *none*               | Switches to PirProfile {profile}                                                                                      |
+    | Announce<br>`A`{bootCount}{resetCause} | *none*           | Broadcast by a panel once, up to 2 s after it boots. The master answers with Map Panels if the panel is mapped        |
+    | Set Color Order<br>`O`{order}      | *none*               | Sets and saves the LED strip color order, see ColorOrder                                                              |
+    | Flash ID<br>`N`                    | *none*               | Flashes the panel's ID on its LED strip, see IdFlash                                                                  |
+    | Reliable<br>`K`{seq}{tag}{data}*   | `k`{seq}             | Message {tag} with its {data}, acknowledged with the same {seq}. Only sent to one panel, and only for messages with no reply of their own. A resend of the same {seq} is acknowledged but not acted on again, see PanelComm::send_unicast_reliable() |
+    | Set TX Power<br>`T`{power}         | *none*               | Sets and saves the radio's transmit power, see TxPower                                                                |
+    | Not Mapped<br>`n`{id}              | *none*               | Sent instead of `c` by a panel {id} that got a broadcast Set Color but has no slot, at most once every NOT_MAPPED_INTERVAL. It goes out NOT_MAPPED_STEP later for each ID, so they don't all land at once |
+    | Set Derating<br>`D`{threshold}     | *none*               | Turns LED derating on at {threshold} percent heat, or off with 0, see Derating                                        |
+    | Poll PIRs<br>`U`                   | `c`{PIR}             | Like Set Color, but without colors. Only mapped panels reply                                                          |
+    | Master Beacon<br>`G`{priority}     | *none*               | Broadcast by an active master sharing the bus, see Arbiter. Panels ignore it |
+    | Set Channel<br>`H`{channel}{confirm} | `h` if {confirm} is 0 | Radio channel, see PanelRadio::frequency(). With {confirm} 0, acknowledge and switch. With 1, save the channel if it's the current one |
+    | Map Panels Pending<br>`E`[{id}]*   | `m`{slot}{zones}     | Like Map Panels, but the panel holds the slot, and keeps using its old one until Commit Mapping                        |
+    | Commit Mapping<br>`L`              | *none*               | Switches to the slot from the last Map Panels Pending. A panel that misses it switches on the first broadcast Set Color with as many slots as that mapping, see slot_count() |
+    | Query Slot<br>`V`                  | `v`{slot}            | The panel's slot, or 0xff if it has none. Changes nothing                                                             |
+    | Time Sync<br>`J`{ms}               | *none*               | Broadcast by an idle master every TIME_SYNC_INTERVAL, in a gap between commands, see Outbox. {ms} is its clock (u32, little-endian). Panels keep theirs in step, see ClockSync |
+    | Set Color At<br>`X`{ms}{tag}{data}* | `c`{PIR}            | Set Color, RGBW, or Zones message {tag} with its {data}, replied to straight away but shown when the master's clock reads {ms} (u32, little-endian), see Packet::to_scheduled(). A panel that hasn't had a Time Sync yet shows it straight away |
+    | Set Color No Reply<br>`s`{tag}{data}* | *none*          | Set Color, RGBW, Zones, Delta, or At message {tag} with its {data}, handled the same but never answered, not even with Not Mapped, see Packet::to_no_reply(). Reading the PIRs doesn't clear them, so the next message that is answered still has them |
 
 */
 
@@ -48,25 +348,103 @@ const MAX_PANEL_SLOTS: usize = 32;
 pub enum Command {
     DefaultMode = b'D',
     Version = b'V',
+    Echo = b'X',
+    Help = b'?',
+    PirConfig = b'F',
+    PirProfile = b'Y',
+    Derating = b'd',
+    Info = b'J',
+    ColorOrder = b'O',
+    CommStats = b'C',
     Enumerate = b'E',
     SetColor = b'L',
+    SetPanelColor = b'l',
+    SetColorDelta = b'u',
+    DeltaFrames = b'e',
     MapPanels = b'M',
     Reset = b'R',
+    PanelStatus = b'P',
+    Health = b'H',
+    Animate = b'A',
+    BusBaud = b'B',
+    PirLog = b'G',
+    DryRun = b'T',
+    Ramp = b'r',
+    Arbitration = b'a',
+    PirStream = b'p',
+    StatusMirror = b's',
+    Correction = b'k',
+    PacketLogs = b'K',
+    Identify = b'N',
+    Latency = b'U',
+    Simulate = b'Q',
+    FlashId = b'I',
+    Group = b'g',
+    RadioRegisters = b'Z',
+    TxPower = b'W',
+    Channel = b'h',
+    SoftRestart = b'b',
+    RebootSeen = b'm',
+    Verbosity = b'v',
+    LedBrightness = b'i',
+    Capture = b'S',
+    Macro = b'x',
+    BusMirror = b'w',
+    QuietFrames = b'q',
+    RadioTest = b'z',
     TestMessage = b'_',
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
-#[repr(u8)]
-pub enum Message {
-    Ping = b'P',
-    SetColor = b'C',
-    MapPanels = b'M',
-    Reset = b'R',
-    SetStatus = b'S',
-    Test = b'_',
-    PingReply = b'I',
-    SetColorReply = b'c',
-    MapPanelsReply = b'm',
+/// A panel's reply to a message, waiting for its time to be sent.
+struct QueuedReply {
+    packet: Packet,
+    arrival_time: Instant,
+    send_at: Instant,
+}
+
+/// The panels an `EI` shows one at a time, in the order Enumerate listed
+/// them, see read_master_command().
+struct IdentifyWalk {
+    ids: heapless::Vec<u8, MAX_PANEL_SLOTS>,
+    next: usize,
+    dwell: Duration,
+    /// When ids[next] is done, once its `IDENTIFY` line has been written
+    until: Option<Instant>,
+}
+
+/// What woke up the master while it was waiting for a command.
+enum MasterEvent<'b> {
+    Command(&'b [u8]),
+    /// A packet nobody was waiting for, like an Announce
+    Packet(Packet),
+    /// There are notifications to send
+    Notifications,
+    /// The user button was held long enough to go to the settings
+    Settings,
+    /// An `EI` is done with a panel
+    IdentifyNext,
+}
+
+/// What the master does about TX power after an Enumerate, see
+/// CmdProcessor::adapt_tx_power().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxPowerAdapt {
+    Off,
+    /// Sends a `!txpower suggest` notification
+    Suggest,
+    /// Changes the power on all the boards, and sends a `!txpower set`
+    /// notification
+    Auto,
+}
+
+impl TxPowerAdapt {
+    fn name(&self) -> &'static str {
+        match self {
+            TxPowerAdapt::Off => "off",
+            TxPowerAdapt::Suggest => "suggest",
+            TxPowerAdapt::Auto => "auto",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -75,25 +453,226 @@ pub struct PanelInfo {
     pub boot_count: u8,
     pub rssi_master: i8,
     pub rssi_panel: i8,
+    pub reset_cause: ResetCause,
+    /// None if the panel's firmware is too old to say
+    pub fw: Option<FirmwareId>,
     pub pirs: u8,
     pub slot: u8,
+    /// LED zones, 1 unless the panel said it has two
+    pub zones: u8,
+    /// What the panel's self-test found, None if its firmware is too old to
+    /// say
+    pub faults: Option<u8>,
+    /// Round trips to this panel in the last command
+    pub rtt: Rtt,
+    /// None if the panel's firmware is too old to say
+    pub tx_power: Option<TxPower>,
+    /// Where the panel panicked before it last reset, None if it didn't or
+    /// its firmware is too old to say
+    pub last_panic: Option<PanicLocation>,
+    /// Bits of the Enumerate rounds it answered, see E
+    pub seen_rounds: u16,
+}
+
+// The longest reply a panel sends to a broadcast, a PingReply's data. The panel
+// bus is set up to hold a burst of these, see comm::MAX_REPLY_BURST.
+pub const MAX_BROADCAST_REPLY_LEN: usize = 6 + FirmwareId::WIRE_LEN + PanicLocation::WIRE_LEN;
+
+/// A panel's reply to StatusRequest.
+///
+/// Wire format:
+///
+/// [boot_count, slot, r, g, b, pirs, uptime (4 bytes, little-endian seconds),
+///  pir_profile, hours (4 bytes, little-endian), derating, usb, last_panic
+///  (4 bytes)]
+///
+/// slot is 0xFF if the panel isn't mapped. pir_profile is a PirProfile, and
+/// hours is the panel's operating hours, see OperatingHours. derating is what
+/// the panel's LED output is scaled by, 0xFF for none, see Derating.
+/// last_panic is where the panel panicked before it last reset, if it did,
+/// see PanicLocation. They're missing from older firmware.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct PanelStatus {
+    pub boot_count: u8,
+    pub slot: Option<u8>,
+    pub color: [u8; 3],
+    pub pirs: u8,
+    pub uptime_secs: u32,
+    pub pir_profile: Option<PirProfile>,
+    pub hours: Option<u32>,
+    pub derating: Option<u8>,
+    /// A host has the panel's USB port, like a technician's laptop
+    pub usb: Option<bool>,
+    /// Older firmware can't say, so it's None too
+    pub last_panic: Option<PanicLocation>,
+}
+
+impl PanelStatus {
+    const WIRE_LEN: usize = 17 + PanicLocation::WIRE_LEN;
+    /// The slot of a panel that isn't mapped, here and in SlotReply
+    pub const NO_SLOT: u8 = 0xFF;
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        // Older firmware sends 10, 11, 15, 16, or 17 bytes
+        if !matches!(bytes.len(), 10 | 11 | 15 | 16 | 17 | Self::WIRE_LEN) {
+            return None;
+        }
+        Some(Self {
+            boot_count: bytes[0],
+            slot: (bytes[1] != Self::NO_SLOT).then_some(bytes[1]),
+            color: [bytes[2], bytes[3], bytes[4]],
+            pirs: bytes[5],
+            uptime_secs: u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]),
+            pir_profile: bytes.get(10).and_then(|&p| PirProfile::try_from(p).ok()),
+            hours: bytes
+                .get(11..15)
+                .map(|h| u32::from_le_bytes([h[0], h[1], h[2], h[3]])),
+            derating: bytes.get(15).copied(),
+            usb: bytes.get(16).map(|&usb| usb != 0),
+            last_panic: bytes
+                .get(17..Self::WIRE_LEN)
+                .and_then(|p| PanicLocation::from_bytes([p[0], p[1], p[2], p[3]])),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::WIRE_LEN] {
+        let uptime = self.uptime_secs.to_le_bytes();
+        let hours = self.hours.unwrap_or(0).to_le_bytes();
+        let panic = PanicLocation::to_bytes(self.last_panic);
+        [
+            self.boot_count,
+            self.slot.unwrap_or(Self::NO_SLOT),
+            self.color[0],
+            self.color[1],
+            self.color[2],
+            self.pirs,
+            uptime[0],
+            uptime[1],
+            uptime[2],
+            uptime[3],
+            self.pir_profile.unwrap_or(PirProfile::A).into(),
+            hours[0],
+            hours[1],
+            hours[2],
+            hours[3],
+            self.derating.unwrap_or(FULL_OUTPUT),
+            self.usb.unwrap_or(false).into(),
+            panic[0],
+            panic[1],
+            panic[2],
+            panic[3],
+        ]
+    }
 }
 
 pub struct CmdProcessor<'a> {
     mode: Mode,
-    interactor: Interactor<'a>,
+    interactor: Interactor<CommandSerial<'a>, UsbPort>,
     comm: PanelComm,
     address: Address,
     led_strip: LedStrip,
-    pirs: Pirs,
+    pirs: PirSensors,
+    button: UserButton,
     panels: heapless::Vec<PanelInfo, MAX_PANEL_SLOTS>,
+    mapping: heapless::Vec<u8, MAX_PANEL_SLOTS>,
+    health: HealthMonitor,
+    pir_log: PirLog,
+    animation: Option<Animation>,
+    identify: Option<Identify>,
+    identify_walk: Option<IdentifyWalk>,
+    capture: Option<Capture>,
+    /// SetColor from the last Set Color command, for putting the colors back
+    last_colors: Option<Packet>,
+    /// Mapped slots whose panels have two LED zones, see slot_colors()
+    two_zone_slots: u32,
+    /// Mapped slots whose panels announced they'd booted and need the mapping
+    remap_slots: u32,
     my_slot: Option<u8>,
-    reply_buf: heapless::String<256>,
+    /// Our slot in a mapping waiting for CommitMapping, and how many slots
+    /// it has, see MS
+    pending_mapping: Option<(Option<u8>, usize)>,
+    color: [u8; 3],
+    /// When we last told the master we're not mapped
+    not_mapped_at: Option<Instant>,
+    /// Unmapped panels that answered the last Set Color command, see L
+    not_mapped: heapless::Vec<u8, MAX_PANEL_SLOTS>,
+    pending_baud: Option<BusBaud>,
+    /// Replies waiting to be sent, oldest first, see send_replies()
+    replies: heapless::Deque<QueuedReply, MAX_QUEUED_REPLIES>,
+    /// Radio channel to switch to once the SetChannel reply is on its way
+    pending_channel: Option<u8>,
+    /// Zone colors to show once the SetColor reply is on its way
+    pending_color: Option<ZoneColors>,
+    /// Zone colors to show at the time a SetColorAt asked for
+    scheduled_color: Option<(Instant, ZoneColors)>,
+    /// The master's clock, from its TimeSyncs
+    clock: ClockSync,
+    /// When to next send the panels our clock, see ClockSync
+    time_sync_at: Instant,
+    /// Management messages waiting for a gap between commands
+    outbox: Outbox,
+    /// Flashing our ID on the LED strip
+    id_flash: Option<IdFlash>,
+    /// Stepping through the LED colors for the user button, see LedTest
+    led_test: Option<LedTest>,
+    /// When the first of the two Resets needed to reset arrived
+    reset_armed_at: Option<Instant>,
+    /// Sequence number of the last Reliable message, and when it arrived
+    last_reliable: Option<(u8, Instant)>,
+    /// Parse and check L and M commands, but don't send anything
+    dry_run: bool,
+    /// L goes out as SetColorNoReply, without waiting for replies, see q
+    quiet_frames: bool,
+    /// Most L can change the total brightness in one frame, see r
+    max_frame_delta: Option<u32>,
+    /// L sends only the slots that changed, with every slot at least this
+    /// often, see e
+    delta_every: Option<Duration>,
+    /// When every slot's colors last went out, None to send them all next
+    full_frame_at: Option<Instant>,
+    /// Each slot's sensor bits from the last frame, for the slots a delta
+    /// leaves out
+    slot_pirs: [u8; MAX_PANEL_SLOTS],
+    /// Showing comm health on the panels' status LEDs, see s
+    status_mirror: Option<StatusMirror>,
+    /// Gains for the colors in L, see k
+    correction: ColorCorrection,
+    /// The master's role when it last changed, if the bus is shared, see a
+    role: Option<Role>,
+    /// Polling the panels' PIRs on its own, see p
+    pir_stream: Option<PirStream>,
+    /// PIR profile the master last switched the panels to
+    pir_profile: PirProfile,
+    /// Derating threshold the master last sent the panels, see d
+    derating: Option<u8>,
+    /// The Enumerate round PingReplies count for, see PanelInfo::seen_rounds
+    enumerate_round: u8,
+    queried_status: Option<PanelStatus>,
+    /// The slots panels said they have, for MV
+    queried_slots: heapless::Vec<(Address, u8), MAX_PANEL_SLOTS>,
+    reply_buf: ReplyBuf,
+    notifications: Notifications,
+    stats: CommandStats,
+    last_command: heapless::Vec<u8, MAX_REPEAT_LEN>,
+    /// When the command being handled arrived
+    command_started: Instant,
+    hours: OperatingHours,
+    /// The last mapping that every panel confirmed, kept in flash, see M
+    saved_mapping: SavedMapping,
+    /// Named command lists, kept in flash, see x
+    macros: Macros,
+    /// The macro to run once the reply to x has gone
+    macro_to_run: Option<MacroName>,
+    tx_power_adapt: TxPowerAdapt,
+    /// A soft restart was asked for, see b
+    restart: bool,
+    led_heartbeat: LedHeartbeat,
 }
 
 impl<'a> CmdProcessor<'a> {
     pub fn new(
-        interactor: Interactor<'a>,
+        interactor: Interactor<CommandSerial<'a>, UsbPort>,
         comm: PanelComm,
         address: Address,
         led_strip: LedStrip,
@@ -105,101 +684,748 @@ impl<'a> CmdProcessor<'a> {
             comm,
             address,
             led_strip,
-            pirs,
+            pirs: PirSensors::new(pirs),
+            button: UserButton::new(address),
             panels: heapless::Vec::new(),
+            mapping: heapless::Vec::new(),
+            health: HealthMonitor::new(),
+            pir_log: PirLog::new(),
+            animation: None,
+            identify: None,
+            identify_walk: None,
+            capture: None,
+            last_colors: None,
+            remap_slots: 0,
             my_slot: None,
-            reply_buf: heapless::String::<256>::new(),
+            pending_mapping: None,
+            color: [0; 3],
+            not_mapped_at: None,
+            not_mapped: heapless::Vec::new(),
+            pending_baud: None,
+            replies: heapless::Deque::new(),
+            pending_channel: None,
+            pending_color: None,
+            scheduled_color: None,
+            clock: ClockSync::new(),
+            time_sync_at: Instant::now(),
+            outbox: Outbox::new(),
+            id_flash: None,
+            led_test: None,
+            two_zone_slots: 0,
+            reset_armed_at: None,
+            last_reliable: None,
+            dry_run: false,
+            quiet_frames: false,
+            max_frame_delta: None,
+            delta_every: None,
+            full_frame_at: None,
+            slot_pirs: [0; MAX_PANEL_SLOTS],
+            status_mirror: None,
+            correction: ColorCorrection::load(),
+            role: None,
+            pir_stream: None,
+            pir_profile: PirProfile::A,
+            derating: None,
+            enumerate_round: 0,
+            queried_status: None,
+            queried_slots: heapless::Vec::new(),
+            reply_buf: ReplyBuf::new(),
+            notifications: Notifications::new(),
+            stats: CommandStats::new(),
+            last_command: heapless::Vec::new(),
+            command_started: Instant::now(),
+            hours: OperatingHours::load(),
+            saved_mapping: SavedMapping::load(),
+            macros: Macros::load(),
+            macro_to_run: None,
+            tx_power_adapt: TxPowerAdapt::Off,
+            restart: false,
+            led_heartbeat: LedHeartbeat::new(Mode::Master),
         }
     }
 
-    pub async fn run_master(mut self) {
+    /// Starts over on the same hardware, for a soft restart: no mapping, no
+    /// panels, nothing pending, and the LED strip off. The command ports are
+    /// kept as they are, so USB stays connected. Counters and operating hours
+    /// carry on, since the board itself didn't reset. Simulated panels are
+    /// dropped, but the comm mode can only change with a real reset.
+    ///
+    pub fn restarted(self) -> Self {
+        let Self {
+            interactor,
+            mut comm,
+            address,
+            mut led_strip,
+            pirs,
+            stats,
+            hours,
+            ..
+        } = self;
+        comm.simulate(None);
+        led_strip.set_colors(0, 0, 0);
+        let mut fresh = Self::new(interactor, comm, address, led_strip, pirs.into_pirs());
+        fresh.stats = stats;
+        fresh.hours = hours;
+        fresh
+    }
+
+    /// Runs until a soft restart is asked for, see restarted().
+    pub async fn run_master(mut self) -> Self {
         self.mode = Mode::Master;
+        self.led_heartbeat = LedHeartbeat::new(self.mode);
         info!("Master mode");
         loop {
+            self.note_role();
+            self.send_notifications().await;
+            self.send_remaps().await;
+            self.announce_identify().await;
             let mut buf = [0; 256];
-            let line = self.interactor.read_command(&mut buf).await;
+            let line = match self.read_master_command(&mut buf).await {
+                MasterEvent::Command(line) => line,
+                MasterEvent::Packet(packet) => {
+                    let _busy = watchdog::busy(Subsystem::Packets);
+                    self.handle_reply(packet, None);
+                    continue;
+                }
+                MasterEvent::Notifications | MasterEvent::IdentifyNext => continue,
+                MasterEvent::Settings => self.enter_settings().await,
+            };
             // defmt::debug!("Command: {:a}", line);
-            self.reply_buf.clear();
-            self.handle_command(Mode::Master, line).await;
-            self.interactor.reply(&self.reply_buf).await;
+            let _busy = watchdog::busy(Subsystem::Commands);
+            self.led_heartbeat.activity();
+            self.run_command(Mode::Master, line).await;
+            if let Some(name) = self.macro_to_run.take() {
+                self.run_macro(&name).await;
+            }
+            if self.restart {
+                return self;
+            }
+        }
+    }
+
+    /// Writes the `IDENTIFY` line for the next panel an `EI` shows, just before
+    /// it's shown, or ends the walk after the last one. The line goes to the
+    /// port that sent the `EI`, since nothing else has come in since.
+    ///
+    async fn announce_identify(&mut self) {
+        let Some(walk) = &mut self.identify_walk else {
+            return;
+        };
+        if walk.until.is_some() {
+            return;
+        }
+        let Some(&id) = walk.ids.get(walk.next) else {
+            self.identify_walk = None;
+            return;
+        };
+        walk.until = Some(Instant::now() + walk.dwell);
+        let mut line = heapless::String::<16>::new();
+        let _ = write!(line, "IDENTIFY id={:02x}", id);
+        self.interactor.reply(&line).await;
+    }
+
+    /// Reads the next command, checking on the panels whenever the host has
+    /// been quiet for a while, or running the animation, identification, or
+    /// `EI` walk if there is one. Also returns early for packets that arrive
+    /// while we're idle, and when there are notifications to send, since we
+    /// can't write while reading.
+    ///
+    async fn read_master_command<'b>(&mut self, buf: &'b mut [u8; 256]) -> MasterEvent<'b> {
+        let mut read = pin!(self.interactor.read_command(buf));
+        if let Some(walk) = &mut self.identify_walk {
+            if let (Some(&id), Some(until)) = (walk.ids.get(walk.next), walk.until) {
+                let to = Address(id);
+                let show = identify::blink(&mut self.comm, self.address, to, until);
+                let line = match select4(
+                    read.as_mut(),
+                    show,
+                    self.button.watch(),
+                    self.led_heartbeat.run(),
+                )
+                .await
+                {
+                    Either4::First(line) => Some(line),
+                    Either4::Second(()) => None,
+                    Either4::Third(()) => return MasterEvent::Settings,
+                };
+                // Any command stops the walk, and puts the panel back right
+                // away, so it isn't left lit up
+                let slot = self.mapping.iter().position(|&m| m == id);
+                identify::put_back(
+                    &mut self.comm,
+                    self.address,
+                    to,
+                    slot,
+                    self.last_colors.as_ref(),
+                )
+                .await;
+                walk.next += 1;
+                walk.until = None;
+                return match line {
+                    Some(line) => {
+                        self.identify_walk = None;
+                        MasterEvent::Command(line)
+                    }
+                    None => MasterEvent::IdentifyNext,
+                };
+            }
+        }
+        if let Some(identify) = &mut self.identify {
+            let run = identify.run(
+                &mut self.comm,
+                self.address,
+                &self.mapping,
+                self.last_colors.as_ref(),
+            );
+            let line = match select4(
+                read.as_mut(),
+                run,
+                self.button.watch(),
+                self.led_heartbeat.run(),
+            )
+            .await
+            {
+                Either4::First(line) => Some(line),
+                Either4::Second(()) => None,
+                Either4::Third(()) => return MasterEvent::Settings,
+            };
+            // Any command stops it, but don't leave a panel lit up
+            identify
+                .restore(
+                    &mut self.comm,
+                    self.address,
+                    &self.mapping,
+                    self.last_colors.as_ref(),
+                )
+                .await;
+            self.identify = None;
+            if let Some(line) = line {
+                return MasterEvent::Command(line);
+            }
+        }
+        if let Some(animation) = &mut self.animation {
+            let run = animation.run(&mut self.comm, self.address, &self.mapping);
+            match select4(
+                read.as_mut(),
+                run,
+                self.button.watch(),
+                self.led_heartbeat.run(),
+            )
+            .await
+            {
+                Either4::First(line) => {
+                    // Any command stops the animation
+                    self.animation = None;
+                    return MasterEvent::Command(line);
+                }
+                Either4::Third(()) => return MasterEvent::Settings,
+            }
+        }
+        // The frame's replies are in and the next command isn't, so this is
+        // the gap the management messages go in, a few at a time, so a
+        // backlog drains over several frames rather than holding one up
+        if !self.comm.standing_by() {
+            self.outbox
+                .send(&mut self.comm, self.address, OUTBOX_BUDGET)
+                .await;
+        }
+        let mut outbox_at = Instant::now() + OUTBOX_GAP;
+        // From here, so the timers below going off doesn't put the health
+        // check off
+        let mut idle_since = Instant::now();
+        loop {
+            // Panels another master has aren't ours to check on
+            let idle_interval = match self.comm.standing_by() {
+                false => self.health.idle_interval(),
+                true => None,
+            };
+            let idle = async move {
+                match idle_interval {
+                    Some(interval) => Timer::at(idle_since + interval).await,
+                    None => core::future::pending().await,
+                }
+            };
+            let arbitration_at = self.comm.next_arbitration_at();
+            let arbitration = async move {
+                match arbitration_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            let time_sync_at = match self.comm.standing_by() {
+                false => Some(self.time_sync_at),
+                true => None,
+            };
+            let time_sync = async move {
+                match time_sync_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            let send_outbox = !self.comm.standing_by() && !self.outbox.is_empty();
+            let outbox = async move {
+                match send_outbox {
+                    true => Timer::at(outbox_at).await,
+                    false => core::future::pending().await,
+                }
+            };
+            let poll_at = match self.comm.standing_by() {
+                false => self.pir_stream.as_ref().map(|s| s.next_poll()),
+                true => None,
+            };
+            let pir_poll = async move {
+                match poll_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            let timers = select4(
+                idle,
+                select3(arbitration, time_sync, outbox),
+                pir_poll,
+                self.led_heartbeat.run(),
+            );
+            match select4(
+                read.as_mut(),
+                self.comm.recv_packet(),
+                timers,
+                self.button.watch(),
+            )
+            .await
+            {
+                Either4::First(line) => return MasterEvent::Command(line),
+                Either4::Second(packet) => return MasterEvent::Packet(packet),
+                Either4::Third(Either4::First(())) => {}
+                Either4::Third(Either4::Second(Either3::First(()))) => {
+                    self.comm.poll_arbitration().await;
+                    self.note_role();
+                    if !self.notifications.is_empty() {
+                        return MasterEvent::Notifications;
+                    }
+                    continue;
+                }
+                Either4::Third(Either4::Second(Either3::Second(()))) => {
+                    self.outbox.push(Queued::TimeSync);
+                    self.time_sync_at = Instant::now() + TIME_SYNC_INTERVAL;
+                    continue;
+                }
+                Either4::Third(Either4::Second(Either3::Third(()))) => {
+                    self.outbox
+                        .send(&mut self.comm, self.address, OUTBOX_BUDGET)
+                        .await;
+                    outbox_at = Instant::now() + OUTBOX_GAP;
+                    continue;
+                }
+                Either4::Third(Either4::Third(())) => {
+                    let Some(stream) = &mut self.pir_stream else {
+                        continue;
+                    };
+                    // A command arriving cuts the poll short
+                    let poll = stream.poll(
+                        &mut self.comm,
+                        self.address,
+                        &self.mapping,
+                        &mut self.notifications,
+                    );
+                    if let Either::First(line) = select(read.as_mut(), poll).await {
+                        return MasterEvent::Command(line);
+                    }
+                    if !self.notifications.is_empty() {
+                        return MasterEvent::Notifications;
+                    }
+                    continue;
+                }
+                Either4::Fourth(()) => return MasterEvent::Settings,
+            }
+            // Nothing from the host for a while, so check on the panels, but
+            // drop everything as soon as a command arrives.
+            let check = self.health.check(
+                &mut self.comm,
+                self.address,
+                &self.mapping,
+                &mut self.notifications,
+            );
+            if let Either::First(line) = select(read.as_mut(), check).await {
+                return MasterEvent::Command(line);
+            }
+            idle_since = Instant::now();
+            if !self.notifications.is_empty() {
+                return MasterEvent::Notifications;
+            }
         }
     }
 
-    pub async fn run_panel(mut self) {
+    /// Runs until a soft restart is asked for, see restarted().
+    pub async fn run_panel(mut self) -> Self {
         self.mode = Mode::Panel;
+        self.led_heartbeat = LedHeartbeat::new(self.mode);
         info!("Panel mode");
+        self.panel_loop().await;
+        self
+    }
+
+    /// A bridge is a panel as far as the master can tell, and the comm layer
+    /// passes everything else on, see PanelComm::start_bridge().
+    pub async fn run_bridge(mut self) -> Self {
+        self.mode = Mode::Bridge;
+        self.led_heartbeat = LedHeartbeat::new(self.mode);
+        info!("Bridge mode");
+        self.panel_loop().await;
+        self
+    }
+
+    async fn panel_loop(&mut self) {
+        let mut announce_at = Some(Instant::now() + self.announce_delay());
+        let mut sample_at = Instant::now();
+        let mut last_packet_at = Instant::now();
         loop {
+            // The PIR sampling comes round often enough to keep it going
+            self.led_heartbeat.poll();
             let mut cmd_buf = [0; 256];
-            match select(
+            let announce = async move {
+                match announce_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            // PIRs are sampled here rather than when a reply needs them, so
+            // replies go out the same time after every message
+            // Only one of these at a time, starting one ends the other
+            let id_flash = self.id_flash.as_mut();
+            let led_test = self.led_test.as_mut();
+            let led_strip = &mut self.led_strip;
+            let flashing = async move {
+                match (id_flash, led_test) {
+                    (Some(id_flash), _) => id_flash.run(led_strip).await,
+                    (None, Some(led_test)) => led_test.run(led_strip).await,
+                    (None, None) => core::future::pending().await,
+                }
+            };
+            let reply_at = self.replies.front().map(|r| r.send_at);
+            let reply_due = async move {
+                match reply_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            let color_at = self.scheduled_color.map(|(at, _)| at);
+            let color_due = async move {
+                match color_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            let timers = select4(
+                announce,
+                Timer::at(sample_at),
+                flashing,
+                select(reply_due, color_due),
+            );
+            match select4(
                 self.interactor.read_command(&mut cmd_buf),
                 self.comm.recv_packet(),
+                timers,
+                self.button.watch_presses(),
             )
             .await
             {
-                Either::First(line) => {
-                    self.reply_buf.clear();
-                    self.handle_command(Mode::Panel, line).await;
-                    self.interactor.reply(&self.reply_buf).await;
+                Either4::First(line) => {
+                    let _busy = watchdog::busy(Subsystem::Commands);
+                    self.run_command(self.mode, line).await;
+                    if self.restart {
+                        return;
+                    }
                 }
-                Either::Second(packet) => {
+                Either4::Second(packet) => {
+                    last_packet_at = Instant::now();
+                    self.led_heartbeat.activity();
+                    let _busy = watchdog::busy(Subsystem::Packets);
+                    // A reply that's due goes before the next message
+                    self.send_replies().await;
                     self.handle_message(packet).await;
                 }
+                Either4::Third(Either4::First(())) => {
+                    // Only once per boot
+                    announce_at = None;
+                    let _busy = watchdog::busy(Subsystem::Packets);
+                    self.announce().await;
+                }
+                Either4::Third(Either4::Second(())) => {
+                    self.pirs.sample();
+                    sample_at += PIR_SAMPLE_INTERVAL;
+                    // Don't try to catch up after a long command
+                    sample_at = sample_at.max(Instant::now());
+                    // Here too, so any flash write is between packets
+                    self.hours.poll(last_packet_at.elapsed());
+                    self.led_strip.poll_derating();
+                }
+                Either4::Third(Either4::Third(())) => {
+                    self.id_flash = None;
+                    self.led_test = None;
+                }
+                Either4::Third(Either4::Fourth(Either::First(()))) => {
+                    let _busy = watchdog::busy(Subsystem::Packets);
+                    self.send_replies().await;
+                }
+                Either4::Third(Either4::Fourth(Either::Second(()))) => {
+                    if let Some((_, (zone_1, zone_2))) = self.scheduled_color.take() {
+                        self.led_strip.set_zone_colors(zone_1, zone_2);
+                    }
+                }
+                Either4::Fourth(Press::Short) => self.step_led_test(),
+                Either4::Fourth(Press::Long) => self.enter_settings().await,
             }
+            self.send_notifications().await;
         }
     }
 
-    pub async fn run_spy(mut self) {
+    /// Goes to the same settings UX as holding the button at boot. Nothing is
+    /// sent or handled from here on, and it ends with a reboot.
+    async fn enter_settings(&mut self) -> ! {
+        info!("Entering settings");
+        self.animation = None;
+        self.identify = None;
+        self.identify_walk = None;
+        self.led_strip.set_colors(0, 0, 0);
+        boot::toggle_mode(self.mode).await
+    }
+
+    /// How long a panel waits after boot to announce itself. There's no RNG,
+    /// but mixing the ID and boot count spreads out a string of panels that
+    /// power up together.
+    fn announce_delay(&self) -> Duration {
+        let seed = (self.address.value() as u32).wrapping_mul(2654435761)
+            ^ (get_boot_count() as u32).wrapping_mul(40503);
+        Duration::from_millis((seed >> 16) as u64 % MAX_ANNOUNCE_DELAY_MS)
+    }
+
+    /// Tells the master we've booted, so it can map us without waiting for
+    /// someone to enumerate.
+    async fn announce(&mut self) {
+        debug!("Announcing");
+        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::Announce);
+        packet.push_data(&[get_boot_count(), get_reset_cause().into()]);
+        self.comm.send_packet(&packet).await;
+    }
+
+    /// Runs until a soft restart is asked for, see restarted().
+    pub async fn run_spy(mut self) -> Self {
         self.mode = Mode::Spy;
+        self.led_heartbeat = LedHeartbeat::new(self.mode);
         info!("Spy mode");
         loop {
-            match select(
+            let mut cmd_buf = [0; 256];
+            let heartbeat_at = self.capture.as_ref().map(|c| c.next_heartbeat());
+            let heartbeat = async move {
+                match heartbeat_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            match select4(
+                self.interactor.read_command(&mut cmd_buf),
                 self.comm.recv_packet(),
-                Timer::after(Duration::from_millis(100)),
+                heartbeat,
+                self.led_heartbeat.run(),
             )
             .await
             {
-                Either::First(packet) => {
-                    debug!("Received packet: {:?}", packet);
+                Either4::First(line) => {
+                    let _busy = watchdog::busy(Subsystem::Commands);
+                    self.run_command(Mode::Spy, line).await;
+                    if self.restart {
+                        return self;
+                    }
                 }
-                Either::Second(_) => {
-                    board::pet_the_watchdog();
+                Either4::Second(packet) => {
+                    packet_debug!("Received packet: {:?}", packet);
+                    self.led_heartbeat.activity();
+                    if let Some(capture) = &self.capture {
+                        let _busy = watchdog::busy(Subsystem::Packets);
+                        let record =
+                            capture.packet_record(&packet, self.comm.mode(), self.comm.last_rssi());
+                        self.write_record(&record).await;
+                    }
+                }
+                Either4::Third(()) => {
+                    if let Some(capture) = &mut self.capture {
+                        let record = capture.heartbeat_record();
+                        self.write_record(&record).await;
+                    }
                 }
             }
         }
     }
 
-    async fn handle_command(&mut self, mode: Mode, line: &[u8]) {
-        if line.is_empty() {
+    /// Writes a capture record to USB, counting it if it had to be dropped.
+    async fn write_record(&mut self, record: &[u8]) {
+        if self.interactor.write_record(record).await {
             return;
         }
+        if let Some(capture) = &mut self.capture {
+            capture.count_drop();
+        }
+    }
+
+    /// Handles a command and sends its reply, or `TIMEOUT` if it's still
+    /// going after COMMAND_BUDGET.
+    async fn run_command(&mut self, mode: Mode, line: &[u8]) {
+        self.reply_buf.clear();
+
+        // The serial port is carrying commands again, so it can't carry the
+        // bus too
+        if !self.interactor.from_usb() && self.comm.mirror_dropped().is_some() {
+            info!("Bus mirror off, command from serial");
+            self.comm.set_mirror(false);
+        }
+        self.reply_buf.set_terse(self.interactor.is_terse());
+        self.command_started = Instant::now();
+        // The budget is what stops a stuck command, so the watchdog doesn't
+        // have to
+        let handle = watchdog::with_watchdog(Subsystem::Commands, self.handle_command(mode, line));
+        if with_timeout(COMMAND_BUDGET, handle).await.is_err() {
+            warn!("Command timed out");
+            // Finish off a reply that was being sent in parts
+            self.interactor.end_line().await;
+            self.reply_buf.clear();
+            self.reply_buf.timeout();
+        }
+        if self.reply_buf.overflowed() {
+            // The error goes on a line of its own, after any parts already
+            // sent
+            self.interactor.end_line().await;
+        }
+        self.interactor.reply(&self.reply_buf).await;
+        if self.command_started.elapsed() >= STALE_AFTER {
+            self.interactor.drop_stale().await;
+        }
+    }
+
+    async fn handle_command(&mut self, mode: Mode, line: &[u8]) {
+        // An empty line repeats the last command
+        let repeat;
+        let line = if line.is_empty() {
+            if self.last_command.is_empty() {
+                return;
+            }
+            repeat = self.last_command.clone();
+            repeat.as_slice()
+        } else {
+            self.last_command.clear();
+            // Too long to remember means nothing to repeat
+            let _ = self.last_command.extend_from_slice(line);
+            line
+        };
 
         self.reply_buf.clear();
+        self.stats.count_command();
 
         // Try to parse the first byte as a Command
         let cmd_byte = line[0];
         let args = &line[1..];
 
+        // Another master has the panels, see a
+        let drives_panels = matches!(
+            Command::try_from(cmd_byte),
+            Ok(Command::Enumerate
+                | Command::SetColor
+                | Command::SetPanelColor
+                | Command::SetColorDelta
+                | Command::MapPanels
+                | Command::Reset
+                | Command::Animate)
+        );
+        if mode == Mode::Master && drives_panels && self.comm.standing_by() {
+            self.reply_buf.standby();
+            return;
+        }
+
         match Command::try_from(cmd_byte) {
-            Ok(Command::DefaultMode) => self.command_default_mode(args),
-            Ok(Command::Version) => self.command_version(args),
+            Ok(Command::DefaultMode) => self.command_default_mode(args).await,
+            Ok(Command::Version) => self.command_version(args).await,
+            Ok(Command::Echo) => self.command_echo(args),
+            Ok(Command::PacketLogs) => self.command_packet_logs(args),
+            Ok(Command::Help) => self.command_help(mode).await,
+            Ok(Command::PirConfig) => self.command_pir_config(mode, args).await,
+            Ok(Command::PirProfile) => self.command_pir_profile(mode, args).await,
+            Ok(Command::Derating) if mode != Mode::Spy => self.command_derating(mode, args).await,
+            Ok(Command::CommStats) => self.command_comm_stats(args),
+            Ok(Command::Info) => self.command_info(args).await,
+            Ok(Command::ColorOrder) => self.command_color_order(mode, args).await,
+            Ok(Command::Group) => self.command_group(args).await,
+            Ok(Command::RadioRegisters) => self.command_radio_registers(args).await,
+            Ok(Command::TxPower) => self.command_tx_power(mode, args).await,
+            Ok(Command::Channel) => self.command_channel(mode, args).await,
+            Ok(Command::SoftRestart) => self.command_soft_restart(args),
+            Ok(Command::RebootSeen) => self.command_reboot_seen(args),
+            Ok(Command::Verbosity) => self.command_verbosity(args),
+            Ok(Command::LedBrightness) => self.command_led_brightness(args),
+            Ok(Command::BusMirror) => self.command_bus_mirror(args),
+            Ok(Command::RadioTest) => self.command_radio_test(args).await,
 
             Ok(Command::Enumerate) if mode == Mode::Master => self.command_enumerate(args).await,
             Ok(Command::SetColor) if mode == Mode::Master => self.command_set_color(args).await,
+            Ok(Command::SetPanelColor) if mode == Mode::Master => {
+                self.command_set_panel_color(args).await
+            }
+            Ok(Command::SetColorDelta) if mode == Mode::Master => {
+                self.command_set_color_delta(args).await
+            }
+            Ok(Command::DeltaFrames) if mode == Mode::Master => self.command_delta_frames(args),
+            Ok(Command::QuietFrames) if mode == Mode::Master => self.command_quiet_frames(args),
             Ok(Command::MapPanels) if mode == Mode::Master => self.command_map_panels(args).await,
             Ok(Command::Reset) if mode == Mode::Master => self.command_reset(args).await,
+            Ok(Command::Health) if mode == Mode::Master => self.command_health(args).await,
+            Ok(Command::Animate) if mode == Mode::Master => self.command_animate(args),
+            Ok(Command::BusBaud) if mode == Mode::Master => self.command_bus_baud(args).await,
+            Ok(Command::PirLog) if mode == Mode::Master => self.command_pir_log(args).await,
+            Ok(Command::DryRun) if mode == Mode::Master => self.command_dry_run(args),
+            Ok(Command::Ramp) if mode == Mode::Master => self.command_ramp(args),
+            Ok(Command::Arbitration) if mode == Mode::Master => self.command_arbitration(args),
+            Ok(Command::PirStream) if mode == Mode::Master => self.command_pir_stream(args),
+            Ok(Command::StatusMirror) if mode == Mode::Master => {
+                self.command_status_mirror(args).await
+            }
+            Ok(Command::Correction) if mode == Mode::Master => self.command_correction(args).await,
+            Ok(Command::Identify) if mode == Mode::Master => self.command_identify(args),
+            Ok(Command::Latency) if mode == Mode::Master => self.command_latency(args).await,
+            Ok(Command::FlashId) if mode != Mode::Spy => self.command_flash_id(mode, args).await,
+            Ok(Command::Simulate) if mode == Mode::Master => self.command_simulate(args),
+            Ok(Command::Capture) if mode == Mode::Spy => self.command_capture(args),
+            Ok(Command::PanelStatus) if mode == Mode::Master => {
+                self.command_panel_status(args).await
+            }
             Ok(Command::TestMessage) if mode == Mode::Master => {
                 self.command_test_message(args).await
             }
+            Ok(Command::Macro) if mode == Mode::Master => self.command_macro(args).await,
 
-            _ => {
-                let _ = self.reply_buf.push_str("ERROR Unknown command");
+            // Known, but not in this mode, which usually means the board
+            // wasn't switched to the mode the host expects
+            Ok(command) => {
+                let only = match command {
+                    Command::Capture => "Spy-only",
+                    Command::FlashId | Command::Derating => "Not a spy mode",
+                    _ => "Master-only",
+                };
+                self.reply_buf.error(
+                    ErrorCode::WrongMode,
+                    format_args!("{} command (current mode: {:?})", only, mode),
+                );
+            }
+            Err(_) => {
+                self.reply_buf.error(
+                    ErrorCode::UnknownCommand,
+                    format_args!("Unknown command (current mode: {:?})", mode),
+                );
             }
         }
     }
 
-    fn command_default_mode(&mut self, args: &[u8]) {
+    async fn command_default_mode(&mut self, args: &[u8]) {
         if args.len() != 1 {
-            let _ = self.reply_buf.push_str("ERROR Expected M, P, or S");
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected M, P, S, or B");
             return;
         }
 
@@ -207,273 +1433,2641 @@ impl<'a> CmdProcessor<'a> {
             b'M' => Mode::Master,
             b'P' => Mode::Panel,
             b'S' => Mode::Spy,
+            b'B' => Mode::Bridge,
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected M, P, S, or B");
+                return;
+            }
+        };
+
+        if let Err(e) = flash::write_settings(|| set_default_mode(new_mode)).await {
+            warn!("Couldn't save default mode: {:?}", e);
+            self.reply_buf
+                .error(ErrorCode::FlashWrite, "Flash write failed");
+            return;
+        }
+        self.reply_and_reset().await;
+    }
+
+    /// Replies OK, and resets once it's out, for settings that take effect
+    /// at the next boot.
+    async fn reply_and_reset(&mut self) -> ! {
+        self.reply_buf.ok();
+        self.flush_reply().await;
+        // A host that isn't reading doesn't get to keep us from resetting
+        let _ = with_timeout(RESET_FLUSH_TIME, self.interactor.flushed()).await;
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
+    async fn command_group(&mut self, args: &[u8]) {
+        if args.is_empty() {
+            let _ = write!(self.reply_buf, "{:02x}", flash::get_group());
+            return;
+        }
+
+        let Some([group]) = self.hex_args(args, 2) else {
+            return;
+        };
+        if group > flash::MAX_GROUP {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected 00 to 1f");
+            return;
+        }
+
+        if let Err(e) = flash::write_settings(|| flash::set_group(group)).await {
+            warn!("Couldn't save group: {:?}", e);
+            self.reply_buf
+                .error(ErrorCode::FlashWrite, "Flash write failed");
+            return;
+        }
+        self.reply_and_reset().await;
+    }
+
+    #[cfg(not(feature = "radio-debug"))]
+    async fn command_radio_registers(&mut self, _args: &[u8]) {
+        self.reply_buf.error(ErrorCode::Unsupported, "Unsupported");
+    }
+
+    #[cfg(feature = "radio-debug")]
+    async fn command_radio_registers(&mut self, args: &[u8]) {
+        match *args {
+            [b'd'] => {
+                let Ok(regs) = self.comm.radio().read_registers() else {
+                    self.reply_buf
+                        .error(ErrorCode::RadioNotResponding, "Radio not responding");
+                    return;
+                };
+                // 0x00 is the FIFO, which isn't read
+                for base in (0..0x50).step_by(16) {
+                    if base > 0 {
+                        self.flush_reply().await;
+                    }
+                    let _ = write!(self.reply_buf, "{:02x}:", base);
+                    for addr in base..base + 16 {
+                        let _ = match addr {
+                            0 => self.reply_buf.push_str(" --"),
+                            _ => write!(self.reply_buf, " {:02x}", regs[addr - 1]),
+                        };
+                    }
+                }
+            }
+            [b'r', ref reg @ ..] => {
+                let Some([addr]) = self.hex_args(reg, 3) else {
+                    return;
+                };
+                if !(0x01..=0x4f).contains(&addr) {
+                    self.reply_buf
+                        .error(ErrorCode::BadArgument, "Expected register 01 to 4f");
+                    return;
+                }
+                let addr = addr as usize;
+                match self.comm.radio().read_registers() {
+                    Ok(regs) => {
+                        let _ = write!(self.reply_buf, "{:02x}", regs[addr - 1]);
+                    }
+                    Err(_) => self
+                        .reply_buf
+                        .error(ErrorCode::RadioNotResponding, "Radio not responding"),
+                }
+            }
+            [b'w', ref rest @ ..] => {
+                let Some([reg, value]) = self.hex_args(rest, 3) else {
+                    return;
+                };
+                let Some(reg) = PanelRadio::tunable_register(reg) else {
+                    self.reply_buf
+                        .error(ErrorCode::BadArgument, "Register not writable");
+                    return;
+                };
+                match self.comm.radio().write_register(reg, value) {
+                    Ok(()) => self.reply_buf.ok(),
+                    Err(_) => self
+                        .reply_buf
+                        .error(ErrorCode::RadioNotResponding, "Radio not responding"),
+                }
+            }
             _ => {
-                let _ = self.reply_buf.push_str("ERROR Expected M, P, or S");
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected r{reg}, w{reg}{val}, or d");
+            }
+        }
+    }
+
+    async fn command_version(&mut self, _args: &[u8]) {
+        let freq = PanelRadio::frequency(self.comm.channel());
+        let mode_str = match self.mode {
+            Mode::Master => "Master",
+            Mode::Panel => "Panel",
+            Mode::Spy => "Spy",
+            Mode::Bridge => "Bridge",
+        };
+
+        let _ = write!(
+            self.reply_buf,
+            "Aunisoma version {} ID={} Mode={} Comm={} Baud={} Channel={:02x} Freq={}.{}MHz Reset={} Order={} SelfTest={:x} Hours={} Boot={} Warm={} Rebooted={} Sensors={} Features={} Dirty={} Built={}",
+            version::VERSION,
+            self.address.value(),
+            mode_str,
+            self.comm.mode_name(),
+            self.comm.bus_baud().rate(),
+            self.comm.channel(),
+            freq / 1_000_000,
+            freq / 100_000 % 10,
+            get_reset_cause().name(),
+            self.led_strip.color_order().name(),
+            self_test::faults(),
+            self.hours.hours(),
+            get_boot_count(),
+            is_warm_boot(),
+            boot::reboot_seen(),
+            SENSOR_BITS,
+            version::FEATURES,
+            version::DIRTY.unwrap_or("?"),
+            version::BUILT.unwrap_or("?"),
+        );
+        // With every feature and a big hour count, it would just overflow
+        self.make_room(" Panic=08003a5c".len()).await;
+        let _ = match last_panic::get() {
+            Some(p) => write!(self.reply_buf, " Panic={:08x}", p.pc),
+            None => write!(self.reply_buf, " Panic=none"),
+        };
+    }
+
+    fn command_reboot_seen(&mut self, args: &[u8]) {
+        if !args.is_empty() {
+            self.reply_buf
+                .error(ErrorCode::UnexpectedArgument, "Unexpected argument");
+            return;
+        }
+        boot::clear_reboot_seen();
+        self.reply_buf.ok();
+    }
+
+    fn command_soft_restart(&mut self, args: &[u8]) {
+        if !args.is_empty() {
+            self.reply_buf
+                .error(ErrorCode::UnexpectedArgument, "Unexpected argument");
+            return;
+        }
+        // Once the reply is out, see restarted()
+        self.restart = true;
+        self.reply_buf.ok();
+    }
+
+    fn command_verbosity(&mut self, args: &[u8]) {
+        let terse = match args {
+            b"h" => false,
+            b"m" => true,
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected h or m");
+                return;
+            }
+        };
+        self.interactor.set_terse(terse);
+        // The reply is in the new style
+        self.reply_buf.set_terse(terse);
+        self.reply_buf.ok();
+    }
+
+    fn command_led_brightness(&mut self, args: &[u8]) {
+        if args.is_empty() {
+            let _ = write!(self.reply_buf, "{}", StatusLEDs::brightness());
+            return;
+        }
+        let brightness = core::str::from_utf8(args)
+            .ok()
+            .and_then(|s| s.parse::<u8>().ok())
+            .filter(|&b| b <= FULL_BRIGHTNESS);
+        let Some(brightness) = brightness else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected 0 to 100");
+            return;
+        };
+        StatusLEDs::set_brightness(brightness);
+        self.reply_buf.ok();
+    }
+
+    fn command_echo(&mut self, args: &[u8]) {
+        let echo = match args {
+            b"0" => false,
+            b"1" => true,
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0 or 1");
+                return;
+            }
+        };
+
+        self.interactor.set_echo(echo);
+        self.reply_buf.ok();
+    }
+
+    fn command_capture(&mut self, args: &[u8]) {
+        match args {
+            b"0" => self.capture = None,
+            b"1" => {
+                if self.capture.is_none() {
+                    self.capture = Some(Capture::new());
+                }
+            }
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0 or 1");
+                return;
+            }
+        }
+        self.reply_buf.ok();
+    }
+
+    fn command_packet_logs(&mut self, args: &[u8]) {
+        let enabled = match args {
+            b"0" => false,
+            b"1" => true,
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0 or 1");
+                return;
+            }
+        };
+
+        logging::set_packet_logs(enabled);
+        self.reply_buf.ok();
+    }
+
+    async fn command_help(&mut self, mode: Mode) {
+        const COMMON: &[&str] = &[
+            "D{M|P|S|B}    Set default mode and reboot",
+            "V             Version",
+            "X{0|1}        Echo off/on",
+            "v{h|m}        Replies for humans or machines",
+            "K{0|1}        Per-packet debug logs off/on",
+            "F{i}{t1}{t2}  PIR config, then [{rf}{profile}]",
+            "Y{A|B}        PIR profile",
+            "d[{pct}]      LED derating above pct heat, d0 for off",
+            "C             Comm stats",
+            "O{ord}[{id}]  Color order, e.g. OGRB",
+            "g[{group}]    Installation group, set and reboot",
+            "Z{r|w|d}...   Radio registers, radio-debug builds only",
+            "W[{p}[{id}]]  TX power, 0 (max) to 3 (min)",
+            "h[{ch}]       Radio channel, 00 to 0f, master sets all",
+            "z             Radio self-test and noise floor",
+            "b             Soft restart, USB stays connected",
+            "m             Clear Rebooted in V, to see if it reboots",
+            "i[{pct}]      Status LED brightness, 0 to 100",
+            "w[{0|1}]      Panel bus mirror out serial off/on, from USB",
+            "J             Info",
+            "?             Help",
+        ];
+        const MASTER: &[&str] = &[
+            "E[{n}][J]     Enumerate panels over n rounds, J for a line each",
+            "EI[{ms}]      Enumerate, then blink each panel in turn for ms",
+            "L[W][{rgb}]*  Set colors of mapped panels, W for RGBW",
+            "L@{ms}...     Set colors ms later, on all panels at once",
+            "l{id}{rgb}    Set color of one panel",
+            "u[{s}{rgb}]*  Set colors of some slots, leaving the rest",
+            "e[{ms}]       L sends only changes, all every ms, e0 for off",
+            "q[{0|1}]      L without replies off/on, for top frame rate",
+            "M[{id}]*      Map panel IDs to slots, Mx clears the saved one, MV checks",
+            "MS[{id}]*     Map panel IDs to slots all at once, mid-show",
+            "R             Reset all",
+            "P{id}         Panel status",
+            "H[{secs}]     Missing panels",
+            "A{1|2|3|4}    Animate",
+            "B{0|1|2|3}    Bus baud",
+            "G[0]          PIR log, G0 clears",
+            "T{0|1}        Dry run of L and M off/on",
+            "r[{max}]      Ramp L over frames, r0 for off",
+            "a[{prio}]     Share the bus with other masters, a00 for off",
+            "p[{ms}]       Stream PIR changes, polling every ms, p0 for off",
+            "s{0|1}        Comm health on panel status LEDs off/on",
+            "k[{s}{rgb}]   Color correction gains, kw saves, kx clears",
+            "N[{slot}]     Identify slot, or all",
+            "U{id}[{n}]    Ping latency histogram",
+            "I{id}         Flash panel ID on its LEDs",
+            "Q[[-]{id}]*   Simulated panels, - never replies",
+            "x[{n}[={l}]]  Macros: list, run n, or save n as lines l split by ;",
+            "_{len}        Send test message",
+        ];
+
+        const PANEL: &[&str] = &["I             Flash ID on the LEDs"];
+        const SPY: &[&str] = &["S{0|1}        Binary packet capture to USB off/on"];
+
+        let extra = match mode {
+            Mode::Master => MASTER,
+            Mode::Panel | Mode::Bridge => PANEL,
+            Mode::Spy => SPY,
+        };
+
+        // Too much for one reply, so send all but the last line ourselves
+        for (i, line) in COMMON.iter().chain(extra).enumerate() {
+            if i > 0 {
+                self.flush_reply().await;
+            }
+            let _ = self.reply_buf.push_str(line);
+        }
+    }
+
+    /// Sends a `...` line saying how a long command is getting on, once it
+    /// has been going for PROGRESS_AFTER. Before that, it's not worth the
+    /// noise.
+    async fn progress(&mut self, args: core::fmt::Arguments<'_>) {
+        if self.command_started.elapsed() < PROGRESS_AFTER {
+            return;
+        }
+        let mut line = heapless::String::<PROGRESS_LEN>::new();
+        let _ = line.push_str("...");
+        let _ = line.write_fmt(args);
+        self.interactor.reply(&line).await;
+    }
+
+    /// Sends what's in reply_buf as a line of its own, for commands with more
+    /// to say than fits in one reply.
+    async fn flush_reply(&mut self) {
+        self.interactor.reply(&self.reply_buf).await;
+        self.reply_buf.clear();
+    }
+
+    /// Sends what's in reply_buf as the start of the reply line if there isn't
+    /// room for `len` more, for replies too long to build all at once.
+    async fn make_room(&mut self, len: usize) {
+        // Once it has overflowed, the reply is an error, which has to stay
+        if self.reply_buf.room() < len && !self.reply_buf.overflowed() {
+            self.interactor.reply_part(&self.reply_buf).await;
+            self.reply_buf.clear();
+        }
+    }
+
+    /// Sends the mapping, PIR profile, and derating to the panels that
+    /// announced they'd booted.
+    async fn send_remaps(&mut self) {
+        for (slot, &id) in self.mapping.iter().enumerate() {
+            if self.remap_slots & (1 << slot) == 0 {
+                continue;
+            }
+            let mut packet = Packet::new(self.address, Address(id), Message::MapPanels);
+            packet.push_data(&self.mapping);
+            self.comm.send_packet(&packet).await;
+
+            let mut packet = Packet::new(self.address, Address(id), Message::SetPirProfile);
+            packet.push_data(&[self.pir_profile.into()]);
+            self.comm.send_packet(&packet).await;
+
+            // Panels boot with it off
+            if let Some(threshold) = self.derating {
+                let mut packet = Packet::new(self.address, Address(id), Message::SetDerating);
+                packet.push_data(&[threshold]);
+                self.comm.send_packet(&packet).await;
+            }
+        }
+        self.remap_slots = 0;
+    }
+
+    async fn send_notifications(&mut self) {
+        while let Some(line) = self.notifications.dequeue() {
+            self.interactor.broadcast(&line).await;
+        }
+    }
+
+    async fn command_pir_config(&mut self, mode: Mode, args: &[u8]) {
+        let mut parsed = [0; 6];
+        let bytes = match hex_groups(args, 1, &mut parsed) {
+            Ok(len @ 3..) => &parsed[..len],
+            Ok(_) => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 3 to 6 hex bytes");
+                return;
+            }
+            Err(e) => {
+                self.hex_error(e, 2);
+                return;
+            }
+        };
+
+        // The master takes a panel ID after the config
+        let (config_len, id) = match (mode, bytes.len()) {
+            (Mode::Master, 4 | 6) => (bytes.len() - 1, bytes.last().copied()),
+            (_, len) => (len, None),
+        };
+        let Some(config) = PirConfig::from_bytes(&bytes[..config_len]) else {
+            self.reply_buf.error(
+                ErrorCode::BadArgument,
+                "Expected 3 or 5 hex bytes, and an ID",
+            );
+            return;
+        };
+
+        if mode == Mode::Master {
+            let to = id.map_or(BROADCAST_ADDRESS, Address);
+            let mut packet = Packet::new(self.address, to, Message::PirConfig);
+            packet.push_data(&config.to_bytes());
+            self.send_reliable(&packet).await;
+        } else {
+            self.pirs.set_config(config);
+            self.reply_buf.ok();
+        }
+    }
+
+    async fn command_pir_profile(&mut self, mode: Mode, args: &[u8]) {
+        let Some(profile) = PirProfile::from_name(args) else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected A or B");
+            return;
+        };
+
+        if mode == Mode::Master {
+            self.pir_profile = profile;
+            let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetPirProfile);
+            packet.push_data(&[profile.into()]);
+            self.comm.send_packet(&packet).await;
+        } else {
+            self.pirs.set_profile(profile);
+        }
+
+        self.reply_buf.ok();
+    }
+
+    async fn command_derating(&mut self, mode: Mode, args: &[u8]) {
+        if args.is_empty() {
+            let threshold = match mode {
+                Mode::Master => self.derating,
+                _ => self.led_strip.derating().threshold(),
+            };
+            let _ = match threshold {
+                Some(threshold) => write!(self.reply_buf, "{}", threshold),
+                None => self.reply_buf.push_str("off"),
+            };
+            if mode != Mode::Master {
+                let derating = self.led_strip.derating();
+                let _ = write!(
+                    self.reply_buf,
+                    " heat {}% output {}%",
+                    derating.heat_percent(),
+                    output_percent(derating.output())
+                );
+            }
+            return;
+        }
+        let threshold = core::str::from_utf8(args)
+            .ok()
+            .and_then(|s| s.parse::<u8>().ok())
+            .filter(|&t| t < 100);
+        let Some(threshold) = threshold else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected 0 to 99");
+            return;
+        };
+        let threshold = (threshold != 0).then_some(threshold);
+
+        if mode == Mode::Master {
+            self.derating = threshold;
+            let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetDerating);
+            packet.push_data(&[threshold.unwrap_or(0)]);
+            self.comm.send_packet(&packet).await;
+        } else {
+            self.led_strip.set_derating(threshold);
+        }
+        self.reply_buf.ok();
+    }
+
+    async fn command_color_order(&mut self, mode: Mode, args: &[u8]) {
+        let (name, id) = args.split_at(args.len().min(3));
+        let Some(order) = ColorOrder::from_name(name) else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected RGB, GRB, BGR, or BRG");
+            return;
+        };
+
+        if mode == Mode::Master {
+            let to = match id {
+                [] => BROADCAST_ADDRESS,
+                _ => match self.hex_args(id, 5) {
+                    Some([id]) => Address(id),
+                    None => return,
+                },
+            };
+            let mut packet = Packet::new(self.address, to, Message::SetColorOrder);
+            packet.push_data(&[order.into()]);
+            self.send_reliable(&packet).await;
+            return;
+        } else if id.is_empty() {
+            if self.set_color_order(order).await.is_err() {
+                self.reply_buf
+                    .error(ErrorCode::FlashWrite, "Flash write failed");
+                return;
+            }
+        } else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Unexpected panel ID");
+            return;
+        }
+
+        self.reply_buf.ok();
+    }
+
+    /// Switches the LED strip to `order` and saves it. The strip uses the new
+    /// order even if it couldn't be saved.
+    async fn set_color_order(&mut self, order: ColorOrder) -> Result<(), flash::FlashError> {
+        if order == self.led_strip.color_order() {
+            return Ok(());
+        }
+        info!("Color order {:?}", order);
+        self.led_strip.set_color_order(order);
+        flash::write_settings(|| flash::set_color_order(order))
+            .await
+            .inspect_err(|e| warn!("Couldn't save color order: {:?}", e))
+    }
+
+    async fn command_tx_power(&mut self, mode: Mode, args: &[u8]) {
+        match args {
+            [] => {
+                let power = self.comm.tx_power();
+                let _ = write!(
+                    self.reply_buf,
+                    "{} {} {}dBm",
+                    power as u8,
+                    power.name(),
+                    power.dbm()
+                );
+                if mode == Mode::Master {
+                    let _ = write!(self.reply_buf, " adapt {}", self.tx_power_adapt.name());
+                }
+                return;
+            }
+            [b'a', adapt] if mode == Mode::Master => {
+                self.tx_power_adapt = match adapt {
+                    b'0' => TxPowerAdapt::Off,
+                    b'1' => TxPowerAdapt::Suggest,
+                    b'2' => TxPowerAdapt::Auto,
+                    _ => {
+                        self.reply_buf
+                            .error(ErrorCode::BadArgument, "Expected 0, 1, or 2");
+                        return;
+                    }
+                };
+                self.reply_buf.ok();
+                return;
+            }
+            _ => {}
+        }
+
+        let (power, id) = args.split_at(1);
+        let Some(power) = (power[0] as char)
+            .to_digit(10)
+            .and_then(|p| TxPower::try_from(p as u8).ok())
+        else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected 0 to 3");
+            return;
+        };
+
+        if mode == Mode::Master {
+            let to = match id {
+                [] => BROADCAST_ADDRESS,
+                _ => match self.hex_args(id, 3) {
+                    Some([id]) => Address(id),
+                    None => return,
+                },
+            };
+            // Sent at the old power, so the panels still hear it
+            let mut packet = Packet::new(self.address, to, Message::SetTxPower);
+            packet.push_data(&[power.into()]);
+            self.send_reliable(&packet).await;
+            if to == BROADCAST_ADDRESS && self.set_tx_power(power).await.is_err() {
+                self.reply_buf.clear();
+                self.reply_buf
+                    .error(ErrorCode::FlashWrite, "Flash write failed");
+            }
+            return;
+        } else if !id.is_empty() {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Unexpected panel ID");
+            return;
+        }
+
+        if self.set_tx_power(power).await.is_err() {
+            self.reply_buf
+                .error(ErrorCode::FlashWrite, "Flash write failed");
+            return;
+        }
+        self.reply_buf.ok();
+    }
+
+    /// Switches the radio to `power` and saves it. The radio uses the new
+    /// power even if it couldn't be saved.
+    async fn set_tx_power(&mut self, power: TxPower) -> Result<(), flash::FlashError> {
+        if power == self.comm.tx_power() {
+            return Ok(());
+        }
+        info!("TX power {:?}", power);
+        self.comm.set_tx_power(power);
+        flash::write_settings(|| flash::set_tx_power(power))
+            .await
+            .inspect_err(|e| warn!("Couldn't save TX power: {:?}", e))
+    }
+
+    /// After an Enumerate, suggests or makes a TX power change if the weakest
+    /// link to a panel is too weak, or even it is strong enough to desense
+    /// the receivers. One step at a time, and only on the radio.
+    async fn adapt_tx_power(&mut self) {
+        if self.tx_power_adapt == TxPowerAdapt::Off || self.comm.mode() != CommMode::Radio {
+            return;
+        }
+        // Panels too old to send their RSSI say 0, which says nothing
+        let weakest = self
+            .panels
+            .iter()
+            .map(|p| match p.rssi_panel {
+                0 => (p.rssi_master, p.id),
+                rssi => (p.rssi_master.min(rssi), p.id),
+            })
+            .min_by_key(|&(rssi, _)| rssi);
+        let Some((rssi, id)) = weakest else {
+            return;
+        };
+        let power = self.comm.tx_power();
+        let change = if rssi < WEAK_LINK_DBM {
+            power.stronger()
+        } else if rssi > STRONG_LINK_DBM {
+            power.weaker()
+        } else {
+            None
+        };
+        let Some(change) = change else {
+            return;
+        };
+
+        if self.tx_power_adapt == TxPowerAdapt::Auto {
+            info!("Adapting TX power to {:?}", change);
+            let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetTxPower);
+            packet.push_data(&[change.into()]);
+            self.comm.send_packet(&packet).await;
+            let _ = self.set_tx_power(change).await;
+        }
+        let verb = match self.tx_power_adapt {
+            TxPowerAdapt::Auto => "set",
+            _ => "suggest",
+        };
+        notify(
+            &mut self.notifications,
+            format_args!("!txpower {} {} {:02x} {}", verb, change as u8, id.0, rssi),
+        );
+    }
+
+    fn command_bus_mirror(&mut self, args: &[u8]) {
+        match args {
+            b"" => {
+                let _ = match self.comm.mirror_dropped() {
+                    Some(dropped) => write!(self.reply_buf, "on dropped={}", dropped),
+                    None => self.reply_buf.push_str("off"),
+                };
+                return;
+            }
+            b"0" => {
+                self.comm.set_mirror(false);
+            }
+            b"1" if !self.interactor.from_usb() => {
+                self.reply_buf.error(
+                    ErrorCode::WrongPort,
+                    "Only from USB, the mirror needs the serial port",
+                );
+                return;
+            }
+            b"1" => {
+                if !self.comm.set_mirror(true) {
+                    self.reply_buf
+                        .error(ErrorCode::Unsupported, "No panel bus on this board");
+                    return;
+                }
+            }
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0 or 1");
+                return;
+            }
+        }
+        self.reply_buf.ok();
+    }
+
+    fn command_comm_stats(&mut self, _args: &[u8]) {
+        let stats = self.comm.stats();
+        let _ = write!(
+            self.reply_buf,
+            "{{\"radioReinits\":{}, \"truncatedFrames\":{}, \"busOverruns\":{}, \"suppressedSends\":{}",
+            stats.radio_reinits, stats.truncated_frames, stats.bus_overruns, stats.suppressed_sends
+        );
+        let _ = match stats.noise_floor {
+            Some(noise) => write!(self.reply_buf, ", \"noiseFloor\":{}}}", noise.average),
+            None => self.reply_buf.push_str(", \"noiseFloor\":null}"),
+        };
+    }
+
+    async fn command_radio_test(&mut self, args: &[u8]) {
+        if !args.is_empty() {
+            self.reply_buf
+                .error(ErrorCode::UnexpectedArgument, "Unexpected argument");
+            return;
+        }
+        let test = self.comm.radio_self_test(self.address).await;
+        info!("Radio self-test: {:?}", test);
+
+        let _ = write!(self.reply_buf, "{{\"radio\":{}", test.radio);
+        let checks = [("modeReady", test.mode_ready), ("pllLock", test.pll_lock)];
+        self.write_checks(&checks);
+        let _ = match test.noise_floor {
+            Some(noise) => write!(
+                self.reply_buf,
+                ", \"noiseDbm\":{}, \"noisePeakDbm\":{}",
+                noise.average, noise.peak
+            ),
+            None => self
+                .reply_buf
+                .push_str(", \"noiseDbm\":null, \"noisePeakDbm\":null"),
+        };
+        let checks = [
+            ("packetSent", test.packet_sent),
+            ("fifoEmpty", test.fifo_empty),
+        ];
+        self.write_checks(&checks);
+        let _ = write!(self.reply_buf, ", \"pass\":{}}}", test.passed());
+    }
+
+    /// Writes checks that pass, fail, or weren't run as JSON members.
+    fn write_checks(&mut self, checks: &[(&str, Option<bool>)]) {
+        for &(name, check) in checks {
+            let _ = match check {
+                Some(ok) => write!(self.reply_buf, ", \"{}\":{}", name, ok),
+                None => write!(self.reply_buf, ", \"{}\":null", name),
+            };
+        }
+    }
+
+    async fn command_info(&mut self, _args: &[u8]) {
+        let _ = self.stats.report(&mut self.reply_buf);
+        // Too long for one reply, so the rest may go in more parts
+        self.make_room(USB_JSON_LEN).await;
+        let _ = self.stats.report_usb(&mut self.reply_buf);
+        self.make_room(OUTBOX_JSON_LEN).await;
+        let _ = self.outbox.report(&mut self.reply_buf);
+        self.make_room(PORTS_JSON_LEN).await;
+        let _ = self.interactor.report(&mut self.reply_buf);
+        self.make_room(PHASES_JSON_LEN).await;
+        let _ = self.stats.report_phases(&mut self.reply_buf);
+    }
+
+    async fn command_enumerate(&mut self, args: &[u8]) {
+        let (rounds, args) = match args {
+            [digit @ b'2'..=b'9', rest @ ..] => (digit - b'0', rest),
+            _ => (1, args),
+        };
+        let (lines, dwell) = match args {
+            [] => (false, None),
+            b"J" => (true, None),
+            [b'I'] => (false, Some(identify::CYCLE_DWELL)),
+            [b'I', ms @ ..] => {
+                let dwell = core::str::from_utf8(ms)
+                    .ok()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .map(Duration::from_millis)
+                    .filter(|&dwell| dwell.as_ticks() > 0 && dwell <= MAX_IDENTIFY_DWELL);
+                let Some(dwell) = dwell else {
+                    self.reply_buf
+                        .error(ErrorCode::BadArgument, "Expected 1 to 60000 ms");
+                    return;
+                };
+                (false, Some(dwell))
+            }
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 2 to 9, J, I, or nothing");
+                return;
+            }
+        };
+
+        let packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::Ping);
+        self.panels.clear();
+
+        // Panels on a marginal link answer some rounds and not others.
+        // send_message() checks in with the watchdog each round.
+        for round in 0..rounds.min(MAX_ENUMERATE_ROUNDS) {
+            self.enumerate_round = round;
+            self.send_message(&packet, ENUMERATE_WINDOW).await;
+        }
+        self.enumerate_round = 0;
+        // Arrival order changes from run to run
+        self.panels.sort_unstable_by_key(|p| p.id.value());
+
+        if lines {
+            for i in 0..self.panels.len() {
+                self.write_panel_json(i);
+                self.flush_reply().await;
+            }
+            // A full list means there may have been more panels than fit
+            let _ = write!(
+                self.reply_buf,
+                "{{\"count\":{}, \"truncated\":{}}}",
+                self.panels.len(),
+                self.panels.is_full()
+            );
+        } else {
+            // Too long for one reply with a lot of panels, so send it in parts
+            let _ = self.reply_buf.push('[');
+            for i in 0..self.panels.len() {
+                self.make_room(PANEL_JSON_LEN).await;
+                if i > 0 {
+                    let _ = self.reply_buf.push_str(", ");
+                }
+                self.write_panel_json(i);
+            }
+            let _ = self.reply_buf.push(']');
+        }
+
+        for panel in &self.panels {
+            let protocol = panel.fw.map(|fw| fw.protocol);
+            if protocol == Some(PROTOCOL_VERSION) {
+                continue;
+            }
+            info!("Panel {} has protocol {:?}", panel.id.0, protocol);
+            match protocol {
+                Some(p) => notify(
+                    &mut self.notifications,
+                    format_args!("!protocol {:02x} {}", panel.id.0, p),
+                ),
+                None => notify(
+                    &mut self.notifications,
+                    format_args!("!protocol {:02x} ?", panel.id.0),
+                ),
+            }
+        }
+
+        self.adapt_tx_power().await;
+
+        // Shown from the master loop after this reply, so any command can stop
+        // it, see announce_identify()
+        if let Some(dwell) = dwell {
+            self.identify_walk = Some(IdentifyWalk {
+                ids: self.panels.iter().map(|p| p.id.0).collect(),
+                next: 0,
+                dwell,
+                until: None,
+            });
+        }
+    }
+
+    /// Writes the Enumerate JSON object for self.panels[index].
+    fn write_panel_json(&mut self, index: usize) {
+        let panel = self.panels[index];
+        let _ = write!(
+            self.reply_buf,
+            "{{\"id\":{}, \"bootCount\":{}, \"rssiM\":{}, \"rssiP\":{}, \"resetCause\":\"{}\", \"fw\":",
+            panel.id.value(),
+            panel.boot_count,
+            panel.rssi_master,
+            panel.rssi_panel,
+            panel.reset_cause.name()
+        );
+        let _ = match panel.fw {
+            Some(fw) => write!(self.reply_buf, "\"{}.{:04x}\"", fw.protocol, fw.hash),
+            None => self.reply_buf.push_str("null"),
+        };
+        let _ = match panel.rtt.summary() {
+            Some((_, avg, _)) => write!(self.reply_buf, ", \"rttUs\":{}", avg),
+            None => self.reply_buf.push_str(", \"rttUs\":null"),
+        };
+        let _ = write!(self.reply_buf, ", \"zones\":{}", panel.zones);
+        let _ = match panel.faults {
+            Some(faults) => write!(self.reply_buf, ", \"faults\":{}", faults),
+            None => self.reply_buf.push_str(", \"faults\":null"),
+        };
+        let _ = match panel.tx_power {
+            Some(power) => write!(self.reply_buf, ", \"txPower\":\"{}\"", power.name()),
+            None => self.reply_buf.push_str(", \"txPower\":null"),
+        };
+        let _ = match panel.last_panic {
+            Some(p) => write!(self.reply_buf, ", \"lastPanic\":\"{:08x}\"", p.pc),
+            None => self.reply_buf.push_str(", \"lastPanic\":null"),
+        };
+        let _ = write!(
+            self.reply_buf,
+            ", \"seen\":{}}}",
+            panel.seen_rounds.count_ones()
+        );
+    }
+
+    async fn command_set_color(&mut self, args: &[u8]) {
+        packet_debug!("Set color: {:a}", args);
+        // Column of the first color digit, for errors, counting the L as 1
+        let mut first_column = 2;
+        let (delay, args) = match args {
+            [b'@', rest @ ..] => {
+                let digits = rest.len().min(4);
+                let Some(delay) = self.hex_args::<2>(&rest[..digits], 3) else {
+                    return;
+                };
+                first_column += 5;
+                (Some(u16::from_be_bytes(delay)), &rest[digits..])
+            }
+            _ => (None, args),
+        };
+        let (tag, channels, args) = match args {
+            [b'W', rest @ ..] => {
+                first_column += 1;
+                (Message::SetColorRgbw, 4, rest)
+            }
+            _ => (Message::SetColor, 3, args),
+        };
+        // Room for the time, see Packet::to_scheduled()
+        let mut max_payload = match delay {
+            Some(_) => MAX_PAYLOAD_SIZE - 5,
+            None => MAX_PAYLOAD_SIZE,
+        };
+        // And for the tag inside a SetColorNoReply, see send_frame()
+        if self.quiet_frames {
+            max_payload -= 1;
+        }
+
+        let mut color_bytes = [0; MAX_PAYLOAD_SIZE];
+        let num_bytes = match hex_groups(args, channels, &mut color_bytes) {
+            Ok(len) => len,
+            Err(HexError {
+                problem: HexProblem::TooLong,
+                ..
+            }) => {
+                self.reply_buf.error(ErrorCode::TooMany, "Too many slots");
+                return;
+            }
+            Err(e) => {
+                self.hex_error(e, first_column);
+                return;
+            }
+        };
+
+        // Slots whose panels have two zones take two RGB colors
+        let two_zone_slots = if tag == Message::SetColor {
+            self.two_zone_slots
+        } else {
+            0
+        };
+        let num_colors = num_bytes / channels;
+        let mut num_slots = 0;
+        let mut colors = 0;
+        while colors < num_colors {
+            colors += if has_two_zones(two_zone_slots, num_slots) {
+                2
+            } else {
+                1
+            };
+            num_slots += 1;
+        }
+        if colors != num_colors {
+            self.reply_buf.error(
+                ErrorCode::BadArgument,
+                format_args!("Slot {} has two zones, expected two colors", num_slots - 1),
+            );
+            return;
+        }
+
+        if num_slots > MAX_PANEL_SLOTS {
+            self.reply_buf.error(ErrorCode::TooMany, "Too many slots");
+            return;
+        }
+
+        // Panels that don't know SetColorZones still work if the slots with
+        // two zones aren't being set
+        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, tag);
+        let two_zone_slots = two_zone_slots & ((1u64 << num_slots) - 1) as u32;
+        if two_zone_slots != 0 {
+            packet.tag = Message::SetColorZones;
+            packet.push_data(&two_zone_slots.to_le_bytes());
+        }
+        if packet.data.len() + num_colors * channels > max_payload {
+            self.reply_buf.error(ErrorCode::TooMany, "Too many slots");
+            return;
+        }
+
+        // Each slot's colors get its gains, zone by zone
+        let mut colors = color_bytes[..num_bytes].chunks_mut(channels);
+        for slot in 0..num_slots {
+            let zones = if has_two_zones(two_zone_slots, slot) {
+                2
+            } else {
+                1
+            };
+            for color in colors.by_ref().take(zones) {
+                self.correction.apply(slot, color);
+            }
+        }
+        packet.push_data(&color_bytes[..num_bytes]);
+
+        // The panels show it when our clock reads this, see ClockSync
+        let scheduled = delay.and_then(|delay| {
+            let at = (Instant::now().as_millis() as u32).wrapping_add(delay as u32);
+            packet.to_scheduled(at)
+        });
+
+        if self.dry_run {
+            let _ = write!(self.reply_buf, "DRY slots={} ", num_slots);
+            self.describe_packet(scheduled.as_ref().unwrap_or(&packet));
+            return;
+        }
+
+        let parse_time = self.command_started.elapsed();
+        self.not_mapped.clear();
+        // A ramp would show before the frame it leads up to
+        if scheduled.is_none() {
+            self.ramp_to(&packet).await;
+        }
+        let delta = match (&scheduled, self.delta_every) {
+            (None, Some(every)) => self.delta_from_last(&packet, every),
+            _ => None,
+        };
+        if delta.is_none() {
+            self.full_frame_at = Some(Instant::now());
+        }
+        self.last_colors = Some(packet.clone());
+
+        let start = Instant::now();
+        self.panels.clear();
+        let sent = delta.as_ref().or(scheduled.as_ref()).unwrap_or(&packet);
+        let sent_at = self.send_frame(sent).await;
+        if self.quiet_frames {
+            // Nobody answered, so the PIRs are left for the next frame that
+            // gets answers
+            self.stats.count_quiet_frame(parse_time, sent_at - start);
+            self.reply_buf.ok();
+            return;
+        }
+
+        for slot in 0..num_slots {
+            let pirs = match self.panels.iter().find(|p| p.slot as usize == slot) {
+                Some(p) => p.pirs,
+                // Left out of the delta, so it didn't answer
+                None if delta
+                    .as_ref()
+                    .is_some_and(|d| slot_colors(d, slot).is_none()) =>
+                {
+                    self.slot_pirs[slot]
+                }
+                None => 0,
+            };
+            self.slot_pirs[slot] = pirs;
+            let _ = write!(self.reply_buf, "{:x}", pirs & 0x0f);
+        }
+        if !self.not_mapped.is_empty() {
+            let _ = self.reply_buf.push('!');
+            self.write_ids(self.not_mapped.clone());
+        }
+        self.stats
+            .count_frame(parse_time, sent_at - start, sent_at.elapsed());
+
+        // It needs an answer from every slot
+        if delta.is_some() {
+            return;
+        }
+        if let Some(mirror) = &mut self.status_mirror {
+            let panels = &self.panels;
+            let mapping = &self.mapping[..num_slots.min(self.mapping.len())];
+            let pirs = |slot| {
+                panels
+                    .iter()
+                    .find(|p| p.slot as usize == slot)
+                    .map(|p| p.pirs)
+            };
+            mirror.show_frame(&mut self.outbox, mapping, pirs);
+        }
+    }
+
+    fn command_arbitration(&mut self, args: &[u8]) {
+        if args.is_empty() {
+            let Some(arbiter) = self.comm.arbiter() else {
+                let _ = self.reply_buf.push_str("off");
+                return;
+            };
+            let _ = match arbiter.role() {
+                Role::Active => write!(self.reply_buf, "active {:02x}", arbiter.priority()),
+                Role::Standby { primary: Some(id) } => {
+                    write!(
+                        self.reply_buf,
+                        "standby {:02x} {:02x}",
+                        arbiter.priority(),
+                        id
+                    )
+                }
+                Role::Standby { primary: None } => {
+                    write!(self.reply_buf, "standby {:02x} --", arbiter.priority())
+                }
+            };
+            return;
+        }
+        let Some([priority]) = self.hex_args(args, 2) else {
+            return;
+        };
+        if priority == 0 {
+            self.comm.stop_arbitration();
+        } else if self.comm.mode() != CommMode::Serial {
+            self.reply_buf
+                .error(ErrorCode::WrongCommMode, "Not in serial mode");
+            return;
+        } else {
+            self.comm.start_arbitration(self.address, priority);
+        }
+        self.reply_buf.ok();
+    }
+
+    /// Sends `!standby` or `!active` when another master takes over the
+    /// panels, or this one does.
+    fn note_role(&mut self) {
+        let role = self.comm.arbiter().map(|a| a.role());
+        if role == self.role {
+            return;
+        }
+        self.role = role;
+        match role {
+            Some(Role::Standby { primary: Some(id) }) => {
+                notify(&mut self.notifications, format_args!("!standby {:02x}", id));
+            }
+            Some(Role::Active) => notify(&mut self.notifications, format_args!("!active")),
+            _ => {}
+        }
+    }
+
+    fn command_pir_stream(&mut self, args: &[u8]) {
+        if args.is_empty() {
+            let _ = match &self.pir_stream {
+                Some(stream) => write!(self.reply_buf, "{}", stream.interval().as_millis()),
+                None => self.reply_buf.push_str("off"),
+            };
+            return;
+        }
+        let ms = core::str::from_utf8(args)
+            .ok()
+            .and_then(|s| s.parse::<u32>().ok());
+        let Some(ms) = ms else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected a decimal number");
+            return;
+        };
+        if ms == 0 {
+            self.pir_stream = None;
+        } else if (ms as u64) < MIN_POLL_INTERVAL.as_millis() {
+            self.reply_buf.error(
+                ErrorCode::BadArgument,
+                format_args!("Expected 0, or {} or more", MIN_POLL_INTERVAL.as_millis()),
+            );
+            return;
+        } else {
+            self.pir_stream = Some(PirStream::new(Duration::from_millis(ms as u64)));
+        }
+        self.reply_buf.ok();
+    }
+
+    fn command_ramp(&mut self, args: &[u8]) {
+        if args.is_empty() {
+            let _ = match self.max_frame_delta {
+                Some(max) => write!(self.reply_buf, "{}", max),
+                None => self.reply_buf.push_str("off"),
+            };
+            return;
+        }
+        let max = core::str::from_utf8(args)
+            .ok()
+            .and_then(|s| s.parse::<u32>().ok());
+        let Some(max) = max else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected a decimal number");
+            return;
+        };
+        self.max_frame_delta = (max != 0).then_some(max);
+        self.reply_buf.ok();
+    }
+
+    async fn command_status_mirror(&mut self, args: &[u8]) {
+        match args {
+            b"0" => {
+                self.status_mirror = None;
+                StatusMirror::clear(&mut self.comm, &mut self.outbox, self.address).await;
+            }
+            b"1" => self.status_mirror = Some(StatusMirror::new()),
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0 or 1");
+                return;
+            }
+        }
+        self.reply_buf.ok();
+    }
+
+    async fn command_correction(&mut self, args: &[u8]) {
+        match args {
+            b"" => {}
+            b"w" => {
+                match self.correction.save() {
+                    Ok(()) => self.reply_buf.ok(),
+                    Err(_) => self
+                        .reply_buf
+                        .error(ErrorCode::FlashWrite, "Flash write failed"),
+                }
+                return;
+            }
+            b"x" => {
+                self.correction.clear();
+                self.reply_buf.ok();
+                return;
+            }
+            _ => {
+                let Some([slot, r, g, b]) = self.hex_args(args, 2) else {
+                    return;
+                };
+                if slot as usize >= MAX_PANEL_SLOTS {
+                    self.reply_buf
+                        .error(ErrorCode::BadArgument, "Slot out of range");
+                    return;
+                }
+                self.correction.set(slot as usize, [r, g, b]);
+                self.reply_buf.ok();
+                return;
+            }
+        }
+
+        for i in 0.. {
+            let Some((slot, [r, g, b])) = self.correction.corrected().nth(i) else {
+                break;
+            };
+            let _ = write!(
+                self.reply_buf,
+                "{{\"slot\":{}, \"gains\":\"{:02x}{:02x}{:02x}\"}}",
+                slot, r, g, b
+            );
+            self.flush_reply().await;
+        }
+        let _ = write!(
+            self.reply_buf,
+            "{{\"saved\":{}}}",
+            self.correction.is_saved()
+        );
+    }
+
+    /// Sends the frames in between the last colors and `packet` that keep
+    /// each change in total brightness within the ramp limit, see r. Slots
+    /// whose last colors aren't known start from black.
+    async fn ramp_to(&mut self, packet: &Packet) {
+        let Some(max_delta) = self.max_frame_delta else {
+            return;
+        };
+        let prefix = if packet.tag == Message::SetColorZones {
+            4
+        } else {
+            0
+        };
+        let target = &packet.data[prefix..];
+        let mut from = Vec::<u8, MAX_PAYLOAD_SIZE>::new();
+        match &self.last_colors {
+            Some(last)
+                if last.tag == packet.tag
+                    && last.data.len() == packet.data.len()
+                    && last.data[..prefix] == packet.data[..prefix] =>
+            {
+                let _ = from.extend_from_slice(&last.data[prefix..]);
+            }
+            _ => {
+                let _ = from.resize(target.len(), 0);
+            }
+        }
+
+        let delta: u32 = from
+            .iter()
+            .zip(target)
+            .map(|(&f, &t)| f.abs_diff(t) as u32)
+            .sum();
+        let frames = delta.div_ceil(max_delta).min(MAX_RAMP_FRAMES);
+        if frames > 1 {
+            debug!("Ramping over {} frames", frames);
+        }
+        for frame in 1..frames {
+            let mut step = Packet::new(self.address, BROADCAST_ADDRESS, packet.tag);
+            step.push_data(&packet.data[..prefix]);
+            for (&f, &t) in from.iter().zip(target) {
+                let value = f as i32 + (t as i32 - f as i32) * frame as i32 / frames as i32;
+                step.push_data(&[value as u8]);
+            }
+            self.send_frame(&step).await;
+        }
+    }
+
+    /// Sends a Set Color frame and collects the replies, or with q on, sends
+    /// it as a SetColorNoReply and goes straight on, since nothing will
+    /// answer. L leaves room for the extra byte. Returns when it finished
+    /// sending, for timing.
+    ///
+    async fn send_frame(&mut self, packet: &Packet) -> Instant {
+        let quiet = match self.quiet_frames {
+            true => packet.to_no_reply(),
+            false => None,
+        };
+        match quiet {
+            Some(quiet) => {
+                watchdog::check_in(Subsystem::Commands);
+                self.comm.send_packet(&quiet).await;
+                Instant::now()
+            }
+            None => {
+                self.send_message(packet, Duration::from_millis(MAX_PANEL_SLOTS as u64))
+                    .await
+            }
+        }
+    }
+
+    fn command_dry_run(&mut self, args: &[u8]) {
+        self.dry_run = match args {
+            b"0" => false,
+            b"1" => true,
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0 or 1");
+                return;
+            }
+        };
+        self.reply_buf.ok();
+    }
+
+    async fn command_flash_id(&mut self, mode: Mode, args: &[u8]) {
+        match mode {
+            Mode::Master => {
+                let Some([id]) = self.hex_args(args, 2) else {
+                    return;
+                };
+                let packet = Packet::new(self.address, Address(id), Message::FlashId);
+                self.send_reliable(&packet).await;
+                return;
+            }
+            _ => {
+                if !args.is_empty() {
+                    self.reply_buf
+                        .error(ErrorCode::UnexpectedArgument, "Unexpected argument");
+                    return;
+                }
+                self.start_id_flash();
+            }
+        }
+        self.reply_buf.ok();
+    }
+
+    /// Starts flashing our ID on the LED strip, or starts over if it already
+    /// is.
+    fn start_id_flash(&mut self) {
+        let saved = self.take_saved_colors();
+        self.id_flash = Some(IdFlash::new(self.address.value(), saved));
+    }
+
+    /// Shows the next LED test color, starting the test if it isn't going.
+    fn step_led_test(&mut self) {
+        if self.led_test.is_none() {
+            let saved = self.take_saved_colors();
+            self.led_test = Some(LedTest::new(saved));
+        }
+        if let Some(led_test) = &mut self.led_test {
+            led_test.press(&mut self.led_strip);
+        }
+    }
+
+    /// Ends flashing our ID or the LED test, and returns the colors the strip
+    /// had before either started.
+    fn take_saved_colors(&mut self) -> ZoneColors {
+        if let Some(id_flash) = self.id_flash.take() {
+            return id_flash.saved();
+        }
+        match self.led_test.take() {
+            Some(led_test) => led_test.saved(),
+            None => self.led_strip.colors(),
+        }
+    }
+
+    fn command_simulate(&mut self, args: &[u8]) {
+        let mut ids = Vec::<(u8, bool), MAX_SIM_PANELS>::new();
+        let mut rest = args;
+        while !rest.is_empty() {
+            let (silent, digits) = match rest {
+                [b'-', digits @ ..] => (true, digits),
+                digits => (false, digits),
+            };
+            let (digits, after) = digits.split_at(digits.len().min(2));
+            let column = 2 + args.len() - digits.len() - after.len();
+            let Some([id]) = self.hex_args(digits, column) else {
+                return;
+            };
+            if ids.push((id, silent)).is_err() {
+                self.reply_buf.error(
+                    ErrorCode::TooMany,
+                    format_args!("At most {} panels", MAX_SIM_PANELS),
+                );
+                return;
+            }
+            rest = after;
+        }
+
+        if ids.is_empty() {
+            info!("Simulation off");
+            self.comm.simulate(None);
+        } else {
+            let sim = SimPanels::new(ids);
+            info!("Simulating {} panels", sim.count());
+            self.comm.simulate(Some(sim));
+        }
+        self.panels.clear();
+        self.reply_buf.ok();
+    }
+
+    /// Says what a dry run would have sent.
+    fn describe_packet(&mut self, packet: &Packet) {
+        // Everything fits in one packet for now
+        let _ = write!(
+            self.reply_buf,
+            "msg={} bytes={} packets=1 data=",
+            packet.tag as u8 as char,
+            packet.data.len()
+        );
+        for b in &packet.data {
+            let _ = write!(self.reply_buf, "{:02x}", b);
+        }
+    }
+
+    /// Parses arguments that are exactly N hex bytes, or replies with where
+    /// they went wrong. `column` is as for hex_error().
+    fn hex_args<const N: usize>(&mut self, args: &[u8], column: usize) -> Option<[u8; N]> {
+        match hex_fields(args) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                self.hex_error(e, column);
+                None
+            }
+        }
+    }
+
+    /// Replies with where hex arguments went wrong. `column` is where the
+    /// arguments start in the command, counting the command letter as 1.
+    fn hex_error(&mut self, e: HexError, column: usize) {
+        let column = column + e.offset;
+        self.reply_buf.error_at(
+            ErrorCode::BadHex,
+            format_args!("at char {}: {}", column, e.problem),
+            column,
+        );
+    }
+
+    async fn command_set_panel_color(&mut self, args: &[u8]) {
+        let Some(bytes) = self.hex_args::<4>(args, 2) else {
+            return;
+        };
+
+        let id = Address(bytes[0]);
+        let mut packet = Packet::new(self.address, id, Message::SetColor);
+        packet.push_data(&bytes[1..]);
+        self.panels.clear();
+        if self
+            .send_to_one(&packet, Message::SetColorReply)
+            .await
+            .is_none()
+        {
+            self.reply_buf.failed();
+            let _ = write!(self.reply_buf, "{:02x}", id.value());
+            return;
+        }
+
+        let pirs = self
+            .panels
+            .iter()
+            .find(|p| p.id == id)
+            .map_or(0, |p| p.pirs);
+        let _ = write!(self.reply_buf, "{:x}", pirs & 0x0f);
+    }
+
+    /// A SetColorDelta with the slots of `packet` whose colors changed since
+    /// the last L, or None if every slot should go: it's been `every` since
+    /// they last did, the last L was laid out differently, or nothing changed,
+    /// since then there'd be no answers to take the PIRs from. Only plain
+    /// SetColor frames are sent as deltas.
+    fn delta_from_last(&self, packet: &Packet, every: Duration) -> Option<Packet> {
+        let last = self.last_colors.as_ref()?;
+        if self.full_frame_at?.elapsed() >= every
+            || packet.tag != Message::SetColor
+            || last.tag != packet.tag
+            || last.data.len() != packet.data.len()
+        {
+            return None;
+        }
+        let mut delta = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetColorDelta);
+        let colors = packet.data.chunks(3).zip(last.data.chunks(3));
+        for (slot, (new, old)) in colors.enumerate().filter(|(_, (new, old))| new != old) {
+            // No shorter than the whole frame
+            if delta.data.len() + DELTA_ENTRY_LEN >= packet.data.len() {
+                return None;
+            }
+            delta.push_data(&[slot as u8]);
+            delta.push_data(new);
+        }
+        (!delta.data.is_empty()).then_some(delta)
+    }
+
+    fn command_quiet_frames(&mut self, args: &[u8]) {
+        let quiet = match args {
+            [] => {
+                let _ = self
+                    .reply_buf
+                    .push_str(if self.quiet_frames { "on" } else { "off" });
+                return;
+            }
+            b"0" => false,
+            b"1" => true,
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0 or 1");
+                return;
+            }
+        };
+        // The PIRs from before are out of date, so the next L goes to every
+        // slot, see delta_from_last()
+        if self.quiet_frames && !quiet {
+            self.full_frame_at = None;
+        }
+        self.quiet_frames = quiet;
+        self.reply_buf.ok();
+    }
+
+    async fn command_set_color_delta(&mut self, args: &[u8]) {
+        let mut entries = [0; MAX_DELTA_SLOTS * DELTA_ENTRY_LEN];
+        let len = match hex_groups(args, DELTA_ENTRY_LEN, &mut entries) {
+            Ok(0) => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected slots and colors");
+                return;
+            }
+            Ok(len) => len,
+            Err(HexError {
+                problem: HexProblem::TooLong,
+                ..
+            }) => {
+                self.reply_buf.error(ErrorCode::TooMany, "Too many slots");
+                return;
+            }
+            Err(e) => {
+                self.hex_error(e, 2);
+                return;
+            }
+        };
+        let entries = &mut entries[..len];
+
+        let mut given: u32 = 0;
+        for entry in entries.chunks_mut(DELTA_ENTRY_LEN) {
+            let slot = entry[0] as usize;
+            if slot >= MAX_PANEL_SLOTS {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Slot out of range");
+                return;
+            }
+            if given & (1 << slot) != 0 {
+                self.reply_buf.error(
+                    ErrorCode::BadArgument,
+                    format_args!("Slot {:02x} given twice", slot),
+                );
+                return;
+            }
+            given |= 1 << slot;
+            self.correction.apply(slot, &mut entry[1..]);
+        }
+        let mut delta = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetColorDelta);
+        delta.push_data(entries);
+
+        // The last L with these slots changed, which goes instead when it's
+        // time for every slot, see e. Without one, the panels aren't all set
+        // again until the next L.
+        let mut full = self
+            .last_colors
+            .clone()
+            .filter(|last| last.tag == Message::SetColor);
+        for entry in entries.chunks(DELTA_ENTRY_LEN) {
+            let start = entry[0] as usize * 3;
+            let colors = full.as_mut().and_then(|f| f.data.get_mut(start..start + 3));
+            match colors {
+                Some(colors) => colors.copy_from_slice(&entry[1..]),
+                None => full = None,
+            }
+        }
+        let every = self.delta_every.unwrap_or(DEFAULT_FULL_FRAME_INTERVAL);
+        let due = self.full_frame_at.is_none_or(|at| at.elapsed() >= every);
+        let send_full = due && full.is_some();
+
+        if self.dry_run {
+            let _ = write!(self.reply_buf, "DRY slots={} ", len / DELTA_ENTRY_LEN);
+            match &full {
+                Some(full) if send_full => self.describe_packet(full),
+                _ => self.describe_packet(&delta),
+            }
+            return;
+        }
+
+        let parse_time = self.command_started.elapsed();
+        let start = Instant::now();
+        self.panels.clear();
+        let sent = match &full {
+            Some(full) if send_full => full,
+            _ => &delta,
+        };
+        let sent_at = self
+            .send_message(sent, Duration::from_millis(MAX_PANEL_SLOTS as u64))
+            .await;
+        if send_full {
+            self.full_frame_at = Some(sent_at);
+        }
+        self.last_colors = full;
+
+        for entry in entries.chunks(DELTA_ENTRY_LEN) {
+            let slot = entry[0] as usize;
+            let pirs = match self.panels.iter().find(|p| p.slot as usize == slot) {
+                Some(p) => p.pirs,
+                None => 0,
+            };
+            self.slot_pirs[slot] = pirs;
+            let _ = write!(self.reply_buf, "{:x}", pirs & 0x0f);
+        }
+        self.stats
+            .count_frame(parse_time, sent_at - start, sent_at.elapsed());
+    }
+
+    fn command_delta_frames(&mut self, args: &[u8]) {
+        if args.is_empty() {
+            let _ = match self.delta_every {
+                Some(every) => write!(self.reply_buf, "{}", every.as_millis()),
+                None => self.reply_buf.push_str("off"),
+            };
+            return;
+        }
+        let ms = core::str::from_utf8(args)
+            .ok()
+            .and_then(|s| s.parse::<u32>().ok());
+        let Some(ms) = ms else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected a decimal number");
+            return;
+        };
+        self.delta_every = (ms != 0).then(|| Duration::from_millis(ms as u64));
+        self.reply_buf.ok();
+    }
+
+    async fn command_latency(&mut self, args: &[u8]) {
+        let parsed = match args.len() {
+            2 => self.hex_args(args, 2).map(|[id]| [id, 16]),
+            _ => self.hex_args(args, 2),
+        };
+        let Some([id, rounds]) = parsed else {
+            return;
+        };
+        if rounds == 0 {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected 01 or more rounds");
+            return;
+        }
+
+        let id = Address(id);
+        let mut rtt = Rtt::new();
+        let mut histogram = RttHistogram::new();
+        for _ in 0..rounds {
+            self.panels.clear();
+            let ping = Packet::new(self.address, id, Message::Ping);
+            if let Some(us) = self.send_to_one(&ping, Message::PingReply).await {
+                rtt.record(us);
+                histogram.record(us);
+            }
+        }
+
+        let _ = write!(
+            self.reply_buf,
+            "{{\"id\":{}, \"sent\":{}, \"received\":{}, ",
+            id.value(),
+            rounds,
+            rtt.count()
+        );
+        let (min, avg, max) = rtt.summary().unwrap_or_default();
+        let _ = write!(
+            self.reply_buf,
+            "\"minUs\":{}, \"avgUs\":{}, \"maxUs\":{}, \"bucketUs\":{}, \"hist\":[",
+            min, avg, max, RTT_BUCKET_US
+        );
+        for (i, count) in histogram.buckets().iter().enumerate() {
+            if i > 0 {
+                let _ = self.reply_buf.push(',');
+            }
+            let _ = write!(self.reply_buf, "{}", count);
+        }
+        let _ = self.reply_buf.push_str("]}");
+    }
+
+    async fn command_map_panels(&mut self, args: &[u8]) {
+        if args == b"V" {
+            self.verify_mapping().await;
+            return;
+        }
+        if args == b"x" {
+            match self.saved_mapping.clear() {
+                Ok(()) => self.reply_buf.ok(),
+                Err(_) => self
+                    .reply_buf
+                    .error(ErrorCode::FlashWrite, "Flash write failed"),
+            }
+            return;
+        }
+        // Held by the panels until they've all confirmed, then switched to
+        // at once
+        let (tag, args) = match args {
+            [b'S', rest @ ..] => (Message::MapPanelsPending, rest),
+            _ => (Message::MapPanels, args),
+        };
+        let column = if tag == Message::MapPanelsPending {
+            3
+        } else {
+            2
+        };
+
+        let mut ids = [0; MAX_PANEL_SLOTS];
+        let num_panels = match hex_groups(args, 1, &mut ids) {
+            Ok(len) => len,
+            Err(HexError {
+                problem: HexProblem::TooLong,
+                ..
+            }) => {
+                self.reply_buf.error(ErrorCode::TooMany, "Too many panels");
+                return;
+            }
+            Err(e) => {
+                self.hex_error(e, column);
+                return;
+            }
+        };
+
+        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, tag);
+
+        let slot_ids = Vec::<u8, MAX_PANEL_SLOTS>::from_slice(&ids[..num_panels]).unwrap();
+
+        packet.push_data(&slot_ids);
+
+        if self.dry_run {
+            let _ = write!(self.reply_buf, "DRY panels={} ", num_panels);
+            self.describe_packet(&packet);
+            return;
+        }
+
+        let old_mapping = self.mapping.clone();
+        let old_two_zone_slots = self.two_zone_slots;
+        let confirmed_slots = self.map_panels(&slot_ids, tag, true).await;
+        let requested_mask = (1 << num_panels) - 1;
+        if confirmed_slots & requested_mask == requested_mask {
+            if tag == Message::MapPanelsPending {
+                self.commit_mapping().await;
+            }
+            // Flash trouble is logged, the mapping itself went fine
+            let _ = self.saved_mapping.save(&slot_ids);
+            self.reply_buf.ok();
+            return;
+        }
+
+        if tag == Message::MapPanelsPending {
+            // Nothing switched, so the panels hold the old mapping instead,
+            // and a frame for the new one can't switch some of them
+            let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, tag);
+            packet.push_data(&old_mapping);
+            self.send_message(&packet, Duration::from_millis(MAX_PANEL_SLOTS as u64))
+                .await;
+            self.health.set_mapping(&old_mapping);
+            self.mapping = old_mapping;
+            self.two_zone_slots = old_two_zone_slots;
+        }
+
+        self.reply_buf.failed();
+        let missing = slot_ids
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| confirmed_slots & (1 << i) == 0)
+            .map(|(_, &id)| id);
+        self.write_ids(missing);
+    }
+
+    /// Asks the panels which slots they have, and replies with the mapped
+    /// ones that don't match, without changing anything on either side.
+    async fn verify_mapping(&mut self) {
+        if self.mapping.is_empty() {
+            self.reply_buf
+                .error(ErrorCode::NotMapped, "No panels mapped");
+            return;
+        }
+
+        self.queried_slots.clear();
+        let packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::QuerySlot);
+        self.send_message(&packet, SLOT_QUERY_WINDOW).await;
+
+        let wrong: Vec<u8, MAX_PANEL_SLOTS> = self
+            .mapping
+            .iter()
+            .enumerate()
+            .filter(|&(slot, &id)| {
+                let reported = self
+                    .queried_slots
+                    .iter()
+                    .find(|(from, _)| from.value() == id);
+                reported.is_none_or(|&(_, s)| s as usize != slot)
+            })
+            .map(|(slot, _)| slot as u8)
+            .collect();
+        if wrong.is_empty() {
+            self.reply_buf.ok();
+            return;
+        }
+        self.reply_buf.mismatch();
+        self.write_ids(wrong);
+    }
+
+    /// Makes `slot_ids` the mapping, and broadcasts it in a `tag` message,
+    /// MapPanels or MapPanelsPending, until every panel has confirmed its
+    /// slot, or it's been tried enough. Returns the slots that were
+    /// confirmed. With `progress`, retries are reported to the host.
+    async fn map_panels(&mut self, slot_ids: &[u8], tag: Message, progress: bool) -> u32 {
+        let num_panels = slot_ids.len();
+        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, tag);
+        packet.push_data(slot_ids);
+
+        self.mapping = Vec::from_slice(slot_ids).unwrap();
+        self.health.set_mapping(slot_ids);
+        self.full_frame_at = None;
+        if let Some(mirror) = &mut self.status_mirror {
+            mirror.reset();
+        }
+        self.remap_slots = 0;
+        self.two_zone_slots = 0;
+
+        let mut confirmed_slots: u32 = 0;
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(5000);
+
+        // Send the packet multiple times to ensure all panels receive it
+        const ATTEMPTS: usize = 4;
+        for attempt in 1..=ATTEMPTS {
+            self.panels.clear();
+            self.send_message(&packet, Duration::from_millis(300)).await;
+
+            // Check which slots were assigned
+            for panel in self.panels.iter() {
+                for (j, &id) in slot_ids.iter().enumerate() {
+                    if panel.id.value() == id {
+                        confirmed_slots |= 1 << j;
+                        if panel.zones == 2 {
+                            self.two_zone_slots |= 1 << j;
+                        }
+                        break;
+                    }
+                }
+            }
+
+            // Check if all slots are assigned
+            let requested_mask = (1 << num_panels) - 1;
+            if (confirmed_slots & requested_mask) == requested_mask {
+                break;
+            }
+
+            if start.elapsed() > timeout {
+                break;
+            }
+
+            if attempt < ATTEMPTS && progress {
+                let confirmed = (confirmed_slots & requested_mask).count_ones();
+                self.progress(format_args!(
+                    "retry {}/{}, {} of {} panels confirmed",
+                    attempt + 1,
+                    ATTEMPTS,
+                    confirmed,
+                    num_panels
+                ))
+                .await;
+            }
+            Timer::after(Duration::from_millis(50)).await;
+        }
+        confirmed_slots
+    }
+
+    /// Switches the panels to the mapping they're holding. Sent twice, since
+    /// nothing answers it, and a panel that misses both switches on the
+    /// first frame laid out for the new mapping.
+    async fn commit_mapping(&mut self) {
+        let packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::CommitMapping);
+        self.comm.send_packet(&packet).await;
+        Timer::after(COMMIT_REPEAT_GAP).await;
+        self.comm.send_packet(&packet).await;
+    }
+
+    /// Sends the panels the mapping saved before the master rebooted, if
+    /// there is one, so `L` has them in the right order without the host
+    /// having to send `M` again. How many confirmed is notified.
+    pub async fn replay_mapping(&mut self) {
+        let saved = Vec::<u8, MAX_PANEL_SLOTS>::from_slice(self.saved_mapping.ids()).unwrap();
+        if saved.is_empty() {
+            return;
+        }
+        info!("Replaying the saved mapping of {} slots", saved.len());
+        let _busy = watchdog::busy(Subsystem::Commands);
+        let confirmed = self.map_panels(&saved, Message::MapPanels, false).await;
+        notify(
+            &mut self.notifications,
+            format_args!("!remapped {} {}", confirmed.count_ones(), saved.len()),
+        );
+    }
+
+    /// Runs the autoexec macro, if there is one. For at boot, after
+    /// replay_mapping(), so it starts with the panels mapped. The replies go
+    /// to the serial port, since no command has come from USB yet.
+    pub async fn run_autoexec(&mut self) {
+        if self.macros.lines(AUTOEXEC).is_none() {
+            return;
+        }
+        info!("Running the autoexec macro");
+        let _busy = watchdog::busy(Subsystem::Commands);
+        self.run_macro(AUTOEXEC).await;
+    }
+
+    /// Runs the lines of macro `name` as commands of their own, with each
+    /// reply after the line's index and a space. Stops after one that asks
+    /// for a soft restart. An empty line still repeats the command before
+    /// the macro.
+    async fn run_macro(&mut self, name: &[u8]) {
+        let last_command = self.last_command.clone();
+        for index in 0..MAX_MACRO_LINES {
+            let mut line = [0; MAX_COMMAND_LEN];
+            let Some(len) = self.macros.line(name, index, &mut line) else {
+                break;
+            };
+            let mut prefix = heapless::String::<4>::new();
+            let _ = write!(prefix, "{} ", index);
+            self.interactor.reply_part(&prefix).await;
+            self.run_command(Mode::Master, &line[..len]).await;
+            if self.restart {
+                break;
+            }
+        }
+        self.last_command = last_command;
+    }
+
+    async fn command_macro(&mut self, args: &[u8]) {
+        if args.is_empty() {
+            let mut index = 0;
+            while self.macros.write(index, &mut self.reply_buf) {
+                self.flush_reply().await;
+                index += 1;
+            }
+            self.reply_buf.ok();
+            return;
+        }
+        let (name, lines) = match args.iter().position(|&b| b == b'=') {
+            Some(at) => (&args[..at], Some(&args[at + 1..])),
+            None => (args, None),
+        };
+        if !valid_name(name) {
+            self.reply_buf.error(
+                ErrorCode::BadArgument,
+                "Expected a name of 1 to 8 of a-z, 0-9, and _",
+            );
+            return;
+        }
+        let Some(lines) = lines else {
+            if self.macros.lines(name).is_none() {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "No such macro");
+                return;
+            }
+            // Run by run_master(), once this reply is out of the way
+            self.macro_to_run = MacroName::from_slice(name).ok();
+            self.reply_buf.ok();
+            return;
+        };
+
+        let mut split: Vec<&[u8], MAX_MACRO_LINES> = Vec::new();
+        if !lines.is_empty() {
+            for line in lines.split(|&b| b == LINE_SEPARATOR) {
+                if split.push(line).is_err() {
+                    self.reply_buf.error(
+                        ErrorCode::TooMany,
+                        format_args!("At most {} lines", MAX_MACRO_LINES),
+                    );
+                    return;
+                }
+            }
+        }
+        let bad_line = split
+            .iter()
+            .any(|line| line.first().is_none_or(|&b| b == Command::Macro as u8));
+        if bad_line {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Lines can't be empty or run macros");
+            return;
+        }
+        match self.macros.save(name, &split) {
+            Ok(()) => self.reply_buf.ok(),
+            Err(MacroError::Full) => self
+                .reply_buf
+                .error(ErrorCode::TooMany, "No room for more macros"),
+            Err(MacroError::Flash(_)) => self
+                .reply_buf
+                .error(ErrorCode::FlashWrite, "Flash write failed"),
+        }
+    }
+
+    async fn command_reset(&mut self, _args: &[u8]) {
+        // Panels only reset on the second one, see RESET_WINDOW
+        let packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::Reset);
+        self.comm.send_packet(&packet).await;
+        Timer::after(RESET_REPEAT_GAP).await;
+
+        // The panels announce themselves once they're back, which queues the
+        // mapped ones for remapping
+        self.panels.clear();
+        let announce_time = PANEL_BOOT_TIME + Duration::from_millis(MAX_ANNOUNCE_DELAY_MS);
+        self.send_message(&packet, announce_time).await;
+        let back = self
+            .mapping
+            .iter()
+            .filter(|&&id| self.panels.iter().any(|p| p.id.value() == id))
+            .count();
+        self.progress(format_args!(
+            "{} of {} panels back, remapping",
+            back,
+            self.mapping.len()
+        ))
+        .await;
+        self.send_remaps().await;
+
+        self.reply_unacknowledged();
+    }
+
+    async fn command_panel_status(&mut self, args: &[u8]) {
+        let Some([id]) = self.hex_args(args, 2) else {
+            return;
+        };
+        let id = Address(id);
+
+        self.panels.clear();
+        self.queried_status = None;
+
+        // The ping gets us RSSI, the status request gets us everything else
+        let ping = Packet::new(self.address, id, Message::Ping);
+        self.send_message(&ping, Duration::from_millis(40)).await;
+        let request = Packet::new(self.address, id, Message::StatusRequest);
+        self.send_message(&request, Duration::from_millis(40)).await;
+
+        let Some(status) = self.queried_status else {
+            let _ = write!(
+                self.reply_buf,
+                "{{\"id\":{}, \"error\":\"no reply\"}}",
+                id.value()
+            );
+            return;
+        };
+
+        let (rssi_master, rssi_panel) = match self.panels.iter().find(|p| p.id == id) {
+            Some(p) => (p.rssi_master, p.rssi_panel),
+            None => (0, 0),
+        };
+
+        let _ = write!(
+            self.reply_buf,
+            "{{\"id\":{}, \"bootCount\":{}, \"rssiM\":{}, \"rssiP\":{}, \"slot\":",
+            id.value(),
+            status.boot_count,
+            rssi_master,
+            rssi_panel
+        );
+        let _ = match status.slot {
+            Some(slot) => write!(self.reply_buf, "{}", slot),
+            None => write!(self.reply_buf, "null"),
+        };
+        let _ = write!(
+            self.reply_buf,
+            ", \"color\":\"{:02x}{:02x}{:02x}\", \"pirs\":{}, \"uptime\":{}, \"pirProfile\":",
+            status.color[0], status.color[1], status.color[2], status.pirs, status.uptime_secs
+        );
+        let _ = match status.pir_profile {
+            Some(profile) => write!(self.reply_buf, "\"{}\"", profile.name()),
+            None => write!(self.reply_buf, "null"),
+        };
+        let _ = match status.hours {
+            Some(hours) => write!(self.reply_buf, ", \"hours\":{}", hours),
+            None => write!(self.reply_buf, ", \"hours\":null"),
+        };
+        let _ = match status.derating {
+            Some(output) => write!(self.reply_buf, ", \"output\":{}", output_percent(output)),
+            None => write!(self.reply_buf, ", \"output\":null"),
+        };
+        let _ = match status.usb {
+            Some(usb) => write!(self.reply_buf, ", \"usb\":{}", usb),
+            None => write!(self.reply_buf, ", \"usb\":null"),
+        };
+        let _ = match status.last_panic {
+            Some(p) => write!(self.reply_buf, ", \"lastPanic\":\"{:08x}\"}}", p.pc),
+            None => write!(self.reply_buf, ", \"lastPanic\":null}}"),
+        };
+    }
+
+    async fn command_health(&mut self, args: &[u8]) {
+        if !args.is_empty() {
+            let Some([secs]) = self.hex_args(args, 2) else {
+                return;
+            };
+            let interval = (secs > 0).then(|| Duration::from_secs(secs as u64));
+            self.health.set_idle_interval(interval);
+        }
+
+        // Too long for one reply with a lot of panels, so send it in parts
+        let _ = self.reply_buf.push('[');
+        for i in 0.. {
+            self.make_room(MISSING_JSON_LEN).await;
+            let Some(panel) = self.health.missing().nth(i) else {
+                break;
+            };
+            if i > 0 {
+                let _ = self.reply_buf.push_str(", ");
+            }
+            let _ = write!(
+                self.reply_buf,
+                "{{\"id\":{}, \"misses\":{}, \"goneSecs\":{}}}",
+                panel.id, panel.misses, panel.gone_secs
+            );
+        }
+        let _ = self.reply_buf.push(']');
+    }
+
+    fn command_animate(&mut self, args: &[u8]) {
+        let pattern = match args {
+            [p] => Pattern::try_from(*p).ok(),
+            _ => None,
+        };
+        let Some(pattern) = pattern else {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected 1, 2, 3, or 4");
+            return;
+        };
+
+        if self.mapping.is_empty() {
+            self.reply_buf
+                .error(ErrorCode::NotMapped, "No panels mapped");
+            return;
+        }
+
+        self.animation = Some(Animation::new(pattern));
+        self.reply_buf.ok();
+    }
+
+    fn command_identify(&mut self, args: &[u8]) {
+        if self.mapping.is_empty() {
+            self.reply_buf
+                .error(ErrorCode::NotMapped, "No panels mapped");
+            return;
+        }
+
+        let identify = if args.is_empty() {
+            Identify::all_slots()
+        } else {
+            let Some([slot]) = self.hex_args(args, 2) else {
+                return;
+            };
+            if slot as usize >= self.mapping.len() {
+                self.reply_buf
+                    .error(ErrorCode::NotMapped, "Expected a mapped slot");
                 return;
             }
+            Identify::slot(slot as usize)
         };
 
-        set_default_mode(new_mode);
-        cortex_m::peripheral::SCB::sys_reset();
+        self.identify = Some(identify);
+        self.reply_buf.ok();
     }
 
-    fn command_version(&mut self, _args: &[u8]) {
-        let mode_str = match self.mode {
-            Mode::Master => "Master",
-            Mode::Panel => "Panel",
-            Mode::Spy => "Spy",
+    async fn command_bus_baud(&mut self, args: &[u8]) {
+        let baud = match args {
+            [d @ b'0'..=b'3'] => BusBaud::try_from(d - b'0').unwrap(),
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected 0, 1, 2, or 3");
+                return;
+            }
         };
 
-        let mut response = heapless::String::<128>::new();
-        write!(
-            response,
-            "Aunisoma version {} ID={} Mode={} Comm={}",
-            version::VERSION,
-            self.address.value(),
-            mode_str,
-            self.comm.mode_name(),
-        )
-        .unwrap();
-
-        let _ = self.reply_buf.push_str(response.as_str());
-    }
+        if self.comm.mode() != CommMode::Serial {
+            self.reply_buf
+                .error(ErrorCode::WrongCommMode, "Not in serial mode");
+            return;
+        }
 
-    async fn command_enumerate(&mut self, _args: &[u8]) {
-        let packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::Ping);
+        // Panels acknowledge at the old rate, then switch
+        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetBaud);
+        packet.push_data(&[baud.into(), 0]);
         self.panels.clear();
+        self.send_message(&packet, Duration::from_millis(300)).await;
 
-        self.send_message(&packet, Duration::from_millis(40)).await;
-
-        // Format response as JSON array
-        let mut w = heapless::String::<256>::new();
-        write!(w, "[").unwrap();
-        for (i, panel) in self.panels.iter().enumerate() {
-            if i > 0 {
-                write!(w, ", ").unwrap();
-            }
-            write!(
-                w,
-                "{{\"id\":{}, \"bootCount\":{}, \"rssiM\":{}, \"rssiP\":{}}}",
-                panel.id.value(),
-                panel.boot_count,
-                panel.rssi_master,
-                panel.rssi_panel
-            )
-            .unwrap();
+        self.comm.set_bus_baud(baud);
+        if let Err(e) = flash::write_settings(|| flash::set_bus_baud(baud)).await {
+            warn!("Couldn't save bus baud: {:?}", e);
         }
-        write!(w, "]").unwrap();
-        let _ = self.reply_buf.push_str(w.as_str());
+
+        // Now that we can hear each other at the new rate, tell the panels to
+        // keep it.
+        packet.data.clear();
+        packet.push_data(&[baud.into(), 1]);
+        self.comm.send_packet(&packet).await;
+
+        self.reply_unacknowledged();
     }
 
-    async fn command_set_color(&mut self, args: &[u8]) {
-        debug!("Set color: {:a}", args);
-        // Each color takes 6 hex digits (2 each for R,G,B)
-        if args.len() % 6 != 0 {
-            let _ = self
-                .reply_buf
-                .push_str("ERROR Expected 6 hex digits per color");
+    async fn command_channel(&mut self, mode: Mode, args: &[u8]) {
+        if args.is_empty() {
+            let channel = self.comm.channel();
+            let freq = PanelRadio::frequency(channel);
+            let _ = write!(
+                self.reply_buf,
+                "{:02x} {}.{}MHz",
+                channel,
+                freq / 1_000_000,
+                freq / 100_000 % 10
+            );
             return;
         }
 
-        let num_slots = args.len() / 6;
-        if num_slots > MAX_PANEL_SLOTS {
-            let _ = self.reply_buf.push_str("ERROR Too many slots");
+        let Some([channel]) = self.hex_args(args, 2) else {
+            return;
+        };
+        if channel > PanelRadio::MAX_CHANNEL {
+            self.reply_buf
+                .error(ErrorCode::BadArgument, "Expected 00 to 0f");
             return;
         }
 
-        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetColor);
-
-        // Parse RGB values for each slot
-        for offset in (0..args.len()).step_by(2) {
-            let b = match parse_hex_byte(&args[offset..offset + 2]) {
-                Some(v) => v,
-                None => {
-                    let _ = self.reply_buf.push_str("ERROR Invalid hex byte");
-                    return;
+        if mode != Mode::Master {
+            // Also how a panel that missed the master's SetChannel is brought
+            // back
+            self.comm.set_channel(channel);
+            match flash::write_settings(|| flash::set_channel(channel)).await {
+                Ok(()) => self.reply_buf.ok(),
+                Err(e) => {
+                    warn!("Couldn't save channel: {:?}", e);
+                    self.reply_buf
+                        .error(ErrorCode::FlashWrite, "Flash write failed");
                 }
-            };
+            }
+            return;
+        }
 
-            packet.push_data(&[b]);
+        if self.comm.mode() != CommMode::Radio {
+            self.reply_buf
+                .error(ErrorCode::WrongCommMode, "Not in radio mode");
+            return;
         }
 
+        // Panels acknowledge on the old channel, then switch
+        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::SetChannel);
+        packet.push_data(&[channel, 0]);
         self.panels.clear();
-        self.send_message(&packet, Duration::from_millis(MAX_PANEL_SLOTS as u64))
-            .await;
+        self.send_message(&packet, Duration::from_millis(300)).await;
 
-        for slot in 0..num_slots {
-            let pirs = match self.panels.iter().find(|p| p.slot as usize == slot) {
-                Some(p) => p.pirs,
-                None => 0,
-            };
-            let _ = self.reply_buf.push((b'0' + pirs) as char);
+        self.comm.set_channel(channel);
+        if let Err(e) = flash::write_settings(|| flash::set_channel(channel)).await {
+            warn!("Couldn't save channel: {:?}", e);
         }
+
+        // Now that we can hear each other on the new channel, tell the panels
+        // to keep it.
+        packet.data.clear();
+        packet.push_data(&[channel, 1]);
+        self.comm.send_packet(&packet).await;
+
+        self.reply_unacknowledged();
     }
 
-    async fn command_map_panels(&mut self, args: &[u8]) {
-        // Each panel ID is 2 hex digits
-        if args.len() % 2 != 0 || args.len() > MAX_PANEL_SLOTS * 2 {
-            let _ = self.reply_buf.push_str("ERROR");
+    /// Replies `OK` if every mapped panel is in the panel list, or `FAILED`
+    /// with the ones that aren't, after a broadcast that all of them should
+    /// have acknowledged.
+    fn reply_unacknowledged(&mut self) {
+        let missing: Vec<u8, MAX_PANEL_SLOTS> = self
+            .mapping
+            .iter()
+            .copied()
+            .filter(|&id| !self.panels.iter().any(|p| p.id.value() == id))
+            .collect();
+        if missing.is_empty() {
+            self.reply_buf.ok();
             return;
         }
+        self.reply_buf.failed();
+        self.write_ids(missing);
+    }
 
-        let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::MapPanels);
-
-        let num_panels = args.len() / 2;
-
-        let mut slot_ids = Vec::<u8, 32>::new();
-        for i in 0..num_panels {
-            let offset = i * 2;
-            let id = match parse_hex_byte(&args[offset..offset + 2]) {
-                Some(v) => v,
-                None => {
-                    let _ = self.reply_buf.push_str("ERROR Invalid hex byte");
-                    return;
-                }
-            };
-            slot_ids.push(id).unwrap();
+    /// Writes panel IDs as two hex digits each, lowest first, so the same
+    /// panels make the same list whatever order they're found in.
+    fn write_ids(&mut self, ids: impl IntoIterator<Item = u8>) {
+        let mut ids: Vec<u8, MAX_PANEL_SLOTS> = ids.into_iter().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let _ = write!(self.reply_buf, "{:02x}", id);
         }
+    }
 
-        packet.push_data(&slot_ids);
-
-        let mut confirmed_slots: u32 = 0;
-
-        let start = Instant::now();
-        let timeout = Duration::from_millis(5000);
-
-        // Send the packet multiple times to ensure all panels receive it
-        for _ in 0..4 {
-            self.panels.clear();
-            self.send_message(&packet, Duration::from_millis(300)).await;
-
-            // Check which slots were assigned
-            for panel in self.panels.iter() {
-                for (j, &id) in slot_ids.iter().enumerate() {
-                    if panel.id.value() == id {
-                        confirmed_slots |= 1 << j;
-                        break;
-                    }
-                }
-            }
-
-            // Check if all slots are assigned
-            let requested_mask = (1 << num_panels) - 1;
-            if (confirmed_slots & requested_mask) == requested_mask {
-                let _ = self.reply_buf.push_str("OK");
+    async fn command_pir_log(&mut self, args: &[u8]) {
+        match args {
+            b"" => {}
+            b"0" => {
+                self.pir_log.clear();
+                self.reply_buf.ok();
                 return;
             }
-
-            if start.elapsed() > timeout {
-                break;
+            _ => {
+                self.reply_buf
+                    .error(ErrorCode::BadArgument, "Expected nothing or 0");
+                return;
             }
-
-            Timer::after(Duration::from_millis(50)).await;
         }
 
-        let _ = self.reply_buf.push_str("FAILED ");
-        for (i, &id) in slot_ids.iter().enumerate() {
-            if (confirmed_slots & (1 << i)) == 0 {
-                write!(&mut self.reply_buf, "{:02x}", id).unwrap();
-            }
+        for i in 0.. {
+            let Some(event) = self.pir_log.events().nth(i).copied() else {
+                break;
+            };
+            let _ = write!(
+                self.reply_buf,
+                "{{\"t\":{}, \"slot\":{}, \"pirs\":{}}}",
+                event.time_ms, event.slot, event.pirs
+            );
+            self.flush_reply().await;
         }
-    }
-
-    async fn command_reset(&mut self, _args: &[u8]) {
-        todo!()
+        let _ = write!(self.reply_buf, "{{\"dropped\":{}}}", self.pir_log.dropped());
     }
 
     async fn command_test_message(&mut self, args: &[u8]) {
-        if args.len() != 2 {
-            let _ = self.reply_buf.push_str("ERROR");
+        let Some([len]) = self.hex_args(args, 2) else {
             return;
-        }
-
-        let len = match parse_hex_byte(&args[0..2]) {
-            Some(v) => v,
-            None => {
-                let _ = self.reply_buf.push_str("ERROR Invalid hex byte");
-                return;
-            }
         };
         let mut packet = Packet::new(self.address, BROADCAST_ADDRESS, Message::Test);
         for i in 0..len {
             packet.push_data(&[i + 1]);
         }
         self.send_message(&packet, Duration::from_millis(10)).await;
-        let _ = self.reply_buf.push_str("OK");
+        self.reply_buf.ok();
     }
 
-    async fn send_message(&mut self, packet: &Packet, reply_time: Duration) {
+    /// Sends a message and handles the replies that come in `reply_time`.
+    /// Returns when it finished sending, for timing.
+    async fn send_message(&mut self, packet: &Packet, reply_time: Duration) -> Instant {
+        // Commands like M send several messages, so keep the watchdog happy
+        watchdog::check_in(Subsystem::Commands);
+
         self.comm.send_packet(packet).await;
+        let sent_at = Instant::now();
 
-        let reply_deadline = Instant::now() + reply_time;
+        let reply_deadline = sent_at + reply_time;
 
         loop {
-            match select3(
-                watchdog_petter(),
-                self.comm.recv_packet(),
-                Timer::at(reply_deadline),
-            )
-            .await
-            {
-                Either3::First(_) => {
-                    // Watchdog petted
-                }
-                Either3::Second(packet) => {
-                    self.handle_reply(packet);
+            match select(self.comm.recv_packet(), Timer::at(reply_deadline)).await {
+                Either::First(reply) => {
+                    let from = reply.from;
+                    let is_reply = reply.tag != Message::Announce;
+                    if self.handle_reply(reply, packet.tag.reply_tag()) && is_reply {
+                        self.record_rtt(from, sent_at);
+                    }
                 }
-                Either3::Third(_) => {
+                Either::Second(_) => {
                     break;
                 }
             }
         }
+        sent_at
+    }
+
+    /// Sends a message that has no reply of its own, making sure it gets
+    /// there if it's for one panel, and replies `OK` or `FAILED `{id}.
+    async fn send_reliable(&mut self, packet: &Packet) {
+        let result = self
+            .comm
+            .send_unicast_reliable(packet, ACK_RETRIES, SINGLE_REPLY_TIME)
+            .await;
+        match result {
+            Ok(()) => {
+                self.reply_buf.ok();
+            }
+            Err(NoAck) => {
+                self.reply_buf.failed();
+                let _ = write!(self.reply_buf, "{:02x}", packet.to.value());
+            }
+        }
+    }
+
+    /// Sends a message to one panel, and waits for its reply only as long as
+    /// it takes to arrive. Returns the round-trip time in µs, or None if the
+    /// reply didn't come.
+    ///
+    async fn send_to_one(&mut self, packet: &Packet, reply_tag: Message) -> Option<u16> {
+        self.comm.send_packet(packet).await;
+        let sent_at = Instant::now();
+
+        let deadline = sent_at + SINGLE_REPLY_TIME;
+        while let Either::First(reply) = select(self.comm.recv_packet(), Timer::at(deadline)).await
+        {
+            let done = reply.from == packet.to && reply.tag == reply_tag;
+            if self.handle_reply(reply, Some(reply_tag)) && done {
+                return Some(self.record_rtt(packet.to, sent_at));
+            }
+        }
+        None
+    }
+
+    /// Notes how long a reply from a panel took to arrive after the message
+    /// was sent, and returns it in µs. The panel's deliberate delay before
+    /// replying doesn't count.
+    ///
+    fn record_rtt(&mut self, from: Address, sent_at: Instant) -> u16 {
+        let elapsed_us = Instant::now()
+            .as_micros()
+            .saturating_sub(sent_at.as_micros());
+        let rtt_us = elapsed_us.saturating_sub(PANEL_REPLY_DELAY.as_micros());
+        let rtt_us = rtt_us.min(u16::MAX as u64) as u16;
+        if let Some(index) = self.find_panel_index(from) {
+            self.panels[index].rtt.record(rtt_us);
+        }
+        rtt_us
     }
 
-    fn handle_reply(&mut self, packet: Packet) {
-        debug!("Received reply: {:?}", packet);
+    /// Takes in a packet from a panel, if it's the reply `expected` by the
+    /// command being handled, or an Announce, which can come any time. Other
+    /// replies are out of phase, which usually means a reply window is too
+    /// short, and anything else is unknown. Both are counted for the Info
+    /// command and dropped. A panel is only added to the list once a reply
+    /// from it checks out. Returns whether the packet was taken.
+    ///
+    fn handle_reply(&mut self, packet: Packet, expected: Option<Message>) -> bool {
+        packet_debug!("Received reply: {:?}", packet);
+
+        // Noted for L, but not taken, since the panel has no slot to list it
+        // under
+        if packet.tag == Message::NotMapped && expected == Some(Message::SetColorReply) {
+            let id = packet.from.value();
+            if !self.not_mapped.contains(&id) {
+                let _ = self.not_mapped.push(id);
+            }
+            return false;
+        }
 
-        let index = self.find_panel_index(packet.from);
-        let panel = self.panels.get_mut(index).unwrap();
+        if packet.tag != Message::Announce && Some(packet.tag) != expected {
+            if packet.tag.is_reply() {
+                debug!(
+                    "Out-of-phase reply from {:x}: {:a}",
+                    packet.from.0, packet.tag as u8 as char
+                );
+                self.stats.count_out_of_phase_reply();
+            } else {
+                debug!(
+                    "Unknown reply from {:x}: {:a}",
+                    packet.from.0, packet.tag as u8 as char
+                );
+                self.stats.count_unknown_reply();
+            }
+            return false;
+        }
 
         match packet.tag {
             Message::PingReply => {
-                if packet.data.len() == 2 {
-                    panel.boot_count = packet.data[0];
-                    panel.rssi_master = packet.data[1] as i8;
-                } else {
+                // Older panels don't send the reset cause or firmware ID
+                let [boot_count, rssi, ref rest @ ..] = packet.data[..] else {
                     debug!("PingReply: Invalid data length");
-                }
+                    return false;
+                };
+                let rssi_master = self.comm.last_rssi();
+                let Some(panel) = self.panel_entry(packet.from) else {
+                    return false;
+                };
+                panel.boot_count = boot_count;
+                // The radio sometimes hears the same reply twice
+                panel.rssi_master = stronger_rssi(panel.rssi_master, rssi_master);
+                panel.rssi_panel = stronger_rssi(panel.rssi_panel, rssi as i8);
+                panel.reset_cause = rest.first().map_or(ResetCause::Unknown, |&c| c.into());
+                panel.fw = rest.get(1..).and_then(FirmwareId::from_bytes);
+                panel.zones = rest.get(4).copied().unwrap_or(1);
+                panel.faults = rest.get(5).copied();
+                panel.tx_power = rest.get(6).and_then(|&p| TxPower::try_from(p).ok());
+                panel.last_panic = rest
+                    .get(7..11)
+                    .and_then(|p| PanicLocation::from_bytes([p[0], p[1], p[2], p[3]]));
+                panel.seen_rounds |= 1 << self.enumerate_round;
             }
             Message::SetColorReply => {
-                if packet.data.len() == 1 {
-                    panel.pirs = packet.data[0];
-                } else {
+                let [pirs] = packet.data[..] else {
                     debug!("SetColorReply: Invalid data length");
+                    return false;
+                };
+                let Some(panel) = self.panel_entry(packet.from) else {
+                    return false;
+                };
+                panel.pirs = pirs;
+                if let Some(slot) = self
+                    .mapping
+                    .iter()
+                    .position(|&id| id == packet.from.value())
+                {
+                    self.pir_log.update(slot, pirs & PIR_BITS);
                 }
             }
             Message::MapPanelsReply => {
-                if packet.data.len() == 1 {
-                    panel.slot = packet.data[0];
-                } else {
-                    debug!("MapPanelsReply: Invalid data length");
+                // Older panels don't send the zones
+                let (slot, zones) = match packet.data[..] {
+                    [slot] => (slot, None),
+                    [slot, zones, ..] => (slot, Some(zones)),
+                    _ => {
+                        debug!("MapPanelsReply: Invalid data length");
+                        return false;
+                    }
+                };
+                let Some(panel) = self.panel_entry(packet.from) else {
+                    return false;
+                };
+                panel.slot = slot;
+                if let Some(zones) = zones {
+                    panel.zones = zones;
                 }
             }
-            _ => {
-                debug!(
-                    "Unknown reply from {:x}: {:a}",
-                    packet.from.0, packet.tag as u8 as char
+            Message::Announce => {
+                let [boot_count, reset_cause] = packet.data[..] else {
+                    debug!("Announce: Invalid data length");
+                    return false;
+                };
+                let Some(panel) = self.panel_entry(packet.from) else {
+                    return false;
+                };
+                info!("Panel {} announced", packet.from.0);
+                panel.boot_count = boot_count;
+                panel.reset_cause = reset_cause.into();
+                notify(
+                    &mut self.notifications,
+                    format_args!("!announce {:02x}", packet.from.0),
                 );
+                if let Some(slot) = self
+                    .mapping
+                    .iter()
+                    .position(|&id| id == packet.from.value())
+                {
+                    self.remap_slots |= 1 << slot;
+                    // It came back dark, and a delta might leave it out
+                    self.full_frame_at = None;
+                }
+            }
+            Message::StatusReply => {
+                let Some(status) = PanelStatus::from_bytes(&packet.data) else {
+                    debug!("StatusReply: Invalid data length");
+                    return false;
+                };
+                if self.panel_entry(packet.from).is_none() {
+                    return false;
+                }
+                self.queried_status = Some(status);
+            }
+            Message::SlotReply => {
+                let [slot] = packet.data[..] else {
+                    debug!("SlotReply: Invalid data length");
+                    return false;
+                };
+                if self.panel_entry(packet.from).is_none() {
+                    return false;
+                }
+                // The radio sometimes hears the same reply twice
+                if !self.queried_slots.iter().any(|&(id, _)| id == packet.from) {
+                    let _ = self.queried_slots.push((packet.from, slot));
+                }
+            }
+            // Expected, but with nothing in it to keep, like SetBaudReply
+            _ => return self.panel_entry(packet.from).is_some(),
+        }
+        true
+    }
+
+    /// find_panel_index(), for updating the panel a reply came from.
+    fn panel_entry(&mut self, id: Address) -> Option<&mut PanelInfo> {
+        match self.find_panel_index(id) {
+            Some(index) => Some(&mut self.panels[index]),
+            None => {
+                debug!("Too many panels, ignoring reply from {:x}", id.0);
+                None
             }
         }
     }
 
-    fn find_panel_index(&mut self, id: Address) -> usize {
+    /// Finds or adds the panel. Returns None if it's new and there's no room.
+    fn find_panel_index(&mut self, id: Address) -> Option<usize> {
         if let Some(index) = self
             .panels
             .iter()
             .enumerate()
             .find(|(_, panel)| panel.id == id)
         {
-            return index.0;
+            return Some(index.0);
         }
 
         let panel = PanelInfo {
@@ -481,44 +4075,302 @@ impl<'a> CmdProcessor<'a> {
             boot_count: 0,
             rssi_master: 0,
             rssi_panel: 0,
+            reset_cause: ResetCause::Unknown,
+            fw: None,
             pirs: 0,
             slot: 0,
+            zones: 1,
+            faults: None,
+            rtt: Rtt::new(),
+            tx_power: None,
+            last_panic: None,
+            seen_rounds: 0,
         };
-        self.panels.push(panel).unwrap();
-        self.panels.len() - 1
+        self.panels.push(panel).ok()?;
+        Some(self.panels.len() - 1)
     }
 
     // Incoming messages (panel mode)
 
-    async fn handle_message(&mut self, packet: Packet) {
+    /// Works out the reply to a message, and queues it to be sent
+    /// PANEL_REPLY_DELAY after the message arrived. Anything slow, like
+    /// changing the LEDs, waits until it's out, so the reply timing doesn't
+    /// depend on what the message was. A message that arrives before an
+    /// earlier reply is out doesn't hold it up, see send_replies().
+    ///
+    async fn handle_message(&mut self, mut packet: Packet) {
         let arrival_time = Instant::now();
 
-        debug!("Received: {:?}", packet);
+        packet_debug!("Received: {:?}", packet);
+
+        if packet.to != BROADCAST_ADDRESS && packet.to != self.address {
+            debug!("Not for me");
+            return;
+        }
+
+        if packet.tag == Message::Reliable {
+            let Some((seq, inner)) = packet.from_reliable() else {
+                debug!("Reliable: Invalid data");
+                return;
+            };
+            let mut ack = Packet::new(self.address, packet.from, Message::Ack);
+            ack.push_data(&[seq]);
+            self.queue_reply(ack, arrival_time, PANEL_REPLY_DELAY);
+
+            // Our Ack got lost and the master sent it again
+            let last = self.last_reliable.replace((seq, arrival_time));
+            if last
+                .is_some_and(|(s, at)| s == seq && arrival_time.duration_since(at) <= RESEND_WINDOW)
+            {
+                debug!("Reliable: Already handled {}", seq);
+                return;
+            }
+            packet = inner;
+        }
+
+        // Handled like the Set Color inside, but nothing goes back. Reading
+        // the PIRs doesn't clear them, so the next frame that's answered
+        // still has them.
+        let quiet = packet.tag == Message::SetColorNoReply;
+        if quiet {
+            let inner = packet.from_no_reply().filter(|inner| {
+                matches!(
+                    inner.tag,
+                    Message::SetColor
+                        | Message::SetColorRgbw
+                        | Message::SetColorZones
+                        | Message::SetColorDelta
+                        | Message::SetColorAt
+                )
+            });
+            let Some(inner) = inner else {
+                debug!("SetColorNoReply: Not a Set Color");
+                return;
+            };
+            packet = inner;
+        }
 
         let mut reply = Packet::new(self.address, packet.from, Message::Test);
-        let reply_delay = Duration::from_millis(2);
 
         match packet.tag {
-            Message::MapPanels => {
+            Message::MapPanels | Message::MapPanelsPending => {
                 self.handle_map_panels(&packet, &mut reply);
             }
+            Message::CommitMapping => {
+                // Sent twice, and the second finds nothing to do
+                if let Some((slot, _)) = self.pending_mapping.take() {
+                    debug!("CommitMapping: Now in slot {}", slot);
+                    self.my_slot = slot;
+                }
+                return;
+            }
+            Message::QuerySlot => {
+                // Only says, so the master can check between frames
+                reply.tag = Message::SlotReply;
+                reply.push_data(&[self.my_slot.unwrap_or(PanelStatus::NO_SLOT)]);
+            }
+            Message::PollPirs => {
+                // Same as the reply to a Set Color, and only from mapped
+                // panels, like that
+                if self.my_slot.is_some() {
+                    reply.tag = Message::SetColorReply;
+                    reply.push_data(&[self.pirs.read()]);
+                }
+            }
             Message::Ping => {
                 reply.tag = Message::PingReply;
                 reply.push_data(&[get_boot_count()]);
-                reply.push_data(&[0u8]);
+                reply.push_data(&[self.comm.last_rssi() as u8]);
+                reply.push_data(&[get_reset_cause().into()]);
+                reply.push_data(&version::FIRMWARE_ID.to_bytes());
+                reply.push_data(&[self.led_strip.zones()]);
+                reply.push_data(&[self_test::faults()]);
+                reply.push_data(&[self.comm.tx_power().into()]);
+                reply.push_data(&PanicLocation::to_bytes(last_panic::get()));
             }
-            Message::SetColor => {
+            Message::SetColor
+            | Message::SetColorRgbw
+            | Message::SetColorZones
+            | Message::SetColorDelta => {
                 self.handle_set_color(&packet, &mut reply);
             }
+            Message::SetColorAt => {
+                let Some((at, inner)) = packet.from_scheduled() else {
+                    debug!("SetColorAt: Invalid data");
+                    return;
+                };
+                if !matches!(
+                    inner.tag,
+                    Message::SetColor | Message::SetColorRgbw | Message::SetColorZones
+                ) {
+                    debug!("SetColorAt: Not a Set Color");
+                    return;
+                }
+                self.handle_set_color(&inner, &mut reply);
+                // Without a TimeSync yet, it's shown straight away, like a
+                // Set Color
+                let local_ms = Instant::now().as_millis() as u32;
+                if let Some(wait) = self.clock.ms_until(at, local_ms) {
+                    if let Some(colors) = self.pending_color.take() {
+                        let at = Instant::now() + Duration::from_millis(wait as u64);
+                        self.scheduled_color = Some((at, colors));
+                    }
+                }
+            }
+            Message::TimeSync => {
+                let [a, b, c, d] = packet.data[..] else {
+                    debug!("TimeSync: Invalid data length");
+                    return;
+                };
+                let master_ms = u32::from_le_bytes([a, b, c, d]);
+                self.clock
+                    .update(master_ms, arrival_time.as_millis() as u32);
+                return;
+            }
+            Message::FlashId => {
+                debug!("Flash ID");
+                self.start_id_flash();
+                return;
+            }
             Message::SetStatus => {
                 debug!("Set status");
                 if packet.data.len() == 1 {
-                    StatusLEDs::set_all(packet.data[0]);
+                    StatusLEDs::show_for(packet.data[0], STATUS_HOLD_TIME);
+                }
+            }
+            Message::PirConfig => {
+                debug!("PIR config");
+                match PirConfig::from_bytes(&packet.data) {
+                    Some(config) => self.pirs.set_config(config),
+                    None => debug!("PirConfig: Invalid data length"),
+                }
+                return;
+            }
+            Message::SetPirProfile => {
+                match packet.data[..] {
+                    [profile] => match PirProfile::try_from(profile) {
+                        Ok(profile) => self.pirs.set_profile(profile),
+                        Err(_) => debug!("SetPirProfile: Unknown profile"),
+                    },
+                    _ => debug!("SetPirProfile: Invalid data length"),
+                }
+                return;
+            }
+            Message::SetDerating => {
+                match packet.data[..] {
+                    [threshold] if threshold < 100 => {
+                        self.led_strip
+                            .set_derating((threshold != 0).then_some(threshold));
+                    }
+                    _ => debug!("SetDerating: Invalid data"),
+                }
+                return;
+            }
+            Message::SetColorOrder => {
+                match packet.data[..] {
+                    [order] => match ColorOrder::try_from(order) {
+                        Ok(order) => {
+                            let _ = self.set_color_order(order).await;
+                        }
+                        Err(_) => debug!("SetColorOrder: Unknown order"),
+                    },
+                    _ => debug!("SetColorOrder: Invalid data length"),
                 }
+                return;
+            }
+            Message::SetTxPower => {
+                match packet.data[..] {
+                    [power] => match TxPower::try_from(power) {
+                        Ok(power) => {
+                            let _ = self.set_tx_power(power).await;
+                        }
+                        Err(_) => debug!("SetTxPower: Unknown power"),
+                    },
+                    _ => debug!("SetTxPower: Invalid data length"),
+                }
+                return;
+            }
+            Message::SetBaud => {
+                let baud = match packet.data[..] {
+                    [baud, confirm] => BusBaud::try_from(baud).ok().map(|b| (b, confirm != 0)),
+                    _ => None,
+                };
+                match baud {
+                    Some((baud, false)) => {
+                        // Switch after the reply goes out at the old rate
+                        reply.tag = Message::SetBaudReply;
+                        self.pending_baud = Some(baud);
+                    }
+                    Some((baud, true)) => {
+                        if baud == self.comm.bus_baud() {
+                            if let Err(e) =
+                                flash::write_settings(|| flash::set_bus_baud(baud)).await
+                            {
+                                warn!("Couldn't save bus baud: {:?}", e);
+                            }
+                        }
+                        return;
+                    }
+                    None => {
+                        debug!("SetBaud: Invalid data");
+                        return;
+                    }
+                }
+            }
+            Message::SetChannel => {
+                let channel = match packet.data[..] {
+                    [channel, confirm] if channel <= PanelRadio::MAX_CHANNEL => {
+                        Some((channel, confirm != 0))
+                    }
+                    _ => None,
+                };
+                match channel {
+                    Some((channel, false)) => {
+                        // Switch after the reply goes out on the old channel
+                        reply.tag = Message::SetChannelReply;
+                        self.pending_channel = Some(channel);
+                    }
+                    Some((channel, true)) => {
+                        if channel == self.comm.channel() {
+                            if let Err(e) =
+                                flash::write_settings(|| flash::set_channel(channel)).await
+                            {
+                                warn!("Couldn't save channel: {:?}", e);
+                            }
+                        }
+                        return;
+                    }
+                    None => {
+                        debug!("SetChannel: Invalid data");
+                        return;
+                    }
+                }
+            }
+            Message::StatusRequest => {
+                reply.tag = Message::StatusReply;
+                let status = PanelStatus {
+                    boot_count: get_boot_count(),
+                    slot: self.my_slot,
+                    color: self.color,
+                    pirs: self.pirs.read(),
+                    uptime_secs: Instant::now().as_secs() as u32,
+                    pir_profile: Some(self.pirs.profile()),
+                    hours: Some(self.hours.hours()),
+                    derating: Some(self.led_strip.derating().output()),
+                    usb: Some(usb_port::is_connected()),
+                    last_panic: last_panic::get(),
+                };
+                reply.push_data(&status.to_bytes());
             }
             Message::Reset => {
-                debug!("Reset");
-                cortex_m::peripheral::SCB::sys_reset();
+                let armed = self.reset_armed_at.take();
+                if armed.is_some_and(|at| arrival_time.duration_since(at) <= RESET_WINDOW) {
+                    debug!("Reset");
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                debug!("Reset armed");
+                self.reset_armed_at = Some(arrival_time);
+                return;
             }
             Message::Test => {
                 debug!("Test message");
@@ -534,16 +4386,84 @@ impl<'a> CmdProcessor<'a> {
             }
         }
 
+        // Nothing to say, like a Set Color with no color for us
+        if reply.tag == Message::Test && packet.tag != Message::Test {
+            return;
+        }
+        // The colors still wait for any replies that are due out first
+        if quiet {
+            self.send_replies().await;
+            return;
+        }
+
         trace!(
             "Arrival {:?}us, reply {:?}us",
             arrival_time.as_micros(),
             Instant::now().as_micros()
         );
 
-        Timer::at(arrival_time + reply_delay).await;
-        self.comm.send_packet(&reply).await;
+        let delay = match reply.tag {
+            Message::NotMapped => PANEL_REPLY_DELAY + not_mapped_delay(self.address),
+            _ => PANEL_REPLY_DELAY,
+        };
+        self.queue_reply(reply, arrival_time, delay);
+        // Sends it now if it's already due, which it is after a slow message
+        self.send_replies().await;
+    }
+
+    /// Queues a reply to be sent `delay` after its message arrived. If the
+    /// queue is full, the oldest reply makes room, since it's the likeliest
+    /// to be stale.
+    fn queue_reply(&mut self, packet: Packet, arrival_time: Instant, delay: Duration) {
+        if self.replies.is_full() {
+            debug!("Reply queue full, dropping the oldest");
+            self.replies.pop_front();
+        }
+        let _ = self.replies.push_back(QueuedReply {
+            packet,
+            arrival_time,
+            send_at: arrival_time + delay,
+        });
+    }
+
+    /// Sends the queued replies that are due, and drops the ones that are
+    /// STALE_REPLY_AFTER late. Once the queue is empty, does what the
+    /// messages asked for that had to wait for the replies to be out.
+    async fn send_replies(&mut self) {
+        while let Some(reply) = self.replies.front() {
+            if reply.send_at > Instant::now() {
+                return;
+            }
+            let Some(reply) = self.replies.pop_front() else {
+                break;
+            };
+            if reply.arrival_time.elapsed() > STALE_REPLY_AFTER {
+                debug!("Dropping stale {:a} reply", reply.packet.tag as u8);
+                continue;
+            }
+            self.comm.send_packet(&reply.packet).await;
+        }
+
+        // Effects that can wait until the reply is out. Colors to show now
+        // replace any still waiting for their time.
+        if let Some((zone_1, zone_2)) = self.pending_color.take() {
+            self.scheduled_color = None;
+            self.led_strip.set_zone_colors(zone_1, zone_2);
+        }
+
+        if let Some(baud) = self.pending_baud.take() {
+            debug!("Switching bus to {} baud", baud.rate());
+            self.comm.set_bus_baud(baud);
+        }
+
+        if let Some(channel) = self.pending_channel.take() {
+            debug!("Switching radio to channel {}", channel);
+            self.comm.set_channel(channel);
+        }
     }
 
+    /// Takes our slot from MapPanels, or from MapPanelsPending to hold until
+    /// CommitMapping. A MapPanels throws away any mapping being held.
     fn handle_map_panels(&mut self, packet: &Packet, reply: &mut Packet) {
         let num_slots = packet.data.len();
         if num_slots > MAX_PANEL_SLOTS {
@@ -551,49 +4471,118 @@ impl<'a> CmdProcessor<'a> {
             return;
         }
 
-        if let Some((slot, _)) = packet
+        let slot = packet
             .data
             .iter()
-            .enumerate()
-            .find(|&(_, &id)| id == self.address.value())
-        {
+            .position(|&id| id == self.address.value())
+            .map(|slot| slot as u8);
+        if packet.tag == Message::MapPanelsPending {
+            debug!("MapPanelsPending: Holding slot {}", slot);
+            self.pending_mapping = Some((slot, num_slots));
+        } else {
             debug!("MapPanels: Mapping to slot {}", slot);
-            self.my_slot = Some(slot as u8);
-            reply.push_data(&[slot as u8]);
+            self.pending_mapping = None;
+            self.my_slot = slot;
+        }
+        if let Some(slot) = slot {
+            reply.push_data(&[slot, self.led_strip.zones()]);
             reply.tag = Message::MapPanelsReply;
-        } else {
-            debug!("MapPanels: Didn't find my ID");
-            self.my_slot = None;
         }
     }
 
+    /// Sets our color from SetColor, SetColorRgbw, SetColorZones, or
+    /// SetColorDelta. A broadcast SetColor has a color for each slot, see
+    /// slot_colors(), and a delta only for the slots that changed. One
+    /// sent just to us with a single color, or two for SetColor, is for us
+    /// whatever our slot, or even if we don't have one.
+    ///
+    /// With two zones, the second zone shows the same color as the first
+    /// unless it's given one of its own.
+    ///
     fn handle_set_color(&mut self, packet: &Packet, reply: &mut Packet) {
-        if let Some(my_slot) = self.my_slot {
-            if (my_slot + 1) as usize * 3 > packet.data.len() {
-                debug!("SetColor: Not enough data");
+        let unicast = packet.to == self.address
+            && matches!(
+                (packet.tag, packet.data.len()),
+                (Message::SetColor, 3 | 6) | (Message::SetColorRgbw, 4)
+            );
+        // The CommitMapping went missing, but a frame laid out for the
+        // mapping we're holding means the others have switched
+        if let Some((slot, num_slots)) = self.pending_mapping {
+            if !unicast && slot_count(packet) == Some(num_slots) {
+                debug!("SetColor: Taking the held mapping, slot {}", slot);
+                self.pending_mapping = None;
+                self.my_slot = slot;
+            }
+        }
+        let color = if unicast {
+            &packet.data[..]
+        } else if let Some(my_slot) = self.my_slot {
+            let Some(color) = slot_colors(packet, my_slot as usize) else {
+                // A delta leaves out the slots that didn't change, and only
+                // the ones it has answer
+                if packet.tag != Message::SetColorDelta {
+                    debug!("SetColor: Not enough data");
+                }
                 return;
+            };
+            color
+        } else if packet.tag == Message::SetColorDelta {
+            return;
+        } else {
+            debug!("SetColor: Not mapped");
+            let now = Instant::now();
+            let quiet = self
+                .not_mapped_at
+                .is_none_or(|at| now.duration_since(at) >= NOT_MAPPED_INTERVAL);
+            if packet.to == BROADCAST_ADDRESS && quiet {
+                self.not_mapped_at = Some(now);
+                reply.tag = Message::NotMapped;
+                reply.push_data(&[self.address.value()]);
             }
+            return;
+        };
 
-            let r = packet.data[my_slot as usize * 3];
-            let g = packet.data[my_slot as usize * 3 + 1];
-            let b = packet.data[my_slot as usize * 3 + 2];
+        let (r, g, b) = (color[0], color[1], color[2]);
+        let w = if color.len() == 4 { color[3] } else { 0 };
+        let zone_2 = match *color {
+            [_, _, _, r2, g2, b2] => [r2, g2, b2],
+            _ => [r, g, b],
+        };
 
-            self.led_strip.set_colors(r, g, b);
+        // Shown after the reply goes out, see handle_message. This also ends
+        // flashing our ID or the LED test, since the host wants its colors
+        // back.
+        self.pending_color = Some(([r, g, b, w], zone_2));
+        self.id_flash = None;
+        self.led_test = None;
+        self.color = [r, g, b];
 
-            debug!("SetColor: RGB {:02x},{:02x},{:02x}", r, g, b);
+        packet_debug!("SetColor: RGBW {:02x},{:02x},{:02x},{:02x}", r, g, b, w);
 
-            let pirs = (self.pirs.pir_1.is_high() as u8) | ((self.pirs.pir_2.is_high() as u8) << 1);
+        let pirs = self.pirs.read();
 
-            reply.push_data(&[pirs]);
-            reply.tag = Message::SetColorReply;
-        } else {
-            debug!("SetColor: Not mapped");
-        }
+        reply.push_data(&[pirs]);
+        reply.tag = Message::SetColorReply;
     }
 }
 
-/// Parse two hex digits into a byte. Returns None if the input is not a valid
-/// hex byte.
-fn parse_hex_byte(input: &[u8]) -> Option<u8> {
-    u8::from_str_radix(core::str::from_utf8(input).ok()?, 16).ok()
+/// How much later than other replies a NotMapped from `address` goes out. IDs
+/// a slot-count apart share a spot, which is fine, since it's rare to have
+/// that many unmapped at once.
+pub fn not_mapped_delay(address: Address) -> Duration {
+    NOT_MAPPED_STEP * (address.value() as u32 % MAX_PANEL_SLOTS as u32)
+}
+
+/// A derating output as a percent of full, rounded.
+fn output_percent(output: u8) -> u32 {
+    (output as u32 * 100 + FULL_OUTPUT as u32 / 2) / FULL_OUTPUT as u32
+}
+
+/// Queues a notification for the run loop to send to both ports. If the queue
+/// is full, the notification is dropped.
+pub fn notify(notifications: &mut Notifications, args: core::fmt::Arguments) {
+    let mut line = Notification::new();
+    if line.write_fmt(args).is_err() || notifications.enqueue(line).is_err() {
+        debug!("Notification dropped");
+    }
 }
@@ -1,147 +1,300 @@
 use core::convert::Infallible;
 
+#[cfg(feature = "panel-bus")]
+use crate::board::{PanelBusPeripherals, PanelBusUsart};
+#[cfg(feature = "panel-bus")]
+use crate::bus_mirror::{self, BusMirror};
+#[cfg(feature = "panel-bus")]
+use crate::cmd_processor::{MAX_BROADCAST_REPLY_LEN, MAX_PANEL_SLOTS};
+#[cfg(feature = "panel-bus")]
+use crate::self_test;
 use crate::{
-    board::{PanelBusPeripherals, PanelBusUsart, RadioPeripherals},
+    board::RadioPeripherals,
     cmd_processor::Message,
+    logging::{Storm, packet_debug},
+    sim::SimPanels,
 };
 use alloc::boxed::Box;
-use defmt::{debug, error, info, Format};
+#[cfg(feature = "panel-bus")]
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::{Format, debug, error, info, warn};
+use embassy_futures::select::{Either, select};
+#[cfg(feature = "panel-bus")]
+use embassy_stm32::{
+    bind_interrupts, interrupt,
+    usart::{self, BufferedUart, HalfDuplexConfig, HalfDuplexReadback},
+};
 use embassy_stm32::{
-    bind_interrupts,
     exti::ExtiInput,
     gpio::{Output, Pull},
     mode::Blocking,
     spi::{self, Spi},
-    usart::{self, BufferedUart, HalfDuplexConfig, HalfDuplexReadback},
 };
-use embassy_time::Timer;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_time::{Duration, Instant, Timer};
+#[cfg(feature = "panel-bus")]
+use embassy_time::{TimeoutError, with_timeout};
 use embedded_hal_bus::spi::{DeviceError, ExclusiveDevice, NoDelay};
+#[cfg(feature = "panel-bus")]
 use embedded_io_async::{Read, Write};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use rfm69::{Rfm69, registers};
+#[cfg(feature = "panel-bus")]
+use static_cell::ConstStaticCell;
 
+#[cfg(feature = "panel-bus")]
 bind_interrupts!(struct Irqs {
-    USART2 => usart::BufferedInterruptHandler<PanelBusUsart>;
+    USART2 => OverrunCounter, usart::BufferedInterruptHandler<PanelBusUsart>;
 });
 
-pub const MAX_PAYLOAD_SIZE: usize = 61;
+#[cfg(feature = "panel-bus")]
+use aunisoma_protocol::SerialParser;
+pub use aunisoma_protocol::{
+    Address, BROADCAST_ADDRESS, MAX_PAYLOAD_SIZE, MAX_RADIO_FRAME_LEN, MAX_SERIAL_FRAME_LEN,
+    Packet, WireError,
+};
+use aunisoma_protocol::{Arbiter, LoopGuard, Role};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct Address(pub u8);
+#[derive(Debug, Format, PartialEq, Eq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum CommMode {
+    Radio = 1,
+    Serial = 2,
+}
 
-impl Address {
-    pub fn value(&self) -> u8 {
-        self.0
-    }
+/// Panel bus baud rate setting, as stored in flash. Erased flash reads as
+/// Default, which is DEFAULT_BUS_BAUD_RATE.
+#[derive(Debug, Format, PartialEq, Eq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum BusBaud {
+    Baud115200 = 0,
+    Baud256000 = 1,
+    Baud512000 = 2,
+    Default = 3,
 }
 
-pub const BROADCAST_ADDRESS: Address = Address(0xFF);
+pub const DEFAULT_BUS_BAUD_RATE: u32 = 256_000;
 
-type PacketData = heapless::Vec<u8, { MAX_PAYLOAD_SIZE }>;
+impl BusBaud {
+    pub fn rate(&self) -> u32 {
+        match self {
+            BusBaud::Baud115200 => 115_200,
+            BusBaud::Baud256000 => 256_000,
+            BusBaud::Baud512000 => 512_000,
+            BusBaud::Default => DEFAULT_BUS_BAUD_RATE,
+        }
+    }
+}
 
-/// Internal representation of a packet
-///
-/// The wire format of a packet is a little goofy because it's
-/// backwards-compatible with the C++ version:
-///
-/// [0x55, 0xaa, to, data_len+2, from, tag, data*, crc]
-///
-/// For this struct, only to, from, tag, and data are stored, the rest are calculated
-/// when the packet is serialized. So self.data is:
-///
-/// [to, data_len+2, from, tag, data*]
-///
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Packet {
-    pub from: Address,
-    pub to: Address,
-    pub tag: Message,
-    pub data: PacketData,
+/// Radio transmit power setting, as stored in flash. Erased flash reads as
+/// Max, which is what the RFM69 comes out of reset with. Only PA0 is used, as
+/// the RFM69W's PA_BOOST pin isn't connected, so Max is +13 dBm.
+#[derive(
+    Debug, Format, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, IntoPrimitive, TryFromPrimitive,
+)]
+#[repr(u8)]
+pub enum TxPower {
+    Max = 0,
+    Medium = 1,
+    Low = 2,
+    Min = 3,
 }
 
-impl Packet {
-    pub fn new(from: Address, to: Address, tag: Message) -> Self {
-        Self {
-            from,
-            to,
-            tag,
-            data: PacketData::new(),
+impl TxPower {
+    pub fn dbm(&self) -> i8 {
+        match self {
+            TxPower::Max => 13,
+            TxPower::Medium => 5,
+            TxPower::Low => -2,
+            TxPower::Min => -11,
         }
     }
 
-    pub fn push_data(&mut self, data: &[u8]) {
-        self.data.extend_from_slice(data).unwrap();
+    /// The PaLevel register: PA0 on, and OutputPower for Pout = -18 + n dBm
+    fn pa_level(&self) -> u8 {
+        0x80 | (self.dbm() + 18) as u8
     }
 
-    /// Write the packet to a buffer in wire format.
-    ///
-    /// The buffer must be at least MAX_PAYLOAD_SIZE + 8 bytes long.
-    ///
-    pub fn serial_wire_format<'a>(&self, buf: &'a mut [u8]) -> &'a [u8] {
-        buf[0..6].copy_from_slice(&[
-            0x55,
-            0xaa,
-            self.to.value(),
-            self.data.len() as u8 + 2,
-            self.from.value(),
-            self.tag.into(),
-        ]);
-        buf[6..6 + self.data.len()].copy_from_slice(&self.data);
-        // TODO: calculate crc
-        buf[6 + self.data.len()] = b'C';
-        &buf[..6 + self.data.len() + 1]
-    }
-
-    pub fn radio_wire_format<'a>(&self, buf: &'a mut [u8]) -> &'a [u8] {
-        buf[0..4].copy_from_slice(&[
-            self.data.len() as u8 + 3,
-            self.to.value(),
-            self.from.value(),
-            self.tag.into(),
-        ]);
-        buf[4..4 + self.data.len()].copy_from_slice(&self.data);
-        &buf[..4 + self.data.len()]
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxPower::Max => "Max",
+            TxPower::Medium => "Medium",
+            TxPower::Low => "Low",
+            TxPower::Min => "Min",
+        }
     }
-}
 
-impl defmt::Format for Packet {
-    fn format(&self, fmt: defmt::Formatter<'_>) {
-        let data = self.data.as_slice();
-        defmt::write!(
-            fmt,
-            "({:x} -> {:x}) {:a} {:02x}",
-            self.from.value(),
-            self.to.value(),
-            self.tag as u8 as char,
-            data
-        );
+    /// One step more powerful, or None if it's already Max.
+    pub fn stronger(&self) -> Option<TxPower> {
+        TxPower::try_from((*self as u8).checked_sub(1)?).ok()
     }
-}
 
-#[derive(Debug, Format, PartialEq, Eq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
-#[repr(u8)]
-pub enum CommMode {
-    Radio = 1,
-    Serial = 2,
+    /// One step less powerful, or None if it's already Min.
+    pub fn weaker(&self) -> Option<TxPower> {
+        TxPower::try_from(*self as u8 + 1).ok()
+    }
 }
 
 pub struct PanelComm {
     mode: CommMode,
     radio: PanelRadio,
     serial: PanelSerial,
+    recv_errors: Storm,
+    /// When set, packets go to simulated panels instead of the radio or bus
+    sim: Option<Box<SimPanels>>,
+    /// Sequence number for the next Reliable message
+    next_seq: u8,
+    /// When set, packets are passed between the radio and the bus
+    bridge: Option<Bridge>,
+    /// When set, other masters share the bus, see start_arbitration()
+    arbiter: Option<Arbiter>,
+    /// Nothing is sent, see inhibit_tx()
+    tx_inhibited: bool,
+    /// Packets inhibit_tx() kept from going out
+    suppressed_sends: u32,
+}
+
+/// What a bridge keeps track of, see PanelComm::start_bridge().
+struct Bridge {
+    address: Address,
+    guard: LoopGuard,
+    /// Where the last packet for us came from, and where replies go
+    reply_via: CommMode,
 }
 
+/// A panel didn't Ack a message, however many times it was sent.
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct NoAck;
+
 impl PanelComm {
     pub fn new(mode: CommMode, radio: PanelRadio, serial: PanelSerial) -> Self {
         Self {
             mode,
             radio,
             serial,
+            recv_errors: Storm::new(),
+            sim: None,
+            next_seq: 0,
+            bridge: None,
+            arbiter: None,
+            tx_inhibited: false,
+            suppressed_sends: 0,
+        }
+    }
+
+    /// Never sends anything from now on, for a spy, which would disturb the
+    /// traffic it's watching. send_packet() counts what it's asked to send
+    /// and drops it, see CommStats. The radio and bus check too, for code
+    /// that gets to them some other way, and the bus driver stays off.
+    ///
+    pub fn inhibit_tx(&mut self) {
+        self.tx_inhibited = true;
+        self.radio.tx_inhibited = true;
+        self.serial.inhibit_tx();
+    }
+
+    /// Shares the panel bus with other masters from now on, as `address`
+    /// with `priority`, see Arbiter. Only packets sent while this master is
+    /// the active one go out, along with its beacons, and beacons heard are
+    /// never returned by recv_packet(). Starts in standby.
+    pub fn start_arbitration(&mut self, address: Address, priority: u8) {
+        let now = Instant::now().as_micros();
+        self.arbiter = Some(Arbiter::new(address.value(), priority, now));
+    }
+
+    pub fn stop_arbitration(&mut self) {
+        self.arbiter = None;
+    }
+
+    /// The arbiter, if the bus is shared.
+    pub fn arbiter(&self) -> Option<&Arbiter> {
+        self.arbiter.as_ref()
+    }
+
+    /// Whether another master has the bus, so nothing we send goes out.
+    pub fn standing_by(&mut self) -> bool {
+        let now = Instant::now().as_micros();
+        self.arbiter
+            .as_mut()
+            .is_some_and(|arbiter| arbiter.poll(now) != Role::Active)
+    }
+
+    /// When poll_arbitration() next has something to do, if the bus is
+    /// shared.
+    pub fn next_arbitration_at(&self) -> Option<Instant> {
+        let at = self.arbiter.as_ref()?.next_event_at();
+        Some(Instant::from_micros(at))
+    }
+
+    /// Takes over the bus if the active master has gone quiet, and sends a
+    /// beacon if one is due. Beacons also go out with other packets, but
+    /// this has to be called when there aren't any.
+    pub async fn poll_arbitration(&mut self) {
+        let now = Instant::now().as_micros();
+        let Some(arbiter) = &mut self.arbiter else {
+            return;
+        };
+        if arbiter.beacon_due(now) {
+            let mut beacon = Packet::new(
+                Address(arbiter.id()),
+                BROADCAST_ADDRESS,
+                Message::MasterBeacon,
+            );
+            beacon.push_data(&[arbiter.priority()]);
+            self.serial.send_packet(&beacon).await;
         }
     }
 
+    /// Passes every packet heard on the radio to the bus, and the other way
+    /// around, from now on. The radio has to be working. Packets for
+    /// `address` aren't passed on, and like broadcasts, they're still
+    /// returned by recv_packet(). Replies go back the way the packet came,
+    /// and other broadcasts we send go both ways.
+    ///
+    /// Packets are passed on as soon as they're heard, so they're only held
+    /// up by how long they take to send again. While the bridge is replying
+    /// for itself, the radio isn't listening, but the bus is buffered, so the
+    /// master should be on the radio side.
+    ///
+    pub fn start_bridge(&mut self, address: Address) {
+        self.bridge = Some(Bridge {
+            address,
+            guard: LoopGuard::new(),
+            reply_via: self.mode,
+        });
+    }
+
     pub async fn send_packet(&mut self, packet: &Packet) {
-        debug!("Sending packet: {:?}", packet);
+        packet_debug!("Sending packet: {:?}", packet);
+        if self.tx_inhibited {
+            warn!("TX inhibited, {:a} not sent", packet.tag as u8);
+            self.suppressed_sends = self.suppressed_sends.wrapping_add(1);
+            return;
+        }
+        if let Some(sim) = &mut self.sim {
+            sim.send_packet(packet);
+            return;
+        }
+        if self.arbiter.is_some() {
+            if self.standing_by() {
+                packet_debug!("Standing by, not sent");
+                return;
+            }
+            self.poll_arbitration().await;
+        }
+        if let Some(bridge) = &self.bridge {
+            if packet.to == BROADCAST_ADDRESS {
+                self.radio.send_packet(packet).await;
+                self.serial.send_packet(packet).await;
+                return;
+            }
+            match bridge.reply_via {
+                CommMode::Radio => self.radio.send_packet(packet).await,
+                CommMode::Serial => self.serial.send_packet(packet).await,
+            }
+            return;
+        }
         match self.mode {
             CommMode::Radio => self.radio.send_packet(packet).await,
             CommMode::Serial => self.serial.send_packet(packet).await,
@@ -149,13 +302,203 @@ impl PanelComm {
     }
 
     pub async fn recv_packet(&mut self) -> Packet {
+        if let Some(sim) = &mut self.sim {
+            return sim.recv_packet().await;
+        }
+        if self.bridge.is_some() {
+            return self.recv_bridged().await;
+        }
+        loop {
+            let packet = match self.mode {
+                CommMode::Radio => recv_radio(&mut self.radio, &mut self.recv_errors).await,
+                CommMode::Serial => self.serial.recv_packet().await,
+            };
+            if packet.tag != Message::MasterBeacon {
+                return packet;
+            }
+            // Only for masters, and panels ignore them
+            if let (Some(arbiter), &[priority, ..]) = (&mut self.arbiter, &packet.data[..]) {
+                let now = Instant::now().as_micros();
+                arbiter.heard(packet.from.value(), priority, now);
+            }
+        }
+    }
+
+    /// recv_packet() for a bridge, which passes on what isn't only for us.
+    async fn recv_bridged(&mut self) -> Packet {
+        loop {
+            let (via, packet) = match select(
+                recv_radio(&mut self.radio, &mut self.recv_errors),
+                self.serial.recv_any_packet(),
+            )
+            .await
+            {
+                Either::First(packet) => (CommMode::Radio, packet),
+                Either::Second(packet) => (CommMode::Serial, packet),
+            };
+            let Some(bridge) = &mut self.bridge else {
+                return packet;
+            };
+            let address = bridge.address;
+
+            // Our own, back around through another bridge
+            if packet.from == address {
+                continue;
+            }
+            if packet.to != address {
+                if !bridge.guard.check(&packet, Instant::now().as_micros()) {
+                    packet_debug!("Not bridging, looped: {:?}", packet);
+                    continue;
+                }
+                match via {
+                    CommMode::Radio => self.serial.send_packet(&packet).await,
+                    CommMode::Serial => self.radio.send_packet(&packet).await,
+                }
+            }
+            if packet.to == address || packet.to == BROADCAST_ADDRESS {
+                bridge.reply_via = via;
+                return packet;
+            }
+        }
+    }
+
+    /// Sends a message to one panel as a Reliable message, and waits for the
+    /// panel to Ack it. If the Ack doesn't come within `timeout`, sends it
+    /// again, up to `retries` more times. The panel only acts on it once,
+    /// however many times it arrives.
+    ///
+    /// Broadcasts are sent once as they are, since every panel answering at
+    /// once would just collide. Other packets that arrive while waiting are
+    /// dropped.
+    ///
+    pub async fn send_unicast_reliable(
+        &mut self,
+        packet: &Packet,
+        retries: u8,
+        timeout: Duration,
+    ) -> Result<(), NoAck> {
+        if packet.to == BROADCAST_ADDRESS {
+            self.send_packet(packet).await;
+            return Ok(());
+        }
+
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        let Some(reliable) = packet.to_reliable(seq) else {
+            error!("Message too long to send reliably");
+            return Err(NoAck);
+        };
+
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                debug!("No Ack from {:x}, sending again", packet.to.value());
+            }
+            self.send_packet(&reliable).await;
+            let deadline = Instant::now() + timeout;
+            while let Either::First(reply) = select(self.recv_packet(), Timer::at(deadline)).await {
+                if reply.from == packet.to && reply.tag == Message::Ack && reply.data[..] == [seq] {
+                    return Ok(());
+                }
+                packet_debug!("Dropped while waiting for Ack: {:?}", reply);
+            }
+        }
+        Err(NoAck)
+    }
+
+    /// Signal strength of the last packet received, in dBm. Always 0 on the
+    /// serial bus.
+    pub fn last_rssi(&self) -> i8 {
+        if let Some(sim) = &self.sim {
+            return sim.last_rssi();
+        }
         match self.mode {
-            CommMode::Radio => self.radio.recv_packet().await,
-            CommMode::Serial => self.serial.recv_packet().await,
+            CommMode::Radio => self.radio.last_rssi,
+            CommMode::Serial => 0,
         }
     }
 
+    pub fn bus_baud(&self) -> BusBaud {
+        self.serial.baud
+    }
+
+    pub fn set_bus_baud(&mut self, baud: BusBaud) {
+        self.serial.set_baud(baud);
+    }
+
+    pub fn tx_power(&self) -> TxPower {
+        self.radio.tx_power
+    }
+
+    /// Changes the radio's transmit power right away. Not saved, see
+    /// flash::set_tx_power().
+    pub fn set_tx_power(&mut self, power: TxPower) {
+        self.radio.set_tx_power(power);
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.radio.channel
+    }
+
+    /// Switches the radio to another channel right away. Not saved, see
+    /// flash::set_channel().
+    pub fn set_channel(&mut self, channel: u8) {
+        self.radio.set_channel(channel);
+    }
+
+    /// Starts or stops copying the panel bus out the command serial port, see
+    /// BusMirror. Returns false if there's no panel bus to copy.
+    pub fn set_mirror(&mut self, on: bool) -> bool {
+        self.serial.set_mirror(on)
+    }
+
+    /// Frames the bus mirror has dropped, or None if it's off.
+    pub fn mirror_dropped(&self) -> Option<u32> {
+        self.serial.mirror_dropped()
+    }
+
+    pub fn stats(&self) -> CommStats {
+        CommStats {
+            radio_reinits: self.radio.reinits,
+            truncated_frames: self.serial.truncated_frames(),
+            bus_overruns: self.serial.overruns(),
+            suppressed_sends: self.suppressed_sends,
+            noise_floor: self.radio.noise_floor,
+        }
+    }
+
+    pub fn mode(&self) -> CommMode {
+        self.mode
+    }
+
+    /// Swaps the radio or bus for simulated panels, or back with None. The
+    /// panels are on the heap, so they only take up room while in use.
+    pub fn simulate(&mut self, sim: Option<SimPanels>) {
+        self.sim = sim.map(Box::new);
+    }
+
+    pub fn simulating(&self) -> bool {
+        self.sim.is_some()
+    }
+
+    /// The radio, for poking at its registers from the console.
+    #[cfg(feature = "radio-debug")]
+    pub fn radio(&mut self) -> &mut PanelRadio {
+        &mut self.radio
+    }
+
+    /// Runs the radio's self-test, see PanelRadio::self_test(). Goes to the
+    /// radio whatever the comm mode, and even while simulating.
+    pub async fn radio_self_test(&mut self, address: Address) -> RadioTest {
+        self.radio.self_test(address).await
+    }
+
     pub fn mode_name(&self) -> &'static str {
+        if self.sim.is_some() {
+            return "Sim";
+        }
+        if self.bridge.is_some() {
+            return "Bridge";
+        }
         match self.mode {
             CommMode::Radio => "Radio",
             CommMode::Serial => "Serial",
@@ -163,6 +506,81 @@ impl PanelComm {
     }
 }
 
+/// Waits for a packet on the radio, getting past errors.
+async fn recv_radio(radio: &mut PanelRadio, recv_errors: &mut Storm) -> Packet {
+    loop {
+        match radio.recv_packet().await {
+            Ok(packet) => return packet,
+            Err(RadioError::Rfm69) => {
+                // Probably a glitch on the SPI bus, but if the radio is
+                // wedged, this gets it going again.
+                error!("Radio recv error: SPI");
+                radio.check_alive().await;
+            }
+            Err(e) => {
+                if let Some(count) = recv_errors.hit() {
+                    error!("Radio recv error: {:?} ({} since last report)", e, count);
+                }
+            }
+        }
+    }
+}
+
+/// Counters for things going wrong in the comm layer.
+#[derive(Debug, Clone, Copy)]
+pub struct CommStats {
+    /// Times the radio stopped responding and had to be reinitialized
+    pub radio_reinits: u32,
+    /// Frames on the panel bus whose sender went quiet partway through
+    pub truncated_frames: u32,
+    /// Bytes lost on the panel bus because the UART wasn't read in time
+    pub bus_overruns: u32,
+    /// Packets not sent because TX is inhibited, see PanelComm::inhibit_tx()
+    pub suppressed_sends: u32,
+    /// From the last radio self-test, if there's been one
+    pub noise_floor: Option<NoiseFloor>,
+}
+
+/// What the radio hears on its channel with nobody sending, in dBm, see
+/// PanelRadio::self_test(). Useful on its own for a site survey.
+#[derive(Debug, Format, Clone, Copy)]
+pub struct NoiseFloor {
+    /// The average of the samples
+    pub average: i8,
+    /// The loudest sample, which catches bursts of interference
+    pub peak: i8,
+}
+
+/// What PanelRadio::self_test() found. A check that wasn't run, because
+/// one before it failed, or TX is inhibited, is None.
+#[derive(Debug, Format, Clone, Copy, Default)]
+pub struct RadioTest {
+    /// It answers over SPI, as a version we know
+    pub radio: bool,
+    /// It switches modes when asked
+    pub mode_ready: Option<bool>,
+    /// Its synthesizer locks on the channel's frequency
+    pub pll_lock: Option<bool>,
+    pub noise_floor: Option<NoiseFloor>,
+    /// A test packet went out
+    pub packet_sent: Option<bool>,
+    /// All of the packet went out, leaving the FIFO empty
+    pub fifo_empty: Option<bool>,
+}
+
+impl RadioTest {
+    /// Whether every check that was run passed, and the ones that always
+    /// can be were.
+    pub fn passed(&self) -> bool {
+        self.radio
+            && self.mode_ready == Some(true)
+            && self.pll_lock == Some(true)
+            && self.noise_floor.is_some()
+            && self.packet_sent != Some(false)
+            && self.fifo_empty != Some(false)
+    }
+}
+
 #[derive(Format)]
 pub enum RadioError {
     Rfm69,
@@ -182,13 +600,55 @@ pub struct PanelRadio {
     radio: Rfm69<ExclusiveDevice<Spi<'static, Blocking>, Output<'static>, NoDelay>>,
     reset: Output<'static>,
     dio_int: ExtiInput<'static>,
+    version: u8,
+    reinits: u32,
+    /// Signal strength of the last packet received, in dBm
+    last_rssi: i8,
+    /// Installation this radio belongs to, see flash::set_group()
+    group: u8,
+    tx_power: TxPower,
+    /// See frequency()
+    channel: u8,
+    /// See PanelComm::inhibit_tx()
+    tx_inhibited: bool,
+    /// From the last self_test()
+    noise_floor: Option<NoiseFloor>,
 }
 
 impl PanelRadio {
-    const FREQUENCY: u32 = 915_000_000;
+    /// Channels are this far apart, starting from BASE_FREQUENCY
+    const CHANNEL_SPACING: u32 = 500_000;
+    const BASE_FREQUENCY: u32 = 911_000_000;
+    pub const MAX_CHANNEL: u8 = 15;
+    /// 915.0 MHz, which is what boards used before there were channels
+    pub const DEFAULT_CHANNEL: u8 = 8;
     const BITRATE: u32 = 250_000;
 
-    pub fn new(radio_peripherals: RadioPeripherals) -> Self {
+    /// If nothing arrives for this long, make sure the radio is still alive.
+    pub const RX_IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Version register values of the RFM69 silicon we know about
+    const KNOWN_VERSIONS: [u8; 2] = [0x23, 0x24];
+
+    /// Switching modes takes well under this, see the datasheet's timing table
+    const MODE_READY_TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// A test packet takes under a millisecond at BITRATE
+    const PACKET_SENT_TIMEOUT: Duration = Duration::from_millis(20);
+
+    /// RSSI readings for the noise floor, a millisecond apart
+    const NOISE_SAMPLES: i32 = 32;
+
+    // RegRssiConfig bits, which the driver doesn't name
+    const RSSI_START: u8 = 1 << 0;
+    const RSSI_DONE: u8 = 1 << 1;
+
+    pub fn new(
+        radio_peripherals: RadioPeripherals,
+        group: u8,
+        tx_power: TxPower,
+        channel: u8,
+    ) -> Self {
         let spi_config = spi::Config::default();
         let spi_driver = Spi::new_blocking(
             radio_peripherals.rf_spi,
@@ -209,9 +669,24 @@ impl PanelRadio {
                 radio_peripherals.rf_exti,
                 Pull::None,
             ),
+            version: 0,
+            reinits: 0,
+            last_rssi: 0,
+            group,
+            tx_power,
+            channel,
+            tx_inhibited: false,
+            noise_floor: None,
         }
     }
 
+    /// The center frequency of a channel, in Hz. Channels 0 to MAX_CHANNEL
+    /// run from 911.0 to 918.5 MHz, to get away from interference at the
+    /// default.
+    pub fn frequency(channel: u8) -> u32 {
+        Self::BASE_FREQUENCY + channel as u32 * Self::CHANNEL_SPACING
+    }
+
     pub async fn init(&mut self) -> RadioResult<()> {
         // 7.2.2. Manual Reset Pin
         //
@@ -223,19 +698,34 @@ impl PanelRadio {
         self.reset.set_low();
         Timer::after_millis(5).await;
 
-        // See if the radio exists
+        // See if the radio exists. With nothing on the other end, MISO
+        // floats, so reads can come back as anything.
         let version = self.radio.read(registers::Registers::Version)?;
-        if version == 0 {
-            info!("Radio not found");
+        if !Self::KNOWN_VERSIONS.contains(&version) {
+            info!("Radio not found: unknown version {:x}", version);
             return Err(RadioError::NoRadio);
         }
+        for pattern in [0x55, 0xaa] {
+            self.radio
+                .write(registers::Registers::SyncValue1, pattern)?;
+            let read_back = self.radio.read(registers::Registers::SyncValue1)?;
+            if read_back != pattern {
+                info!(
+                    "Radio not found: wrote {:x}, read back {:x}",
+                    pattern, read_back
+                );
+                return Err(RadioError::NoRadio);
+            }
+        }
 
         debug!("Radio version: {:x}", version);
+        self.version = version;
 
         use rfm69::registers::Mode;
         use rfm69::registers::*;
 
         self.radio.mode(Mode::Standby)?;
+        self.wait_mode_ready().await?;
 
         // Start TX when first byte reaches FIFO
         self.radio.fifo_mode(FifoMode::NotEmpty)?;
@@ -243,16 +733,20 @@ impl PanelRadio {
         self.radio
             .continuous_dagc(ContinuousDagc::ImprovedMarginAfcLowBetaOn0)?;
 
-        self.radio
-            .dio_mapping(DioMapping {
-                pin: DioPin::Dio0,
-                dio_type: DioType::Dio01,
-                dio_mode: DioMode::Rx,
-            })
-            .unwrap();
+        self.radio.dio_mapping(DioMapping {
+            pin: DioPin::Dio0,
+            dio_type: DioType::Dio01,
+            dio_mode: DioMode::Rx,
+        })?;
 
         self.radio.rssi_threshold(220)?;
-        self.radio.sync(&[0x2d, 0xd4])?;
+        // Other groups get a third sync byte, so they only hear each other.
+        // Group 0 keeps the original two, to work with boards that don't
+        // know about groups.
+        match self.group {
+            0 => self.radio.sync(&[0x2d, 0xd4])?,
+            group => self.radio.sync(&[0x2d, 0xd4, group])?,
+        }
         self.radio.packet(PacketConfig {
             format: PacketFormat::Variable(66),
             dc: PacketDc::Whitening,
@@ -268,7 +762,7 @@ impl PanelRadio {
         })?;
         self.radio.preamble(4)?;
         self.radio.bit_rate(Self::BITRATE)?;
-        self.radio.frequency(Self::FREQUENCY)?;
+        self.radio.frequency(Self::frequency(self.channel))?;
         self.radio.fdev(50_000)?;
         // reg 0x19 RxBw = 0xe0 = 0b11100000
         // -> DccFreq = 7, RxBwMant = 00, RxBwExp = 000
@@ -280,10 +774,245 @@ impl PanelRadio {
             zin: LnaImpedance::Ohm50,
             gain_select: LnaGain::AgcLoop,
         })?;
+        self.radio
+            .write(Registers::PaLevel, self.tx_power.pa_level())?;
+        Ok(())
+    }
+
+    /// Sets the transmit power, which also sticks through a reinit.
+    pub fn set_tx_power(&mut self, power: TxPower) {
+        self.tx_power = power;
+        // Without a radio, it's only remembered
+        if self.version == 0 {
+            return;
+        }
+        if let Err(e) = self
+            .radio
+            .write(registers::Registers::PaLevel, power.pa_level())
+        {
+            error!("Setting TX power failed: {:?}", RadioError::from(e));
+        }
+    }
+
+    /// Switches to another channel, which also sticks through a reinit. The
+    /// radio is left in standby, and goes back to receiving at the next
+    /// recv_packet().
+    pub fn set_channel(&mut self, channel: u8) {
+        self.channel = channel;
+        // Without a radio, it's only remembered
+        if self.version == 0 {
+            return;
+        }
+        let result = self
+            .radio
+            .mode(rfm69::registers::Mode::Standby)
+            .and_then(|()| self.radio.frequency(Self::frequency(channel)));
+        if let Err(e) = result {
+            error!("Setting channel failed: {:?}", RadioError::from(e));
+        }
+    }
+
+    /// Reads registers 0x01 to 0x4f, which is all of them except the FIFO
+    /// and the test registers. Reading the FIFO would eat a packet.
+    #[cfg(feature = "radio-debug")]
+    pub fn read_registers(&mut self) -> RadioResult<[u8; 0x4f]> {
+        Ok(self.radio.read_all_regs()?)
+    }
+
+    /// The register at `addr`, if it's one that's safe to change for tuning.
+    /// The rest, like the FIFO, the DIO mapping, and the packet and sync
+    /// settings, are the driver's business.
+    #[cfg(feature = "radio-debug")]
+    pub fn tunable_register(addr: u8) -> Option<registers::Registers> {
+        use registers::Registers::*;
+        const TUNABLE: [registers::Registers; 16] = [
+            OpMode, DataModul, BitrateMsb, BitrateLsb, FdevMsb, FdevLsb, FrfMsb, FrfMid, FrfLsb,
+            PaLevel, PaRamp, Ocp, Lna, RxBw, AfcBw, RssiThresh,
+        ];
+        TUNABLE.into_iter().find(|&reg| reg as u8 == addr)
+    }
+
+    #[cfg(feature = "radio-debug")]
+    pub fn write_register(&mut self, reg: registers::Registers, value: u8) -> RadioResult<()> {
+        self.radio.write(reg, value)?;
+        Ok(())
+    }
+
+    /// Waits for the radio to finish switching modes. A radio that never
+    /// does isn't really there.
+    async fn wait_mode_ready(&mut self) -> RadioResult<()> {
+        use rfm69::registers::{IrqFlags1, Registers};
+
+        let ready = self
+            .wait_flag(
+                Registers::IrqFlags1,
+                IrqFlags1::ModeReady,
+                Self::MODE_READY_TIMEOUT,
+            )
+            .await?;
+        if !ready {
+            info!("Radio not found: never ready after mode switch");
+            return Err(RadioError::NoRadio);
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for `flag` to come up in `reg`, and returns
+    /// whether it did.
+    async fn wait_flag(
+        &mut self,
+        reg: registers::Registers,
+        flag: u8,
+        timeout: Duration,
+    ) -> RadioResult<bool> {
+        let deadline = Instant::now() + timeout;
+        while self.radio.read(reg)? & flag == 0 {
+            if Instant::now() > deadline {
+                return Ok(false);
+            }
+            Timer::after_millis(1).await;
+        }
+        Ok(true)
+    }
+
+    /// Checks each part of the radio path this board can check on its own,
+    /// for telling a dead radio from a bad antenna or a noisy site: that the
+    /// radio answers, switches modes, and locks on the channel, what the
+    /// channel's noise floor is, and that a test packet goes out.
+    ///
+    /// The packet is addressed to `address`, so no panel acts on it, with
+    /// the time in microseconds as its payload. Nothing hears it, so whether
+    /// it arrives is for E from another board to say. With TX inhibited, it
+    /// isn't sent.
+    ///
+    /// A radio that wasn't set up at boot, because the board is on the panel
+    /// bus, is set up first. The radio is left in standby, and goes back to
+    /// receiving at the next recv_packet().
+    ///
+    pub async fn self_test(&mut self, address: Address) -> RadioTest {
+        let mut test = RadioTest::default();
+        if let Err(e) = self.run_self_test(address, &mut test).await {
+            warn!("Radio self-test stopped: {:?}", e);
+        }
+        if self.version != 0 {
+            let _ = self.radio.mode(rfm69::registers::Mode::Standby);
+        }
+        if test.noise_floor.is_some() {
+            self.noise_floor = test.noise_floor;
+        }
+        test
+    }
+
+    async fn run_self_test(&mut self, address: Address, test: &mut RadioTest) -> RadioResult<()> {
+        use rfm69::registers::{IrqFlags1, IrqFlags2, Mode, Registers};
+
+        if self.version == 0 {
+            self.init().await?;
+        } else if self.radio.read(Registers::Version)? != self.version {
+            return Err(RadioError::NoRadio);
+        }
+        test.radio = true;
+
+        test.mode_ready = Some(false);
+        self.radio.mode(Mode::Standby)?;
+        self.wait_mode_ready().await?;
+        test.mode_ready = Some(true);
+
+        test.pll_lock = Some(false);
+        self.radio.mode(Mode::FrequencySynthesizer)?;
+        let locked = self
+            .wait_flag(
+                Registers::IrqFlags1,
+                IrqFlags1::PllLock,
+                Self::MODE_READY_TIMEOUT,
+            )
+            .await?;
+        test.pll_lock = Some(locked);
+        if !locked {
+            return Ok(());
+        }
+
+        test.noise_floor = self.sample_noise().await?;
+        if test.noise_floor.is_none() || self.tx_inhibited {
+            return Ok(());
+        }
+        self.radio.mode(Mode::Standby)?;
+        self.wait_mode_ready().await?;
+        // Writing FifoOverrun clears the FIFO, of a packet that arrived
+        // while sampling
+        self.radio
+            .write(Registers::IrqFlags2, IrqFlags2::FifoOverrun)?;
+        let mut packet = Packet::new(address, address, Message::Test);
+        packet.push_data(&Instant::now().as_micros().to_le_bytes());
+        let mut buf = [0u8; MAX_PAYLOAD_SIZE + 8];
+        let wire_data = packet.radio_wire_format(&mut buf);
+        self.radio.write_many(Registers::Fifo, wire_data)?;
+
+        test.packet_sent = Some(false);
+        self.radio.mode(Mode::Transmitter)?;
+        let sent = self
+            .wait_flag(
+                Registers::IrqFlags2,
+                IrqFlags2::PacketSent,
+                Self::PACKET_SENT_TIMEOUT,
+            )
+            .await?;
+        test.packet_sent = Some(sent);
+        let flags = self.radio.read(Registers::IrqFlags2)?;
+        test.fifo_empty = Some(flags & IrqFlags2::FifoNotEmpty == 0);
         Ok(())
     }
 
+    /// Samples the RSSI on the channel in receive mode, NOISE_SAMPLES
+    /// times, or None if the radio never got to measuring. A packet that
+    /// arrives meanwhile raises the peak.
+    async fn sample_noise(&mut self) -> RadioResult<Option<NoiseFloor>> {
+        use rfm69::registers::{IrqFlags1, Mode, Registers};
+
+        self.radio.mode(Mode::Receiver)?;
+        let ready = self
+            .wait_flag(
+                Registers::IrqFlags1,
+                IrqFlags1::RxReady,
+                Self::MODE_READY_TIMEOUT,
+            )
+            .await?;
+        if !ready {
+            return Ok(None);
+        }
+        let mut total = 0;
+        let mut peak = i8::MIN;
+        for _ in 0..Self::NOISE_SAMPLES {
+            self.radio.write(Registers::RssiConfig, Self::RSSI_START)?;
+            let done = self
+                .wait_flag(
+                    Registers::RssiConfig,
+                    Self::RSSI_DONE,
+                    Self::MODE_READY_TIMEOUT,
+                )
+                .await?;
+            if !done {
+                return Ok(None);
+            }
+            // -dBm in half-dB steps, like in recv_packet()
+            let rssi = -((self.radio.read(Registers::RssiValue)? / 2) as i8);
+            total += rssi as i32;
+            peak = peak.max(rssi);
+            Timer::after_millis(1).await;
+        }
+        Ok(Some(NoiseFloor {
+            average: (total / Self::NOISE_SAMPLES) as i8,
+            peak,
+        }))
+    }
+
     pub async fn send_packet(&mut self, packet: &Packet) {
+        // PanelComm::send_packet() drops these, so this is a path around it
+        defmt::debug_assert!(!self.tx_inhibited, "Radio send with TX inhibited");
+        if self.tx_inhibited {
+            error!("Radio send with TX inhibited");
+            return;
+        }
         if packet.data.len() > MAX_PAYLOAD_SIZE {
             error!("Data length too long");
             return;
@@ -291,29 +1020,69 @@ impl PanelRadio {
 
         let mut buf = [0u8; MAX_PAYLOAD_SIZE + 8];
         let wire_data = packet.radio_wire_format(&mut buf);
-        debug!("Sending packet: {:x}", wire_data);
+        packet_debug!("Sending packet: {:x}", wire_data);
         if self.radio.send(wire_data).is_err() {
             error!("Radio send error");
         }
     }
 
-    pub async fn recv_packet(&mut self) -> Packet {
-        self.radio.mode(rfm69::registers::Mode::Receiver).unwrap();
+    /// We've seen the radio stop interrupting after a brownout, and it
+    /// doesn't come back until it's reset. So if it's been quiet for a while,
+    /// check that it's still there and still receiving, and start it over if
+    /// not.
+    ///
+    async fn check_alive(&mut self) {
+        use rfm69::registers::{IrqFlags1, Registers};
+
+        let alive = match (
+            self.radio.read(Registers::Version),
+            self.radio.read(Registers::IrqFlags1),
+        ) {
+            (Ok(version), Ok(flags)) => {
+                version == self.version && flags & IrqFlags1::ModeReady != 0
+            }
+            _ => false,
+        };
+        if alive {
+            return;
+        }
+
+        error!("Radio not responding, reinitializing");
+        self.reinits = self.reinits.wrapping_add(1);
+        if let Err(e) = self.init().await {
+            error!("Radio reinit failed: {:?}", e);
+        }
+        if self.radio.mode(rfm69::registers::Mode::Receiver).is_err() {
+            error!("Radio receive mode failed");
+        }
+    }
+
+    /// Waits for a packet. Errors are returned rather than retried here, so
+    /// the caller can decide what to do about them. Either way, calling this
+    /// again puts the radio back in receive mode.
+    ///
+    pub async fn recv_packet(&mut self) -> RadioResult<Packet> {
+        self.radio.mode(rfm69::registers::Mode::Receiver)?;
         loop {
-            self.dio_int.wait_for_rising_edge().await;
+            if let Either::Second(_) = select(
+                self.dio_int.wait_for_rising_edge(),
+                Timer::after(Self::RX_IDLE_TIMEOUT),
+            )
+            .await
+            {
+                self.check_alive().await;
+                continue;
+            }
 
-            match try_recv(&mut self.radio).await {
-                Ok(packet) => return packet,
+            match try_recv(&mut self.radio, &mut self.last_rssi).await {
                 Err(RadioError::NoPacketAvailable) => continue,
-                Err(e) => {
-                    error!("Radio recv error: {:?}", e);
-                    continue;
-                }
+                result => return result,
             }
         }
 
         async fn try_recv(
             radio: &mut Rfm69<ExclusiveDevice<Spi<'static, Blocking>, Output<'static>, NoDelay>>,
+            rssi: &mut i8,
         ) -> RadioResult<Packet> {
             // A complete message has been received with good CRC. Must look for
             // PAYLOADREADY, not CRCOK, since only PAYLOADREADY occurs _after_ AES
@@ -328,45 +1097,168 @@ impl PanelRadio {
                 return Err(RadioError::NoPacketAvailable);
             }
 
-            radio.mode(rfm69::registers::Mode::Standby)?;
-
-            let mut buf = [0; 4];
-            radio.read_many(rfm69::registers::Registers::Fifo, &mut buf)?;
-            debug!("Received buf: {:x}", buf);
+            // Still holds the level measured during the packet, as long as
+            // the radio stays in receive mode. It's -dBm in half-dB steps.
+            let rssi_value = radio.read(rfm69::registers::Registers::RssiValue)?;
+            *rssi = -((rssi_value / 2) as i8);
 
-            let len = buf[0] as usize;
-            let to = buf[1];
-            let from = Address(buf[2]);
-            let tag = Message::try_from(buf[3]).map_err(|_| RadioError::InvalidPacket)?;
+            radio.mode(rfm69::registers::Mode::Standby)?;
 
-            let mut packet = Packet::new(from, Address(to), tag);
+            let mut buf = [0; MAX_RADIO_FRAME_LEN];
+            radio.read_many(rfm69::registers::Registers::Fifo, &mut buf[..4])?;
+            packet_debug!("Received buf: {:x}", &buf[..4]);
 
-            if len > 0 {
-                let _ = packet.data.resize(len - 3, 0);
-                radio.read_many(rfm69::registers::Registers::Fifo, &mut packet.data)?;
+            let len = Packet::radio_frame_len(buf[0]).ok_or(RadioError::InvalidPacket)?;
+            if len > 4 {
+                radio.read_many(rfm69::registers::Registers::Fifo, &mut buf[4..len])?;
             }
-            debug!("Received data: {:x}", packet.data.as_slice());
+            let packet = Packet::from_radio_wire_format(&buf[..len])
+                .map_err(|_| RadioError::InvalidPacket)?;
+            packet_debug!("Received data: {:x}", packet.data.as_slice());
 
             Ok(packet)
         }
     }
 }
 
+/// How much longer than the rest of a frame should take to wait for it,
+/// before taking it that the sender went quiet
+#[cfg(feature = "panel-bus")]
+const MID_FRAME_SLACK: Duration = Duration::from_millis(5);
+
+/// Read errors and bad frames, or zero bytes, without a packet in between
+/// before the panel bus is taken to be stuck, see PanelSerial. Noise on a
+/// working bus comes nowhere near, and a line held low reads as a stream of
+/// zeros, or errors, with nothing else.
+#[cfg(feature = "panel-bus")]
+const STUCK_ERRORS: u32 = 100;
+#[cfg(feature = "panel-bus")]
+const STUCK_ZEROS: u32 = 1024;
+
+/// How often a stuck panel bus is set up again and tried
+#[cfg(feature = "panel-bus")]
+const STUCK_RETRY: Duration = Duration::from_secs(1);
+
+/// How long a frame with `data` bytes of data is on the panel bus
+#[cfg(feature = "panel-bus")]
+const fn serial_frame_bytes(data: usize) -> usize {
+    data + MAX_SERIAL_FRAME_LEN - MAX_PAYLOAD_SIZE
+}
+
+/// The most the panels send back to one broadcast: a PingReply, the longest
+/// reply to one, from every slot. Beats a Set Color's, which is a
+/// SetColorReply from every slot and a NotMapped from as many IDs again,
+/// spread NOT_MAPPED_STEP apart into the same window. All of it can arrive
+/// while the master is busy with something else.
+#[cfg(feature = "panel-bus")]
+const MAX_REPLY_BURST: usize = MAX_PANEL_SLOTS * serial_frame_bytes(MAX_BROADCAST_REPLY_LEN);
+
+// The Set Color burst, a one-byte reply and a one-byte NotMapped per slot
+#[cfg(feature = "panel-bus")]
+const _: () = assert!(serial_frame_bytes(MAX_BROADCAST_REPLY_LEN) >= 2 * serial_frame_bytes(1));
+
+/// Bytes received on the panel bus that haven't been read yet. Bytes that
+/// arrive while it's full are lost without a trace, so it holds two bursts.
+#[cfg(feature = "panel-bus")]
+const BUS_RX_BUFFER_LEN: usize = 1280;
+
+#[cfg(feature = "panel-bus")]
+const _: () = assert!(BUS_RX_BUFFER_LEN >= 2 * MAX_REPLY_BURST);
+
+#[cfg(feature = "panel-bus")]
+static BUS_RX_BUFFER: ConstStaticCell<[u8; BUS_RX_BUFFER_LEN]> =
+    ConstStaticCell::new([0; BUS_RX_BUFFER_LEN]);
+
+/// Bytes the panel bus UART had no time to take before the next arrived,
+/// see OverrunCounter
+#[cfg(feature = "panel-bus")]
+static BUS_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+
+/// Counts overruns on the panel bus. Runs before BufferedInterruptHandler,
+/// which only logs them, and clears the flag by reading the byte after.
+#[cfg(feature = "panel-bus")]
+struct OverrunCounter;
+
+#[cfg(feature = "panel-bus")]
+impl interrupt::typelevel::Handler<interrupt::typelevel::USART2> for OverrunCounter {
+    unsafe fn on_interrupt() {
+        if embassy_stm32::pac::USART2.sr().read().ore() {
+            BUS_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Held while a frame goes out on the panel bus, see hold_sending()
+static SENDING: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Waits for the frame going out on the panel bus, if there is one, to
+/// finish, and holds off the next until the guard is dropped. For flash
+/// writes, which stall the CPU long enough to garble a frame, see
+/// flash::write_settings(). Before there's a PanelSerial, there's nothing to
+/// wait for.
+pub async fn hold_sending() -> MutexGuard<'static, CriticalSectionRawMutex, ()> {
+    SENDING.lock().await
+}
+
+/// The panel bus.
+///
+/// Installations sharing a bus are kept apart by XORing the second byte of
+/// the packet header with the group, see flash::set_group(). Group 0 leaves
+/// it alone, so it works with boards that don't know about groups.
+///
+/// A line that's shorted or held low reads as nothing but errors or junk.
+/// After STUCK_ERRORS or STUCK_ZEROS of that without a packet, the bus is
+/// stuck: it's logged, FAULT_PANEL_BUS is set, which the status LEDs and
+/// PingReply show, and rather than spinning on the errors, the UART is set
+/// up again and tried once every STUCK_RETRY. The first good packet clears
+/// it.
+///
+/// Received bytes wait in a BUS_RX_BUFFER_LEN buffer, enough for the replies
+/// to a broadcast to pile up while the master is busy. Bytes lost anyway,
+/// because the interrupt was held off too long, are counted in CommStats.
+///
+#[cfg(feature = "panel-bus")]
 pub struct PanelSerial {
     ser_out_en: Output<'static>,
     tx: usart::BufferedUartTx<'static>,
     rx: usart::BufferedUartRx<'static>,
+    /// Where send_packet() builds the frame. Kept here rather than in the
+    /// future, which every future that sends a packet would carry a copy of
+    wire_buf: [u8; MAX_SERIAL_FRAME_LEN],
+    /// Holds what's been received of the next packet
+    parser: SerialParser,
     address: Address,
+    baud: BusBaud,
+    group: u8,
+    bad_tags: Storm,
+    crc_errors: Storm,
+    read_errors: Storm,
+    truncated_frames: u32,
+    /// Read errors and bad frames since the last packet
+    errors_in_a_row: u32,
+    /// Zero bytes read since the last packet
+    zeros_in_a_row: u32,
+    stuck: bool,
+    /// Copying the bus out the command serial port, see w
+    mirror: Option<BusMirror>,
+    /// See PanelComm::inhibit_tx()
+    tx_inhibited: bool,
 }
 
+#[cfg(feature = "panel-bus")]
 impl PanelSerial {
-    pub fn new(mut panel_bus_peripherals: PanelBusPeripherals, address: Address) -> Self {
+    pub fn new(
+        mut panel_bus_peripherals: PanelBusPeripherals,
+        address: Address,
+        baud: BusBaud,
+        group: u8,
+    ) -> Self {
         let mut config = usart::Config::default();
-        config.baudrate = 256_000;
+        config.baudrate = baud.rate();
 
         panel_bus_peripherals.ser_out_en.set_low();
 
-        let rx_buffer = Box::leak(Box::new([0; 256]));
+        let rx_buffer = BUS_RX_BUFFER.take();
         let tx_buffer = Box::leak(Box::new([0; 256]));
 
         let uart = BufferedUart::new_half_duplex(
@@ -390,19 +1282,62 @@ impl PanelSerial {
             ser_out_en: panel_bus_peripherals.ser_out_en,
             tx,
             rx,
+            wire_buf: [0; MAX_SERIAL_FRAME_LEN],
+            parser: SerialParser::new(group),
             address,
+            baud,
+            group,
+            bad_tags: Storm::new(),
+            crc_errors: Storm::new(),
+            read_errors: Storm::new(),
+            truncated_frames: 0,
+            errors_in_a_row: 0,
+            zeros_in_a_row: 0,
+            stuck: false,
+            mirror: None,
+            tx_inhibited: false,
         }
     }
 
+    /// Never sends from now on, so the driver stays off and the UART's
+    /// transmitter is left alone, see PanelComm::inhibit_tx().
+    pub fn inhibit_tx(&mut self) {
+        self.tx_inhibited = true;
+    }
+
+    /// Switches the bus to a new baud rate. Anything in flight is lost, so
+    /// make sure the last packet has been sent first.
+    pub fn set_baud(&mut self, baud: BusBaud) {
+        let mut config = usart::Config::default();
+        config.baudrate = baud.rate();
+        if let Err(e) = self.rx.set_config(&config) {
+            error!("Bus baud change failed: {:?}", e);
+            return;
+        }
+        self.baud = baud;
+    }
+
     pub async fn send_packet(&mut self, packet: &Packet) {
+        // PanelComm::send_packet() drops these, so this is a path around it
+        defmt::debug_assert!(!self.tx_inhibited, "Bus send with TX inhibited");
+        if self.tx_inhibited {
+            error!("Bus send with TX inhibited");
+            return;
+        }
         if packet.data.len() > MAX_PAYLOAD_SIZE {
             error!("Data length too long");
             return;
         }
 
-        let mut buf = [0u8; MAX_PAYLOAD_SIZE + 8];
-        let wire_data = packet.serial_wire_format(&mut buf);
+        let _sending = SENDING.lock().await;
+        let len = packet.serial_wire_format(&mut self.wire_buf).len();
+        self.wire_buf[1] ^= self.group;
+        let wire_data = &self.wire_buf[..len];
         // debug!("Wire format: {:x}", wire_data);
+        if let Some(mirror) = &mut self.mirror {
+            mirror.record(bus_mirror::SENT, wire_data);
+            mirror.flush();
+        }
 
         self.ser_out_en.set_high();
 
@@ -427,68 +1362,205 @@ impl PanelSerial {
         });
     }
 
-    // TODO: mid-packet timeout
     // TODO: crc check
     // TODO: could we just receive until idle?
 
+    pub fn truncated_frames(&self) -> u32 {
+        self.truncated_frames
+    }
+
+    pub fn set_mirror(&mut self, on: bool) -> bool {
+        if !on {
+            if let Some(mirror) = &mut self.mirror {
+                mirror.flush();
+            }
+            self.mirror = None;
+        } else if self.mirror.is_none() {
+            self.mirror = Some(BusMirror::new());
+        }
+        true
+    }
+
+    pub fn mirror_dropped(&self) -> Option<u32> {
+        self.mirror.as_ref().map(|m| m.dropped())
+    }
+
+    pub fn overruns(&self) -> u32 {
+        BUS_OVERRUNS.load(Ordering::Relaxed)
+    }
+
+    /// How long `bytes` bytes take on the wire, at 10 bits a byte.
+    fn time_for(&self, bytes: usize) -> Duration {
+        Duration::from_micros(bytes as u64 * 10_000_000 / self.baud.rate() as u64)
+    }
+
+    /// Waits for a packet for us, or a broadcast.
     pub async fn recv_packet(&mut self) -> Packet {
         loop {
-            while self.read_byte().await != 0x55 {}
-            if self.read_byte().await != 0xaa {
-                continue;
-            }
-            let to = self.read_byte().await;
-            let len = self.read_byte().await as usize;
-            if !(2..=MAX_PAYLOAD_SIZE + 2).contains(&len) {
-                // +2 for from and tag
-                continue;
+            let packet = self.recv_any_packet().await;
+            if packet.to == BROADCAST_ADDRESS || packet.to == self.address {
+                return packet;
             }
-            let from = Address(self.read_byte().await);
-            let tag = self.read_byte().await;
-            let tag = match Message::try_from(tag) {
-                Ok(tag) => tag,
-                Err(_) => {
-                    error!("Invalid tag: {:02x}", tag);
+        }
+    }
+
+    /// Waits for a packet to anyone, like a bridge needs. Fine to drop at any
+    /// await, the bytes read so far stay in the parser.
+    async fn recv_any_packet(&mut self) -> Packet {
+        let mut bytes = [0; MAX_SERIAL_FRAME_LEN];
+        loop {
+            // No more than the parser wants, so it all fits
+            let wanted = self.parser.wanted();
+            let timeout = self.time_for(wanted) + MID_FRAME_SLACK;
+            let read = self.rx.read(&mut bytes[..wanted]);
+            let result = match wanted {
+                0 => Ok(Ok(0)),
+                // A sender that dies partway through a frame mustn't leave
+                // it holding up the packets after it
+                _ if self.parser.buffered() > 0 => with_timeout(timeout, read).await,
+                _ => Ok(read.await),
+            };
+            let len = match result {
+                Ok(Ok(len)) => len,
+                Ok(Err(e)) => {
+                    if let Some(count) = self.read_errors.hit() {
+                        error!("Bus read error: {:?} ({} since last report)", e, count);
+                    }
+                    self.errors_in_a_row += 1;
+                    self.check_stuck().await;
                     continue;
                 }
-            };
-
-            let data_len = len - 2; // Subtract from and tag
-            let mut packet = Packet::new(from, Address(to), tag);
-
-            if data_len > 0 {
-                let _ = packet.data.resize(data_len, 0);
-                if self
-                    .rx
-                    .read_exact(&mut packet.data[0..data_len])
-                    .await
-                    .is_err()
-                {
+                Err(TimeoutError) => {
+                    self.truncated_frames += 1;
+                    self.parser.cut_short();
                     continue;
                 }
+            };
+            // debug!("Received: {:02x}", bytes[..len]);
+            if let Some(mirror) = &mut self.mirror {
+                mirror.record(bus_mirror::RECEIVED, &bytes[..len]);
             }
 
-            let crc = self.read_byte().await;
-            // TODO: real crc check
-            if crc != b'C' {
-                error!("CRC error: {:02x}", crc);
-                continue;
+            let mut trouble = false;
+            match self.parser.feed(&bytes[..len]) {
+                Some(Ok(packet)) => {
+                    self.unstuck();
+                    if let Some(mirror) = &mut self.mirror {
+                        mirror.flush();
+                    }
+                    return packet;
+                }
+                Some(Err(WireError::BadTag(tag))) => {
+                    if let Some(count) = self.bad_tags.hit() {
+                        error!("Invalid tag: {:02x} ({} since last report)", tag, count);
+                    }
+                    self.errors_in_a_row += 1;
+                    trouble = true;
+                }
+                Some(Err(WireError::BadCrc(crc))) => {
+                    if let Some(count) = self.crc_errors.hit() {
+                        error!("CRC error: {:02x} ({} since last report)", crc, count);
+                    }
+                    self.errors_in_a_row += 1;
+                    trouble = true;
+                }
+                Some(Err(_)) | None => {}
             }
+            // Only zeros that weren't taken for the start of a frame, since
+            // packets have plenty of their own
+            if self.parser.buffered() == 0 {
+                // The end of junk between packets
+                if let Some(mirror) = &mut self.mirror {
+                    mirror.flush();
+                }
+                let zeros = bytes[..len].iter().filter(|&&b| b == 0).count();
+                self.zeros_in_a_row = self.zeros_in_a_row.saturating_add(zeros as u32);
+                trouble |= zeros > 0;
+            }
+            if trouble {
+                self.check_stuck().await;
+            }
+        }
+    }
 
-            // debug!("Received packet: {:?}", packet);
-
-            if to == BROADCAST_ADDRESS.value() || to == self.address.value() {
-                return packet;
+    /// Declares the bus stuck once there's been too much trouble without a
+    /// packet, and from then on, waits STUCK_RETRY and sets the UART up
+    /// again after each bit of trouble, rather than spinning on it.
+    async fn check_stuck(&mut self) {
+        if !self.stuck {
+            if self.errors_in_a_row < STUCK_ERRORS && self.zeros_in_a_row < STUCK_ZEROS {
+                return;
             }
+            error!(
+                "Panel bus stuck: {} errors and {} zeros without a packet",
+                self.errors_in_a_row, self.zeros_in_a_row
+            );
+            self.stuck = true;
+            self_test::set_fault(self_test::FAULT_PANEL_BUS, true);
+        }
+        Timer::after(STUCK_RETRY).await;
+        let mut config = usart::Config::default();
+        config.baudrate = self.baud.rate();
+        if let Err(e) = self.rx.set_config(&config) {
+            error!("Panel bus setup failed: {:?}", e);
         }
+        self.parser = SerialParser::new(self.group);
     }
 
-    async fn read_byte(&mut self) -> u8 {
-        let mut buffer = [0; 1];
-        if let Err(e) = self.rx.read(&mut buffer).await {
-            error!("read_byte error: {:?}", e);
+    /// A packet came through, so the bus is fine.
+    fn unstuck(&mut self) {
+        self.errors_in_a_row = 0;
+        self.zeros_in_a_row = 0;
+        if self.stuck {
+            info!("Panel bus working again");
+            self.stuck = false;
+            self_test::set_fault(self_test::FAULT_PANEL_BUS, false);
         }
-        // debug!("Received: {:02x}", buffer[0]);
-        buffer[0]
+    }
+}
+
+/// Stands in for the panel bus on boards without one, like rev-d. Nothing is
+/// sent, and nothing arrives.
+#[cfg(not(feature = "panel-bus"))]
+pub struct PanelSerial {
+    baud: BusBaud,
+}
+
+#[cfg(not(feature = "panel-bus"))]
+impl PanelSerial {
+    pub fn absent(baud: BusBaud) -> Self {
+        Self { baud }
+    }
+
+    pub fn set_baud(&mut self, baud: BusBaud) {
+        self.baud = baud;
+    }
+
+    pub async fn send_packet(&mut self, _packet: &Packet) {}
+
+    pub fn inhibit_tx(&mut self) {}
+
+    pub fn truncated_frames(&self) -> u32 {
+        0
+    }
+
+    pub fn set_mirror(&mut self, _on: bool) -> bool {
+        false
+    }
+
+    pub fn mirror_dropped(&self) -> Option<u32> {
+        None
+    }
+
+    pub fn overruns(&self) -> u32 {
+        0
+    }
+
+    pub async fn recv_packet(&mut self) -> Packet {
+        core::future::pending().await
+    }
+
+    async fn recv_any_packet(&mut self) -> Packet {
+        core::future::pending().await
     }
 }
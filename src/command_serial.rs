@@ -1,9 +1,14 @@
 use crate::board::CmdPortPeripherals;
 use crate::board::DbgUsart;
-use crate::line_breaker::LineBreaker;
+use crate::interactor::CommandPort;
+use crate::output::OutputQueue;
 use alloc::boxed::Box;
+use aunisoma_protocol::{LineBreaker, LineTooLong};
+use core::task::Poll;
 use defmt::info;
-use embassy_stm32::usart::BufferedUart;
+use embassy_executor::Spawner;
+use embassy_futures::poll_once;
+use embassy_stm32::usart::{BufferedUart, BufferedUartRx, BufferedUartTx};
 use embassy_stm32::{bind_interrupts, usart};
 use embedded_io_async::{Read, Write};
 
@@ -11,55 +16,147 @@ bind_interrupts!(struct Irqs {
         USART1 => usart::BufferedInterruptHandler<DbgUsart>;
 });
 
+/// Output for the serial port, written out by writer_task()
+static OUTPUT: OutputQueue = OutputQueue::new();
+
+/// Room for four full command lines, so a host that keeps sending while a
+/// long command runs doesn't overrun the UART, see drop_waiting()
+const RX_BUFFER_LEN: usize = 1024;
+
+/// Queues a bus mirror frame, or returns false if there's no room for it,
+/// see BusMirror.
+#[cfg(feature = "panel-bus")]
+pub fn mirror(frame: &[u8]) -> bool {
+    OUTPUT.try_push_record(frame)
+}
+
 pub struct CommandSerial<'a> {
-    uart: BufferedUart<'a>,
+    rx: BufferedUartRx<'a>,
     breaker: LineBreaker<256>,
 }
 
-impl CommandSerial<'_> {
-    pub fn new(p: CmdPortPeripherals) -> Self {
+impl CommandSerial<'static> {
+    pub fn new(p: CmdPortPeripherals, spawner: &'_ Spawner) -> Self {
         let mut config = usart::Config::default();
         config.baudrate = 230400;
 
-        let rx_buffer = Box::leak(Box::new([0; 256]));
+        let rx_buffer = Box::leak(Box::new([0; RX_BUFFER_LEN]));
         let tx_buffer = Box::leak(Box::new([0; 256]));
 
+        let uart = BufferedUart::new(
+            p.cmd_usart,
+            Irqs,
+            p.cmd_usart_rx,
+            p.cmd_usart_tx,
+            tx_buffer,
+            rx_buffer,
+            config,
+        )
+        .unwrap();
+        let (tx, rx) = uart.split();
+        spawner.must_spawn(writer_task(tx));
+
         Self {
-            uart: BufferedUart::new(
-                p.cmd_usart,
-                Irqs,
-                p.cmd_usart_rx,
-                p.cmd_usart_tx,
-                tx_buffer,
-                rx_buffer,
-                config,
-            )
-            .unwrap(),
+            rx,
             breaker: LineBreaker::new(),
         }
     }
+}
 
-    pub async fn read_line<'i>(&mut self, into: &'i mut [u8]) -> &'i [u8] {
+impl CommandPort for CommandSerial<'_> {
+    /// Reads a line. This is safe to cancel: input that has been read stays
+    /// in the LineBreaker, including a finished line, until a later call
+    /// returns it. A line that was too long is an error, so the host can be
+    /// told.
+    ///
+    async fn read_line<'i>(&mut self, into: &'i mut [u8]) -> Result<&'i [u8], LineTooLong> {
+        let mut buf = [0; 128];
+        // The first time around, look for another line in what was left over
+        // from last time
+        let mut n = 0;
         loop {
-            let mut buf = [0; 128];
-            match self.uart.read(&mut buf).await {
-                Ok(n) => {
-                    if let Some(line) = self.breaker.process(&buf[..n]) {
-                        into[..line.len()].copy_from_slice(line);
-                        return &into[..line.len()];
-                    }
-                }
+            // Also returns a line found by a call that was cancelled before it
+            // could return it
+            if let Some(line) = self.breaker.take_line() {
+                into[..line.len()].copy_from_slice(line);
+                return Ok(&into[..line.len()]);
+            }
+
+            let found = self.breaker.process(&buf[..n]);
+            let echo = self.breaker.echo_output();
+            if !echo.is_empty() {
+                OUTPUT.push_text(&[echo]);
+            }
+            if self.breaker.take_too_long() {
+                return Err(LineTooLong);
+            }
+            if found {
+                continue;
+            }
+
+            n = match self.rx.read(&mut buf).await {
+                Ok(n) => n,
+                // Not an empty line, which would repeat the last command.
+                // The line it hit is garbled, so it's thrown away whole.
                 Err(e) => {
                     info!("UART read error: {}", e);
-                    return &[];
+                    self.breaker.discard_line();
+                    0
                 }
-            }
+            };
         }
     }
 
-    pub async fn write_line(&mut self, line: &[u8]) {
-        let _ = self.uart.write_all(line).await;
-        let _ = self.uart.write(b"\n").await;
-        let _ = self.uart.flush().await;
+    /// Throws away the lines that have already arrived, without waiting for
+    /// more, and returns how many there were. The start of a line still
+    /// being typed is kept.
+    async fn drop_waiting(&mut self) -> usize {
+        self.breaker.drop_waiting(
+            &mut [0; 128],
+            // Reads whatever is in the buffer, or nothing if it's empty
+            |buf| match poll_once(self.rx.read(buf)) {
+                Poll::Ready(Ok(n)) => n,
+                _ => 0,
+            },
+            |echo| OUTPUT.push_text(&[echo]),
+        )
+    }
+
+    fn set_echo(&mut self, echo: bool) {
+        self.breaker.set_echo(echo);
+    }
+
+    /// Queues a line to be written. This doesn't wait for the UART, so a
+    /// slow terminal can't hold up the caller. If the writer falls too far
+    /// behind, the oldest output is dropped, see OutputQueue.
+    ///
+    fn write_line(&mut self, line: &[u8]) {
+        OUTPUT.push_text(&[line, b"\n"]);
+    }
+
+    /// Queues a line of a reply. Waits for the UART only while the queue is
+    /// full.
+    async fn reply_line(&mut self, line: &[u8]) {
+        OUTPUT.send_text(&[line, b"\n"]).await;
+    }
+
+    async fn reply_part(&mut self, part: &[u8]) {
+        OUTPUT.send_text(&[part]).await;
+    }
+
+    /// Waits until what's queued is out of the UART.
+    async fn flushed(&self) {
+        OUTPUT.flushed().await;
+    }
+}
+
+#[embassy_executor::task]
+async fn writer_task(mut tx: BufferedUartTx<'static>) {
+    loop {
+        let chunk = OUTPUT.pop().await;
+        let _ = tx.write_all(&chunk.data).await;
+        if OUTPUT.is_empty() {
+            let _ = tx.flush().await;
+        }
     }
 }
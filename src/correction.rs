@@ -0,0 +1,103 @@
+use aunisoma_protocol::{UNITY_GAIN, correct_color};
+use defmt::{info, warn};
+
+use crate::cmd_processor::MAX_PANEL_SLOTS;
+use crate::flash::{FlashError, RecordPage};
+
+/// The saved table lives in the page before the settings page, which memory.x
+/// leaves out
+const TABLE_PAGE: u32 = 0x0800_F000;
+
+/// Each record is the gains, red, green, and blue for each slot in turn
+const TABLE_LEN: usize = MAX_PANEL_SLOTS * 3;
+
+type Gains = [[u8; 3]; MAX_PANEL_SLOTS];
+
+/// Per-slot gains the master applies to the colors in `L`, so strips from
+/// different batches in one installation can be made to match. See k.
+///
+/// Saved tables are appended to a RecordPage, like the operating hours log,
+/// and the last whole one is used at boot. The page is only erased when it's
+/// full, so it lasts for ten saves per erase. Until something is saved,
+/// every slot has unity gains.
+///
+pub struct ColorCorrection {
+    page: RecordPage<TABLE_LEN>,
+    gains: Gains,
+    /// The gains are what's in flash
+    saved: bool,
+}
+
+impl ColorCorrection {
+    pub fn load() -> Self {
+        let page = RecordPage::open(TABLE_PAGE);
+        let mut gains = [[UNITY_GAIN; 3]; MAX_PANEL_SLOTS];
+        if let Some((_, table)) = page.records().next_back() {
+            gains.as_flattened_mut().copy_from_slice(&table);
+        }
+        if !page.is_empty() {
+            info!("Color correction loaded");
+        }
+        Self {
+            page,
+            gains,
+            saved: true,
+        }
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.saved
+    }
+
+    /// Slots whose gains aren't all unity, and their gains.
+    pub fn corrected(&self) -> impl Iterator<Item = (usize, [u8; 3])> + '_ {
+        self.gains
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, gains)| *gains != [UNITY_GAIN; 3])
+    }
+
+    /// Sets the gains of `slot`, which has to be less than MAX_PANEL_SLOTS.
+    pub fn set(&mut self, slot: usize, gains: [u8; 3]) {
+        if self.gains[slot] != gains {
+            self.gains[slot] = gains;
+            self.saved = false;
+        }
+    }
+
+    /// Puts every slot back to unity gains.
+    pub fn clear(&mut self) {
+        for slot in 0..MAX_PANEL_SLOTS {
+            self.set(slot, [UNITY_GAIN; 3]);
+        }
+    }
+
+    /// Corrects one color, RGB or RGBW, for `slot`.
+    pub fn apply(&self, slot: usize, color: &mut [u8]) {
+        if let Some(&gains) = self.gains.get(slot) {
+            correct_color(color, gains);
+        }
+    }
+
+    /// Appends the gains to the page, erasing it first if it's full. Nothing
+    /// is written if they haven't changed. Writing takes about 2.5 ms, and
+    /// the rare erase up to 40 ms.
+    pub fn save(&mut self) -> Result<(), FlashError> {
+        if self.saved {
+            return Ok(());
+        }
+        let mut table = [0; TABLE_LEN];
+        table.copy_from_slice(self.gains.as_flattened());
+        match self.page.append(&table) {
+            Ok(_) => {
+                self.saved = true;
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Color correction write failed: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+}
@@ -1,8 +1,14 @@
-
-use crate::{Mode, boot, comm::CommMode};
+use crate::{
+    Mode,
+    board::ColorOrder,
+    boot,
+    comm::{self, BusBaud, CommMode, PanelRadio, TxPower},
+};
 use bitfield::bitfield;
-use defmt::{Format, debug, info, panic};
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::{Format, debug, info, panic, warn};
 use embassy_stm32::pac::FLASH;
+use embassy_time::{Duration, block_for};
 
 // The option bytes register is only read from flash at power-up, so we cache
 // the current values in .noinit RAM.
@@ -11,6 +17,7 @@ use embassy_stm32::pac::FLASH;
 static mut CACHED_USER_BYTES: UserBytes = UserBytes {
     id: 0,
     data1: Data1(0),
+    group: 0,
 };
 
 fn user_bytes() -> &'static mut UserBytes {
@@ -31,26 +38,186 @@ pub fn init_user_configuration() {
     info!("user bytes {:?}", user_bytes());
 }
 
+/// Saves settings with `write`, like `set_default_mode(mode)`, once the panel
+/// bus is quiet. Waits for the frame going out to finish, and holds off the
+/// next until the write is done, since writing flash stalls the CPU for up to
+/// 40 ms, and a frame that's going out comes out garbled. Before comm is up,
+/// like in the boot settings, there's nothing to wait for.
+pub async fn write_settings<T>(
+    write: impl FnOnce() -> Result<T, FlashError>,
+) -> Result<T, FlashError> {
+    let _quiet = comm::hold_sending().await;
+    write()
+}
+
 pub fn get_my_id() -> u8 {
     user_bytes().get_id()
 }
 
+/// Mode::Bridge doesn't fit in the two bits the option bytes have for the
+/// mode, so it's saved as Mode::Panel there, and flagged in main flash.
 pub fn get_default_mode() -> Mode {
-    Mode::try_from(user_bytes().default_mode()).unwrap_or(Mode::Panel)
+    match Mode::try_from(user_bytes().default_mode()) {
+        Ok(Mode::Panel) if read_setting(Setting::Bridge).is_some() => Mode::Bridge,
+        Ok(mode) => mode,
+        Err(_) => Mode::Panel,
+    }
 }
 
-pub fn set_default_mode(mode: Mode) {
-    user_bytes().set_default_mode(mode.into());
+pub fn set_default_mode(mode: Mode) -> Result<(), FlashError> {
+    write_setting(Setting::Bridge, (mode == Mode::Bridge).then_some(1))?;
+    let mode = if mode == Mode::Bridge {
+        Mode::Panel
+    } else {
+        mode
+    };
+    user_bytes().set_default_mode(mode.into())
 }
 
 pub fn get_comm_mode() -> CommMode {
     CommMode::try_from(user_bytes().comm_mode()).unwrap_or(CommMode::Radio)
 }
 
-pub fn set_comm_mode(mode: CommMode) {
-    user_bytes().set_comm_mode(mode.into());
+pub fn set_comm_mode(mode: CommMode) -> Result<(), FlashError> {
+    user_bytes().set_comm_mode(mode.into())
+}
+
+pub fn get_bus_baud() -> BusBaud {
+    BusBaud::try_from(user_bytes().bus_baud()).unwrap_or(BusBaud::Default)
+}
+
+pub fn set_bus_baud(baud: BusBaud) -> Result<(), FlashError> {
+    user_bytes().set_bus_baud(baud.into())
+}
+
+/// Which installation the board belongs to, see set_group().
+pub fn get_group() -> u8 {
+    user_bytes().group()
+}
+
+/// Sets the installation the board belongs to, so installations in range of
+/// each other don't hear each other. Boards only hear others in the same
+/// group. Group 0 is what boards that have never been set are in. Takes
+/// effect at the next reset.
+pub fn set_group(group: u8) -> Result<(), FlashError> {
+    user_bytes().set_group(group)
+}
+
+/// Radio transmit power, see TxPower. Unset reads as TxPower::Max.
+pub fn get_tx_power() -> TxPower {
+    read_setting(Setting::TxPower)
+        .and_then(|power| TxPower::try_from(power).ok())
+        .unwrap_or(TxPower::Max)
+}
+
+/// Saves the radio transmit power, unless it's already saved. Erases a page,
+/// so nothing runs for up to 40 ms.
+pub fn set_tx_power(power: TxPower) -> Result<(), FlashError> {
+    write_setting(
+        Setting::TxPower,
+        (power != TxPower::Max).then_some(power.into()),
+    )
+}
+
+/// Radio channel, see PanelRadio::frequency(). Unset reads as
+/// PanelRadio::DEFAULT_CHANNEL.
+pub fn get_channel() -> u8 {
+    match read_setting(Setting::Channel) {
+        Some(channel) if channel <= PanelRadio::MAX_CHANNEL => channel,
+        _ => PanelRadio::DEFAULT_CHANNEL,
+    }
+}
+
+/// Saves the radio channel, unless it's already saved. Erases a page, so
+/// nothing runs for up to 40 ms. Takes effect at the next reset, see
+/// PanelComm::set_channel() to switch now.
+pub fn set_channel(channel: u8) -> Result<(), FlashError> {
+    if channel > PanelRadio::MAX_CHANNEL {
+        panic!("invalid channel");
+    }
+    write_setting(
+        Setting::Channel,
+        (channel != PanelRadio::DEFAULT_CHANNEL).then_some(channel),
+    )
+}
+
+/// Settings kept in main flash, since the option bytes are full. Each is a
+/// halfword pair, the value and its complement, at its index in
+/// SETTINGS_PAGE, so erased, or a torn write, reads as unset.
+#[derive(Copy, Clone)]
+enum Setting {
+    Channel = 0,
+    /// Set when the default mode is Mode::Bridge, which flash has as
+    /// Mode::Panel
+    Bridge = 1,
+    TxPower = 2,
+}
+
+const SETTINGS: [Setting; 3] = [Setting::Channel, Setting::Bridge, Setting::TxPower];
+
+fn read_setting(setting: Setting) -> Option<u8> {
+    let address = (SETTINGS_PAGE as *const u16).wrapping_add(setting as usize * 2);
+    let (value, check) = unsafe {
+        (
+            core::ptr::read_volatile(address),
+            core::ptr::read_volatile(address.add(1)),
+        )
+    };
+    match u8::try_from(value) {
+        Ok(value) if check == !(value as u16) => Some(value),
+        _ => None,
+    }
+}
+
+/// Saves one setting, keeping the others, unless it's already saved. The
+/// whole page is erased and written again, which takes up to 40 ms.
+fn write_setting(setting: Setting, value: Option<u8>) -> Result<(), FlashError> {
+    if read_setting(setting) == value {
+        return Ok(());
+    }
+    let mut values = SETTINGS.map(read_setting);
+    values[setting as usize] = value;
+    erase_page(SETTINGS_PAGE)?;
+    for (i, value) in values.into_iter().enumerate() {
+        if let Some(value) = value {
+            program(
+                SETTINGS_PAGE + i as u32 * 4,
+                &[value as u16, !(value as u16)],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn get_color_order() -> ColorOrder {
+    ColorOrder::try_from(user_bytes().color_order()).unwrap_or(ColorOrder::Rgb)
+}
+
+pub fn set_color_order(order: ColorOrder) -> Result<(), FlashError> {
+    user_bytes().set_color_order(order.into())
+}
+
+/// How many times the option bytes have been written since power-up. They're
+/// only good for so many erase cycles, so the J command reports this.
+static WRITES: AtomicU32 = AtomicU32::new(0);
+
+pub fn write_count() -> u32 {
+    WRITES.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+    /// The flash or option bytes wouldn't unlock
+    Locked,
+    /// The flash controller reported an error
+    Program,
+    /// What was read back wasn't what was written
+    Verify,
 }
 
+/// A failed write is tried once more after this long
+const RETRY_DELAY: Duration = Duration::from_millis(5);
+
 // I'd rather use bitfield-struct, but it's generating defmt stuff that
 // won't compile, despite defmt=false.
 
@@ -60,9 +227,21 @@ bitfield! {
     u8;
     default_mode, set_default_mode: 1, 0;  // bits 0-1 for default mode
     comm_mode, set_comm_mode: 3, 2;       // bit 2-3 for comm mode
+    bus_baud, set_bus_baud: 5, 4;         // bit 4-5 for panel bus baud rate
+    color_order, set_color_order: 7, 6;   // bit 6-7 for LED strip color order
 }
 
-/// Assigns meaning to the 2 bytes of EEPROM user data on the STM32F1.
+/// Highest group there's room for
+pub const MAX_GROUP: u8 = 0x1f;
+
+/// The USER option byte only uses bits 0-2, for the watchdog and reset
+/// options. The group is kept inverted in bits 3-7, so erased bits are group
+/// 0, and the hardware options are left as they are when erased.
+const USER_HW_BITS: u8 = 0x07;
+const USER_GROUP_SHIFT: u32 = 3;
+
+/// Assigns meaning to the 2 bytes of EEPROM user data on the STM32F1, plus
+/// the spare bits of the USER option byte.
 ///
 /// This deals in raw values. The get_ and set_ functions above translate
 /// to/from the enums.
@@ -70,6 +249,7 @@ bitfield! {
 struct UserBytes {
     id: u8,
     data1: Data1,
+    group: u8,
 }
 
 impl Format for UserBytes {
@@ -91,6 +271,9 @@ impl Format for UserBytes {
         } else {
             defmt::write!(fmt, "(invalid)");
         }
+        defmt::write!(fmt, ", bus_baud={}", self.data1.bus_baud());
+        defmt::write!(fmt, ", color_order={}", self.data1.color_order());
+        defmt::write!(fmt, ", group={}", self.group);
         defmt::write!(fmt, ")");
     }
 }
@@ -110,7 +293,12 @@ impl UserBytes {
             data1.set_comm_mode(CommMode::Radio.into());
         }
 
-        let result = Self { id, data1 };
+        // OBR has the USER byte in bits 2-9, including the bits the
+        // register's fields leave out
+        let user = (FLASH.obr().read().0 >> 2) as u8;
+        let group = !(user >> USER_GROUP_SHIFT) & MAX_GROUP;
+
+        let result = Self { id, data1, group };
         debug!("Read from flash: {:?}", &result);
         result
     }
@@ -126,105 +314,209 @@ impl UserBytes {
         self.data1.default_mode()
     }
 
-    pub fn set_default_mode(&mut self, mode: u8) {
+    pub fn set_default_mode(&mut self, mode: u8) -> Result<(), FlashError> {
         if mode > 3 {
             panic!("invalid default mode");
         }
         self.data1.set_default_mode(mode);
-        self.write();
+        self.write()
     }
 
     pub fn comm_mode(&self) -> u8 {
         self.data1.comm_mode()
     }
 
-    pub fn set_comm_mode(&mut self, mode: u8) {
+    pub fn set_comm_mode(&mut self, mode: u8) -> Result<(), FlashError> {
         if mode > 3 {
             panic!("invalid comm mode");
         }
         self.data1.set_comm_mode(mode);
-        self.write();
+        self.write()
+    }
+
+    pub fn bus_baud(&self) -> u8 {
+        self.data1.bus_baud()
+    }
+
+    pub fn set_bus_baud(&mut self, baud: u8) -> Result<(), FlashError> {
+        if baud > 3 {
+            panic!("invalid bus baud");
+        }
+        self.data1.set_bus_baud(baud);
+        self.write()
+    }
+
+    pub fn group(&self) -> u8 {
+        self.group & MAX_GROUP
+    }
+
+    pub fn set_group(&mut self, group: u8) -> Result<(), FlashError> {
+        if group > MAX_GROUP {
+            panic!("invalid group");
+        }
+        self.group = group;
+        self.write()
+    }
+
+    /// The USER option byte for the group. Group 0 is 0xff, the same as
+    /// erased.
+    fn user_byte(&self) -> u8 {
+        USER_HW_BITS | ((!self.group() & MAX_GROUP) << USER_GROUP_SHIFT)
+    }
+
+    pub fn color_order(&self) -> u8 {
+        self.data1.color_order()
+    }
+
+    pub fn set_color_order(&mut self, order: u8) -> Result<(), FlashError> {
+        if order > 3 {
+            panic!("invalid color order");
+        }
+        self.data1.set_color_order(order);
+        self.write()
     }
 
-    pub fn write(&self) {
+    /// Writes the user bytes to the option bytes, unless they're already
+    /// there. Settings are cached in RAM and the OBR register isn't reloaded
+    /// until the next power-up, so this compares against the option bytes
+    /// themselves.
+    pub fn write(&self) -> Result<(), FlashError> {
+        if read_option_byte(OB_DATA_ADDRESS_DATA0) == Some(self.id)
+            && read_option_byte(OB_DATA_ADDRESS_DATA1) == Some(self.data1.0)
+            && read_option_byte(OB_USER_ADDRESS).unwrap_or(0xff) == self.user_byte()
+        {
+            debug!("already written {:?}", self);
+            return Ok(());
+        }
+
         debug!("writing {:?}", self);
-        unlock();
-        ob_unlock();
-        ob_erase();
-        ob_write_data_bytes(self.id, self.data1.0);
+        WRITES.fetch_add(1, Ordering::Relaxed);
+        let mut result = self.try_write();
+        if let Err(e) = result {
+            warn!("flash write failed: {:?}, retrying", e);
+            block_for(RETRY_DELAY);
+            result = self.try_write();
+        }
+        result
+    }
+
+    fn try_write(&self) -> Result<(), FlashError> {
+        let result = unlock()
+            .and_then(|()| ob_unlock())
+            .and_then(|()| ob_erase())
+            .and_then(|()| ob_write_data_bytes(self.id, self.data1.0))
+            .and_then(|()| match self.user_byte() {
+                // Left erased
+                0xff => Ok(()),
+                user => ob_write_user_byte(user),
+            });
+        // Lock up even if something went wrong, and clear the errors for the
+        // next try
+        FLASH.cr().modify(|w| {
+            w.set_opter(false);
+            w.set_optpg(false);
+        });
+        FLASH.sr().modify(|w| {
+            w.set_wrprterr(true);
+            w.set_pgerr(true);
+        });
         ob_lock();
         lock();
+        result
     }
 }
 
-fn unlock() {
+fn unlock() -> Result<(), FlashError> {
     if FLASH.cr().read().lock() {
         FLASH.keyr().write_value(0x45670123);
         FLASH.keyr().write_value(0xCDEF89AB);
     }
     if FLASH.cr().read().lock() {
-        panic!("flash didn't unlock");
+        return Err(FlashError::Locked);
     }
+    Ok(())
 }
 
-fn ob_unlock() {
+fn ob_unlock() -> Result<(), FlashError> {
     FLASH.optkeyr().write_value(0x45670123);
     FLASH.optkeyr().write_value(0xCDEF89AB);
     if !FLASH.cr().read().optwre() {
-        panic!("OB didn't unlock");
+        return Err(FlashError::Locked);
     }
+    Ok(())
 }
 
 // TODO: These addresses are for STM32F103C8. I couldn't find option bytes
 // support in embassy-stm32. Maybe submit a PR.
 
 const OB_RDP_ADDRESS: *mut u16 = 0x1FFFF800 as *mut u16;
+const OB_USER_ADDRESS: *mut u16 = 0x1FFFF802 as *mut u16;
 const OB_DATA_ADDRESS_DATA0: *mut u16 = 0x1FFFF804 as *mut u16;
 const OB_DATA_ADDRESS_DATA1: *mut u16 = 0x1FFFF806 as *mut u16;
 
 // Assumes there's no read protection, and that we don't want
 // any option bytes to be set, so we can just erase them all
-// and write only the user data bytes.
+// and write only the user data bytes, and the USER byte if
+// there's a group.
 
-fn ob_erase() {
+fn ob_erase() -> Result<(), FlashError> {
     let rdprt = FLASH.obr().read().rdprt();
 
-    wait_for_flash_idle();
+    wait_for_flash_idle()?;
     FLASH.cr().modify(|w| w.set_opter(true));
     FLASH.cr().modify(|w| w.set_strt(true));
-    wait_for_flash_idle();
+    wait_for_flash_idle()?;
     FLASH.cr().modify(|w| w.set_opter(false));
 
     FLASH.cr().modify(|w| w.set_optpg(true));
     unsafe {
         core::ptr::write_volatile(OB_RDP_ADDRESS, if rdprt { 0x0000 } else { 0x00a5 });
     }
-    wait_for_flash_idle();
+    wait_for_flash_idle()?;
     FLASH.cr().modify(|w| w.set_optpg(false));
+    Ok(())
 }
 
-fn ob_write_data_bytes(data0: u8, data1: u8) {
-    wait_for_flash_idle();
+fn ob_write_data_bytes(data0: u8, data1: u8) -> Result<(), FlashError> {
+    wait_for_flash_idle()?;
     FLASH.cr().modify(|w| w.set_optpg(true));
-    write_option_word(OB_DATA_ADDRESS_DATA0, data0 as u16);
-    write_option_word(OB_DATA_ADDRESS_DATA1, data1 as u16);
-    wait_for_flash_idle();
+    write_option_word(OB_DATA_ADDRESS_DATA0, data0 as u16)?;
+    write_option_word(OB_DATA_ADDRESS_DATA1, data1 as u16)?;
+    wait_for_flash_idle()?;
     FLASH.cr().modify(|w| w.set_optpg(false));
+    Ok(())
 }
 
-fn write_option_word(address: *mut u16, value: u16) {
+fn ob_write_user_byte(user: u8) -> Result<(), FlashError> {
+    wait_for_flash_idle()?;
+    FLASH.cr().modify(|w| w.set_optpg(true));
+    write_option_word(OB_USER_ADDRESS, user as u16)?;
+    FLASH.cr().modify(|w| w.set_optpg(false));
+    Ok(())
+}
+
+fn write_option_word(address: *mut u16, value: u16) -> Result<(), FlashError> {
     debug!("writing {:x} to {:x}", value, address);
     unsafe {
         core::ptr::write_volatile(address, value);
     }
-    wait_for_flash_idle();
+    wait_for_flash_idle()?;
     let read_value = unsafe { core::ptr::read_volatile(address) };
     debug!("read {:x} from {:x}", read_value, address);
     let expected_value = (!value << 8) | value;
     if read_value != expected_value {
         debug!("expected {:x} but got {:x}", expected_value, read_value);
-        panic!("flash write failed");
+        return Err(FlashError::Verify);
     }
+    Ok(())
+}
+
+/// Reads an option byte, or None if it's erased or its complement doesn't
+/// match.
+fn read_option_byte(address: *mut u16) -> Option<u8> {
+    let word = unsafe { core::ptr::read_volatile(address) };
+    let value = word as u8;
+    ((word >> 8) as u8 == !value).then_some(value)
 }
 
 fn ob_lock() {
@@ -235,19 +527,186 @@ fn lock() {
     FLASH.cr().modify(|w| w.set_lock(true));
 }
 
-fn wait_for_flash_idle() {
+fn wait_for_flash_idle() -> Result<(), FlashError> {
     while FLASH.sr().read().bsy() {}
     if FLASH.sr().read().eop() {
         FLASH.sr().modify(|w| w.set_eop(false));
     }
     if FLASH.sr().read().wrprterr() {
-        panic!("flash wrprterr");
+        debug!("flash wrprterr");
+        return Err(FlashError::Program);
     }
     if FLASH.sr().read().pgerr() {
-        cortex_m::asm::bkpt();
-        panic!("flash pgerr");
+        debug!("flash pgerr");
+        return Err(FlashError::Program);
     }
     if FLASH.obr().read().opterr() {
-        panic!("flash opterr");
+        debug!("flash opterr");
+        return Err(FlashError::Program);
+    }
+    Ok(())
+}
+
+// Main flash, for logs that change too often for the option bytes. The pages
+// used have to be left out of FLASH in memory.x.
+
+/// Pages are 1K on the STM32F103C8
+pub const PAGE_SIZE: usize = 1024;
+
+/// Holds the settings that don't fit in the option bytes, see Setting. The
+/// two pages before it are the saved mapping and the color correction table,
+/// and the two pages after it are the operating hours log.
+const SETTINGS_PAGE: u32 = 0x0800_F400;
+
+/// Erases the page starting at `address`, leaving it all 0xff. Takes 20 to
+/// 40 ms, during which nothing runs, since the code is in flash too.
+pub fn erase_page(address: u32) -> Result<(), FlashError> {
+    let result = unlock().and_then(|()| {
+        wait_for_flash_idle()?;
+        FLASH.cr().modify(|w| w.set_per(true));
+        FLASH.ar().write(|w| w.set_far(address));
+        FLASH.cr().modify(|w| w.set_strt(true));
+        let result = wait_for_flash_idle();
+        FLASH.cr().modify(|w| w.set_per(false));
+        result
+    });
+    finish_main_flash();
+    result
+}
+
+/// Writes halfwords to erased flash starting at `address`, and checks them.
+/// Each one takes about 50 us.
+pub fn program(address: u32, halfwords: &[u16]) -> Result<(), FlashError> {
+    let result = unlock().and_then(|()| {
+        wait_for_flash_idle()?;
+        FLASH.cr().modify(|w| w.set_pg(true));
+        let mut result = Ok(());
+        for (i, &value) in halfwords.iter().enumerate() {
+            let address = (address as usize + i * 2) as *mut u16;
+            unsafe {
+                core::ptr::write_volatile(address, value);
+            }
+            result = wait_for_flash_idle();
+            if result.is_ok() && unsafe { core::ptr::read_volatile(address) } != value {
+                result = Err(FlashError::Verify);
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+        FLASH.cr().modify(|w| w.set_pg(false));
+        result
+    });
+    finish_main_flash();
+    result
+}
+
+/// A page of main flash that records of LEN bytes are appended to, so it's
+/// only erased when it's full. Each record is followed by the wrapping sum
+/// of its halfwords and the sum's complement, so one the power went out in
+/// the middle of doesn't match, and is skipped. LEN has to be even.
+///
+/// The saved mapping, the color correction table, and the macros each keep
+/// the newest whole record in one of these, and the operating hours log uses
+/// two in turn.
+///
+pub struct RecordPage<const LEN: usize> {
+    address: u32,
+    /// Where the next record goes
+    slot: usize,
+}
+
+impl<const LEN: usize> RecordPage<LEN> {
+    const RECORD_LEN: usize = LEN + 4;
+    const RECORDS: usize = PAGE_SIZE / Self::RECORD_LEN;
+
+    /// The page starting at `address`, with the next record going after the
+    /// last one written, whole or not.
+    pub fn open(address: u32) -> Self {
+        const { assert!(LEN % 2 == 0) };
+        let mut page = Self { address, slot: 0 };
+        page.slot = (0..Self::RECORDS)
+            .rposition(|slot| !page.is_erased(slot))
+            .map_or(0, |slot| slot + 1);
+        page
+    }
+
+    /// The whole records and their slots, oldest first.
+    pub fn records(&self) -> impl DoubleEndedIterator<Item = (usize, [u8; LEN])> + '_ {
+        (0..self.slot).filter_map(|slot| Some((slot, self.read(slot)?)))
+    }
+
+    /// The record in `slot`, or None if it's erased or torn.
+    pub fn read(&self, slot: usize) -> Option<[u8; LEN]> {
+        let address = self.slot_address(slot) as *const u16;
+        let mut record = [0; LEN];
+        let mut sum: u16 = 0;
+        for (i, pair) in record.chunks_mut(2).enumerate() {
+            let halfword = unsafe { core::ptr::read_volatile(address.add(i)) };
+            pair.copy_from_slice(&halfword.to_le_bytes());
+            sum = sum.wrapping_add(halfword);
+        }
+        let check = unsafe {
+            [
+                core::ptr::read_volatile(address.add(LEN / 2)),
+                core::ptr::read_volatile(address.add(LEN / 2 + 1)),
+            ]
+        };
+        (check == [sum, !sum]).then_some(record)
+    }
+
+    /// Nothing's been written since the page was erased.
+    pub fn is_empty(&self) -> bool {
+        self.slot == 0
     }
+
+    pub fn is_full(&self) -> bool {
+        self.slot == Self::RECORDS
+    }
+
+    /// Erases the page, which takes up to 40 ms.
+    pub fn erase(&mut self) -> Result<(), FlashError> {
+        erase_page(self.address)?;
+        self.slot = 0;
+        Ok(())
+    }
+
+    /// Appends `record`, erasing the page first if it's full, and returns
+    /// the slot it went in. Each halfword takes about 50 us.
+    pub fn append(&mut self, record: &[u8; LEN]) -> Result<usize, FlashError> {
+        if self.is_full() {
+            self.erase()?;
+        }
+        let address = self.slot_address(self.slot);
+        // Even a failed write leaves the slot used
+        let slot = self.slot;
+        self.slot += 1;
+        let mut sum: u16 = 0;
+        for (i, pair) in record.chunks(2).enumerate() {
+            let halfword = u16::from_le_bytes([pair[0], pair[1]]);
+            sum = sum.wrapping_add(halfword);
+            program(address + (i * 2) as u32, &[halfword])?;
+        }
+        program(address + LEN as u32, &[sum, !sum])?;
+        Ok(slot)
+    }
+
+    fn slot_address(&self, slot: usize) -> u32 {
+        self.address + (slot * Self::RECORD_LEN) as u32
+    }
+
+    fn is_erased(&self, slot: usize) -> bool {
+        let address = self.slot_address(slot) as *const u16;
+        (0..Self::RECORD_LEN / 2)
+            .all(|i| unsafe { core::ptr::read_volatile(address.add(i)) } == 0xffff)
+    }
+}
+
+/// Locks up, and clears any errors for the next try.
+fn finish_main_flash() {
+    FLASH.sr().modify(|w| {
+        w.set_wrprterr(true);
+        w.set_pgerr(true);
+    });
+    lock();
 }
@@ -0,0 +1,164 @@
+use defmt::{debug, info};
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::cmd_processor::{MAX_PANEL_SLOTS, Message, Notifications, notify};
+use crate::comm::{Address, BROADCAST_ADDRESS, Packet, PanelComm};
+
+/// A panel is reported missing after this many pings in a row go unanswered.
+const MISSING_THRESHOLD: u8 = 2;
+
+const DEFAULT_IDLE_INTERVAL: Duration = Duration::from_secs(5);
+const PING_REPLY_TIME: Duration = Duration::from_millis(40);
+
+struct SlotHealth {
+    id: u8,
+    /// Consecutive unanswered pings
+    misses: u8,
+    /// Last boot count the panel reported, 0 if we haven't heard
+    boot_count: u8,
+    /// When the panel stopped answering, in seconds since boot
+    missing_since_secs: u32,
+}
+
+/// Keeps an eye on the mapped panels while the master is idle.
+///
+/// When nothing has come from the host for a while, the master pings all the
+/// panels and notes which mapped panels didn't answer. If a panel answers with
+/// a new boot count, it has rebooted and lost its slot, so the master sends
+/// the mapping again.
+///
+pub struct HealthMonitor {
+    slots: Vec<SlotHealth, MAX_PANEL_SLOTS>,
+    idle_interval: Option<Duration>,
+}
+
+impl HealthMonitor {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            idle_interval: Some(DEFAULT_IDLE_INTERVAL),
+        }
+    }
+
+    /// How long the master must be idle before checking on the panels, or
+    /// None if health checks are off.
+    pub fn idle_interval(&self) -> Option<Duration> {
+        self.idle_interval
+    }
+
+    pub fn set_idle_interval(&mut self, interval: Option<Duration>) {
+        self.idle_interval = interval;
+    }
+
+    /// Starts watching a new set of mapped panels.
+    pub fn set_mapping(&mut self, ids: &[u8]) {
+        self.slots.clear();
+        for &id in ids {
+            let _ = self.slots.push(SlotHealth {
+                id,
+                misses: 0,
+                boot_count: 0,
+                missing_since_secs: 0,
+            });
+        }
+    }
+
+    /// Pings the panels and updates their health. Resends `mapping` to the
+    /// panels if any of them have rebooted. Changes are reported through
+    /// `notifications`.
+    ///
+    /// This is meant to be raced against incoming commands, so it's fine for
+    /// it to be dropped at any await.
+    ///
+    pub async fn check(
+        &mut self,
+        comm: &mut PanelComm,
+        from: Address,
+        mapping: &[u8],
+        notifications: &mut Notifications,
+    ) {
+        if self.slots.is_empty() {
+            return;
+        }
+
+        debug!("Health check");
+        let mut seen: u32 = 0;
+        let mut remap = false;
+
+        comm.send_packet(&Packet::new(from, BROADCAST_ADDRESS, Message::Ping))
+            .await;
+        let deadline = Instant::now() + PING_REPLY_TIME;
+        while let Either::First(packet) = select(comm.recv_packet(), Timer::at(deadline)).await {
+            if packet.tag != Message::PingReply || packet.data.len() < 2 {
+                continue;
+            }
+            let boot_count = packet.data[0];
+            for (i, slot) in self.slots.iter_mut().enumerate() {
+                if slot.id != packet.from.value() {
+                    continue;
+                }
+                seen |= 1 << i;
+                if slot.boot_count != 0 && slot.boot_count != boot_count {
+                    info!("Panel {} rebooted", slot.id);
+                    notify(notifications, format_args!("!rebooted {:02x}", slot.id));
+                    remap = true;
+                }
+                slot.boot_count = boot_count;
+            }
+        }
+
+        let now_secs = Instant::now().as_secs() as u32;
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            if seen & (1 << i) != 0 {
+                if slot.misses >= MISSING_THRESHOLD {
+                    info!("Panel {} is back", slot.id);
+                    notify(notifications, format_args!("!back {:02x}", slot.id));
+                }
+                slot.misses = 0;
+            } else {
+                if slot.misses == 0 {
+                    slot.missing_since_secs = now_secs;
+                }
+                slot.misses = slot.misses.saturating_add(1);
+                if slot.misses == MISSING_THRESHOLD {
+                    info!("Panel {} is missing", slot.id);
+                    notify(notifications, format_args!("!missing {:02x}", slot.id));
+                }
+            }
+        }
+
+        if remap {
+            let mut packet = Packet::new(from, BROADCAST_ADDRESS, Message::MapPanels);
+            packet.push_data(mapping);
+            comm.send_packet(&packet).await;
+        }
+    }
+
+    /// The mapped panels that have stopped answering, by ID, so the list
+    /// doesn't depend on the mapping's order.
+    pub fn missing(&self) -> impl Iterator<Item = MissingPanel> {
+        let now_secs = Instant::now().as_secs() as u32;
+        let mut missing: Vec<MissingPanel, MAX_PANEL_SLOTS> = self
+            .slots
+            .iter()
+            .filter(|s| s.misses >= MISSING_THRESHOLD)
+            .map(|s| MissingPanel {
+                id: s.id,
+                misses: s.misses,
+                gone_secs: now_secs.wrapping_sub(s.missing_since_secs),
+            })
+            .collect();
+        missing.sort_unstable_by_key(|p| p.id);
+        missing.into_iter()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MissingPanel {
+    pub id: u8,
+    /// Pings in a row it hasn't answered
+    pub misses: u8,
+    pub gone_secs: u32,
+}
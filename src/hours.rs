@@ -0,0 +1,120 @@
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+
+use crate::flash::RecordPage;
+
+/// The log lives in the last two pages of flash, which memory.x leaves out
+const LOG_PAGES: [u32; 2] = [0x0800_F800, 0x0800_FC00];
+
+/// Each record is the hours as a little-endian u32
+const RECORD_LEN: usize = 4;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Erasing a page stops everything for up to 40 ms, so it waits until no
+/// packets have arrived for this long.
+const ERASE_QUIET: Duration = Duration::from_secs(1);
+
+/// How many hours the panel has run, over its whole life, for planning when
+/// to replace LED strips and supplies.
+///
+/// The count is kept in minutes in RAM, and each new hour is appended to a
+/// log in main flash, since the option bytes have neither the room nor the
+/// erase cycles. Up to 59 minutes are lost at each power-off.
+///
+/// The log is two RecordPages used in turn. When one fills up, records go
+/// on in the other, which is erased first if it has to be, and then the full
+/// one is erased at the next boot. The highest record in either page is the
+/// count, so a power loss during an erase or a write only loses the record
+/// being written.
+///
+pub struct OperatingHours {
+    /// Minutes run, including the hours from the log
+    minutes: u32,
+    next_minute: Instant,
+    /// The last hours written to the log
+    saved: u32,
+    pages: [RecordPage<RECORD_LEN>; 2],
+    /// The page the next record goes in
+    page: usize,
+    /// The page not being written to is all erased
+    other_erased: bool,
+}
+
+impl OperatingHours {
+    /// Reads the count from the log, and erases the page that isn't needed
+    /// any more. Meant for boot, before there are packets to handle.
+    pub fn load() -> Self {
+        let mut pages = LOG_PAGES.map(RecordPage::open);
+        let best = pages.each_ref().map(|page| {
+            page.records()
+                .map(|(_, record)| u32::from_le_bytes(record))
+                .max()
+                .unwrap_or(0)
+        });
+        let page = if best[1] > best[0] { 1 } else { 0 };
+        let saved = best[page];
+
+        let other = 1 - page;
+        let mut other_erased = pages[other].is_empty();
+        if !other_erased {
+            match pages[other].erase() {
+                Ok(()) => other_erased = true,
+                Err(e) => warn!("Hours log erase failed: {:?}", e),
+            }
+        }
+        info!("Operating hours {}", saved);
+
+        Self {
+            minutes: saved.saturating_mul(60),
+            next_minute: Instant::now() + MINUTE,
+            saved,
+            pages,
+            page,
+            other_erased,
+        }
+    }
+
+    pub fn hours(&self) -> u32 {
+        self.minutes / 60
+    }
+
+    /// Counts the minutes, and logs each new hour. Call it often, between
+    /// packets, with how long it's been since the last one. Writing a record
+    /// takes about 200 us, and the rare erase waits for ERASE_QUIET.
+    pub fn poll(&mut self, quiet_for: Duration) {
+        if Instant::now() >= self.next_minute {
+            self.minutes = self.minutes.saturating_add(1);
+            self.next_minute += MINUTE;
+        }
+        let hours = self.hours();
+        if hours <= self.saved {
+            return;
+        }
+
+        if self.pages[self.page].is_full() {
+            let other = 1 - self.page;
+            if !self.other_erased {
+                if quiet_for < ERASE_QUIET {
+                    return;
+                }
+                if let Err(e) = self.pages[other].erase() {
+                    warn!("Hours log erase failed: {:?}", e);
+                    // Not again until the next hour
+                    self.saved = hours;
+                    return;
+                }
+            }
+            self.page = other;
+            // The full page is erased at the next boot, or here if it comes
+            // to that first
+            self.other_erased = false;
+        }
+
+        if let Err(e) = self.pages[self.page].append(&hours.to_le_bytes()) {
+            warn!("Hours log write failed: {:?}", e);
+        }
+        // Not again until the next hour, even if it failed
+        self.saved = hours;
+    }
+}
@@ -0,0 +1,101 @@
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::board::{LedStrip, ZoneColors};
+
+const FLASH_ON: Duration = Duration::from_millis(300);
+const FLASH_OFF: Duration = Duration::from_millis(300);
+/// A zero digit is a single flash this long
+const ZERO_ON: Duration = Duration::from_millis(1000);
+const DIGIT_GAP: Duration = Duration::from_millis(1000);
+const REPEAT_GAP: Duration = Duration::from_millis(2000);
+const REPEATS: usize = 2;
+
+const GREEN: [u8; 3] = [0, 0xff, 0];
+const RED: [u8; 3] = [0xff, 0, 0];
+const BLUE: [u8; 3] = [0, 0, 0xff];
+const BLACK: [u8; 3] = [0, 0, 0];
+
+/// Flashes a panel's ID in decimal on its LED strip, for panels in enclosures
+/// where the status LEDs can't be seen.
+///
+/// Each digit is counted out in flashes: green for hundreds, red for tens,
+/// and blue for ones, leaving off leading zeros. A zero is one long flash.
+/// The whole thing is shown twice, then the strip goes back to the colors it
+/// had before.
+///
+/// run() is meant to be raced against packet handling, so all its progress is
+/// kept here and it's fine to drop it at any await. Dropping the IdFlash
+/// leaves the strip as it is, for when a SetColor cancels it.
+///
+pub struct IdFlash {
+    id: u8,
+    /// Next step to show, and when to show it
+    step: usize,
+    step_at: Instant,
+    saved: ZoneColors,
+}
+
+impl IdFlash {
+    pub fn new(id: u8, saved: ZoneColors) -> Self {
+        Self {
+            id,
+            step: 0,
+            step_at: Instant::now(),
+            saved,
+        }
+    }
+
+    /// The colors the strip goes back to at the end.
+    pub fn saved(&self) -> ZoneColors {
+        self.saved
+    }
+
+    /// Shows the steps as they come due, and returns once the strip is back
+    /// to its saved colors.
+    pub async fn run(&mut self, led_strip: &mut LedStrip) {
+        loop {
+            Timer::at(self.step_at).await;
+            let Some(([r, g, b], time)) = nth_step(self.id, self.step) else {
+                led_strip.set_zone_colors(self.saved.0, self.saved.1);
+                return;
+            };
+            led_strip.set_colors(r, g, b);
+            self.step += 1;
+            self.step_at = Instant::now() + time;
+        }
+    }
+}
+
+/// The color to show for step `n` of flashing `id`, and for how long, or None
+/// once it's done.
+fn nth_step(id: u8, mut n: usize) -> Option<([u8; 3], Duration)> {
+    let digits = [(id / 100, GREEN), (id / 10 % 10, RED), (id % 10, BLUE)];
+    let first = match id {
+        100.. => 0,
+        10.. => 1,
+        _ => 2,
+    };
+    for _ in 0..REPEATS {
+        for &(digit, color) in &digits[first..] {
+            let (count, on) = if digit == 0 {
+                (1, ZERO_ON)
+            } else {
+                (digit, FLASH_ON)
+            };
+            for i in 0..count {
+                let off = if i + 1 == count { DIGIT_GAP } else { FLASH_OFF };
+                for step in [(color, on), (BLACK, off)] {
+                    if n == 0 {
+                        return Some(step);
+                    }
+                    n -= 1;
+                }
+            }
+        }
+        if n == 0 {
+            return Some((BLACK, REPEAT_GAP));
+        }
+        n -= 1;
+    }
+    None
+}
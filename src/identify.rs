@@ -0,0 +1,151 @@
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::cmd_processor::{Message, slot_colors};
+use crate::comm::{Address, Packet, PanelComm};
+use crate::status_leds::PANEL_STATUS;
+
+/// How long a single slot is shown
+const HOLD_TIME: Duration = Duration::from_secs(4);
+/// How long each slot is shown when cycling through all of them
+pub const CYCLE_DWELL: Duration = Duration::from_secs(2);
+const BLINK_TIME: Duration = Duration::from_millis(250);
+
+const BLINK_STATUS: u8 = 0x0f;
+const WHITE: [u8; 3] = [0xff, 0xff, 0xff];
+
+/// Makes the panel in a slot stand out, so someone walking the installation
+/// can write down which panel is where.
+///
+/// The panel blinks its status LEDs and lights up white, then goes back to the
+/// color the host last set for its slot. Only that panel is sent anything, so
+/// the mapping is left alone.
+///
+pub struct Identify {
+    /// Slot to show next
+    slot: usize,
+    /// Go on to the following slots until the last one has been shown
+    cycle: bool,
+    /// Slot whose panel is currently lit up
+    showing: Option<usize>,
+}
+
+impl Identify {
+    pub fn slot(slot: usize) -> Self {
+        Self {
+            slot,
+            cycle: false,
+            showing: None,
+        }
+    }
+
+    pub fn all_slots() -> Self {
+        Self {
+            slot: 0,
+            cycle: true,
+            showing: None,
+        }
+    }
+
+    /// Shows the slot, or each slot in turn. Meant to be raced against
+    /// incoming commands, so call restore() if it doesn't finish.
+    ///
+    pub async fn run(
+        &mut self,
+        comm: &mut PanelComm,
+        from: Address,
+        mapping: &[u8],
+        colors: Option<&Packet>,
+    ) {
+        while let Some(&id) = mapping.get(self.slot) {
+            let to = Address(id);
+            self.showing = Some(self.slot);
+
+            let hold = if self.cycle { CYCLE_DWELL } else { HOLD_TIME };
+            blink(comm, from, to, Instant::now() + hold).await;
+
+            self.restore(comm, from, mapping, colors).await;
+            if !self.cycle {
+                break;
+            }
+            self.slot += 1;
+        }
+    }
+
+    /// Puts the panel currently lit up back the way the host left it.
+    /// `colors` is the last SetColor the host had sent, if any.
+    ///
+    pub async fn restore(
+        &mut self,
+        comm: &mut PanelComm,
+        from: Address,
+        mapping: &[u8],
+        colors: Option<&Packet>,
+    ) {
+        let Some(slot) = self.showing.take() else {
+            return;
+        };
+        let Some(&id) = mapping.get(slot) else {
+            return;
+        };
+        put_back(comm, from, Address(id), Some(slot), colors).await;
+    }
+}
+
+/// Blinks the panel's status LEDs and lights it up white until `end`. The
+/// panel stays that way, so follow it with put_back().
+///
+pub async fn blink(comm: &mut PanelComm, from: Address, to: Address, end: Instant) {
+    let mut lit = true;
+    while Instant::now() < end {
+        let status = if lit { BLINK_STATUS } else { 0 };
+        send_status(comm, from, to, status).await;
+        send_color(comm, from, to, Message::SetColor, &WHITE).await;
+        // The panel answers SetColor, but we don't care what it says
+        drain(comm, (Instant::now() + BLINK_TIME).min(end)).await;
+        lit = !lit;
+    }
+}
+
+/// Puts a panel that blink() lit up back the way the host left it: its
+/// color from `colors` if it's in `slot`, otherwise off.
+///
+pub async fn put_back(
+    comm: &mut PanelComm,
+    from: Address,
+    to: Address,
+    slot: Option<usize>,
+    colors: Option<&Packet>,
+) {
+    send_status(comm, from, to, PANEL_STATUS).await;
+    // Both zones of a panel with two go in a single SetColor
+    let tag = match colors.map(|c| c.tag) {
+        Some(Message::SetColorRgbw) => Message::SetColorRgbw,
+        _ => Message::SetColor,
+    };
+    let channels = if tag == Message::SetColorRgbw { 4 } else { 3 };
+    let color = slot
+        .zip(colors)
+        .and_then(|(slot, c)| slot_colors(c, slot))
+        .unwrap_or(&[0; 4][..channels]);
+    send_color(comm, from, to, tag, color).await;
+}
+
+/// Throws away whatever comes in until the deadline.
+async fn drain(comm: &mut PanelComm, deadline: Instant) {
+    while let Either::First(_) = select(comm.recv_packet(), Timer::at(deadline)).await {}
+}
+
+async fn send_status(comm: &mut PanelComm, from: Address, to: Address, status: u8) {
+    let mut packet = Packet::new(from, to, Message::SetStatus);
+    packet.push_data(&[status]);
+    comm.send_packet(&packet).await;
+}
+
+/// Sends SetColor or SetColorRgbw with a single color, or SetColor with one
+/// for each zone, which the panel takes as its own whatever its slot.
+async fn send_color(comm: &mut PanelComm, from: Address, to: Address, tag: Message, color: &[u8]) {
+    let mut packet = Packet::new(from, to, tag);
+    packet.push_data(color);
+    comm.send_packet(&packet).await;
+}
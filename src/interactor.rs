@@ -0,0 +1,293 @@
+use aunisoma_protocol::{ErrorCode, LineTooLong};
+use core::fmt::Write as _;
+use defmt::debug;
+use embassy_futures::select::{Either3, select3};
+
+use crate::reply::ReplyBuf;
+
+/// Longest the port part of the Info reply gets, see Interactor::report()
+pub const PORTS_JSON_LEN: usize = 160;
+
+/// Where commands come in and replies go out, like the serial port and USB,
+/// see Interactor. Writes are queued for the port to send when it can, so
+/// they never wait on a slow host.
+pub trait CommandPort {
+    /// Reads a line. Safe to cancel, and a line that was too long is an
+    /// error, so the host can be told.
+    async fn read_line<'i>(&mut self, into: &'i mut [u8]) -> Result<&'i [u8], LineTooLong>;
+
+    /// Throws away the lines that have already arrived, without waiting for
+    /// more, and returns how many there were.
+    async fn drop_waiting(&mut self) -> usize;
+
+    fn set_echo(&mut self, echo: bool);
+
+    /// Queues a line that isn't a reply, like a notification. It never
+    /// waits, so if the port has fallen behind, output may be dropped.
+    fn write_line(&mut self, line: &[u8]);
+
+    /// Queues a line of a reply, waiting for room if the port has fallen
+    /// behind, so a long reply comes out whole.
+    async fn reply_line(&mut self, line: &[u8]);
+
+    /// Queues the start of a line, for replies that are built in parts,
+    /// waiting for room like reply_line().
+    async fn reply_part(&mut self, part: &[u8]);
+
+    /// Queues a binary capture record, or returns false if something had to
+    /// be dropped. Ports that can't take them drop them all.
+    fn write_record(&mut self, _record: &[u8]) -> bool {
+        false
+    }
+
+    /// Waits until what's queued has been written out.
+    async fn flushed(&self);
+
+    /// Waits for a host to connect (true) or go away (false), for ports that
+    /// can tell.
+    async fn connection_changed() -> bool {
+        core::future::pending().await
+    }
+}
+
+/// Lines through one port since boot.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PortStats {
+    /// Command lines read
+    pub lines_in: u32,
+    /// Whole lines written: replies, notifications, and errors
+    pub lines_out: u32,
+    /// Command lines thrown away unrun, too long or stale
+    pub discarded: u32,
+}
+
+#[derive(Clone, Copy)]
+enum CommandSource {
+    Serial,
+    Usb,
+}
+
+/// Interactor reads commands from the serial port and USB port, and replies to
+/// the port that sent the command. Notifications go to both ports.
+///
+/// Commands are handled one at a time. Lines that arrive meanwhile wait in
+/// the ports, and if a command is so slow they're out of date by the time
+/// it's done, they're thrown away, see drop_stale().
+///
+pub struct Interactor<S, U> {
+    port: S,
+    usb: U,
+    source: CommandSource,
+    /// A reply has been started with reply_part() and not finished
+    mid_line: bool,
+    /// Machine replies for each port, see ReplyBuf
+    serial_terse: bool,
+    usb_terse: bool,
+    serial_stats: PortStats,
+    usb_stats: PortStats,
+}
+
+impl<S: CommandPort, U: CommandPort> Interactor<S, U> {
+    pub fn new(port: S, usb: U) -> Self {
+        Self {
+            port,
+            usb,
+            source: CommandSource::Serial,
+            mid_line: false,
+            serial_terse: false,
+            usb_terse: false,
+            serial_stats: PortStats::default(),
+            usb_stats: PortStats::default(),
+        }
+    }
+
+    pub async fn read_command<'b, const MAX_LEN: usize>(
+        &mut self,
+        buf: &'b mut [u8; MAX_LEN],
+    ) -> &'b [u8] {
+        let mut cmd_buf = [0; MAX_LEN];
+        let mut usb_buf = [0; MAX_LEN];
+        let line = loop {
+            match select3(
+                self.port.read_line(&mut cmd_buf),
+                self.usb.read_line(&mut usb_buf),
+                U::connection_changed(),
+            )
+            .await
+            {
+                Either3::First(Ok(line)) => {
+                    debug!("Command from serial");
+                    self.source = CommandSource::Serial;
+                    self.serial_stats.lines_in = self.serial_stats.lines_in.wrapping_add(1);
+                    break line;
+                }
+                Either3::Second(Ok(line)) => {
+                    debug!("Command from USB");
+                    self.source = CommandSource::Usb;
+                    self.usb_stats.lines_in = self.usb_stats.lines_in.wrapping_add(1);
+                    break line;
+                }
+                // The host is waiting for a reply to a command that never
+                // arrived, so it gets an error instead
+                Either3::First(Err(LineTooLong)) => {
+                    self.serial_stats.discarded = self.serial_stats.discarded.wrapping_add(1);
+                    let reply = line_too_long(self.serial_terse);
+                    self.write_to(CommandSource::Serial, reply.as_bytes());
+                }
+                Either3::Second(Err(LineTooLong)) => {
+                    self.usb_stats.discarded = self.usb_stats.discarded.wrapping_add(1);
+                    let reply = line_too_long(self.usb_terse);
+                    self.write_to(CommandSource::Usb, reply.as_bytes());
+                }
+                // Only to the serial port, since it's about USB
+                Either3::Third(true) => self.write_to(CommandSource::Serial, b"!usb connected"),
+                Either3::Third(false) => self.write_to(CommandSource::Serial, b"!usb disconnected"),
+            }
+        };
+
+        buf[..line.len()].copy_from_slice(line);
+        &buf[..line.len()]
+    }
+
+    /// Throws away the command lines already waiting on both ports, and tells
+    /// each port that had some how many with a `!stale` line. For after a
+    /// command so slow that they're out of date, see STALE_AFTER.
+    pub async fn drop_stale(&mut self) {
+        let mut line = heapless::String::<16>::new();
+        let serial = self.port.drop_waiting().await;
+        if serial > 0 {
+            self.serial_stats.discarded = self.serial_stats.discarded.wrapping_add(serial as u32);
+            let _ = write!(line, "!stale {}", serial);
+            self.write_to(CommandSource::Serial, line.as_bytes());
+        }
+        let usb = self.usb.drop_waiting().await;
+        if usb > 0 {
+            self.usb_stats.discarded = self.usb_stats.discarded.wrapping_add(usb as u32);
+            line.clear();
+            let _ = write!(line, "!stale {}", usb);
+            self.write_to(CommandSource::Usb, line.as_bytes());
+        }
+    }
+
+    /// Waits until everything queued for both ports has been written out, for
+    /// before a reset.
+    pub async fn flushed(&mut self) {
+        self.port.flushed().await;
+        self.usb.flushed().await;
+    }
+
+    /// Turns echo of typed input on or off for the port that sent the
+    /// current command.
+    pub fn set_echo(&mut self, echo: bool) {
+        match self.source {
+            CommandSource::Serial => self.port.set_echo(echo),
+            CommandSource::Usb => self.usb.set_echo(echo),
+        }
+    }
+
+    /// Whether the current command came from USB, rather than the serial
+    /// port.
+    pub fn from_usb(&self) -> bool {
+        matches!(self.source, CommandSource::Usb)
+    }
+
+    /// Turns machine replies on or off for the port that sent the current
+    /// command.
+    pub fn set_terse(&mut self, terse: bool) {
+        match self.source {
+            CommandSource::Serial => self.serial_terse = terse,
+            CommandSource::Usb => self.usb_terse = terse,
+        }
+    }
+
+    /// Whether the port that sent the current command wants machine replies.
+    pub fn is_terse(&self) -> bool {
+        match self.source {
+            CommandSource::Serial => self.serial_terse,
+            CommandSource::Usb => self.usb_terse,
+        }
+    }
+
+    /// Queues a reply to the port that sent the current command. It's
+    /// written out by the port's writer task, so this only waits for a slow
+    /// terminal when the port's queue is full, and nothing is dropped.
+    pub async fn reply(&mut self, line: &str) {
+        self.mid_line = false;
+        match self.source {
+            CommandSource::Serial => {
+                self.port.reply_line(line.as_bytes()).await;
+                self.serial_stats.lines_out = self.serial_stats.lines_out.wrapping_add(1);
+            }
+            CommandSource::Usb => {
+                self.usb.reply_line(line.as_bytes()).await;
+                self.usb_stats.lines_out = self.usb_stats.lines_out.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Writes the start of a reply that's too long to build all at once. The
+    /// rest of it, and the end of the line, is written by reply().
+    pub async fn reply_part(&mut self, part: &str) {
+        self.mid_line = true;
+        match self.source {
+            CommandSource::Serial => self.port.reply_part(part.as_bytes()).await,
+            CommandSource::Usb => self.usb.reply_part(part.as_bytes()).await,
+        }
+    }
+
+    /// Ends a reply that was started with reply_part() and abandoned, so the
+    /// next line starts on a line of its own.
+    pub async fn end_line(&mut self) {
+        if self.mid_line {
+            self.reply("").await;
+        }
+    }
+
+    /// Queues a binary capture record for USB, or returns false if something
+    /// had to be dropped. See capture.
+    pub async fn write_record(&mut self, record: &[u8]) -> bool {
+        self.usb.write_record(record)
+    }
+
+    /// Writes a line to both ports, for things nobody asked about. USB is
+    /// skipped if nothing is connected. Lines are written whole, so this can't
+    /// end up in the middle of a reply.
+    pub async fn broadcast(&mut self, line: &str) {
+        self.write_to(CommandSource::Serial, line.as_bytes());
+        self.write_to(CommandSource::Usb, line.as_bytes());
+    }
+
+    /// Writes the lines through each port, like `, "serialIn":12,
+    /// "serialOut":15, "serialDiscarded":0, "usbIn":0, "usbOut":3,
+    /// "usbDiscarded":0`.
+    pub fn report(&self, w: &mut impl core::fmt::Write) -> core::fmt::Result {
+        for (name, stats) in [("serial", &self.serial_stats), ("usb", &self.usb_stats)] {
+            write!(
+                w,
+                ", \"{name}In\":{}, \"{name}Out\":{}, \"{name}Discarded\":{}",
+                stats.lines_in, stats.lines_out, stats.discarded
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_to(&mut self, to: CommandSource, line: &[u8]) {
+        match to {
+            CommandSource::Serial => {
+                self.port.write_line(line);
+                self.serial_stats.lines_out = self.serial_stats.lines_out.wrapping_add(1);
+            }
+            CommandSource::Usb => {
+                self.usb.write_line(line);
+                self.usb_stats.lines_out = self.usb_stats.lines_out.wrapping_add(1);
+            }
+        }
+    }
+}
+
+fn line_too_long(terse: bool) -> ReplyBuf {
+    let mut reply = ReplyBuf::new();
+    reply.set_terse(terse);
+    reply.error(ErrorCode::LineTooLong, "LineTooLong");
+    reply
+}
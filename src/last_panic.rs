@@ -0,0 +1,74 @@
+use aunisoma_protocol::PanicLocation;
+use cortex_m_rt::{ExceptionFrame, exception};
+use defmt::warn;
+
+use crate::status_leds::StatusLEDs;
+
+/// Where the last panic was, left in RAM that survives the reset after it.
+/// The magic says it was written, rather than left over from power-on.
+#[repr(C)]
+struct PanicRecord {
+    magic: u32,
+    pc: u32,
+}
+
+#[unsafe(link_section = ".noinit")]
+static mut PANIC_RECORD: PanicRecord = PanicRecord { magic: 0, pc: 0 };
+const PANIC_MAGIC_VALUE: u32 = 0x9a41c0de;
+
+static mut LAST_PANIC: Option<PanicLocation> = None;
+
+/// Alternating pairs of status LEDs, unlike anything else they show
+const BLINK_PATTERN: [u8; 2] = [0b1001, 0b0110];
+const BLINKS: u32 = 6;
+/// 80 ms at 72 MHz. The timers may be what faulted, so it's a busy wait.
+const BLINK_CYCLES: u32 = 72_000 * 80;
+
+/// The build uses panic_immediate_abort, so a panic never reaches the panic
+/// handler, it's an undefined instruction right where it happened, which
+/// ends up here. Records the address, blinks, and resets, rather than
+/// hanging until the watchdog resets the board without saying why.
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    cortex_m::interrupt::disable();
+
+    // Safety: Interrupts are off, and nothing else runs after a HardFault
+    unsafe {
+        PANIC_RECORD = PanicRecord {
+            magic: PANIC_MAGIC_VALUE,
+            pc: frame.pc(),
+        };
+    }
+
+    for i in 0..BLINKS as usize {
+        let leds = BLINK_PATTERN[i % 2];
+        StatusLEDs::write_fast(0b1111 & !leds, false);
+        StatusLEDs::write_fast(leds, true);
+        cortex_m::asm::delay(BLINK_CYCLES);
+    }
+
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Takes the panic record, if the last reset was a panic, and clears it, so
+/// it's only reported by the boot after the panic. Called from
+/// check_boot_status().
+pub fn check() {
+    // Safety: We just booted so there aren't any threads
+    unsafe {
+        if PANIC_RECORD.magic == PANIC_MAGIC_VALUE {
+            let location = PanicLocation {
+                pc: PANIC_RECORD.pc,
+            };
+            warn!("Reset after a panic at {:08x}", location.pc);
+            LAST_PANIC = Some(location);
+        }
+        PANIC_RECORD.magic = 0;
+    }
+}
+
+/// Where the board panicked before this boot, or None if it didn't.
+pub fn get() -> Option<PanicLocation> {
+    // Safety: This is only written once at boot time.
+    unsafe { LAST_PANIC }
+}
@@ -0,0 +1,61 @@
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::board::{LedStrip, ZoneColors};
+
+/// The strip goes back to its colors this long after the last press
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Red, green, blue, white, and off, as RGBW. White has the RGB channels on
+/// too, so it's white on strips without a white channel.
+const STEPS: [[u8; 4]; 5] = [
+    [0xff, 0, 0, 0],
+    [0, 0xff, 0, 0],
+    [0, 0, 0xff, 0],
+    [0xff, 0xff, 0xff, 0xff],
+    [0, 0, 0, 0],
+];
+
+/// Steps a panel's LED strip through its colors, one per press of the user
+/// button, so an installer can check the strip without a master.
+///
+/// After TIMEOUT with no presses, the strip goes back to the colors it had
+/// before. Like IdFlash, run() is meant to be raced against packet handling,
+/// and dropping the LedTest leaves the strip as it is, for when a SetColor
+/// cancels it.
+///
+pub struct LedTest {
+    /// Next step to show
+    step: usize,
+    last_press: Instant,
+    saved: ZoneColors,
+}
+
+impl LedTest {
+    pub fn new(saved: ZoneColors) -> Self {
+        Self {
+            step: 0,
+            last_press: Instant::now(),
+            saved,
+        }
+    }
+
+    /// The colors the strip goes back to at the end.
+    pub fn saved(&self) -> ZoneColors {
+        self.saved
+    }
+
+    /// Shows the next color, for a press of the button.
+    pub fn press(&mut self, led_strip: &mut LedStrip) {
+        let [r, g, b, w] = STEPS[self.step];
+        led_strip.set_colors_rgbw(r, g, b, w);
+        self.step = (self.step + 1) % STEPS.len();
+        self.last_press = Instant::now();
+    }
+
+    /// Returns once there have been no presses for TIMEOUT, and the strip is
+    /// back to its saved colors.
+    pub async fn run(&mut self, led_strip: &mut LedStrip) {
+        Timer::at(self.last_press + TIMEOUT).await;
+        led_strip.set_zone_colors(self.saved.0, self.saved.1);
+    }
+}
@@ -0,0 +1,66 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::{Duration, Instant};
+
+/// Per-packet logging is slow enough over RTT to make panels miss replies, so
+/// it's off unless asked for with the K command. Warnings and errors aren't
+/// affected.
+///
+/// To leave it out of the build entirely, lower the level for the comm module
+/// in DEFMT_LOG, e.g. `aunisoma::comm=info`.
+///
+static PACKET_LOGS: AtomicBool = AtomicBool::new(false);
+
+pub fn packet_logs_enabled() -> bool {
+    PACKET_LOGS.load(Ordering::Relaxed)
+}
+
+pub fn set_packet_logs(enabled: bool) {
+    PACKET_LOGS.store(enabled, Ordering::Relaxed);
+}
+
+/// defmt::debug!, for the per-packet hot paths. When packet logging is off,
+/// this is a load and a branch, and nothing is formatted.
+macro_rules! packet_debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::packet_logs_enabled() {
+            defmt::debug!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use packet_debug;
+
+/// Storms of the same message are summarized at most this often
+const STORM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Collapses a message that can repeat many times a second, like a CRC error
+/// on a noisy bus, into a count.
+///
+pub struct Storm {
+    count: u32,
+    last_report: Option<Instant>,
+}
+
+impl Storm {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            last_report: None,
+        }
+    }
+
+    /// Counts the message, and returns how many there have been since the
+    /// last report if it's time to log another one.
+    pub fn hit(&mut self) -> Option<u32> {
+        self.count = self.count.saturating_add(1);
+        let now = Instant::now();
+        if self
+            .last_report
+            .is_some_and(|at| now.duration_since(at) < STORM_INTERVAL)
+        {
+            return None;
+        }
+        self.last_report = Some(now);
+        Some(core::mem::take(&mut self.count))
+    }
+}
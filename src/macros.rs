@@ -0,0 +1,229 @@
+use core::fmt::Write;
+use defmt::{Format, info, warn};
+
+use crate::flash::{FlashError, RecordPage};
+
+/// The macros live in the page before the saved mapping, which memory.x
+/// leaves out
+const MACRO_PAGE: u32 = 0x0800_E800;
+
+/// Most macros kept at once
+pub const MAX_MACROS: usize = 4;
+
+/// Most lines in one macro
+pub const MAX_MACRO_LINES: usize = 8;
+
+/// Longest macro name
+pub const MAX_NAME_LEN: usize = 8;
+
+/// The macro the master runs when it boots, if there is one
+pub const AUTOEXEC: &[u8] = b"autoexec";
+
+/// Separates a macro's lines when it's saved or listed
+pub const LINE_SEPARATOR: u8 = b';';
+
+/// All the macros, one after the other: the name's length, the name, the
+/// number of lines, then each line's length and the line. A name length of 0
+/// ends it early. Each record is one of these.
+const SET_LEN: usize = 320;
+
+type Set = [u8; SET_LEN];
+
+pub type MacroName = heapless::Vec<u8, MAX_NAME_LEN>;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum MacroError {
+    /// MAX_MACROS already, or the lines don't fit with the rest
+    Full,
+    Flash(FlashError),
+}
+
+/// One macro in a Set.
+struct Entry<'a> {
+    name: &'a [u8],
+    /// The line count, then the lines
+    lines: &'a [u8],
+    /// All of it, as it is in the Set
+    bytes: &'a [u8],
+}
+
+/// Named lists of master commands, kept across reboots, for the setup typed
+/// in at every power-up, see x. The one named AUTOEXEC runs at boot.
+///
+/// Like the saved mapping, all the macros are appended to a RecordPage as
+/// one record each time one changes, and the last whole one is used at
+/// boot. Nothing is kept in RAM; each line is read from flash when it's
+/// run. A page with nothing whole on it, or nothing that makes sense, leaves
+/// the master with no macros, and nothing to run at boot, until the next
+/// save.
+///
+pub struct Macros {
+    page: RecordPage<SET_LEN>,
+    /// The slot with the current macros, None if there are none
+    current: Option<usize>,
+}
+
+impl Macros {
+    pub fn load() -> Self {
+        let page = RecordPage::open(MACRO_PAGE);
+        let current = page
+            .records()
+            .rev()
+            .find(|(_, set)| valid(set))
+            .map(|(slot, _)| slot);
+        match current {
+            Some(_) => info!("Macros loaded"),
+            None if !page.is_empty() => warn!("Macro page is corrupt, no macros"),
+            None => {}
+        }
+        Self { page, current }
+    }
+
+    fn set(&self) -> Option<Set> {
+        self.page.read(self.current?)
+    }
+
+    /// How many lines the macro `name` has, or None if there's no such
+    /// macro.
+    pub fn lines(&self, name: &[u8]) -> Option<usize> {
+        let set = self.set()?;
+        let entry = entries(&set).find(|e| e.name == name)?;
+        Some(entry.lines[0] as usize)
+    }
+
+    /// Copies line `index` of the macro `name` into `buf`, and returns its
+    /// length, or None if there's no such line.
+    pub fn line(&self, name: &[u8], index: usize, buf: &mut [u8]) -> Option<usize> {
+        let set = self.set()?;
+        let entry = entries(&set).find(|e| e.name == name)?;
+        let line = lines(entry.lines).nth(index)?;
+        let buf = buf.get_mut(..line.len())?;
+        buf.copy_from_slice(line);
+        Some(line.len())
+    }
+
+    /// Writes macro number `index` like `setup=E;M0a0b0c;L000000`, or
+    /// returns false if there are no more.
+    pub fn write(&self, index: usize, w: &mut impl Write) -> bool {
+        let Some(set) = self.set() else {
+            return false;
+        };
+        let Some(entry) = entries(&set).nth(index) else {
+            return false;
+        };
+        let text = |bytes| core::str::from_utf8(bytes).unwrap_or("?");
+        let _ = write!(w, "{}=", text(entry.name));
+        for (i, line) in lines(entry.lines).enumerate() {
+            if i > 0 {
+                let _ = w.write_char(LINE_SEPARATOR as char);
+            }
+            let _ = w.write_str(text(line));
+        }
+        true
+    }
+
+    /// Saves `lines` as the macro `name`, in place of any macro by that
+    /// name, or deletes it if there are none. The page is erased first if
+    /// it's full. Writing takes about 10 ms, and the rare erase up to 40 ms.
+    pub fn save(&mut self, name: &[u8], new_lines: &[&[u8]]) -> Result<(), MacroError> {
+        let old = self.set().unwrap_or([0; SET_LEN]);
+        if new_lines.is_empty() && !entries(&old).any(|e| e.name == name) {
+            return Ok(());
+        }
+        let mut set = [0; SET_LEN];
+        let mut len = 0;
+        let mut count = 0;
+        for entry in entries(&old).filter(|e| e.name != name) {
+            set[len..len + entry.bytes.len()].copy_from_slice(entry.bytes);
+            len += entry.bytes.len();
+            count += 1;
+        }
+        if !new_lines.is_empty() {
+            let needed = 2 + name.len() + new_lines.iter().map(|l| 1 + l.len()).sum::<usize>();
+            if count == MAX_MACROS || len + needed > SET_LEN {
+                return Err(MacroError::Full);
+            }
+            set[len] = name.len() as u8;
+            set[len + 1..=len + name.len()].copy_from_slice(name);
+            len += 1 + name.len();
+            set[len] = new_lines.len() as u8;
+            len += 1;
+            for line in new_lines {
+                set[len] = line.len() as u8;
+                set[len + 1..=len + line.len()].copy_from_slice(line);
+                len += 1 + line.len();
+            }
+        }
+
+        match self.page.append(&set) {
+            Ok(slot) => {
+                self.current = Some(slot);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Macro write failed: {:?}", e);
+                Err(MacroError::Flash(e))
+            }
+        }
+    }
+}
+
+/// A name of 1 to MAX_NAME_LEN lowercase letters, digits, and underscores.
+pub fn valid_name(name: &[u8]) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .iter()
+            .all(|&b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// The macros in `set`. Stops at the first one that doesn't fit.
+fn entries(set: &Set) -> impl Iterator<Item = Entry<'_>> {
+    let mut at = 0;
+    core::iter::from_fn(move || {
+        let name_len = *set.get(at)? as usize;
+        if name_len == 0 {
+            return None;
+        }
+        let name = set.get(at + 1..at + 1 + name_len)?;
+        let lines_at = at + 1 + name_len;
+        let count = *set.get(lines_at)? as usize;
+        let mut end = lines_at + 1;
+        for _ in 0..count {
+            end += 1 + *set.get(end)? as usize;
+        }
+        let entry = Entry {
+            name,
+            lines: set.get(lines_at..end)?,
+            bytes: set.get(at..end)?,
+        };
+        at = end;
+        Some(entry)
+    })
+}
+
+/// The lines of an Entry.
+fn lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let count = bytes[0] as usize;
+    let mut at = 1;
+    (0..count).map(move |_| {
+        let len = bytes[at] as usize;
+        let line = &bytes[at + 1..at + 1 + len];
+        at += 1 + len;
+        line
+    })
+}
+
+/// Whether every macro in `set` fits, and there aren't too many.
+fn valid(set: &Set) -> bool {
+    let mut count = 0;
+    let mut end = 0;
+    for entry in entries(set) {
+        if !valid_name(entry.name) || entry.lines[0] as usize > MAX_MACRO_LINES {
+            return false;
+        }
+        count += 1;
+        end += entry.bytes.len();
+    }
+    // Anything left over that isn't the end means one didn't fit
+    count <= MAX_MACROS && set.get(end).is_none_or(|&b| b == 0)
+}
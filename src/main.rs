@@ -3,15 +3,14 @@
 
 extern crate alloc;
 
-use board::watchdog_petter;
 use cmd_processor::CmdProcessor;
 use comm::{Address, CommMode, PanelComm, PanelRadio, PanelSerial};
 use command_serial::CommandSerial;
-use defmt::{Format, debug, info};
+use defmt::{Format, info};
 use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_futures::select::{Either3, select3};
 use embedded_alloc::LlffHeap as Heap;
+use interactor::Interactor;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use panic_halt as _;
 use status_leds::StatusLEDs;
@@ -30,6 +29,10 @@ pub enum Mode {
     Master = 1,
     Panel = 2,
     Spy = 3,
+    /// A panel that also passes packets between the radio and the panel bus,
+    /// see PanelComm::start_bridge(). 0 stays invalid, so a blank board
+    /// comes up as a panel, see flash::get_default_mode().
+    Bridge = 4,
 }
 
 #[embassy_executor::main]
@@ -39,7 +42,7 @@ async fn main(spawner: Spawner) {
     // Initialize the heap
     {
         use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 4096;
+        const HEAP_SIZE: usize = 5120;
         static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
         #[allow(static_mut_refs)]
         unsafe {
@@ -52,11 +55,21 @@ async fn main(spawner: Spawner) {
     let board = board::hookup();
 
     board::unleash_the_watchdog();
+    spawner.must_spawn(watchdog::watchdog_task());
 
     StatusLEDs::init(board.status_leds);
+    spawner.must_spawn(status_leds::dimming_task());
 
     flash::init_user_configuration();
 
+    // As early as the color order is known, so a reset mid-show barely
+    // blinks
+    let mut led_strip = board.led_strip;
+    led_strip.set_color_order(flash::get_color_order());
+    if led_strip.restore_colors() {
+        defmt::info!("Restored colors {:?}", led_strip.colors());
+    }
+
     let address = Address(flash::get_my_id());
 
     let mode = boot::determine_mode(address);
@@ -64,37 +77,62 @@ async fn main(spawner: Spawner) {
         boot::toggle_mode(mode).await;
     }
 
-    match mode {
-        Mode::Master => {
-            StatusLEDs::set(0);
-        }
-        Mode::Panel => {
-            StatusLEDs::set(1);
-        }
-        Mode::Spy => {
-            StatusLEDs::set(0);
-            StatusLEDs::set(1);
-        }
-    }
+    show_mode(mode);
 
-    let cmd_port = CommandSerial::new(board.cmd_port);
-    let usb_port = UsbPort::new(board.usb, address, &spawner).await;
+    let cmd_port = CommandSerial::new(board.cmd_port, &spawner);
+    let usb_port = UsbPort::new(board.usb, address, mode, &spawner).await;
     let interactor = Interactor::new(cmd_port, usb_port);
 
     let mut comm_mode = flash::get_comm_mode();
 
-    let mut radio = PanelRadio::new(board.radio);
+    let group = flash::get_group();
+    let mut radio = PanelRadio::new(
+        board.radio,
+        group,
+        flash::get_tx_power(),
+        flash::get_channel(),
+    );
+
+    #[cfg(not(feature = "panel-bus"))]
+    if comm_mode == CommMode::Serial {
+        defmt::error!("No panel bus on this board, using radio comm instead");
+        comm_mode = CommMode::Radio;
+    }
 
-    if comm_mode == CommMode::Radio && radio.init().await.is_err() {
-        defmt::error!("Radio init failed");
-        comm_mode = CommMode::Serial;
+    let mut radio_failed = false;
+    // A bridge needs the radio whatever its comm mode
+    if comm_mode == CommMode::Radio || mode == Mode::Bridge {
+        match radio.init().await {
+            Ok(()) => {}
+            Err(e) => {
+                defmt::error!("Radio init failed ({:?}), using serial comm instead", e);
+                comm_mode = CommMode::Serial;
+                radio_failed = true;
+            }
+        }
     }
 
-    let panel_serial = PanelSerial::new(board.panel_bus, address);
+    #[cfg(feature = "panel-bus")]
+    let panel_serial = PanelSerial::new(board.panel_bus, address, flash::get_bus_baud(), group);
+    #[cfg(not(feature = "panel-bus"))]
+    let panel_serial = PanelSerial::absent(flash::get_bus_baud());
+
+    let mut comm = PanelComm::new(comm_mode, radio, panel_serial);
+    if mode == Mode::Bridge {
+        if radio_failed {
+            defmt::error!("No radio, so not bridging");
+        } else {
+            comm.start_bridge(address);
+        }
+    }
+    // A spy only listens, whatever it's asked to do
+    if mode == Mode::Spy {
+        comm.inhibit_tx();
+    }
 
-    let comm = PanelComm::new(comm_mode, radio, panel_serial);
+    self_test::run(&mut led_strip, &board.pirs, radio_failed).await;
 
-    let cmd_processor = CmdProcessor::new(interactor, comm, address, board.led_strip, board.pirs);
+    let mut cmd_processor = CmdProcessor::new(interactor, comm, address, led_strip, board.pirs);
 
     info!(
         "Aunisoma version {} ID={} Mode={:?} Comm={:?}",
@@ -104,79 +142,34 @@ async fn main(spawner: Spawner) {
         comm_mode
     );
 
-    match mode {
-        Mode::Master => cmd_processor.run_master().await,
-        Mode::Panel => cmd_processor.run_panel().await,
-        Mode::Spy => cmd_processor.run_spy().await,
-    }
-}
-
-enum CommandSource {
-    Serial,
-    Usb,
-}
-
-/// Interactor reads commands from the serial port and USB port, and replies to
-/// the port that sent the command.
-///
-pub struct Interactor<'a> {
-    port: CommandSerial<'a>,
-    usb: UsbPort,
-    source: CommandSource,
-}
-
-impl<'a> Interactor<'a> {
-    fn new(port: CommandSerial<'a>, usb: UsbPort) -> Self {
-        Self {
-            port,
-            usb,
-            source: CommandSource::Serial,
-        }
+    if mode == Mode::Master {
+        cmd_processor.replay_mapping().await;
+        cmd_processor.run_autoexec().await;
     }
 
-    pub async fn read_command<'b, const MAX_LEN: usize>(
-        &mut self,
-        buf: &'b mut [u8; MAX_LEN],
-    ) -> &'b [u8] {
-        let mut cmd_buf = [0; MAX_LEN];
-        let mut usb_buf = [0; MAX_LEN];
-        let line = loop {
-            match select3(
-                watchdog_petter(),
-                self.port.read_line(&mut cmd_buf),
-                self.usb.read_line(&mut usb_buf),
-            )
-            .await
-            {
-                Either3::First(_) => {
-                    // Watchdog petted
-                }
-                Either3::Second(line) => {
-                    debug!("Command from serial");
-                    self.source = CommandSource::Serial;
-                    break line;
-                }
-                Either3::Third(line) => {
-                    debug!("Command from USB");
-                    self.source = CommandSource::Usb;
-                    break line;
-                }
-            }
+    loop {
+        let done = match mode {
+            Mode::Master => cmd_processor.run_master().await,
+            Mode::Panel => cmd_processor.run_panel().await,
+            Mode::Spy => cmd_processor.run_spy().await,
+            Mode::Bridge => cmd_processor.run_bridge().await,
         };
-
-        buf[..line.len()].copy_from_slice(line);
-        &buf[..line.len()]
+        info!("Soft restart");
+        cmd_processor = done.restarted();
+        show_mode(mode);
     }
+}
 
-    pub async fn reply(&mut self, line: &str) {
-        match self.source {
-            CommandSource::Serial => self.port.write_line(line.as_bytes()).await,
-            CommandSource::Usb => self.usb.write_line(line.as_bytes()).await,
-        }
-    }
+/// Shows the mode on the status LEDs, which is where they start out.
+fn show_mode(mode: Mode) {
+    StatusLEDs::set_all(status_leds::mode_leds(mode));
 }
 
-// Can't do this, because the panic strings are too big for flash
+/// Longest command line, the size of the buffers they're read into
+pub const MAX_COMMAND_LEN: usize = 256;
+
+// Can't do this, because the panic strings are too big for flash. Panics
+// abort instead, and last_panic records where.
 //
 // #[inline(never)]
 // #[panic_handler]
@@ -185,14 +178,39 @@ impl<'a> Interactor<'a> {
 //     loop {}
 // }
 
+mod animation;
 mod board;
 mod boot;
+#[cfg(feature = "panel-bus")]
+mod bus_mirror;
+mod button;
+mod capture;
 mod cmd_processor;
 mod comm;
 mod command_serial;
+mod correction;
 mod debouncer;
 mod flash;
-mod line_breaker;
+mod health;
+mod hours;
+mod id_flash;
+mod identify;
+mod interactor;
+mod last_panic;
+mod led_test;
+mod logging;
+mod macros;
+mod outbox;
+mod output;
+mod pir;
+mod pir_stream;
+mod reply;
+mod saved_mapping;
+mod self_test;
+mod sim;
+mod stats;
 mod status_leds;
+mod status_mirror;
 mod usb_port;
 mod version;
+mod watchdog;
@@ -0,0 +1,102 @@
+use core::fmt::Write;
+use embassy_time::Instant;
+use heapless::Deque;
+
+use crate::cmd_processor::{MAX_PANEL_SLOTS, Message};
+use crate::comm::{Address, BROADCAST_ADDRESS, Packet, PanelComm};
+
+/// Longest the outbox part of the Info reply gets, see Outbox::report()
+pub const OUTBOX_JSON_LEN: usize = 48;
+
+/// A message the master sends on its own, rather than for a command, which
+/// waits in the Outbox for a gap.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Queued {
+    /// Our clock, for the panels, stamped when it goes out, not when queued
+    TimeSync,
+    /// What a panel's status LEDs should show, see StatusMirror
+    SetStatus { to: Address, status: u8 },
+}
+
+/// The master's management messages, held for the gaps between commands so
+/// they can't land in a frame's reply window.
+///
+/// TimeSync goes before SetStatus, and only one is ever waiting, since a
+/// second would say the same thing. SetStatus waits for up to
+/// MAX_PANEL_SLOTS, enough for every panel after a new mapping. Past that,
+/// the oldest is dropped and counted, so the newest status gets through.
+/// send() sends a few at a time, so a backlog drains over several gaps
+/// rather than holding up the next frame.
+///
+pub struct Outbox {
+    time_sync: bool,
+    statuses: Deque<(Address, u8), MAX_PANEL_SLOTS>,
+    dropped: u32,
+}
+
+impl Outbox {
+    pub const fn new() -> Self {
+        Self {
+            time_sync: false,
+            statuses: Deque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, message: Queued) {
+        match message {
+            Queued::TimeSync => self.time_sync = true,
+            Queued::SetStatus { to, status } => {
+                if self.statuses.is_full() {
+                    self.statuses.pop_front();
+                    self.dropped = self.dropped.wrapping_add(1);
+                }
+                let _ = self.statuses.push_back((to, status));
+            }
+        }
+    }
+
+    /// Forgets the SetStatus messages waiting, for when they'd be out of
+    /// date as soon as they're sent.
+    pub fn clear_statuses(&mut self) {
+        self.statuses.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.time_sync as usize + self.statuses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sends up to `budget` of the messages waiting, TimeSync first.
+    pub async fn send(&mut self, comm: &mut PanelComm, from: Address, budget: usize) {
+        for _ in 0..budget {
+            let packet = if self.time_sync {
+                self.time_sync = false;
+                let mut packet = Packet::new(from, BROADCAST_ADDRESS, Message::TimeSync);
+                packet.push_data(&(Instant::now().as_millis() as u32).to_le_bytes());
+                packet
+            } else if let Some((to, status)) = self.statuses.pop_front() {
+                let mut packet = Packet::new(from, to, Message::SetStatus);
+                packet.push_data(&[status]);
+                packet
+            } else {
+                return;
+            };
+            comm.send_packet(&packet).await;
+        }
+    }
+
+    /// Writes how backed up it is, like `, "outboxDepth":0,
+    /// "outboxDropped":12`.
+    pub fn report(&self, w: &mut impl Write) -> core::fmt::Result {
+        write!(
+            w,
+            ", \"outboxDepth\":{}, \"outboxDropped\":{}",
+            self.len(),
+            self.dropped
+        )
+    }
+}
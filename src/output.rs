@@ -0,0 +1,195 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, TrySendError};
+use embassy_time::Timer;
+use heapless::Vec;
+
+use crate::reply::REPLY_LEN;
+
+/// How many chunks can wait for each port's writer task
+const QUEUE_LEN: usize = 4;
+
+/// A whole reply and its newline fit in one chunk
+pub const CHUNK_LEN: usize = REPLY_LEN + 1;
+
+/// Some output on its way to a port.
+pub struct Chunk {
+    /// A binary capture record rather than text, see capture
+    pub record: bool,
+    /// Part of a command's reply, which is never dropped
+    reply: bool,
+    pub data: Vec<u8, CHUNK_LEN>,
+}
+
+/// Output waiting for a port's writer task, so whoever is replying doesn't
+/// have to wait for a slow terminal to drain it.
+///
+/// Replies to commands wait for room, see send_text(), so they come out
+/// whole, however long they are. Everything else, like notifications, echo,
+/// and capture records, is queued at once: when the writer can't keep up
+/// and the queue is full, the oldest chunk is dropped to make room, and
+/// counted. While a reply is waiting in the queue, it's the new chunk that's
+/// dropped instead, so a reply never loses a piece.
+///
+pub struct OutputQueue {
+    chunks: Channel<CriticalSectionRawMutex, Chunk, QUEUE_LEN>,
+    /// Reply chunks in the queue
+    replies: AtomicUsize,
+    /// The writer task has a chunk it hasn't finished with, see flushed()
+    writing: AtomicBool,
+}
+
+/// Chunks dropped from all the queues since power-up, for the J command
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+pub fn dropped_count() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+impl OutputQueue {
+    pub const fn new() -> Self {
+        Self {
+            chunks: Channel::new(),
+            replies: AtomicUsize::new(0),
+            writing: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues the parts of a reply one after the other, in as many chunks as
+    /// it takes, waiting for room rather than dropping anything. Safe to
+    /// cancel, though the reply is then cut short.
+    pub async fn send_text(&self, parts: &[&[u8]]) {
+        let mut data = Vec::new();
+        for part in parts {
+            let mut rest = *part;
+            while !rest.is_empty() {
+                let n = rest.len().min(CHUNK_LEN - data.len());
+                // Can't fail, n is no more than the room left
+                let _ = data.extend_from_slice(&rest[..n]);
+                rest = &rest[n..];
+                if data.is_full() {
+                    self.send(core::mem::take(&mut data)).await;
+                }
+            }
+        }
+        if !data.is_empty() {
+            self.send(data).await;
+        }
+    }
+
+    async fn send(&self, data: Vec<u8, CHUNK_LEN>) {
+        let mut chunk = Chunk {
+            record: false,
+            reply: true,
+            data,
+        };
+        loop {
+            match self.chunks.try_send(chunk) {
+                Ok(()) => {
+                    // Counted before the writer task can run and take it
+                    self.replies.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(TrySendError::Full(c)) => {
+                    chunk = c;
+                    Timer::after_millis(1).await;
+                }
+            }
+        }
+    }
+
+    /// Queues the parts one after the other, in as many chunks as it takes.
+    /// Returns false if output had to be dropped to make room.
+    pub fn push_text(&self, parts: &[&[u8]]) -> bool {
+        let mut ok = true;
+        let mut data = Vec::new();
+        for part in parts {
+            let mut rest = *part;
+            while !rest.is_empty() {
+                let n = rest.len().min(CHUNK_LEN - data.len());
+                // Can't fail, n is no more than the room left
+                let _ = data.extend_from_slice(&rest[..n]);
+                rest = &rest[n..];
+                if data.is_full() {
+                    ok &= self.push(false, core::mem::take(&mut data));
+                }
+            }
+        }
+        if !data.is_empty() {
+            ok &= self.push(false, data);
+        }
+        ok
+    }
+
+    /// Queues a capture record, which has to go out in one piece. Returns
+    /// false if it's too big, or older output had to be dropped to make room.
+    pub fn push_record(&self, record: &[u8]) -> bool {
+        match Vec::from_slice(record) {
+            Ok(data) => self.push(true, data),
+            Err(()) => false,
+        }
+    }
+
+    /// Queues a record only if there's room, for output that should give
+    /// way rather than push out what's already waiting. Returns false if it
+    /// was dropped.
+    pub fn try_push_record(&self, record: &[u8]) -> bool {
+        let Ok(data) = Vec::from_slice(record) else {
+            return false;
+        };
+        let chunk = Chunk {
+            record: true,
+            reply: false,
+            data,
+        };
+        self.chunks.try_send(chunk).is_ok()
+    }
+
+    fn push(&self, record: bool, data: Vec<u8, CHUNK_LEN>) -> bool {
+        let mut chunk = Chunk {
+            record,
+            reply: false,
+            data,
+        };
+        let mut dropped = false;
+        loop {
+            match self.chunks.try_send(chunk) {
+                Ok(()) => return !dropped,
+                Err(TrySendError::Full(_)) if self.replies.load(Ordering::Relaxed) > 0 => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                Err(TrySendError::Full(c)) => {
+                    chunk = c;
+                    let _ = self.chunks.try_receive();
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    dropped = true;
+                }
+            }
+        }
+    }
+
+    /// Waits for the next chunk to write. Calling it again says the last one
+    /// has been written, or dropped.
+    pub async fn pop(&self) -> Chunk {
+        self.writing.store(false, Ordering::Release);
+        let chunk = self.chunks.receive().await;
+        self.writing.store(true, Ordering::Release);
+        if chunk.reply {
+            self.replies.fetch_sub(1, Ordering::Relaxed);
+        }
+        chunk
+    }
+
+    /// Waits until everything queued has been written, or dropped, for before
+    /// a reset.
+    pub async fn flushed(&self) {
+        while !self.chunks.is_empty() || self.writing.load(Ordering::Acquire) {
+            Timer::after_millis(1).await;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
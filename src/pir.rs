@@ -0,0 +1,302 @@
+use embassy_time::{Duration, Instant};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::board::Pirs;
+use crate::cmd_processor::MAX_PANEL_SLOTS;
+
+/// How often a panel samples its PIRs, see PirSensors::sample(). Matches the
+/// resolution of the minimum active time.
+pub const PIR_SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many sensor bits a panel reports: PIR1, PIR2, then the extra inputs,
+/// see PirSensors::read()
+pub const SENSOR_BITS: usize = 4;
+
+/// The sensor bits that are PIRs, which are the ones that mean motion
+pub const PIR_BITS: u8 = 0b0011;
+
+/// The extra inputs are switches, so they're only debounced
+const AUX_MIN_ACTIVE: Duration = Duration::from_millis(50);
+
+/// Which set of PIR timings is in use. Panels start with A, and the master
+/// switches all of them at once with SetPirProfile, e.g. at sunset. Kept in
+/// RAM only.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PirProfile {
+    A = 0,
+    B = 1,
+}
+
+impl PirProfile {
+    pub fn name(self) -> &'static str {
+        match self {
+            PirProfile::A => "A",
+            PirProfile::B => "B",
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"A" => Some(PirProfile::A),
+            b"B" => Some(PirProfile::B),
+            _ => None,
+        }
+    }
+}
+
+/// How long a PIR must be active to count, and how long to ignore it after
+/// it goes quiet, for one PirProfile.
+#[derive(Debug, Clone, Copy, Default)]
+struct PirTiming {
+    min_active_10ms: [u8; 2],
+    refractory_100ms: u8,
+}
+
+/// How the PIR inputs are interpreted. Kept in RAM only, so the master has to
+/// push it again after a panel reboots.
+///
+/// Wire format (in the PirConfig message and the `F` command) is three or
+/// five bytes:
+///
+/// [invert, min_active_1, min_active_2, (refractory, profile)]
+///
+/// `invert` has bit 0 set to invert PIR1 and bit 1 set to invert PIR2, for
+/// modules that idle high, and bits 2 and 3 invert the extra inputs, if any.
+/// `min_active_N` is how long PIR N must be continuously active before it
+/// counts as a detection, in units of 10 ms. `refractory` is how long a PIR is
+/// ignored after a detection ends, in units of 100 ms, and `profile` is the
+/// PirProfile the timings are for. The short form sets profile A with no
+/// refractory period. The extra inputs are always debounced for 50 ms, with
+/// no refractory period.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct PirConfig {
+    pub invert: u8,
+    pub min_active_10ms: [u8; 2],
+    pub refractory_100ms: u8,
+    pub profile: PirProfile,
+}
+
+impl PirConfig {
+    pub const WIRE_LEN: usize = 3;
+    pub const LONG_WIRE_LEN: usize = 5;
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [invert, min_1, min_2] => Some(Self {
+                invert,
+                min_active_10ms: [min_1, min_2],
+                refractory_100ms: 0,
+                profile: PirProfile::A,
+            }),
+            [invert, min_1, min_2, refractory, profile] => Some(Self {
+                invert,
+                min_active_10ms: [min_1, min_2],
+                refractory_100ms: refractory,
+                profile: PirProfile::try_from(profile).ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The short form if it says everything, so older panels understand it.
+    pub fn to_bytes(&self) -> heapless::Vec<u8, { Self::LONG_WIRE_LEN }> {
+        let mut bytes = heapless::Vec::new();
+        let _ = bytes.extend_from_slice(&[
+            self.invert,
+            self.min_active_10ms[0],
+            self.min_active_10ms[1],
+        ]);
+        if self.refractory_100ms != 0 || self.profile != PirProfile::A {
+            let _ = bytes.extend_from_slice(&[self.refractory_100ms, self.profile.into()]);
+        }
+        bytes
+    }
+}
+
+/// The PIR inputs, filtered according to a PirConfig and the active
+/// PirProfile, and the extra inputs on boards built with aux-sensors. Inputs
+/// a board doesn't have always read 0.
+///
+/// The inputs are only looked at when sample() is called, every
+/// PIR_SAMPLE_INTERVAL from the panel's run loop. read() returns the result of
+/// the last sample, so replies don't wait on the inputs.
+///
+pub struct PirSensors {
+    pirs: Pirs,
+    invert: u8,
+    timings: [PirTiming; 2],
+    profile: PirProfile,
+    active_since: [Option<Instant>; SENSOR_BITS],
+    detected: [bool; SENSOR_BITS],
+    ignore_until: [Option<Instant>; SENSOR_BITS],
+    /// Detections as of the last sample
+    bits: u8,
+}
+
+impl PirSensors {
+    pub fn new(pirs: Pirs) -> Self {
+        Self {
+            pirs,
+            invert: 0,
+            timings: [PirTiming::default(); 2],
+            profile: PirProfile::A,
+            active_since: [None; SENSOR_BITS],
+            detected: [false; SENSOR_BITS],
+            ignore_until: [None; SENSOR_BITS],
+            bits: 0,
+        }
+    }
+
+    /// Gives back the pins, forgetting the config.
+    pub fn into_pirs(self) -> Pirs {
+        self.pirs
+    }
+
+    pub fn set_config(&mut self, config: PirConfig) {
+        self.invert = config.invert;
+        self.timings[config.profile as usize] = PirTiming {
+            min_active_10ms: config.min_active_10ms,
+            refractory_100ms: config.refractory_100ms,
+        };
+        self.reset();
+    }
+
+    pub fn profile(&self) -> PirProfile {
+        self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: PirProfile) {
+        if profile != self.profile {
+            self.profile = profile;
+            self.reset();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.active_since = [None; SENSOR_BITS];
+        self.detected = [false; SENSOR_BITS];
+        self.ignore_until = [None; SENSOR_BITS];
+        self.bits = 0;
+    }
+
+    /// Returns the bitwise OR of 1 for PIR1, 2 for PIR2, and 4 and 8 for the
+    /// extra inputs, as of the last sample.
+    pub fn read(&self) -> u8 {
+        self.bits
+    }
+
+    /// Looks at the inputs and updates what read() returns.
+    pub fn sample(&mut self) {
+        let now = Instant::now();
+        let [aux_1, aux_2] = &self.pirs.aux;
+        let raw = [
+            Some(self.pirs.pir_1.is_high()),
+            Some(self.pirs.pir_2.is_high()),
+            aux_1.as_ref().map(|input| input.is_high()),
+            aux_2.as_ref().map(|input| input.is_high()),
+        ];
+        let timing = self.timings[self.profile as usize];
+
+        let mut bits = 0;
+        for (i, &high) in raw.iter().enumerate() {
+            let Some(high) = high else {
+                continue;
+            };
+            let is_pir = PIR_BITS & (1 << i) != 0;
+            let inverted = self.invert & (1 << i) != 0;
+            if high == inverted {
+                self.active_since[i] = None;
+                if core::mem::take(&mut self.detected[i]) && is_pir {
+                    let refractory = Duration::from_millis(timing.refractory_100ms as u64 * 100);
+                    self.ignore_until[i] = Some(now + refractory);
+                }
+                continue;
+            }
+            if self.ignore_until[i].is_some_and(|until| now < until) {
+                continue;
+            }
+
+            let since = *self.active_since[i].get_or_insert(now);
+            let min_active = match timing.min_active_10ms.get(i) {
+                Some(&min) => Duration::from_millis(min as u64 * 10),
+                None => AUX_MIN_ACTIVE,
+            };
+            if now - since >= min_active {
+                self.detected[i] = true;
+                bits |= 1 << i;
+            }
+        }
+        self.bits = bits;
+    }
+}
+
+const PIR_LOG_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PirEvent {
+    /// Milliseconds since boot
+    pub time_ms: u32,
+    pub slot: u8,
+    pub pirs: u8,
+}
+
+/// Master-side log of when motion started in each slot.
+///
+/// An event is logged when a slot's PIR bits go from 0 to nonzero. When the
+/// log is full, the oldest event is overwritten and counted as dropped.
+///
+pub struct PirLog {
+    events: heapless::Deque<PirEvent, PIR_LOG_LEN>,
+    last_pirs: [u8; MAX_PANEL_SLOTS],
+    dropped: u32,
+}
+
+impl PirLog {
+    pub fn new() -> Self {
+        Self {
+            events: heapless::Deque::new(),
+            last_pirs: [0; MAX_PANEL_SLOTS],
+            dropped: 0,
+        }
+    }
+
+    /// Notes the PIR bits a slot reported, logging an event if motion just
+    /// started.
+    pub fn update(&mut self, slot: usize, pirs: u8) {
+        let Some(last) = self.last_pirs.get_mut(slot) else {
+            return;
+        };
+        let started = *last == 0 && pirs != 0;
+        *last = pirs;
+        if !started {
+            return;
+        }
+
+        if self.events.is_full() {
+            self.events.pop_front();
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        let _ = self.events.push_back(PirEvent {
+            time_ms: Instant::now().as_millis() as u32,
+            slot: slot as u8,
+            pirs,
+        });
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &PirEvent> {
+        self.events.iter()
+    }
+
+    /// Events overwritten since the log was last cleared.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.dropped = 0;
+    }
+}
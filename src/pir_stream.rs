@@ -0,0 +1,83 @@
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::cmd_processor::{MAX_PANEL_SLOTS, Message, Notifications, notify};
+use crate::comm::{Address, BROADCAST_ADDRESS, Packet, PanelComm};
+use crate::pir::PIR_BITS;
+
+/// Shortest interval between polls, so the replies to one are in before the
+/// next
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Every slot gets a reply window of 1 ms, like an L
+const POLL_REPLY_TIME: Duration = Duration::from_millis(MAX_PANEL_SLOTS as u64);
+
+/// Tells the host when the panels see motion, without it having to send L.
+///
+/// While the master is idle, it polls the mapped panels' PIRs with a short
+/// PollPirs broadcast, and compares which slots saw motion with the last
+/// poll. When that changes, a `PIR` line goes to the host. A steady
+/// installation sends nothing. Panels that don't answer count as no motion.
+///
+pub struct PirStream {
+    interval: Duration,
+    next_poll: Instant,
+    /// A bit for each slot whose panel saw motion, as of the last poll, or
+    /// None before the first
+    last: Option<u32>,
+}
+
+impl PirStream {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_poll: Instant::now(),
+            last: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn next_poll(&self) -> Instant {
+        self.next_poll
+    }
+
+    /// Polls the panels in `mapping`, and queues a `PIR` line if the slots
+    /// with motion changed. Fine to drop at any await, the poll is just
+    /// missed.
+    pub async fn poll(
+        &mut self,
+        comm: &mut PanelComm,
+        from: Address,
+        mapping: &[u8],
+        notifications: &mut Notifications,
+    ) {
+        // Don't try to catch up on polls that were missed
+        self.next_poll = (self.next_poll + self.interval).max(Instant::now());
+
+        comm.send_packet(&Packet::new(from, BROADCAST_ADDRESS, Message::PollPirs))
+            .await;
+        let mut bits = 0u32;
+        let deadline = Instant::now() + POLL_REPLY_TIME;
+        while let Either::First(packet) = select(comm.recv_packet(), Timer::at(deadline)).await {
+            let [pirs] = packet.data[..] else {
+                continue;
+            };
+            if packet.tag != Message::SetColorReply || pirs & PIR_BITS == 0 {
+                continue;
+            }
+            for (slot, &id) in mapping.iter().enumerate() {
+                if id == packet.from.value() {
+                    bits |= 1 << slot;
+                }
+            }
+        }
+
+        if self.last != Some(bits) {
+            self.last = Some(bits);
+            notify(notifications, format_args!("PIR {:08x}", bits));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use aunisoma_protocol::ErrorCode;
+use core::fmt;
+use core::fmt::Write as _;
+use core::ops::Deref;
+
+pub const REPLY_LEN: usize = 256;
+
+/// A reply line being built.
+///
+/// If something doesn't fit, the reply is replaced by
+/// `ERROR ReplyTooLarge {needed} {capacity}`, so the host never gets a
+/// fragment of JSON or a PIR string that's missing panels. Anything added
+/// after that only counts towards {needed}. Commands with long replies should
+/// send them in parts, see Interactor::reply_part().
+///
+/// Replies that say how a command went, like `OK` and errors, are written
+/// with ok(), error(), and the like, so they come out terse when the host
+/// asked for machine replies, see v.
+///
+pub struct ReplyBuf {
+    buf: heapless::String<REPLY_LEN>,
+    /// How long the reply would have been, once it has overflowed
+    overflow: Option<usize>,
+    /// Machine replies, `+` and `-`{code} instead of words
+    terse: bool,
+}
+
+impl ReplyBuf {
+    pub fn new() -> Self {
+        Self {
+            buf: heapless::String::new(),
+            overflow: None,
+            terse: false,
+        }
+    }
+
+    /// Switches to machine replies or back, for the next command.
+    pub fn set_terse(&mut self, terse: bool) {
+        self.terse = terse;
+    }
+
+    /// `OK`, or `+` for machines.
+    pub fn ok(&mut self) {
+        let _ = self.push_str(if self.terse { "+" } else { "OK" });
+    }
+
+    /// `ERROR ` and `text`, or `-` and the code for machines.
+    pub fn error(&mut self, code: ErrorCode, text: impl fmt::Display) {
+        self.status(code, format_args!("ERROR {}", text), None);
+    }
+
+    /// Like error(), but machines get the column after the code too, for a
+    /// bad hex digit.
+    pub fn error_at(&mut self, code: ErrorCode, text: impl fmt::Display, column: usize) {
+        self.status(code, format_args!("ERROR {}", text), Some(column));
+    }
+
+    /// The start of a `FAILED ` reply, or of `-` and the code for machines.
+    /// The IDs go after it either way.
+    pub fn failed(&mut self) {
+        self.status(ErrorCode::Failed, "FAILED", None);
+        let _ = self.push(' ');
+    }
+
+    /// The start of a `MISMATCH ` reply, or of `-` and the code for
+    /// machines. The slots go after it either way.
+    pub fn mismatch(&mut self) {
+        self.status(ErrorCode::Mismatch, "MISMATCH", None);
+        let _ = self.push(' ');
+    }
+
+    /// `TIMEOUT`, or `-` and the code for machines.
+    pub fn timeout(&mut self) {
+        self.status(ErrorCode::Timeout, "TIMEOUT", None);
+    }
+
+    /// `STANDBY`, or `-` and the code for machines.
+    pub fn standby(&mut self) {
+        self.status(ErrorCode::Standby, "STANDBY", None);
+    }
+
+    fn status(&mut self, code: ErrorCode, human: impl fmt::Display, payload: Option<usize>) {
+        let _ = match (self.terse, payload) {
+            (false, _) => write!(self, "{}", human),
+            (true, None) => write!(self, "-{}", u8::from(code)),
+            (true, Some(payload)) => write!(self, "-{} {}", u8::from(code), payload),
+        };
+    }
+
+    /// Empties the reply for the next one. Terse or not stays as it is.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.overflow = None;
+    }
+
+    /// How much more fits before the reply overflows.
+    pub fn room(&self) -> usize {
+        match self.overflow {
+            Some(_) => 0,
+            None => REPLY_LEN - self.buf.len(),
+        }
+    }
+
+    /// Whether something didn't fit, and the reply is now an error.
+    pub fn overflowed(&self) -> bool {
+        self.overflow.is_some()
+    }
+
+    pub fn push_str(&mut self, s: &str) -> fmt::Result {
+        if s.len() <= self.room() {
+            // Can't fail, there's room
+            let _ = self.buf.push_str(s);
+            return Ok(());
+        }
+
+        let needed = self.overflow.unwrap_or(self.buf.len()) + s.len();
+
+        defmt::warn!("Reply too large: {} bytes", needed);
+        self.overflow = Some(needed);
+        self.buf.clear();
+        // Can't fail, it's far shorter than REPLY_LEN
+        let _ = match self.terse {
+            false => write!(self.buf, "ERROR ReplyTooLarge {} {}", needed, REPLY_LEN),
+            true => write!(
+                self.buf,
+                "-{} {} {}",
+                u8::from(ErrorCode::ReplyTooLarge),
+                needed,
+                REPLY_LEN
+            ),
+        };
+        Err(fmt::Error)
+    }
+
+    pub fn push(&mut self, c: char) -> fmt::Result {
+        self.push_str(c.encode_utf8(&mut [0; 4]))
+    }
+}
+
+impl fmt::Write for ReplyBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s)
+    }
+}
+
+impl Deref for ReplyBuf {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.buf
+    }
+}
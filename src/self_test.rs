@@ -0,0 +1,109 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::board::{LedStrip, Pirs};
+use crate::boot;
+use crate::pir::PIR_SAMPLE_INTERVAL;
+use crate::status_leds::StatusLEDs;
+
+// Faults found by the self-test, as bits of the result
+
+/// PIR1 never went low
+pub const FAULT_PIR_1: u8 = 1 << 0;
+/// PIR2 never went low
+pub const FAULT_PIR_2: u8 = 1 << 1;
+/// The radio didn't answer, or didn't read back what was written to it
+pub const FAULT_RADIO: u8 = 1 << 2;
+/// The panel bus is stuck, see PanelSerial. Unlike the others, this one is
+/// found while running, and clears once the bus works again.
+pub const FAULT_PANEL_BUS: u8 = 1 << 3;
+
+/// Each LED channel is lit this long
+const CHANNEL_TIME: Duration = Duration::from_millis(150);
+/// Dim, so a strip on a weak supply doesn't brown out the board
+const TEST_BRIGHTNESS: u8 = 0x20;
+/// How long the result stays on the status LEDs
+const SHOW_TIME: Duration = Duration::from_secs(2);
+
+static FAULTS: AtomicU8 = AtomicU8::new(0);
+
+/// What the self-test found at power-on, and whether the panel bus is stuck
+/// now, 0 if nothing was wrong. See the FAULT_ bits.
+pub fn faults() -> u8 {
+    FAULTS.load(Ordering::Relaxed)
+}
+
+/// Sets or clears a fault found while running.
+pub fn set_fault(fault: u8, on: bool) {
+    match on {
+        true => FAULTS.fetch_or(fault, Ordering::Relaxed),
+        false => FAULTS.fetch_and(!fault, Ordering::Relaxed),
+    };
+}
+
+/// Checks for the usual assembly mistakes at power-on, before the board
+/// settles into its mode.
+///
+/// Each LED channel is lit dimly in turn, red, green, blue, and then white,
+/// so someone watching can see a dead or miswired strip; there's no way for
+/// the board to tell. Meanwhile the PIRs are watched, and one that never
+/// goes low is reported, since it's probably stuck or plugged in wrong. A
+/// PIR that's still warming up, someone moving in front of it, or a module
+/// that idles high can set its bit too. The radio was already checked by
+/// PanelRadio::init(), so its result is passed in as `radio_failed`. There's
+/// no check of the panel bus, since the transceiver can't read back what it
+/// sends; a stuck one is found later, see FAULT_PANEL_BUS.
+///
+/// A warm boot, most likely a reset mid-show, leaves the strip on the colors
+/// it came back with, see LedStrip::restore_colors(), and only watches the
+/// PIRs, for just as long.
+///
+/// The result is shown on the status LEDs for SHOW_TIME, one LED per fault
+/// bit, then the LEDs go back to what they were. Everything here awaits, so
+/// the watchdog task keeps petting throughout.
+///
+pub async fn run(led_strip: &mut LedStrip, pirs: &Pirs, radio_failed: bool) {
+    const B: u8 = TEST_BRIGHTNESS;
+    let saved = led_strip.colors();
+    let sweep = !boot::is_warm_boot();
+    let mut pir_went_low = [false; 2];
+    for [r, g, b, w] in [[B, 0, 0, 0], [0, B, 0, 0], [0, 0, B, 0], [0, 0, 0, B]] {
+        if sweep {
+            led_strip.set_colors_rgbw(r, g, b, w);
+        }
+        let until = Instant::now() + CHANNEL_TIME;
+        while Instant::now() < until {
+            pir_went_low[0] |= pirs.pir_1.is_low();
+            pir_went_low[1] |= pirs.pir_2.is_low();
+            Timer::after(PIR_SAMPLE_INTERVAL).await;
+        }
+    }
+    if sweep {
+        led_strip.set_zone_colors(saved.0, saved.1);
+    }
+
+    let mut faults = 0;
+    if !pir_went_low[0] {
+        faults |= FAULT_PIR_1;
+    }
+    if !pir_went_low[1] {
+        faults |= FAULT_PIR_2;
+    }
+    if radio_failed {
+        faults |= FAULT_RADIO;
+    }
+    // Or'd in, so a stuck bus found meanwhile isn't lost
+    FAULTS.fetch_or(faults, Ordering::Relaxed);
+
+    if faults == 0 {
+        info!("Self-test passed");
+    } else {
+        warn!("Self-test faults: {:04b}", faults);
+    }
+
+    let status = StatusLEDs::get_all();
+    StatusLEDs::set_all(faults);
+    Timer::after(SHOW_TIME).await;
+    StatusLEDs::set_all(status);
+}
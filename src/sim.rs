@@ -0,0 +1,314 @@
+use aunisoma_protocol::FULL_OUTPUT;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::boot::ResetCause;
+use crate::cmd_processor::{
+    Message, NOT_MAPPED_INTERVAL, PANEL_REPLY_DELAY, PanelStatus, RESET_WINDOW, not_mapped_delay,
+    slot_colors,
+};
+use crate::comm::{Address, BROADCAST_ADDRESS, Packet, TxPower};
+use crate::pir::PirProfile;
+use crate::version;
+
+/// Most panels that can be simulated at once
+pub const MAX_SIM_PANELS: usize = 8;
+
+/// Each panel can have a reply and an announce waiting
+const MAX_QUEUED: usize = MAX_SIM_PANELS * 2;
+
+/// Replies to a broadcast are spread out by this much per panel, so they
+/// don't all arrive at the same instant.
+const REPLY_STAGGER: Duration = Duration::from_micros(300);
+
+/// How long a simulated panel takes to come back after a Reset
+const REBOOT_TIME: Duration = Duration::from_millis(500);
+
+/// Each panel's PIRs go through a cycle this long, see SimPanel::pirs()
+const PIR_CYCLE_SECS: u64 = 8;
+
+/// A panel that only exists in software.
+struct SimPanel {
+    id: u8,
+    /// Never replies, like a panel that's unplugged or out of range
+    silent: bool,
+    boot_count: u8,
+    slot: Option<u8>,
+    /// The slot from a MapPanelsPending, until CommitMapping
+    pending_slot: Option<Option<u8>>,
+    color: [u8; 3],
+    /// When it booted, or will have once it's done rebooting
+    booted_at: Instant,
+    /// When the first of the two Resets needed to reset arrived
+    reset_armed_at: Option<Instant>,
+    /// When it last said it's not mapped
+    not_mapped_at: Option<Instant>,
+    /// State of the RSSI noise
+    noise: u32,
+}
+
+impl SimPanel {
+    fn new(id: u8, silent: bool) -> Self {
+        Self {
+            id,
+            silent,
+            // Never 0, which the master takes to mean it hasn't heard
+            boot_count: id.wrapping_mul(37) | 1,
+            slot: None,
+            pending_slot: None,
+            color: [0; 3],
+            booted_at: Instant::now(),
+            reset_armed_at: None,
+            not_mapped_at: None,
+            noise: (id as u32 + 1).wrapping_mul(2654435761),
+        }
+    }
+
+    /// Somewhere between -40 and -69 dBm, different every time.
+    fn rssi(&mut self) -> i8 {
+        // xorshift32
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        -40 - (self.noise % 30) as i8
+    }
+
+    /// Scripted motion, offset by ID so the panels take turns: PIR1 for two
+    /// seconds, then both, then PIR2, then nothing for the rest of the cycle.
+    fn pirs(&self) -> u8 {
+        let secs = Instant::now().as_secs() + self.id as u64;
+        match secs % PIR_CYCLE_SECS {
+            0 | 1 => 1,
+            2 => 3,
+            3 => 2,
+            _ => 0,
+        }
+    }
+
+    /// Does what a real panel would with the packet, and returns its reply
+    /// if it has one.
+    fn handle(&mut self, packet: &Packet) -> Option<Packet> {
+        let mut reply = Packet::new(Address(self.id), packet.from, Message::Test);
+        match packet.tag {
+            Message::Ping => {
+                let rssi = self.rssi();
+                reply.tag = Message::PingReply;
+                reply.push_data(&[self.boot_count, rssi as u8, ResetCause::PowerOn.into()]);
+                reply.push_data(&version::FIRMWARE_ID.to_bytes());
+                // One zone, a clean self-test, and full power
+                reply.push_data(&[1, 0, TxPower::Max.into()]);
+            }
+            Message::MapPanels | Message::MapPanelsPending => {
+                let slot = packet.data.iter().position(|&id| id == self.id);
+                let slot = slot.map(|s| s as u8);
+                if packet.tag == Message::MapPanels {
+                    self.slot = slot;
+                    self.pending_slot = None;
+                } else {
+                    self.pending_slot = Some(slot);
+                }
+                reply.tag = Message::MapPanelsReply;
+                reply.push_data(&[slot?, 1]);
+            }
+            // The fallback for a missed one isn't simulated
+            Message::CommitMapping => {
+                self.slot = self.pending_slot.take()?;
+                return None;
+            }
+            Message::SetColor
+            | Message::SetColorRgbw
+            | Message::SetColorZones
+            | Message::SetColorDelta => {
+                let unicast = packet.to.value() == self.id
+                    && matches!(
+                        (packet.tag, packet.data.len()),
+                        (Message::SetColor, 3) | (Message::SetColorRgbw, 4)
+                    );
+                let color = match self.slot {
+                    _ if unicast => &packet.data[..],
+                    Some(slot) => slot_colors(packet, slot as usize)?,
+                    None if packet.tag == Message::SetColorDelta => return None,
+                    None => {
+                        let now = Instant::now();
+                        let quiet = self
+                            .not_mapped_at
+                            .is_none_or(|at| now.duration_since(at) >= NOT_MAPPED_INTERVAL);
+                        if packet.to != BROADCAST_ADDRESS || !quiet {
+                            return None;
+                        }
+                        self.not_mapped_at = Some(now);
+                        reply.tag = Message::NotMapped;
+                        reply.push_data(&[self.id]);
+                        return Some(reply);
+                    }
+                };
+                self.color.copy_from_slice(&color[..3]);
+                reply.tag = Message::SetColorReply;
+                reply.push_data(&[self.pirs()]);
+            }
+            // Shown straight away, the timing isn't simulated
+            Message::SetColorAt => {
+                let (_, inner) = packet.from_scheduled()?;
+                return self.handle(&inner);
+            }
+            Message::SetColorNoReply => {
+                let inner = packet.from_no_reply()?;
+                self.handle(&inner);
+                return None;
+            }
+            Message::QuerySlot => {
+                reply.tag = Message::SlotReply;
+                reply.push_data(&[self.slot.unwrap_or(PanelStatus::NO_SLOT)]);
+            }
+            Message::PollPirs => {
+                self.slot?;
+                reply.tag = Message::SetColorReply;
+                reply.push_data(&[self.pirs()]);
+            }
+            Message::StatusRequest => {
+                let status = PanelStatus {
+                    boot_count: self.boot_count,
+                    slot: self.slot,
+                    color: self.color,
+                    pirs: self.pirs(),
+                    uptime_secs: self.booted_at.elapsed().as_secs() as u32,
+                    pir_profile: Some(PirProfile::A),
+                    hours: Some(self.id as u32 * 100),
+                    derating: Some(FULL_OUTPUT),
+                    usb: Some(false),
+                    last_panic: None,
+                };
+                reply.tag = Message::StatusReply;
+                reply.push_data(&status.to_bytes());
+            }
+            Message::SetBaud => match packet.data[..] {
+                [_, 0] => reply.tag = Message::SetBaudReply,
+                _ => return None,
+            },
+            Message::SetChannel => match packet.data[..] {
+                [_, 0] => reply.tag = Message::SetChannelReply,
+                _ => return None,
+            },
+            Message::Reset => {
+                let now = Instant::now();
+                let armed = self.reset_armed_at.replace(now);
+                if !armed.is_some_and(|at| now.duration_since(at) <= RESET_WINDOW) {
+                    return None;
+                }
+                self.reset_armed_at = None;
+                self.boot_count = self.boot_count.wrapping_add(1).max(1);
+                self.slot = None;
+                self.pending_slot = None;
+                self.color = [0; 3];
+                self.booted_at = Instant::now() + REBOOT_TIME;
+                return None;
+            }
+            Message::Reliable => {
+                let (seq, inner) = packet.from_reliable()?;
+                let _ = self.handle(&inner);
+                reply.tag = Message::Ack;
+                reply.push_data(&[seq]);
+            }
+            Message::Test => {
+                reply.push_data(&packet.data);
+            }
+            _ => return None,
+        }
+        Some(reply)
+    }
+
+    fn announcement(&self) -> Packet {
+        let mut packet = Packet::new(Address(self.id), BROADCAST_ADDRESS, Message::Announce);
+        packet.push_data(&[self.boot_count, ResetCause::Software.into()]);
+        packet
+    }
+}
+
+/// Stands in for the panel bus, so a master can be tried out on the bench.
+///
+/// Packets sent to the simulated panels are answered like real panels would,
+/// after the usual reply delay. Panels that are reset come back and announce
+/// themselves. Set up with the Q command, see PanelComm::simulate().
+///
+pub struct SimPanels {
+    panels: Vec<SimPanel, MAX_SIM_PANELS>,
+    /// Packets "in flight", and when they arrive
+    queued: Vec<(Instant, Packet), MAX_QUEUED>,
+    last_rssi: i8,
+}
+
+impl SimPanels {
+    /// Simulates panels with the given IDs. The ones marked silent never
+    /// reply. Extra IDs past MAX_SIM_PANELS are ignored.
+    pub fn new(ids: impl IntoIterator<Item = (u8, bool)>) -> Self {
+        let mut panels = Vec::new();
+        for (id, silent) in ids {
+            if panels.push(SimPanel::new(id, silent)).is_err() {
+                break;
+            }
+        }
+        Self {
+            panels,
+            queued: Vec::new(),
+            last_rssi: 0,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.panels.len()
+    }
+
+    /// Hands a packet to the panels it's addressed to, and queues up their
+    /// replies. Replies that don't fit are lost, like a collision.
+    pub fn send_packet(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let arrival = now + PANEL_REPLY_DELAY;
+        for (i, panel) in self.panels.iter_mut().enumerate() {
+            let for_panel = packet.to == BROADCAST_ADDRESS || packet.to.value() == panel.id;
+            if !for_panel || panel.booted_at > now {
+                continue;
+            }
+            let Some(reply) = panel.handle(packet) else {
+                let rebooting = panel.booted_at > now;
+                if rebooting && !panel.silent {
+                    let at = panel.booted_at + REPLY_STAGGER * i as u32;
+                    let _ = self.queued.push((at, panel.announcement()));
+                }
+                continue;
+            };
+            if !panel.silent {
+                let at = match reply.tag {
+                    Message::NotMapped => arrival + not_mapped_delay(reply.from),
+                    _ => arrival + REPLY_STAGGER * i as u32,
+                };
+                let _ = self.queued.push((at, reply));
+            }
+        }
+    }
+
+    /// Waits for the next queued packet to arrive. Fine to drop at any await,
+    /// nothing is taken off the queue until it's returned.
+    pub async fn recv_packet(&mut self) -> Packet {
+        let next = self
+            .queued
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (at, _))| *at)
+            .map(|(i, (at, _))| (i, *at));
+        let Some((index, at)) = next else {
+            return core::future::pending().await;
+        };
+        Timer::at(at).await;
+        let (_, packet) = self.queued.swap_remove(index);
+        self.last_rssi = match self.panels.iter_mut().find(|p| p.id == packet.from.value()) {
+            Some(panel) => panel.rssi(),
+            None => 0,
+        };
+        packet
+    }
+
+    /// Signal strength of the last packet received, in dBm.
+    pub fn last_rssi(&self) -> i8 {
+        self.last_rssi
+    }
+}
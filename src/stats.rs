@@ -0,0 +1,253 @@
+use core::fmt::Write;
+use embassy_time::{Duration, Instant};
+use heapless::HistoryBuffer;
+
+use crate::boot::get_boot_count;
+use crate::{flash, output, usb_port};
+
+/// How many Set Color commands the frame timing covers
+const FRAME_HISTORY_LEN: usize = 100;
+
+/// Frame times are kept in these units, so they fit in a u16
+const FRAME_TIME_UNIT_US: u64 = 10;
+
+/// Longest the second part of the Info reply gets, see report_phases()
+pub const PHASES_JSON_LEN: usize = 224;
+
+/// Longest the USB part of the Info reply gets, see report_usb()
+pub const USB_JSON_LEN: usize = 96;
+
+/// Where the time for one Set Color went, in FRAME_TIME_UNIT_US.
+#[derive(Clone, Copy)]
+struct FrameTime {
+    /// From the command arriving to the frame being ready to send
+    parse: u16,
+    /// Writing the frame to the bus or the radio
+    send: u16,
+    /// Waiting out the reply window
+    collect: u16,
+}
+
+/// Counters for the Info command, so you can tell how a master is doing
+/// without a debug probe.
+///
+pub struct CommandStats {
+    commands: u32,
+    frames: u32,
+    /// Replies that came when the master wasn't waiting for that kind, see
+    /// CmdProcessor::handle_reply()
+    out_of_phase_replies: u32,
+    /// Packets to the master that aren't replies to anything
+    unknown_replies: u32,
+    /// How long each recent Set Color took, phase by phase
+    frame_times: HistoryBuffer<FrameTime, FRAME_HISTORY_LEN>,
+    /// Set Colors sent without replies, see CmdProcessor::send_frame()
+    quiet_frames: u32,
+    /// How long each recent one of those took, parse and send together
+    quiet_times: HistoryBuffer<u16, FRAME_HISTORY_LEN>,
+}
+
+impl CommandStats {
+    pub fn new() -> Self {
+        Self {
+            commands: 0,
+            frames: 0,
+            out_of_phase_replies: 0,
+            unknown_replies: 0,
+            frame_times: HistoryBuffer::new(),
+            quiet_frames: 0,
+            quiet_times: HistoryBuffer::new(),
+        }
+    }
+
+    pub fn count_command(&mut self) {
+        self.commands = self.commands.wrapping_add(1);
+    }
+
+    pub fn count_out_of_phase_reply(&mut self) {
+        self.out_of_phase_replies = self.out_of_phase_replies.wrapping_add(1);
+    }
+
+    pub fn count_unknown_reply(&mut self) {
+        self.unknown_replies = self.unknown_replies.wrapping_add(1);
+    }
+
+    /// Counts a Set Color frame that took `parse` to get ready to send,
+    /// `send` to send, and `collect` to collect the replies.
+    pub fn count_frame(&mut self, parse: Duration, send: Duration, collect: Duration) {
+        self.frames = self.frames.wrapping_add(1);
+        self.frame_times.write(FrameTime {
+            parse: units(parse),
+            send: units(send),
+            collect: units(collect),
+        });
+    }
+
+    /// Counts a Set Color frame sent without replies, which took `parse` to
+    /// get ready to send and `send` to send, and had nothing to collect.
+    pub fn count_quiet_frame(&mut self, parse: Duration, send: Duration) {
+        self.frames = self.frames.wrapping_add(1);
+        self.quiet_frames = self.quiet_frames.wrapping_add(1);
+        self.quiet_times.write(units(parse + send));
+    }
+
+    /// Average and max of one phase of the recent frames, in microseconds.
+    fn frame_summary(&self, phase: impl Fn(&FrameTime) -> u64) -> (u64, u64) {
+        let times = self.frame_times.as_slice();
+        let total: u64 = times.iter().map(&phase).sum();
+        let avg = total.checked_div(times.len() as u64).unwrap_or(0);
+        let max = times.iter().map(&phase).max().unwrap_or(0);
+        (avg * FRAME_TIME_UNIT_US, max * FRAME_TIME_UNIT_US)
+    }
+
+    /// Writes the start of a JSON object like `{"uptime":3600,
+    /// "bootCount":12, "commands":5012, "frames":4990, "frameAvgUs":35100,
+    /// "frameMaxUs":41200, "flashWrites":0, "outputDropped":0, "outOfPhase":3,
+    /// "unknownReplies":0`, which report_phases() finishes.
+    pub fn report(&self, w: &mut impl Write) -> core::fmt::Result {
+        let (avg, max) = self.frame_summary(|t| t.send as u64 + t.collect as u64);
+        write!(
+            w,
+            "{{\"uptime\":{}, \"bootCount\":{}, \"commands\":{}, \"frames\":{}, \"frameAvgUs\":{}, \"frameMaxUs\":{}, \"flashWrites\":{}, \"outputDropped\":{}, \"outOfPhase\":{}, \"unknownReplies\":{}",
+            Instant::now().as_secs(),
+            get_boot_count(),
+            self.commands,
+            self.frames,
+            avg,
+            max,
+            flash::write_count(),
+            output::dropped_count(),
+            self.out_of_phase_replies,
+            self.unknown_replies
+        )
+    }
+
+    /// Writes how the USB host has come and gone, like `, "usbConnects":3,
+    /// "usbDisconnects":2, "usbDisconnectedSecs":610`, with null for the
+    /// seconds if it's never been disconnected. See UsbEvents.
+    pub fn report_usb(&self, w: &mut impl Write) -> core::fmt::Result {
+        let (connects, disconnects, since) = usb_port::connection_counts();
+        write!(
+            w,
+            ", \"usbConnects\":{}, \"usbDisconnects\":{}, \"usbDisconnectedSecs\":",
+            connects, disconnects
+        )?;
+        match since {
+            Some(secs) => write!(w, "{}", secs),
+            None => w.write_str("null"),
+        }
+    }
+
+    /// Writes the rest of the Info object, where the frame time went, like
+    /// `, "parseAvgUs":120, "parseMaxUs":310, "sendAvgUs":2900,
+    /// "sendMaxUs":3100, "collectAvgUs":32000, "collectMaxUs":32010,
+    /// "fps":28, "quietFrames":0, "quietFps":null}`. `fps` is how many frames
+    /// a second the master could keep up, going by the recent ones, and
+    /// `quietFps` the same for frames without replies, or null for none.
+    pub fn report_phases(&self, w: &mut impl Write) -> core::fmt::Result {
+        let parse = self.frame_summary(|t| t.parse as u64);
+        let send = self.frame_summary(|t| t.send as u64);
+        let collect = self.frame_summary(|t| t.collect as u64);
+        write!(
+            w,
+            ", \"parseAvgUs\":{}, \"parseMaxUs\":{}, \"sendAvgUs\":{}, \"sendMaxUs\":{}, \"collectAvgUs\":{}, \"collectMaxUs\":{}",
+            parse.0, parse.1, send.0, send.1, collect.0, collect.1
+        )?;
+        let frame_us = match self.frame_times.as_slice().is_empty() {
+            false => Some(parse.0 + send.0 + collect.0),
+            true => None,
+        };
+        let quiet = self.quiet_times.as_slice();
+        let quiet_total: u64 = quiet.iter().map(|&t| t as u64).sum();
+        let quiet_us = quiet_total
+            .checked_div(quiet.len() as u64)
+            .map(|avg| avg * FRAME_TIME_UNIT_US);
+        w.write_str(", \"fps\":")?;
+        write_fps(w, frame_us)?;
+        write!(w, ", \"quietFrames\":{}, \"quietFps\":", self.quiet_frames)?;
+        write_fps(w, quiet_us)?;
+        w.write_char('}')
+    }
+}
+
+/// Writes the frame rate for frames that take `frame_us` on average, or null
+/// if there weren't any.
+fn write_fps(w: &mut impl Write, frame_us: Option<u64>) -> core::fmt::Result {
+    match frame_us {
+        Some(us) => write!(w, "{}", 1_000_000 / us.max(1)),
+        None => w.write_str("null"),
+    }
+}
+
+/// A frame time in FRAME_TIME_UNIT_US, as much of it as fits.
+fn units(time: Duration) -> u16 {
+    let units = time.as_micros() / FRAME_TIME_UNIT_US;
+    units.min(u16::MAX as u64) as u16
+}
+
+/// Round-trip times from the master's point of view, from the end of sending
+/// a message to a reply arriving, less the time the panel deliberately waits
+/// before replying.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Rtt {
+    min_us: u16,
+    max_us: u16,
+    total_us: u32,
+    count: u16,
+}
+
+impl Rtt {
+    pub const fn new() -> Self {
+        Self {
+            min_us: u16::MAX,
+            max_us: 0,
+            total_us: 0,
+            count: 0,
+        }
+    }
+
+    pub fn record(&mut self, us: u16) {
+        self.min_us = self.min_us.min(us);
+        self.max_us = self.max_us.max(us);
+        self.total_us = self.total_us.saturating_add(us as u32);
+        self.count = self.count.saturating_add(1);
+    }
+
+    pub fn count(&self) -> u16 {
+        self.count
+    }
+
+    /// Min, average, and max, or None if there's nothing recorded.
+    pub fn summary(&self) -> Option<(u16, u16, u16)> {
+        let avg = self.total_us.checked_div(self.count as u32)?;
+        Some((self.min_us, avg as u16, self.max_us))
+    }
+}
+
+/// Width of each bucket of an RttHistogram
+pub const RTT_BUCKET_US: u16 = 250;
+const RTT_BUCKETS: usize = 8;
+
+/// Counts of round-trip times in RTT_BUCKET_US wide buckets. The last bucket
+/// takes everything longer.
+pub struct RttHistogram {
+    buckets: [u16; RTT_BUCKETS],
+}
+
+impl RttHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: [0; RTT_BUCKETS],
+        }
+    }
+
+    pub fn record(&mut self, us: u16) {
+        let i = ((us / RTT_BUCKET_US) as usize).min(RTT_BUCKETS - 1);
+        self.buckets[i] = self.buckets[i].saturating_add(1);
+    }
+
+    pub fn buckets(&self) -> &[u16] {
+        &self.buckets
+    }
+}
@@ -2,9 +2,56 @@
 
 use alloc::boxed::Box;
 use embassy_stm32::gpio::Output;
+use embassy_time::{Duration, Instant, Timer};
 
+use crate::{Mode, self_test};
+
+/// What a panel normally shows on its status LEDs, see main
+pub const PANEL_STATUS: u8 = 1 << 1;
+
+/// How long the LEDs keep what a SetStatus says before the heartbeat takes
+/// them back
+pub const STATUS_HOLD_TIME: Duration = Duration::from_secs(5);
+
+/// Pulses at 1 Hz in every mode
+const ALIVE_LED: usize = 3;
+const ALIVE_HALF_PERIOD: Duration = Duration::from_millis(500);
+/// Flashed against LED3 while the panel bus is stuck, see LedHeartbeat
+const BUS_STUCK_LEDS: u8 = 0b0111;
+
+/// How long the activity LED is flipped for a command or packet
+const BLINK_TIME: Duration = Duration::from_millis(50);
+
+/// Full brightness, with nothing for dimming_task() to do
+pub const FULL_BRIGHTNESS: u8 = 100;
+
+/// The brightness the LEDs start at, in percent, until the `i` command
+/// changes it
+pub const DEFAULT_BRIGHTNESS: u8 = FULL_BRIGHTNESS;
+
+/// 200 Hz, too fast to see flicker and slow enough for a timer
+const PWM_PERIOD: Duration = Duration::from_micros(5000);
+
+/// The status LEDs, GPIOs that are on or off, dimmed by dimming_task().
+///
+/// set(), reset(), and set_all() say which LEDs are lit, and get_all()
+/// returns that, whatever the brightness. At full brightness, the pins follow
+/// straight away. Dimmed, reset() still turns an LED off straight away, but
+/// one that's lit comes on at the start of the next PWM period.
+///
+/// set_fast() and reset_fast() write the pins directly, for timing, and the
+/// dimming only touches LEDs that are lit, so they work as before on LEDs
+/// that aren't.
+///
 pub struct StatusLEDs {
     pub leds: [Output<'static>; 4],
+    /// Until then, the heartbeat leaves the LEDs alone, see show_for()
+    held_until: Instant,
+    /// Bits of the LEDs that are on, whether or not the PWM has them on right
+    /// now
+    lit: u8,
+    /// In percent
+    brightness: u8,
 }
 
 static mut STATUS_LEDS: *mut StatusLEDs = core::ptr::null_mut();
@@ -16,36 +63,78 @@ impl StatusLEDs {
         }
 
         unsafe {
-            STATUS_LEDS = Box::leak(Box::new(StatusLEDs { leds }));
+            STATUS_LEDS = Box::leak(Box::new(StatusLEDs {
+                leds,
+                held_until: Instant::from_ticks(0),
+                lit: 0,
+                brightness: DEFAULT_BRIGHTNESS,
+            }));
         }
     }
 
     #[inline(never)]
     pub fn set(which: usize) {
         unsafe {
-            (*STATUS_LEDS).leds[which].set_high();
+            (*STATUS_LEDS).lit |= 1 << which;
+            if (*STATUS_LEDS).brightness >= FULL_BRIGHTNESS {
+                (*STATUS_LEDS).leds[which].set_high();
+            }
         }
     }
 
     #[inline(never)]
     pub fn reset(which: usize) {
         unsafe {
+            (*STATUS_LEDS).lit &= !(1 << which);
             (*STATUS_LEDS).leds[which].set_low();
         }
     }
 
     pub fn set_all(value: u8) {
-        unsafe {
-            for i in 0..4 {
-                if value & (1 << i) != 0 {
-                    (*STATUS_LEDS).leds[i].set_high();
-                } else {
-                    (*STATUS_LEDS).leds[i].set_low();
-                }
+        for i in 0..4 {
+            if value & (1 << i) != 0 {
+                Self::set(i);
+            } else {
+                Self::reset(i);
             }
         }
     }
 
+    /// The brightness, in percent.
+    pub fn brightness() -> u8 {
+        unsafe { (*STATUS_LEDS).brightness }
+    }
+
+    /// Dims the LEDs to `percent`, or turns them off altogether with 0.
+    /// Takes effect at the next PWM period.
+    pub fn set_brightness(percent: u8) {
+        unsafe {
+            (*STATUS_LEDS).brightness = percent.min(FULL_BRIGHTNESS);
+        }
+        // Without the PWM, nothing else would turn them back on
+        if percent >= FULL_BRIGHTNESS {
+            Self::set_all(Self::get_all());
+        }
+    }
+
+    /// Like set_all(), but the heartbeat leaves the LEDs alone for `time`,
+    /// so it stays up.
+    pub fn show_for(value: u8, time: Duration) {
+        Self::set_all(value);
+        unsafe {
+            (*STATUS_LEDS).held_until = Instant::now() + time;
+        }
+    }
+
+    /// Something shown with show_for() is still up.
+    pub fn held() -> bool {
+        unsafe { Instant::now() < (*STATUS_LEDS).held_until }
+    }
+
+    pub fn get_all() -> u8 {
+        unsafe { (*STATUS_LEDS).lit }
+    }
+
     #[inline(always)]
     pub fn set_fast(which: usize) {
         if which < 4 {
@@ -63,4 +152,143 @@ impl StatusLEDs {
                 .write(|w| w.set_br(15 - which, true));
         }
     }
+
+    /// Turns the LEDs in `leds` on or off at once, like set_fast(). Needs no
+    /// StatusLEDs, for the HardFault handler, see last_panic.
+    pub fn write_fast(leds: u8, on: bool) {
+        embassy_stm32::pac::GPIOB.bsrr().write(|w| {
+            for which in (0..4).filter(|i| leds & (1 << i) != 0) {
+                match on {
+                    true => w.set_bs(15 - which, true),
+                    false => w.set_br(15 - which, true),
+                }
+            }
+        });
+    }
+}
+
+/// Dims the status LEDs with a software PWM, see StatusLEDs. Each period,
+/// the lit LEDs are on for the brightness's share of it, and off for the
+/// rest. At full brightness, it leaves the pins alone.
+#[embassy_executor::task]
+pub async fn dimming_task() {
+    loop {
+        let start = Instant::now();
+        let brightness = StatusLEDs::brightness();
+        if brightness >= FULL_BRIGHTNESS {
+            Timer::at(start + PWM_PERIOD).await;
+            continue;
+        }
+        if brightness > 0 {
+            StatusLEDs::write_fast(StatusLEDs::get_all(), true);
+            Timer::at(start + PWM_PERIOD * brightness as u32 / FULL_BRIGHTNESS as u32).await;
+        }
+        // Anything reset() since the start is off already
+        StatusLEDs::write_fast(StatusLEDs::get_all(), false);
+        Timer::at(start + PWM_PERIOD).await;
+    }
+}
+
+/// What each mode shows on the status LEDs, as bits of set_all()
+pub fn mode_leds(mode: Mode) -> u8 {
+    match mode {
+        Mode::Master => 0b0001,
+        Mode::Panel => 0b0010,
+        Mode::Spy => 0b0011,
+        Mode::Bridge => 0b0110,
+    }
+}
+
+/// Keeps the status LEDs moving, so a board that's hung can be told from one
+/// that's fine at a glance.
+///
+/// On top of the mode's LEDs, LED3 pulses at 1 Hz in every mode, and one LED
+/// flickers with activity: LED0 blinks off for each command a master runs,
+/// LED1 for each packet a panel or bridge receives, and LED2 toggles for each
+/// packet a spy captures. It's all done by the mode's own loop, calling
+/// poll() or racing run(), so a loop that's stuck stops the pulse too.
+/// Whatever StatusLEDs::show_for() puts up, like a SetStatus from the master,
+/// stays until its time is up. While the panel bus is stuck, LED0-2 flash
+/// together, out of step with LED3, in place of the rest.
+///
+pub struct LedHeartbeat {
+    base: u8,
+    activity_led: usize,
+    /// Toggle the activity LED, rather than blink it
+    toggle: bool,
+    toggled: bool,
+    blink_until: Instant,
+    started: Instant,
+}
+
+impl LedHeartbeat {
+    pub fn new(mode: Mode) -> Self {
+        let (activity_led, toggle) = match mode {
+            Mode::Master => (0, false),
+            Mode::Panel | Mode::Bridge => (1, false),
+            Mode::Spy => (2, true),
+        };
+        Self {
+            base: mode_leds(mode),
+            activity_led,
+            toggle,
+            toggled: false,
+            blink_until: Instant::from_ticks(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Flickers the activity LED, for a command or packet.
+    pub fn activity(&mut self) {
+        if self.toggle {
+            self.toggled = !self.toggled;
+        } else {
+            self.blink_until = Instant::now() + BLINK_TIME;
+        }
+        self.poll();
+    }
+
+    /// Brings the LEDs up to date. Cheap enough to call every time round a
+    /// loop.
+    pub fn poll(&mut self) {
+        if StatusLEDs::held() {
+            return;
+        }
+        let now = Instant::now();
+        let half_periods =
+            now.duration_since(self.started).as_ticks() / ALIVE_HALF_PERIOD.as_ticks();
+        let alive = half_periods % 2 == 0;
+        let mut leds = self.base;
+        if self.toggled || now < self.blink_until {
+            leds ^= 1 << self.activity_led;
+        }
+        if self_test::faults() & self_test::FAULT_PANEL_BUS != 0 {
+            leds = if alive { 0 } else { BUS_STUCK_LEDS };
+        }
+        if alive {
+            leds |= 1 << ALIVE_LED;
+        }
+        StatusLEDs::set_all(leds);
+    }
+
+    /// Keeps the LEDs up to date for as long as it's raced against whatever
+    /// else the loop is waiting for. Fine to drop at any await.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            self.poll();
+            Timer::at(self.next_change()).await;
+        }
+    }
+
+    /// When poll() next has something to change.
+    fn next_change(&self) -> Instant {
+        let now = Instant::now();
+        let half_period = ALIVE_HALF_PERIOD.as_ticks();
+        let elapsed = now.duration_since(self.started).as_ticks();
+        let pulse = self.started + Duration::from_ticks((elapsed / half_period + 1) * half_period);
+        match self.blink_until > now {
+            true => pulse.min(self.blink_until),
+            false => pulse,
+        }
+    }
 }
@@ -0,0 +1,109 @@
+use heapless::Vec;
+
+use crate::cmd_processor::{MAX_PANEL_SLOTS, Message};
+use crate::comm::{Address, BROADCAST_ADDRESS, Packet, PanelComm};
+use crate::outbox::{Outbox, Queued};
+use crate::pir::PIR_BITS;
+use crate::status_leds::PANEL_STATUS;
+
+/// A panel that misses this many frames in a row lights MISSING
+const MISSING_FRAMES: u8 = 3;
+
+// What each panel's status LEDs show, as bits of SetStatus
+
+/// The panel replied to the last frame
+const REPLIED: u8 = 1 << 0;
+/// The panel missed MISSING_FRAMES or more frames in a row
+const MISSING: u8 = 1 << 1;
+/// The panel saw motion in the last frame
+const MOTION: u8 = 1 << 2;
+
+#[derive(Clone, Copy)]
+struct SlotStatus {
+    /// Frames missed in a row
+    misses: u8,
+    /// What the panel was last sent, None if nothing yet
+    shown: Option<u8>,
+}
+
+/// Shows how each mapped panel's comm is doing on its own status LEDs, so
+/// someone tearing down can look along the line and see which panels are
+/// still live.
+///
+/// After each Set Color frame, the master works out each slot's status from
+/// its reply, and queues SetStatus for the panels whose status changed, to go
+/// out in the gaps between commands, see Outbox. A panel shows it for STATUS_HOLD_TIME, then
+/// goes back to its heartbeat. A steady installation costs nothing extra on
+/// the bus. A panel that's missing probably won't hear its status either, but
+/// it's sent anyway, for when it's only missing the replies.
+///
+pub struct StatusMirror {
+    slots: Vec<SlotStatus, MAX_PANEL_SLOTS>,
+}
+
+impl StatusMirror {
+    pub fn new() -> Self {
+        Self { slots: Vec::new() }
+    }
+
+    /// Forgets the slots, for a new mapping. Every panel is sent its status
+    /// after the next frame.
+    pub fn reset(&mut self) {
+        self.slots.clear();
+    }
+
+    /// Queues the panels in `mapping` their status after a frame.
+    /// `pirs(slot)` is the PIR value the slot's panel replied with, or None
+    /// if it didn't.
+    pub fn show_frame(
+        &mut self,
+        outbox: &mut Outbox,
+        mapping: &[u8],
+        pirs: impl Fn(usize) -> Option<u8>,
+    ) {
+        if self.slots.len() < mapping.len() {
+            let unknown = SlotStatus {
+                misses: 0,
+                shown: None,
+            };
+            let _ = self.slots.resize(mapping.len(), unknown);
+        }
+        for (slot, (&id, state)) in mapping.iter().zip(self.slots.iter_mut()).enumerate() {
+            let status = match pirs(slot) {
+                Some(pirs) => {
+                    state.misses = 0;
+                    if pirs & PIR_BITS != 0 {
+                        REPLIED | MOTION
+                    } else {
+                        REPLIED
+                    }
+                }
+                None => {
+                    state.misses = state.misses.saturating_add(1);
+                    if state.misses >= MISSING_FRAMES {
+                        MISSING
+                    } else {
+                        0
+                    }
+                }
+            };
+            if state.shown == Some(status) {
+                continue;
+            }
+            state.shown = Some(status);
+            outbox.push(Queued::SetStatus {
+                to: Address(id),
+                status,
+            });
+        }
+    }
+
+    /// Puts all the panels' status LEDs back to what panels normally show,
+    /// straight away, and drops any status still waiting to go.
+    pub async fn clear(comm: &mut PanelComm, outbox: &mut Outbox, from: Address) {
+        outbox.clear_statuses();
+        let mut packet = Packet::new(from, BROADCAST_ADDRESS, Message::SetStatus);
+        packet.push_data(&[PANEL_STATUS]);
+        comm.send_packet(&packet).await;
+    }
+}
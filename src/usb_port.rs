@@ -1,17 +1,28 @@
 use crate::board::UsbPeripherals;
+use crate::boot::{ResetCause, get_reset_cause};
 use crate::comm::Address;
-use crate::line_breaker::LineBreaker;
+use crate::interactor::CommandPort;
+use crate::output::OutputQueue;
+use crate::version;
+use crate::{MAX_COMMAND_LEN, Mode};
 use alloc::boxed::Box;
+use aunisoma_protocol::{LineBreaker, LineTooLong};
 use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::Poll;
 use defmt::{info, trace};
 use embassy_executor::Spawner;
+use embassy_futures::poll_once;
 use embassy_stm32::gpio::Output;
 use embassy_stm32::peripherals::USB;
 use embassy_stm32::usb::Driver;
 use embassy_stm32::{bind_interrupts, usb};
-use embassy_time::Timer;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
 use embassy_usb::class::cdc_acm;
-use embassy_usb::{Builder, UsbDevice};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, Handler, UsbDevice};
 use embedded_io_async::Write;
 
 bind_interrupts!(struct Irqs {
@@ -20,9 +31,113 @@ bind_interrupts!(struct Irqs {
 
 const MAX_PACKET_SIZE: u8 = 64;
 
+/// Hosts write several commands at once, like a pasted script, so there's
+/// room for lines behind the one being read
+const LINE_BUFFER_LEN: usize = 1024;
+
+/// How long to wait for the host to drain a reply before giving up on it.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long a capture record waits for the host before it's dropped. Short,
+/// so the spy doesn't miss packets waiting on a slow host.
+const RECORD_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// Output for USB, written out by writer_task()
+static OUTPUT: OutputQueue = OutputQueue::new();
+
+/// Capture records writer_task() couldn't write
+static RECORDS_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// How many capture records were queued but never made it to the host.
+pub fn dropped_records() -> u32 {
+    RECORDS_DROPPED.load(Ordering::Relaxed)
+}
+
+/// Whether a host has us configured, see UsbEvents
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static CONNECTS: AtomicU32 = AtomicU32::new(0);
+static DISCONNECTS: AtomicU32 = AtomicU32::new(0);
+/// Uptime in seconds at the last disconnect, u32::MAX for none
+static DISCONNECTED_AT: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Connects and disconnects for the Interactor to pass on, see
+/// connection_changed(). Ones that don't fit are only counted.
+static CHANGES: Channel<CriticalSectionRawMutex, bool, 4> = Channel::new();
+
+/// Whether a host has us configured and awake right now.
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+/// How many times a host has connected and disconnected since boot, and
+/// how many seconds ago the last disconnect was, if there's been one.
+pub fn connection_counts() -> (u32, u32, Option<u32>) {
+    let at = DISCONNECTED_AT.load(Ordering::Relaxed);
+    let since = (at != u32::MAX).then(|| (Instant::now().as_secs() as u32).saturating_sub(at));
+    (
+        CONNECTS.load(Ordering::Relaxed),
+        DISCONNECTS.load(Ordering::Relaxed),
+        since,
+    )
+}
+
+/// Waits for the next connect (true) or disconnect (false).
+pub async fn connection_changed() -> bool {
+    CHANGES.receive().await
+}
+
+/// Keeps track of the host coming and going, for the counts and the `!usb`
+/// notifications.
+///
+/// A host is connected once it has configured us, and until it resets us or
+/// the bus is suspended. Nothing senses VBUS on this board, so a cable
+/// pulled out only shows as the bus going quiet, which is a suspend, and so
+/// is a host going to sleep.
+///
+#[derive(Default)]
+struct UsbEvents {
+    configured: bool,
+    suspended: bool,
+}
+
+impl UsbEvents {
+    fn update(&self) {
+        let connected = self.configured && !self.suspended;
+        if CONNECTED.swap(connected, Ordering::Relaxed) == connected {
+            return;
+        }
+        if connected {
+            info!("USB connected");
+            CONNECTS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            info!("USB disconnected");
+            DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+            DISCONNECTED_AT.store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+        }
+        let _ = CHANGES.try_send(connected);
+    }
+}
+
+impl Handler for UsbEvents {
+    fn reset(&mut self) {
+        self.configured = false;
+        self.update();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        self.configured = configured;
+        self.update();
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        self.suspended = suspended;
+        self.update();
+    }
+}
+
 pub struct UsbPort {
-    pub class: cdc_acm::CdcAcmClass<'static, Driver<'static, USB>>,
-    breaker: LineBreaker<256>,
+    receiver: cdc_acm::Receiver<'static, Driver<'static, USB>>,
+    breaker: LineBreaker<LINE_BUFFER_LEN>,
     _usb_pullup: Output<'static>,
 }
 
@@ -30,15 +145,20 @@ impl UsbPort {
     pub async fn new(
         mut usb_peripherals: UsbPeripherals,
         address: Address,
+        mode: Mode,
         spawner: &'_ Spawner,
     ) -> UsbPort {
         trace!("USB init");
 
         // Reset the USB D+ pin to simulate a disconnect, so we don't have to
         // manually disconnect the USB cable every time we upload new code.
+        // After power-on, the host hasn't seen us yet, so there's no need to
+        // wait.
         //
-        usb_peripherals.usb_pullup.set_low();
-        Timer::after_millis(100).await;
+        if get_reset_cause() != ResetCause::PowerOn {
+            usb_peripherals.usb_pullup.set_low();
+            Timer::after_millis(100).await;
+        }
         usb_peripherals.usb_pullup.set_high();
 
         let driver = Driver::new(
@@ -50,7 +170,17 @@ impl UsbPort {
 
         let mut config = embassy_usb::Config::new(1155, 22336);
         config.manufacturer.replace("Walter's Basement");
-        config.product.replace("Aunisoma Controller");
+        // Include the mode so you can tell the master from the spares, and
+        // the features, so you can tell what it was built with
+        let mode_name = match mode {
+            Mode::Master => "Master",
+            Mode::Panel => "Panel",
+            Mode::Spy => "Spy",
+            Mode::Bridge => "Bridge",
+        };
+        let product = Box::leak(Box::new(heapless::String::<64>::new()));
+        write!(product, "Aunisoma {} ({})", mode_name, version::FEATURES).unwrap();
+        config.product.replace(product.as_str());
         let serial_number = Box::leak(Box::new(heapless::String::<16>::new()));
         write!(serial_number, "aunisoma-{:02}", address.0).unwrap();
         config.serial_number.replace(serial_number.as_str());
@@ -89,46 +219,117 @@ impl UsbPort {
             &mut resources.serial_state,
             MAX_PACKET_SIZE as u16,
         );
+        builder.handler(Box::leak(Box::new(UsbEvents::default())));
 
         let device = builder.build();
         spawner.must_spawn(driver_task(device));
+        let (sender, receiver) = class.split();
+        spawner.must_spawn(writer_task(sender));
 
         UsbPort {
-            class,
-            breaker: LineBreaker::new(),
+            receiver,
+            breaker: LineBreaker::with_max_line_len(MAX_COMMAND_LEN),
             // This has to continue living, or else the pin will float.
             _usb_pullup: usb_peripherals.usb_pullup,
         }
     }
+}
 
-    pub async fn read_line<'i>(&mut self, into: &'i mut [u8]) -> &'i [u8] {
+impl CommandPort for UsbPort {
+    /// Reads a line. Safe to cancel, because input that has been read stays
+    /// in the LineBreaker until a line is returned. A line that was too long
+    /// is an error, so the host can be told.
+    ///
+    async fn read_line<'i>(&mut self, into: &'i mut [u8]) -> Result<&'i [u8], LineTooLong> {
         let mut buf = [0; MAX_PACKET_SIZE as usize];
+        // The first time around, look for another line in what was left over
+        // from last time
+        let mut n = 0;
         loop {
-            self.class.wait_connection().await;
-            loop {
-                match self.class.read_packet(&mut buf).await {
-                    Ok(n) => {
-                        // debug!("USB read {:a}", &buf[..n]);
-                        if let Some(line) = self.breaker.process(&buf[..n]) {
-                            into[..line.len()].copy_from_slice(line);
-                            return &into[..line.len()];
-                        }
-                    }
-                    Err(e) => {
-                        info!("USB read error: {}", e);
-                        self.breaker.reset();
-                        break;
-                    }
-                };
+            // Also returns a line found by a call that was cancelled before it
+            // could return it
+            if let Some(line) = self.breaker.take_line() {
+                into[..line.len()].copy_from_slice(line);
+                return Ok(&into[..line.len()]);
+            }
+
+            let found = self.breaker.process(&buf[..n]);
+            let echo = self.breaker.echo_output();
+            if !echo.is_empty() {
+                OUTPUT.push_text(&[echo]);
             }
+            if self.breaker.take_too_long() {
+                return Err(LineTooLong);
+            }
+            if found {
+                continue;
+            }
+
+            self.receiver.wait_connection().await;
+            n = match self.receiver.read_packet(&mut buf).await {
+                // debug!("USB read {:a}", &buf[..n]);
+                Ok(n) => n,
+                Err(e) => {
+                    info!("USB read error: {}", e);
+                    self.breaker.reset();
+                    0
+                }
+            };
         }
     }
 
-    pub async fn write_line(&mut self, line: &[u8]) {
-        let mut writer = CdcWriter::new(&mut self.class);
-        let _ = writer.write_all(line).await;
-        let _ = writer.write(b"\n").await;
-        let _ = writer.flush().await;
+    /// Throws away the lines that have already arrived, without waiting for
+    /// more, and returns how many there were. The start of a line still
+    /// being typed is kept. The host can't overrun USB, it just has to wait,
+    /// so only what's in the LineBreaker and the endpoint is dropped.
+    async fn drop_waiting(&mut self) -> usize {
+        self.breaker.drop_waiting(
+            &mut [0; MAX_PACKET_SIZE as usize],
+            // Reads a packet that's waiting, or nothing if there isn't one,
+            // or if nothing is connected
+            |buf| match poll_once(self.receiver.read_packet(buf)) {
+                Poll::Ready(Ok(n)) => n,
+                _ => 0,
+            },
+            |echo| OUTPUT.push_text(&[echo]),
+        )
+    }
+
+    fn set_echo(&mut self, echo: bool) {
+        self.breaker.set_echo(echo);
+    }
+
+    /// Queues a line for the host. It's written by writer_task(), so this
+    /// never waits on a host that isn't reading.
+    fn write_line(&mut self, line: &[u8]) {
+        OUTPUT.push_text(&[line, b"\n"]);
+    }
+
+    /// Queues a line of a reply. Waits only while the queue is full, and
+    /// writer_task() gives up on a chunk after WRITE_TIMEOUT, so a host
+    /// that isn't reading holds it up by no more than that a chunk.
+    async fn reply_line(&mut self, line: &[u8]) {
+        OUTPUT.send_text(&[line, b"\n"]).await;
+    }
+
+    async fn reply_part(&mut self, part: &[u8]) {
+        OUTPUT.send_text(&[part]).await;
+    }
+
+    /// Queues a binary capture record, or returns false if output had to be
+    /// dropped to make room for it. See writer_task() for how it's written.
+    fn write_record(&mut self, record: &[u8]) -> bool {
+        OUTPUT.push_record(record)
+    }
+
+    /// Waits until what's queued has been written to the host, or dropped,
+    /// which takes no longer than WRITE_TIMEOUT a chunk.
+    async fn flushed(&self) {
+        OUTPUT.flushed().await;
+    }
+
+    async fn connection_changed() -> bool {
+        connection_changed().await
     }
 }
 
@@ -137,24 +338,122 @@ async fn driver_task(mut device: UsbDevice<'static, Driver<'static, USB>>) {
     device.run().await;
 }
 
+/// Writes what's queued for USB to the host.
+///
+/// If nobody has the port open (no DTR), or the host isn't reading and a line
+/// doesn't finish within WRITE_TIMEOUT, the line is dropped and counted.
+///
+/// Records are all or nothing. If the host isn't ready for the first USB
+/// packet of one within RECORD_TIMEOUT, nothing is written. Once that has
+/// gone out, the rest is written with the usual timeout, and only a host that
+/// stops reading partway through a record can get part of one.
+///
+#[embassy_executor::task]
+async fn writer_task(mut sender: cdc_acm::Sender<'static, Driver<'static, USB>>) {
+    let mut dropped_lines: u32 = 0;
+    loop {
+        let chunk = OUTPUT.pop().await;
+        let reason = if !sender.dtr() {
+            "not connected"
+        } else if chunk.record {
+            if write_record(&mut sender, &chunk.data).await {
+                continue;
+            }
+            "host not ready for record"
+        } else {
+            let mut writer = CdcWriter::new(&mut sender);
+            let result = with_timeout(WRITE_TIMEOUT, async {
+                writer.write_all(&chunk.data).await?;
+                writer.flush().await
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => continue,
+                Ok(Err(CdcWriterError::Disconnected)) => "disconnected",
+                Ok(Err(CdcWriterError::Other)) => "write error",
+                Err(_) => "host not reading",
+            }
+        };
+        if chunk.record {
+            RECORDS_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        dropped_lines = dropped_lines.wrapping_add(1);
+        info!(
+            "USB output dropped ({=str}), {} lines dropped so far",
+            reason, dropped_lines
+        );
+    }
+}
+
+/// Writes a record, returning false if none of it could be written.
+async fn write_record(
+    sender: &mut cdc_acm::Sender<'static, Driver<'static, USB>>,
+    record: &[u8],
+) -> bool {
+    let (first, rest) = record.split_at(record.len().min(MAX_PACKET_SIZE as usize));
+    let mut writer = CdcWriter::new(sender);
+    // The endpoint only takes the packet once it's free, so if this
+    // times out nothing was sent
+    if !matches!(
+        with_timeout(RECORD_TIMEOUT, writer.write(first)).await,
+        Ok(Ok(_))
+    ) {
+        return false;
+    }
+
+    let result = with_timeout(WRITE_TIMEOUT, async {
+        writer.write_all(rest).await?;
+        writer.flush().await
+    })
+    .await;
+    if !matches!(result, Ok(Ok(()))) {
+        info!("USB record cut short");
+    }
+    true
+}
+
+/// Writes a stream of bytes to the CDC IN endpoint as full-size packets.
+///
+/// A transfer ends with the first short packet, so if the last packet written
+/// was exactly MAX_PACKET_SIZE bytes, flush() sends a zero-length packet to
+/// tell the host the transfer is complete. Without it, some host stacks hold
+/// on to the data until the next write.
+///
 struct CdcWriter<'s, 'a> {
-    class: &'s mut cdc_acm::CdcAcmClass<'a, Driver<'a, USB>>,
+    sender: &'s mut cdc_acm::Sender<'a, Driver<'a, USB>>,
+    needs_zlp: bool,
 }
 
 impl<'s, 'a> CdcWriter<'s, 'a> {
-    fn new(class: &'s mut cdc_acm::CdcAcmClass<'a, Driver<'a, USB>>) -> Self {
-        CdcWriter { class }
+    fn new(sender: &'s mut cdc_acm::Sender<'a, Driver<'a, USB>>) -> Self {
+        CdcWriter {
+            sender,
+            needs_zlp: false,
+        }
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, defmt::Format)]
 pub enum CdcWriterError {
+    Disconnected,
     Other,
 }
 
+impl From<EndpointError> for CdcWriterError {
+    fn from(e: EndpointError) -> Self {
+        match e {
+            EndpointError::Disabled => CdcWriterError::Disconnected,
+            EndpointError::BufferOverflow => CdcWriterError::Other,
+        }
+    }
+}
+
 impl embedded_io::Error for CdcWriterError {
     fn kind(&self) -> embedded_io::ErrorKind {
-        embedded_io::ErrorKind::Other
+        match self {
+            CdcWriterError::Disconnected => embedded_io::ErrorKind::NotConnected,
+            CdcWriterError::Other => embedded_io::ErrorKind::Other,
+        }
     }
 }
 
@@ -164,24 +463,23 @@ impl embedded_io::ErrorType for CdcWriter<'_, '_> {
 
 impl Write for CdcWriter<'_, '_> {
     async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        match self.class.write_packet(buf).await {
-            Ok(_) => Ok(buf.len()),
-            Err(_) => Err(CdcWriterError::Other),
+        if buf.is_empty() {
+            return Ok(0);
         }
-    }
 
-    async fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        let n = buf.len().min(MAX_PACKET_SIZE as usize);
+        // Forget about any pending ZLP if the packet doesn't make it out,
+        // so the next line starts clean.
+        self.needs_zlp = false;
+        self.sender.write_packet(&buf[..n]).await?;
+        self.needs_zlp = n == MAX_PACKET_SIZE as usize;
+        Ok(n)
     }
 
-    async fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
-        let mut buf = buf;
-        for chunk in buf.chunks(MAX_PACKET_SIZE as usize - 1) {
-            match self.write(chunk).await {
-                Ok(0) => core::panic!("write() returned Ok(0)"),
-                Ok(n) => buf = &buf[n..],
-                Err(e) => return Err(e),
-            }
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if self.needs_zlp {
+            self.needs_zlp = false;
+            self.sender.write_packet(&[]).await?;
         }
         Ok(())
     }
@@ -1,2 +1,95 @@
+use aunisoma_protocol::{FirmwareId, PROTOCOL_VERSION};
+
 // TODO: This should be generated by the build system.
 pub const VERSION: &str = "0.0.1";
+
+/// Short stand-in for the commit the firmware was built from, that fits in
+/// a packet. Boards whose hashes match are running the same source, give or
+/// take uncommitted changes. If build.rs couldn't ask git, it's a hash of
+/// VERSION, which only tells versions apart.
+pub const VERSION_HASH: u16 = match COMMIT {
+    Some(commit) => hash(commit.as_bytes()),
+    None => hash(VERSION.as_bytes()),
+};
+
+/// What this board says it's running in PingReply
+pub const FIRMWARE_ID: FirmwareId = FirmwareId {
+    protocol: PROTOCOL_VERSION,
+    hash: VERSION_HASH,
+};
+
+/// The git commit hash, or None if build.rs couldn't ask git
+const COMMIT: Option<&str> = option_env!("AUNISOMA_COMMIT");
+
+/// The cargo features this was built with, comma separated, e.g.
+/// "revE,bus". Only the most specific board revision is listed, since each
+/// implies the ones before it.
+pub const FEATURES: &str = match core::str::from_utf8(&FEATURE_BYTES) {
+    Ok(features) => features,
+    Err(_) => panic!("feature names must be ASCII"),
+};
+
+/// Whether the tree had uncommitted changes, "true" or "false", or None if
+/// build.rs couldn't ask git
+pub const DIRTY: Option<&str> = option_env!("AUNISOMA_DIRTY");
+
+/// When build.rs last ran, in seconds since 1970, or SOURCE_DATE_EPOCH if
+/// that was set
+pub const BUILT: Option<&str> = option_env!("AUNISOMA_BUILT");
+
+const FEATURE_NAMES: [(bool, &str); 6] = [
+    (cfg!(feature = "rev-d"), "revD"),
+    (cfg!(all(feature = "rev-e", not(feature = "rev-f"))), "revE"),
+    (cfg!(feature = "rev-f"), "revF"),
+    (cfg!(feature = "panel-bus"), "bus"),
+    (cfg!(feature = "aux-sensors"), "aux"),
+    (cfg!(feature = "radio-debug"), "radioDebug"),
+];
+
+const FEATURES_LEN: usize = {
+    let mut len = 0;
+    let mut i = 0;
+    while i < FEATURE_NAMES.len() {
+        let (on, name) = FEATURE_NAMES[i];
+        if on {
+            len += name.len() + (len > 0) as usize;
+        }
+        i += 1;
+    }
+    len
+};
+
+const FEATURE_BYTES: [u8; FEATURES_LEN] = {
+    let mut bytes = [0; FEATURES_LEN];
+    let mut len = 0;
+    let mut i = 0;
+    while i < FEATURE_NAMES.len() {
+        let (on, name) = FEATURE_NAMES[i];
+        if on {
+            if len > 0 {
+                bytes[len] = b',';
+                len += 1;
+            }
+            let mut j = 0;
+            while j < name.len() {
+                bytes[len] = name.as_bytes()[j];
+                len += 1;
+                j += 1;
+            }
+        }
+        i += 1;
+    }
+    bytes
+};
+
+/// FNV-1a, folded down to 16 bits
+const fn hash(bytes: &[u8]) -> u16 {
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    ((hash >> 16) ^ (hash & 0xffff)) as u16
+}
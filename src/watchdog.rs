@@ -0,0 +1,109 @@
+use core::pin::pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::{Format, error, warn};
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::board;
+
+const PET_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The IWDG resets us after 1 s without a pet. If pets get this far apart,
+/// something is holding up the executor, and we're close to a reset.
+const MAX_PET_GAP: Duration = Duration::from_millis(800);
+
+/// How long a subsystem can stay busy without checking in before we let the
+/// watchdog reset us.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parts of the firmware that check in with the watchdog task.
+///
+/// A subsystem is either idle, waiting for something that may legitimately
+/// never happen (like a command from the host), or busy. While busy, it has to
+/// check in at least every BUSY_TIMEOUT, or the watchdog resets the board.
+///
+#[derive(Debug, Clone, Copy, Format)]
+pub enum Subsystem {
+    /// Handling a console command
+    Commands = 0,
+    /// Handling a packet from the panel bus or radio
+    Packets = 1,
+}
+
+const SUBSYSTEMS: [Subsystem; 2] = [Subsystem::Commands, Subsystem::Packets];
+
+/// Time of the last check-in in ms, or IDLE.
+static HEARTBEATS: [AtomicU32; SUBSYSTEMS.len()] =
+    [const { AtomicU32::new(IDLE) }; SUBSYSTEMS.len()];
+const IDLE: u32 = 0;
+
+fn now_ms() -> u32 {
+    // Never IDLE, so a check-in at the wrong moment doesn't look idle
+    (Instant::now().as_millis() as u32).max(1)
+}
+
+/// Marks the subsystem busy until the returned guard is dropped.
+pub fn busy(subsystem: Subsystem) -> Busy {
+    check_in(subsystem);
+    Busy(subsystem)
+}
+
+/// Tells the watchdog a busy subsystem is still making progress.
+pub fn check_in(subsystem: Subsystem) {
+    HEARTBEATS[subsystem as usize].store(now_ms(), Ordering::Release);
+}
+
+/// Runs `fut`, checking in for the subsystem every PET_INTERVAL until it's
+/// done. For long command bodies made of many awaits, so none of them has to
+/// remember to check in.
+///
+/// Only for work that ends on its own, or has a time limit of its own, because
+/// it can't be caught if it hangs.
+///
+pub async fn with_watchdog<F: Future>(subsystem: Subsystem, fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    loop {
+        check_in(subsystem);
+        if let Either::First(output) = select(fut.as_mut(), Timer::after(PET_INTERVAL)).await {
+            return output;
+        }
+    }
+}
+
+pub struct Busy(Subsystem);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        HEARTBEATS[self.0 as usize].store(IDLE, Ordering::Release);
+    }
+}
+
+/// Pets the watchdog as long as no subsystem has been busy for too long
+/// without checking in. If one has, stop petting and let the IWDG reset us.
+///
+/// This task is the only thing that pets the IWDG, and it keeps its own
+/// schedule, so there's no shared deadline for other tasks to race on. They
+/// only store their check-in times, each a single atomic store.
+///
+#[embassy_executor::task]
+pub async fn watchdog_task() {
+    let mut last_pet = Instant::now();
+    loop {
+        let gap = last_pet.elapsed();
+        if gap > MAX_PET_GAP {
+            warn!("Watchdog pet {} ms late", (gap - PET_INTERVAL).as_millis());
+        }
+        board::pet_the_watchdog();
+        last_pet = Instant::now();
+        Timer::after(PET_INTERVAL).await;
+
+        let now = now_ms();
+        for subsystem in SUBSYSTEMS {
+            let last = HEARTBEATS[subsystem as usize].load(Ordering::Acquire);
+            if last != IDLE && now.wrapping_sub(last) as u64 > BUSY_TIMEOUT.as_millis() {
+                error!("{:?} is stuck, letting the watchdog reset", subsystem);
+                return;
+            }
+        }
+    }
+}